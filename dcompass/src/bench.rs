@@ -0,0 +1,452 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Replay queries against a running instance or the router directly, and report the latencies.
+
+use super::DcompassRouter;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use tokio::{net::UdpSocket, time::timeout};
+
+// Maximum size of UDP responses, so that none is truncated whatever the queries replayed advertise
+const MAX_LEN: usize = 65535;
+
+#[derive(Debug, StructOpt)]
+pub struct BenchOpts {
+    /// Path to a list of queries, one `<qname> [qtype]` per line. `qtype` defaults to `A`.
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless = "pcap",
+        conflicts_with = "pcap"
+    )]
+    qnames: Option<PathBuf>,
+
+    /// Path to a pcap file, from which DNS queries sent over UDP port 53 are extracted.
+    #[structopt(short, long, parse(from_os_str))]
+    pcap: Option<PathBuf>,
+
+    /// Send queries to a running instance at the address instead of going through the router built from the configuration.
+    #[structopt(short, long)]
+    target: Option<SocketAddr>,
+
+    /// Queries sent per second.
+    #[structopt(long, default_value = "100")]
+    qps: u32,
+
+    /// Total number of queries to send. The list of queries is replayed in cycles. Defaults to the length of the list.
+    #[structopt(short = "n", long)]
+    count: Option<usize>,
+
+    /// Timeout of a single query in milliseconds.
+    #[structopt(long, default_value = "5000")]
+    timeout: u64,
+}
+
+impl BenchOpts {
+    /// Whether the router built from the configuration is needed.
+    pub fn needs_router(&self) -> bool {
+        self.target.is_none()
+    }
+}
+
+enum Outcome {
+    Answered(Rcode),
+    Failed,
+    TimedOut,
+}
+
+/// Statistics collected from a benchmark run.
+pub struct Report {
+    latencies: Vec<Duration>,
+    rcodes: BTreeMap<String, usize>,
+    failed: usize,
+    timed_out: usize,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn new(mut latencies: Vec<Duration>, elapsed: Duration) -> Self {
+        latencies.sort_unstable();
+        Self {
+            latencies,
+            rcodes: BTreeMap::new(),
+            failed: 0,
+            timed_out: 0,
+            elapsed,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.latencies.len() + self.failed + self.timed_out
+    }
+
+    /// Latency at the given percentile (`0.0..=100.0`) among the answered queries, using the nearest-rank method.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+
+    /// Ratio of queries that failed, timed out, or got SERVFAIL.
+    pub fn error_ratio(&self) -> f64 {
+        let servfail = self
+            .rcodes
+            .get(&Rcode::ServFail.to_string())
+            .copied()
+            .unwrap_or(0);
+        match self.total() {
+            0 => 0.0,
+            total => (self.failed + self.timed_out + servfail) as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} queries in {:.2?} ({:.1} qps)",
+            self.total(),
+            self.elapsed,
+            self.total() as f64 / self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "answered: {}, failed: {}, timed out: {}, error ratio: {:.2}%",
+            self.latencies.len(),
+            self.failed,
+            self.timed_out,
+            self.error_ratio() * 100.0
+        )?;
+        for (rcode, n) in &self.rcodes {
+            writeln!(f, "  {}: {}", rcode, n)?;
+        }
+        if let (Some(p50), Some(p90), Some(p99), Some(max)) = (
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last(),
+        ) {
+            write!(
+                f,
+                "latency p50: {:.2?}, p90: {:.2?}, p99: {:.2?}, max: {:.2?}",
+                p50, p90, p99, max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a list of queries in the form of `<qname> [qtype]`. Empty lines and lines starting with `#` are skipped.
+pub fn parse_qnames(content: &str) -> Result<Vec<Message<Bytes>>> {
+    let mut queries = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        // `line` is not empty, there is at least one field.
+        let qname = Dname::<Bytes>::from_str(fields.next().unwrap())
+            .map_err(|e| anyhow!("line {}: invalid qname: {}", n + 1, e))?;
+        let qtype = match fields.next() {
+            Some(t) => Rtype::from_str(t).map_err(|e| anyhow!("line {}: {}", n + 1, e))?,
+            None => Rtype::A,
+        };
+
+        let mut builder = MessageBuilder::from_target(BytesMut::new())?;
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((&qname, qtype))?;
+        queries.push(builder.into_message());
+    }
+    Ok(queries)
+}
+
+/// Extract DNS queries sent over UDP to port 53 from a pcap file. Ethernet, raw IP, and BSD loopback link types are supported.
+pub fn parse_pcap(content: &[u8]) -> Result<Vec<Message<Bytes>>> {
+    Ok(parse_pcap_clients(content)?
+        .into_iter()
+        .map(|(_, query)| query)
+        .collect())
+}
+
+/// Same as `parse_pcap`, along with the address of the client sending each query.
+pub fn parse_pcap_clients(content: &[u8]) -> Result<Vec<(IpAddr, Message<Bytes>)>> {
+    if content.len() < 24 {
+        bail!("pcap file is too short");
+    }
+    let le = match content[0..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => true,
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => false,
+        _ => bail!("not a pcap file (pcapng is not supported)"),
+    };
+    let u32_at = |b: &[u8], i: usize| {
+        let bytes = [b[i], b[i + 1], b[i + 2], b[i + 3]];
+        if le {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let linktype = u32_at(content, 20);
+
+    let mut queries = Vec::new();
+    let mut pos = 24;
+    while pos + 16 <= content.len() {
+        let caplen = u32_at(content, pos + 8) as usize;
+        let start = pos + 16;
+        let end = start
+            .checked_add(caplen)
+            .filter(|&end| end <= content.len())
+            .ok_or_else(|| anyhow!("truncated packet record at offset {}", pos))?;
+        pos = end;
+
+        let ip = match linktype {
+            // Ethernet
+            1 if caplen >= 14 => {
+                match u16::from_be_bytes([content[start + 12], content[start + 13]]) {
+                    0x0800 | 0x86dd => &content[start + 14..end],
+                    _ => continue,
+                }
+            }
+            // BSD loopback
+            0 if caplen >= 4 => &content[start + 4..end],
+            // Raw IP
+            101 | 228 | 229 => &content[start..end],
+            1 | 0 => continue,
+            t => bail!("unsupported link type {}", t),
+        };
+
+        if let Some((src, payload)) = udp_payload_to_dns(ip) {
+            // Only keep well-formed queries
+            if let Ok(msg) = Message::from_octets(Bytes::copy_from_slice(payload)) {
+                if !msg.header().qr() && msg.first_question().is_some() {
+                    queries.push((src, msg));
+                }
+            }
+        }
+    }
+    Ok(queries)
+}
+
+// Get the source address and the payload of the UDP datagram destined to port 53 in the IP packet.
+fn udp_payload_to_dns(ip: &[u8]) -> Option<(IpAddr, &[u8])> {
+    let (src, udp) = match ip.first()? >> 4 {
+        4 => {
+            let ihl = ((ip[0] & 0x0f) as usize) * 4;
+            // Protocol must be UDP
+            if ip.len() < 20 || ip[9] != 17 {
+                return None;
+            }
+            let src: [u8; 4] = ip[12..16].try_into().ok()?;
+            (IpAddr::from(src), ip.get(ihl..)?)
+        }
+        // Extension headers are not handled
+        6 if ip.len() >= 40 && ip[6] == 17 => {
+            let src: [u8; 16] = ip[8..24].try_into().ok()?;
+            (IpAddr::from(src), &ip[40..])
+        }
+        _ => return None,
+    };
+    if udp.len() < 8 || u16::from_be_bytes([udp[2], udp[3]]) != 53 {
+        return None;
+    }
+    Some((src, udp.get(8..)?))
+}
+
+async fn send_udp(target: SocketAddr, query: &Message<Bytes>) -> Result<Rcode> {
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    })
+    .await?;
+    socket.connect(target).await?;
+    socket.send(query.as_slice()).await?;
+    let mut buf = BytesMut::new();
+    buf.resize(MAX_LEN, 0);
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let resp = Message::from_octets(Bytes::copy_from_slice(&buf[..len]))?;
+        // Ignore stray responses
+        if resp.header().id() == query.header().id() {
+            return Ok(resp.header().rcode());
+        }
+    }
+}
+
+/// Run the benchmark with the options given. `router` is used if no target is given.
+pub async fn run(opts: BenchOpts, router: Option<DcompassRouter>) -> Result<Report> {
+    let queries = if let Some(path) = &opts.pcap {
+        parse_pcap(
+            &tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read the pcap file: {}", path.display()))?,
+        )?
+    } else if let Some(path) = &opts.qnames {
+        parse_qnames(
+            &tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read the qname list: {}", path.display()))?,
+        )?
+    } else {
+        unreachable!("either `qnames` or `pcap` is required")
+    };
+    if queries.is_empty() {
+        bail!("no query found to send");
+    }
+    if opts.qps == 0 {
+        bail!("`qps` must be positive");
+    }
+
+    let count = opts.count.unwrap_or(queries.len());
+    let per_query = Duration::from_millis(opts.timeout);
+    let router = router.map(Arc::new);
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / opts.qps);
+
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(count);
+    for (id, query) in queries.iter().cycle().take(count).enumerate() {
+        interval.tick().await;
+
+        // Give each query a distinct ID so that responses can be told apart.
+        let mut query = Message::from_octets(BytesMut::from(query.as_slice())).unwrap();
+        query.header_mut().set_id(id as u16);
+        let query = Message::from_octets(query.into_octets().freeze()).unwrap();
+
+        let router = router.clone();
+        let target = opts.target;
+        tasks.push(tokio::spawn(async move {
+            let sent = Instant::now();
+            let res = match (&router, target) {
+                (_, Some(target)) => timeout(per_query, send_udp(target, &query)).await,
+                (Some(router), None) => {
+                    timeout(per_query, async {
+                        Ok::<_, anyhow::Error>(
+                            router.resolve(query.clone(), None).await?.header().rcode(),
+                        )
+                    })
+                    .await
+                }
+                (None, None) => unreachable!("router is required if no target is given"),
+            };
+            let outcome = match res {
+                Ok(Ok(rcode)) => Outcome::Answered(rcode),
+                Ok(Err(e)) => {
+                    log::debug!("query failed: {}", e);
+                    Outcome::Failed
+                }
+                Err(_) => Outcome::TimedOut,
+            };
+            (outcome, sent.elapsed())
+        }));
+    }
+
+    let mut outcomes = Vec::with_capacity(count);
+    for task in tasks {
+        outcomes.push(task.await?);
+    }
+    let mut report = Report::new(
+        outcomes
+            .iter()
+            .filter(|(o, _)| matches!(o, Outcome::Answered(_)))
+            .map(|(_, d)| *d)
+            .collect(),
+        start.elapsed(),
+    );
+    for (outcome, _) in outcomes {
+        match outcome {
+            Outcome::Answered(rcode) => *report.rcodes.entry(rcode.to_string()).or_default() += 1,
+            Outcome::Failed => report.failed += 1,
+            Outcome::TimedOut => report.timed_out += 1,
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_pcap, parse_pcap_clients, parse_qnames, Report};
+    use domain::base::Rtype;
+    use std::time::Duration;
+
+    #[test]
+    fn qnames() {
+        let queries = parse_qnames("# comment\nexample.com\n\nexample.org AAAA\n").unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[1].first_question().unwrap().qtype(), Rtype::Aaaa);
+        assert!(queries[0].header().rd());
+        assert!(parse_qnames("example.com NOTATYPE").is_err());
+    }
+
+    #[test]
+    fn pcap() {
+        let query = &parse_qnames("example.com").unwrap()[0];
+        let dns = query.as_slice();
+
+        // Little-endian pcap header with raw IP link type
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend([0; 8]);
+        pcap.extend(65535u32.to_le_bytes());
+        pcap.extend(101u32.to_le_bytes());
+
+        // IPv4 header + UDP header + DNS query
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend([0x30, 0x39, 0, 53, 0, (8 + dns.len()) as u8, 0, 0]);
+        packet.extend(dns);
+
+        pcap.extend([0; 8]);
+        pcap.extend((packet.len() as u32).to_le_bytes());
+        pcap.extend((packet.len() as u32).to_le_bytes());
+        pcap.extend(&packet);
+
+        let queries = parse_pcap(&pcap).unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].as_slice(), dns);
+        assert_eq!(
+            parse_pcap_clients(&pcap).unwrap()[0].0,
+            std::net::IpAddr::from([10, 0, 0, 1])
+        );
+    }
+
+    #[test]
+    fn percentiles() {
+        let report = Report::new(
+            (1..=100).rev().map(Duration::from_millis).collect(),
+            Duration::from_secs(1),
+        );
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(report.error_ratio(), 0.0);
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bundles of the configuration along with the files it references, e.g. rule files and the lists downloaded, packed into a single tar archive. Bundles hold no binaries, so the same bundle runs on routers of any architecture.
+
+use anyhow::{anyhow, bail, Context, Result};
+use droute::utils::SubscriptionBuilder;
+use serde_yaml::Value;
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+use structopt::StructOpt;
+
+// Name of the configuration in the archive
+const CONFIG: &str = "config.yaml";
+
+// Directory of the files referenced in the archive
+const FILES: &str = "files";
+
+const BLOCK: usize = 512;
+
+#[derive(Debug, StructOpt)]
+pub struct BundleOpts {
+    /// Path to the archive written, which can be given to `-c` to run from it directly.
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+}
+
+impl BundleOpts {
+    /// Path to the archive written.
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+}
+
+// Replace the string literals in the script, e.g. `"cn.txt"` in `Domain::new().add_file("cn.txt")`.
+fn rewrite_literals(script: &str, f: &mut impl FnMut(&str) -> Option<String>) -> String {
+    let mut parts: Vec<String> = script.split('"').map(str::to_string).collect();
+    // Parts at odd indices are enclosed in quotes
+    for part in parts.iter_mut().skip(1).step_by(2) {
+        if let Some(new) = f(part) {
+            *part = new;
+        }
+    }
+    parts.join("\"")
+}
+
+// Replace the strings in the configuration with what `f` gives, including the string literals in the scripts. Keys are left alone.
+fn rewrite(value: &mut Value, f: &mut impl FnMut(&str) -> Option<String>) {
+    match value {
+        Value::String(s) => {
+            if let Some(new) = f(s.as_str()) {
+                *s = new;
+            } else if s.contains('"') {
+                *s = rewrite_literals(s, f);
+            }
+        }
+        Value::Sequence(v) => v.iter_mut().for_each(|v| rewrite(v, f)),
+        Value::Mapping(m) => m.iter_mut().for_each(|(_, v)| rewrite(v, f)),
+        Value::Tagged(t) => rewrite(&mut t.value, f),
+        _ => (),
+    }
+}
+
+// Write a file into the archive as a ustar entry.
+fn append(archive: &mut Vec<u8>, name: &str, content: &[u8]) -> Result<()> {
+    if name.len() > 100 {
+        bail!("name too long for the archive: {}", name);
+    }
+    let mut header = [0_u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is taken with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+
+    archive.extend(header);
+    archive.extend(content);
+    archive.resize(archive.len() + (BLOCK - content.len() % BLOCK) % BLOCK, 0);
+    Ok(())
+}
+
+// Parse an octal field of the header.
+fn octal(field: &[u8]) -> Option<usize> {
+    let s = std::str::from_utf8(field).ok()?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    usize::from_str_radix(s, 8).ok()
+}
+
+// Read the regular files in the archive. Names escaping the archive, e.g. `../x` or `/x`, are rejected.
+fn entries(archive: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let mut files = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = archive.get(pos..pos + BLOCK) {
+        // Two zero blocks mark the end, one is enough for us
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let mut summed = header.to_vec();
+        summed[148..156].copy_from_slice(b"        ");
+        if octal(&header[148..156]) != Some(summed.iter().map(|&b| b as usize).sum()) {
+            bail!("corrupted archive: checksum mismatch at offset {}", pos);
+        }
+        let field = |r: std::ops::Range<usize>| {
+            let f = &header[r];
+            String::from_utf8_lossy(&f[..f.iter().position(|&b| b == 0).unwrap_or(f.len())])
+                .into_owned()
+        };
+        let mut name = field(0..100);
+        let prefix = field(345..500);
+        if !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let size = octal(&header[124..136])
+            .ok_or_else(|| anyhow!("corrupted archive: invalid size of `{}`", name))?;
+        let content = archive
+            .get(pos + BLOCK..pos + BLOCK + size)
+            .ok_or_else(|| anyhow!("corrupted archive: `{}` is truncated", name))?;
+        if matches!(header[156], b'0' | 0) {
+            if !Path::new(&name)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                bail!("`{}` in the archive is outside of it", name);
+            }
+            files.push((name, content));
+        }
+        pos += BLOCK + size + (BLOCK - size % BLOCK) % BLOCK;
+    }
+    Ok(files)
+}
+
+/// Whether the content is a tar archive, e.g. a bundle rather than a configuration file.
+pub fn is_bundle(content: &[u8]) -> bool {
+    content.get(257..262) == Some(b"ustar")
+}
+
+/// Pack the configuration along with the files it references into a single archive. Strings in the configuration naming existing files, including the string literals in the scripts, are taken as the files referenced, and so are the lists cached. Paths are relative to the current directory. Returns the paths of the files packed besides the configuration.
+pub fn export(config: &str, opts: &BundleOpts) -> Result<Vec<String>> {
+    let mut value: Value =
+        serde_yaml::from_str(config).context("Failed to parse the configuration file")?;
+
+    // Cached lists are referenced by their names rather than the paths unless given
+    if let Some(Value::Mapping(lists)) = value.get_mut("lists") {
+        for (name, list) in lists.iter_mut() {
+            if let (Some(name), Value::Mapping(list)) = (name.as_str(), list) {
+                match SubscriptionBuilder::cache_path(name) {
+                    Some(cache) if !list.contains_key("cache") && cache.is_file() => {
+                        list.insert("cache".into(), cache.to_string_lossy().into_owned().into());
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    let mut archive = Vec::new();
+    // Files by their paths in the configuration, the same file referenced twice is packed once
+    let mut packed: HashMap<String, String> = HashMap::new();
+    let mut paths = Vec::new();
+    let mut error = None;
+    rewrite(&mut value, &mut |s| {
+        if s.is_empty() || s.contains('\n') || !Path::new(s).is_file() || error.is_some() {
+            return None;
+        }
+        if let Some(name) = packed.get(s) {
+            return Some(name.clone());
+        }
+        let base = Path::new(s).file_name()?.to_string_lossy();
+        let mut name = format!("{}/{}-{}", FILES, packed.len(), base);
+        if name.len() > 100 {
+            name = format!("{}/{}", FILES, packed.len());
+        }
+        match std::fs::read(s) {
+            Ok(content) => {
+                if let Err(e) = append(&mut archive, &name, &content) {
+                    error = Some(e);
+                }
+            }
+            Err(e) => {
+                error = Some(anyhow!("Failed to read `{}`: {}", s, e));
+                return None;
+            }
+        }
+        paths.push(s.to_string());
+        packed.insert(s.to_string(), name.clone());
+        Some(name)
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    append(
+        &mut archive,
+        CONFIG,
+        serde_yaml::to_string(&value)?.as_bytes(),
+    )?;
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+
+    std::fs::write(&opts.output, archive)
+        .with_context(|| format!("Failed to write the bundle: {}", opts.output.display()))?;
+    Ok(paths)
+}
+
+/// Unpack the bundle into a new directory under the temporary directory, which only the user may access, and return the configuration in it with the paths of the files packed pointing to where they are unpacked.
+pub fn open(archive: &[u8]) -> Result<String> {
+    let files = entries(archive)?;
+    let config = files
+        .iter()
+        .find(|(name, _)| name == CONFIG)
+        .ok_or_else(|| anyhow!("`{}` not found in the bundle", CONFIG))?
+        .1;
+    let mut value: Value = serde_yaml::from_slice(config)
+        .with_context(|| format!("Failed to parse `{}` in the bundle", CONFIG))?;
+
+    // Created afresh under a random name, and kept as the lists cached there are updated on schedule
+    let dir = tempfile::Builder::new()
+        .prefix("dcompass-bundle-")
+        .tempdir()
+        .context("Failed to create the directory to unpack the bundle into")?
+        .into_path();
+    let mut paths = HashMap::new();
+    for (name, content) in &files {
+        if name == CONFIG {
+            continue;
+        }
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to unpack `{}`", path.display()))?;
+        paths.insert(name.as_str(), path.to_string_lossy().into_owned());
+    }
+    rewrite(&mut value, &mut |s| paths.get(s).cloned());
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, entries, export, is_bundle, open, BundleOpts};
+
+    #[test]
+    fn archive() {
+        let mut archive = Vec::new();
+        append(&mut archive, "a.txt", b"hello").unwrap();
+        append(&mut archive, "files/b", &[7; 600]).unwrap();
+        archive.resize(archive.len() + 1024, 0);
+        assert!(is_bundle(&archive));
+        assert_eq!(archive.len() % 512, 0);
+
+        let files = entries(&archive).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], ("a.txt".to_string(), &b"hello"[..]));
+        assert_eq!(files[1].1, &[7; 600][..]);
+
+        // Corrupted
+        let mut corrupted = archive.clone();
+        corrupted[0] = b'b';
+        assert!(entries(&corrupted).is_err());
+
+        // Escaping the directory unpacked to
+        let mut evil = Vec::new();
+        append(&mut evil, "../evil", b"").unwrap();
+        assert!(entries(&evil).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let rules = dir.join("rules.txt");
+        std::fs::write(&rules, "example.com").unwrap();
+        let rules = rules.to_string_lossy();
+        let config = format!(
+            "files: [\"{0}\"]\nscript: |\n  Domain::new().add_file(\"{0}\")\nother: not a file\n",
+            rules
+        );
+
+        let output = dir.join("bundle.tar");
+        let opts = BundleOpts {
+            output: output.clone(),
+        };
+        assert_eq!(export(&config, &opts).unwrap(), vec![rules.to_string()]);
+
+        let archive = std::fs::read(&output).unwrap();
+        assert!(is_bundle(&archive));
+        let opened: serde_yaml::Value = serde_yaml::from_str(&open(&archive).unwrap()).unwrap();
+        let path = opened["files"][0].as_str().unwrap();
+        assert_ne!(path, rules);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "example.com");
+        assert!(opened["script"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("add_file(\"{}\")", path)));
+        assert_eq!(opened["other"].as_str(), Some("not a file"));
+
+        // Every opening unpacks into a new directory
+        let reopened: serde_yaml::Value = serde_yaml::from_str(&open(&archive).unwrap()).unwrap();
+        assert_ne!(reopened["files"][0].as_str().unwrap(), path);
+
+        for path in [path, reopened["files"][0].as_str().unwrap()] {
+            std::fs::remove_dir_all(std::path::Path::new(path).ancestors().nth(2).unwrap())
+                .unwrap();
+        }
+    }
+}
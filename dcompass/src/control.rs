@@ -0,0 +1,312 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! HTTP API to inspect and update the running instance. It is not authenticated, so it should only be bound to trusted addresses.
+
+use super::{parser::ControlServer, top};
+use anyhow::{Context, Result};
+use droute::{errors::UtilsError, utils::DomainList, Offline};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::Infallible, future::Future, time::Duration};
+
+// Number of entries returned if not specified
+const DEFAULT_N: usize = 10;
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn json(v: Value) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(v.to_string()))
+        .unwrap()
+}
+
+fn counts(top: Vec<(String, u32)>) -> Value {
+    top.into_iter()
+        .map(|(name, count)| json!({ "name": name, "count": count }))
+        .collect()
+}
+
+// Add the domain given to or remove it from the list registered under `name`
+async fn edit(name: &str, add: bool, params: &HashMap<String, String>) -> Response<Body> {
+    let (list, domain) = match (DomainList::get(name), params.get("domain")) {
+        (Some(list), Some(domain)) => (list, domain.clone()),
+        (None, _) => return empty(StatusCode::NOT_FOUND),
+        (_, None) => return empty(StatusCode::BAD_REQUEST),
+    };
+    // Changes may be written back to the file
+    let res = tokio::task::spawn_blocking(move || {
+        if add {
+            list.add(&domain)
+        } else {
+            list.remove(&domain)
+        }
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => empty(StatusCode::NO_CONTENT),
+        Ok(Err(e @ (UtilsError::InvalidDomain(_) | UtilsError::FromStrError(_)))) => {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+        // Exceptions to the rules listed can't be written back to the file
+        Ok(Err(e @ UtilsError::NotListed(_))) => Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+        Ok(Err(e)) => {
+            warn!("failed to update list `{}`: {}", name, e);
+            empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => empty(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// Turn offline mode on or off as `enabled` says
+fn set_offline(offline: &Offline, params: &HashMap<String, String>) -> Response<Body> {
+    match params.get("enabled").map(|e| e.parse()) {
+        Some(Ok(enabled)) => {
+            info!("offline mode turned {}", if enabled { "on" } else { "off" });
+            offline.set(enabled);
+            empty(StatusCode::NO_CONTENT)
+        }
+        _ => empty(StatusCode::BAD_REQUEST),
+    }
+}
+
+async fn handle(req: Request<Body>, offline: Offline) -> Response<Body> {
+    let params: HashMap<_, _> =
+        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let segments: Vec<_> = req.uri().path().trim_matches('/').split('/').collect();
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["stats", stats]) => {
+            let n = match params.get("n").map(|n| n.parse()) {
+                Some(Ok(n)) => n,
+                Some(Err(_)) => return empty(StatusCode::BAD_REQUEST),
+                None => DEFAULT_N,
+            };
+            match *stats {
+                "top-domains" => json(counts(top::domains(n))),
+                "top-clients" => json(counts(top::clients(n))),
+                _ => empty(StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::GET, ["offline"]) => json(json!({ "offline": offline.get() })),
+        (&Method::POST, ["offline"]) => set_offline(&offline, &params),
+        (&Method::POST, ["lists", name, "add"]) => edit(name, true, &params).await,
+        (&Method::POST, ["lists", name, "remove"]) => edit(name, false, &params).await,
+        (_, ["stats", _]) | (_, ["offline"]) | (_, ["lists", _, "add" | "remove"]) => {
+            empty(StatusCode::METHOD_NOT_ALLOWED)
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Start collecting the statistics, bind to the address configured, and return the server, which runs until it fails. `offline` is the switch of offline mode the API flips.
+pub fn bind(
+    config: ControlServer,
+    offline: Offline,
+) -> Result<impl Future<Output = hyper::Result<()>>> {
+    top::init(config.top_size, Duration::from_secs(config.top_window));
+    let make_svc = make_service_fn(move |_| {
+        let offline = offline.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let offline = offline.clone();
+                async move { Ok::<_, Infallible>(handle(req, offline).await) }
+            }))
+        }
+    });
+    Ok(Server::try_bind(&config.address)
+        .with_context(|| format!("failed to bind to {}", config.address))?
+        .serve(make_svc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, top};
+    use bytes::BytesMut;
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use droute::{
+        utils::{Domain, DomainList},
+        Offline,
+    };
+    use hyper::{Body, Method, Request, StatusCode};
+    use serde_json::Value;
+    use std::{fs, str::FromStr, time::Duration};
+
+    async fn call(method: Method, uri: &str, offline: &Offline) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let resp = handle(req, offline.clone()).await;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn names(body: &str) -> Vec<String> {
+        serde_json::from_str::<Value>(body)
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn offline() {
+        let offline = Offline::default();
+        let (status, body) = call(Method::GET, "/offline", &offline).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"offline":false}"#);
+
+        let (status, _) = call(Method::POST, "/offline?enabled=true", &offline).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(offline.get());
+        let (_, body) = call(Method::GET, "/offline", &offline).await;
+        assert_eq!(body, r#"{"offline":true}"#);
+
+        let (status, _) = call(Method::POST, "/offline?enabled=maybe", &offline).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(offline.get());
+        let (status, _) = call(Method::PUT, "/offline", &offline).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let offline = Offline::default();
+        top::init(100, Duration::from_secs(600));
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((
+                Dname::<bytes::Bytes>::from_str("Top.Example").unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        let query = builder.into_message();
+        for _ in 0..10 {
+            top::record([192, 0, 2, 1].into(), &query);
+        }
+
+        let (status, body) = call(Method::GET, "/stats/top-domains?n=100", &offline).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(names(&body).contains(&"top.example".to_string()));
+        let (status, body) = call(Method::GET, "/stats/top-clients", &offline).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(names(&body).contains(&"192.0.2.1".to_string()));
+
+        let (status, _) = call(Method::GET, "/stats/top-domains?n=all", &offline).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(Method::GET, "/stats/top-types", &offline).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(Method::POST, "/stats/top-domains", &offline).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn lists() {
+        let offline = Offline::default();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ads.txt");
+        fs::write(&path, "ads.example\n").unwrap();
+        let mut domain = Domain::new();
+        domain.add_qname("ads.example").unwrap();
+        let list = DomainList::register("control-ads", domain, Some(path.clone()));
+        let contains = |name| list.contains(&Dname::from_str(name).unwrap());
+
+        let (status, _) = call(
+            Method::POST,
+            "/lists/control-ads/add?domain=*.bad.example",
+            &offline,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(contains("www.bad.example"));
+        assert!(!contains("bad.example"));
+        // Added once only, with the wildcard kept
+        call(
+            Method::POST,
+            "/lists/control-ads/add?domain=*.bad.example",
+            &offline,
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "ads.example\n*.bad.example\n"
+        );
+
+        // Exceptions to the rules listed can't be written back
+        let (status, body) = call(
+            Method::POST,
+            "/lists/control-ads/remove?domain=www.ads.example",
+            &offline,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("www.ads.example"));
+        assert!(contains("www.ads.example"));
+
+        let (status, _) = call(
+            Method::POST,
+            "/lists/control-ads/remove?domain=ads.example",
+            &offline,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!contains("www.ads.example"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "*.bad.example\n");
+
+        let (status, _) = call(
+            Method::POST,
+            "/lists/control-ads/add?domain=not_a_domain",
+            &offline,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(Method::POST, "/lists/control-ads/add", &offline).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(
+            Method::POST,
+            "/lists/nonexist/add?domain=bad.example",
+            &offline,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(Method::GET, "/lists/control-ads/add", &offline).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
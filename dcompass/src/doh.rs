@@ -0,0 +1,316 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNS over HTTPS (RFC 8484) frontend, which also serves the JSON API used by Google and Cloudflare.
+
+use super::{
+    parser::DohServer,
+    query_log,
+    tls::{self, Acceptor},
+    top, DcompassRouter,
+};
+use anyhow::{Context, Result};
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{octets::ParseError, Dname, Message, MessageBuilder, RecordSection, Rtype},
+    rdata::AllRecordData,
+};
+use droute::{
+    inbound::{self, Inbound},
+    padding::{self, Padding},
+    QueryContext,
+};
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use hyper::{
+    body::HttpBody,
+    header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE},
+    server::conn::{AddrStream, Http},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap, convert::Infallible, net::IpAddr, str::FromStr, sync::Arc, time::Instant,
+};
+use tracing::Instrument;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+
+// Maximum size of a DNS message over HTTP
+const MAX_LEN: u64 = 65535;
+
+// Clients may or may not pad the `dns` parameter despite that RFC 8484 asks them not to.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+fn error(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// The minimum TTL among the answers, which is used as the max age of the HTTP response.
+fn min_ttl(msg: &Message<Bytes>) -> Option<u32> {
+    msg.answer()
+        .ok()?
+        .filter_map(|r| r.ok().map(|r| r.ttl()))
+        .min()
+}
+
+fn records(section: RecordSection<&'_ Bytes>) -> std::result::Result<Vec<Value>, ParseError> {
+    let mut records = Vec::new();
+    for item in section {
+        let item = item?;
+        // EDNS is a property of the HTTP transport in the JSON API
+        if item.rtype() == Rtype::Opt {
+            continue;
+        }
+        if let Some(record) = item.into_record::<AllRecordData<_, _>>()? {
+            records.push(json!({
+                "name": format!("{}.", record.owner()),
+                "type": record.rtype().to_int(),
+                "TTL": record.ttl(),
+                "data": record.data().to_string(),
+            }));
+        }
+    }
+    Ok(records)
+}
+
+/// Convert the response into the JSON format used by Google and Cloudflare.
+pub fn to_json(msg: &Message<Bytes>) -> std::result::Result<Value, ParseError> {
+    let header = msg.header();
+    let mut json = json!({
+        "Status": header.rcode().to_int(),
+        "TC": header.tc(),
+        "RD": header.rd(),
+        "RA": header.ra(),
+        "AD": header.ad(),
+        "CD": header.cd(),
+        "Question": msg.question().map(|q| {
+            let q = q?;
+            Ok(json!({"name": format!("{}.", q.qname()), "type": q.qtype().to_int()}))
+        }).collect::<std::result::Result<Vec<_>, ParseError>>()?,
+    });
+    let answer = records(msg.answer()?)?;
+    if !answer.is_empty() {
+        json["Answer"] = answer.into();
+    }
+    let authority = records(msg.authority()?)?;
+    if !authority.is_empty() {
+        json["Authority"] = authority.into();
+    }
+    let additional = records(msg.additional()?)?;
+    if !additional.is_empty() {
+        json["Additional"] = additional.into();
+    }
+    Ok(json)
+}
+
+/// Build the query from the `name` and `type` parameters of the JSON API. `type` can be either a mnemonic or a number.
+pub fn json_query(name: &str, qtype: Option<&str>) -> Option<Message<Bytes>> {
+    let qtype = match qtype {
+        None => Rtype::A,
+        Some(t) => match t.parse::<u16>() {
+            Ok(n) => Rtype::from_int(n),
+            Err(_) => Rtype::from_str(t).ok()?,
+        },
+    };
+    let qname = Dname::<Bytes>::from_str(name).ok()?;
+
+    let mut builder = MessageBuilder::from_target(BytesMut::new()).ok()?;
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&qname, qtype)).ok()?;
+    Some(builder.into_message())
+}
+
+async fn resolve(
+    router: &DcompassRouter,
+    query: Message<Bytes>,
+    ip: IpAddr,
+) -> Option<Message<Bytes>> {
+    let start = Instant::now();
+    top::record(ip, &query);
+    match router
+        .resolve(query.clone(), Some(QueryContext { ip }))
+        .instrument(tracing::info_span!("query", protocol = "doh", client = %ip))
+        .await
+    {
+        Ok(resp) => {
+            query_log::record(ip, &query, &resp, start.elapsed());
+            Some(resp)
+        }
+        Err(e) => {
+            warn!("handling query failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn wire(router: &DcompassRouter, query: Bytes, ip: IpAddr) -> Response<Body> {
+    let query = match inbound::parse(query) {
+        Inbound::Query(m) => m,
+        // Errors of the DNS layer are reported in DNS messages (RFC 8484)
+        Inbound::FormErr(resp) => {
+            return Response::builder()
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(Body::from(resp.into_octets()))
+                .unwrap()
+        }
+        Inbound::Drop => return error(StatusCode::BAD_REQUEST),
+    };
+    // Pad the response only if the client asks for it
+    let pad = padding::requested(&query);
+    match resolve(router, query, ip).await {
+        Some(resp) => {
+            let resp = if pad && !padding::signed(&resp) {
+                match Padding::RESPONSE.pad(&resp) {
+                    Ok(padded) => padded,
+                    Err(e) => {
+                        warn!("failed to pad the response: {}", e);
+                        resp
+                    }
+                }
+            } else {
+                resp
+            };
+            let mut builder = Response::builder().header(CONTENT_TYPE, DNS_MESSAGE);
+            if let Some(ttl) = min_ttl(&resp) {
+                builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl));
+            }
+            builder.body(Body::from(resp.into_octets())).unwrap()
+        }
+        None => error(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn json(router: &DcompassRouter, query: Message<Bytes>, ip: IpAddr) -> Response<Body> {
+    match resolve(router, query, ip).await.map(|r| to_json(&r)) {
+        Some(Ok(v)) => Response::builder()
+            .header(CONTENT_TYPE, DNS_JSON)
+            .body(Body::from(v.to_string()))
+            .unwrap(),
+        _ => error(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn handle(
+    router: &DcompassRouter,
+    path: &str,
+    ip: IpAddr,
+    req: Request<Body>,
+) -> Response<Body> {
+    if req.uri().path() != path {
+        return error(StatusCode::NOT_FOUND);
+    }
+
+    match *req.method() {
+        Method::GET => {
+            let params: HashMap<String, String> = req
+                .uri()
+                .query()
+                .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+                .unwrap_or_default();
+            if let Some(dns) = params.get("dns") {
+                match BASE64.decode(dns) {
+                    Ok(query) => wire(router, query.into(), ip).await,
+                    Err(_) => error(StatusCode::BAD_REQUEST),
+                }
+            } else if let Some(name) = params.get("name") {
+                match json_query(name, params.get("type").map(String::as_str)) {
+                    Some(query) => {
+                        // Only answer in wire format if explicitly asked
+                        if req.headers().get(ACCEPT).map(|v| v.as_bytes())
+                            == Some(DNS_MESSAGE.as_bytes())
+                        {
+                            wire(router, query.into_octets(), ip).await
+                        } else {
+                            json(router, query, ip).await
+                        }
+                    }
+                    None => error(StatusCode::BAD_REQUEST),
+                }
+            } else {
+                error(StatusCode::BAD_REQUEST)
+            }
+        }
+        Method::POST => {
+            if req.headers().get(CONTENT_TYPE).map(|v| v.as_bytes()) != Some(DNS_MESSAGE.as_bytes())
+            {
+                return error(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            if req.body().size_hint().lower() > MAX_LEN {
+                return error(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            match hyper::body::to_bytes(req.into_body()).await {
+                Ok(query) if query.len() as u64 <= MAX_LEN => wire(router, query, ip).await,
+                Ok(_) => error(StatusCode::PAYLOAD_TOO_LARGE),
+                Err(_) => error(StatusCode::BAD_REQUEST),
+            }
+        }
+        _ => error(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Bind to the address configured and return the server, which runs until it fails. Queries are served over TLS if it is configured, and over plain HTTP otherwise.
+pub fn bind(
+    config: DohServer,
+    router: Arc<DcompassRouter>,
+) -> Result<BoxFuture<'static, Result<()>>> {
+    let path: Arc<str> = config.path.into();
+    if let Some(tls) = config.tls {
+        let acceptor = Acceptor::new(&tls, &[b"h2", b"http/1.1"])?;
+        let listener = tls::listen(config.address)?;
+        return Ok(tls::serve(listener, acceptor, move |stream, src| {
+            let (router, path) = (router.clone(), path.clone());
+            async move {
+                let svc = service_fn(move |req| {
+                    let (router, path) = (router.clone(), path.clone());
+                    async move { Ok::<_, Infallible>(handle(&router, &path, src.ip(), req).await) }
+                });
+                if let Err(e) = Http::new().serve_connection(stream, svc).await {
+                    debug!("serving the connection from {} failed: {}", src, e);
+                }
+            }
+        })
+        .boxed());
+    }
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let (router, path, ip) = (router.clone(), path.clone(), conn.remote_addr().ip());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let (router, path) = (router.clone(), path.clone());
+                async move { Ok::<_, Infallible>(handle(&router, &path, ip, req).await) }
+            }))
+        }
+    });
+    Ok(Server::try_bind(&config.address)
+        .with_context(|| format!("failed to bind to {}", config.address))?
+        .serve(make_svc)
+        .err_into::<anyhow::Error>()
+        .boxed())
+}
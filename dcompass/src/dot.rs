@@ -0,0 +1,128 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNS over TLS (RFC 7858) frontend. Queries on a connection are resolved concurrently, and their responses are written back as soon as they are ready (RFC 7766).
+
+use super::{
+    parser::DotServer,
+    query_log,
+    tls::{self, Acceptor, Stream},
+    top, DcompassRouter,
+};
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use droute::{
+    inbound::{self, Inbound},
+    padding::{self, Padding},
+    QueryContext,
+};
+use log::*;
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, WriteHalf},
+    sync::Mutex,
+    time::{timeout, Duration},
+};
+use tracing::Instrument;
+
+// Idle connections are closed after this long, as suggested by RFC 7766
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn resolve(router: &DcompassRouter, query: Message<Bytes>, src: SocketAddr) -> Option<Bytes> {
+    let start = Instant::now();
+    top::record(src.ip(), &query);
+    // Pad the response only if the client asks for it (RFC 8467)
+    let pad = padding::requested(&query);
+    match router
+        .resolve(query.clone(), Some(QueryContext { ip: src.ip() }))
+        .instrument(tracing::info_span!("query", protocol = "dot", client = %src))
+        .await
+    {
+        Ok(resp) => {
+            query_log::record(src.ip(), &query, &resp, start.elapsed());
+            if pad && !padding::signed(&resp) {
+                match Padding::RESPONSE.pad(&resp) {
+                    Ok(padded) => return Some(padded.into_octets()),
+                    Err(e) => warn!("failed to pad the response: {}", e),
+                }
+            }
+            Some(resp.into_octets())
+        }
+        Err(e) => {
+            warn!("handling query failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn reply(writer: &Mutex<WriteHalf<Stream>>, resp: Bytes) -> std::io::Result<()> {
+    let mut writer = writer.lock().await;
+    writer.write_u16(resp.len() as u16).await?;
+    writer.write_all(&resp).await?;
+    writer.flush().await
+}
+
+async fn serve(router: Arc<DcompassRouter>, stream: Stream, src: SocketAddr) {
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    loop {
+        let len = match timeout(IDLE_TIMEOUT, reader.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            // Closed by the client or idle for too long
+            _ => return,
+        };
+        let mut buf = BytesMut::zeroed(len);
+        if let Err(e) = reader.read_exact(&mut buf).await {
+            debug!("failed to read the query from {}: {}", src, e);
+            return;
+        }
+        let query = match inbound::parse(buf.freeze()) {
+            Inbound::Query(query) => query,
+            Inbound::FormErr(resp) => {
+                debug!("malformed query from {}", src);
+                if let Err(e) = reply(&writer, resp.into_octets()).await {
+                    debug!("failed to send back the response to {}: {}", src, e);
+                    return;
+                }
+                continue;
+            }
+            Inbound::Drop => {
+                debug!("unexpected message from {}, closing the connection", src);
+                return;
+            }
+        };
+        let (router, writer) = (router.clone(), writer.clone());
+        tokio::spawn(async move {
+            if let Some(resp) = resolve(&router, query, src).await {
+                if let Err(e) = reply(&writer, resp).await {
+                    debug!("failed to send back the response to {}: {}", src, e);
+                }
+            }
+        });
+    }
+}
+
+/// Bind to the address configured and return the server, which runs until it fails.
+pub fn bind(
+    config: DotServer,
+    router: Arc<DcompassRouter>,
+) -> Result<impl Future<Output = Result<()>>> {
+    let acceptor = Acceptor::new(&config.tls, &[b"dot"])?;
+    let listener = tls::listen(config.address)?;
+    Ok(tls::serve(listener, acceptor, move |stream, src| {
+        serve(router.clone(), stream, src)
+    }))
+}
@@ -20,31 +20,54 @@
 // #[global_allocator]
 // static GLOBAL: Jemalloc = Jemalloc;
 
+mod bench;
+mod bundle;
+mod control;
+mod doh;
+mod dot;
 mod parser;
+mod query_log;
+mod replay;
+mod runtime;
+mod script;
+mod service;
+mod telemetry;
 #[cfg(test)]
 mod tests;
+mod tls;
+mod top;
+mod udp;
 mod worker;
 
-use self::{parser::Parsed, worker::worker};
+use self::{
+    bench::BenchOpts,
+    bundle::BundleOpts,
+    parser::{ControlServer, DohServer, DotServer, Otlp, Parsed, QueryLog},
+    replay::ReplayOpts,
+    script::Script,
+    service::ServiceCommand,
+};
 use anyhow::{Context, Result};
-use bytes::BytesMut;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+use domain::base::Dname;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+use droute::{builders::GuardedBuilder, errors::MessageError, tsig::Guarded};
 use droute::{
-    builders::{RouterBuilder, RuneScript},
+    builders::{RouterBuilder, ViewsBuilder},
     errors::ScriptError,
-    AsyncTryInto, Router,
+    utils::IpCidr,
+    AsyncTryInto, Offline, Router, Views,
 };
 use log::*;
 use simple_logger::SimpleLogger;
-use std::{net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc, time::Duration};
-use structopt::StructOpt;
-use tokio::{
-    fs::File,
-    io::AsyncReadExt,
-    net::UdpSocket,
-    signal,
-    sync::broadcast::{self, Sender},
-    time::sleep,
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+use std::str::FromStr;
+use std::{
+    future::Future, net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc,
+    time::Duration,
 };
+use structopt::StructOpt;
+use tokio::{signal, sync::broadcast, time::sleep};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -59,86 +82,108 @@ struct DcompassOpts {
     /// Set this flag to validate the configuration file only.
     #[structopt(short, long, parse(from_flag))]
     validate: bool,
-}
 
-async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, SocketAddr, LevelFilter), ScriptError> {
-    Ok((
-        RouterBuilder::new(p.script, p.upstreams)
-            .async_try_into()
-            .await?,
-        p.address,
-        p.verbosity,
-    ))
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sender<()>) {
-    loop {
-        // Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
-        let mut buf = BytesMut::with_capacity(1024);
-        buf.resize(1024, 0);
-        // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
-        let (len, src) = match socket.recv_from(&mut buf).await {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("failed to receive query: {}", e);
-                continue;
-            }
-        };
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Replay queries at a given rate and report latency percentiles and error ratios.
+    Bench(BenchOpts),
+    /// Replay captured queries against the configuration and report the rules they match and the upstreams they take.
+    Replay(ReplayOpts),
+    /// Manage dcompass as a background service (Windows Service Control Manager or launchd on macOS).
+    Service(ServiceCommand),
+    /// Pack the configuration along with the rule files and the lists it references into a single archive, which runs on its own with `-c`.
+    Bundle(BundleOpts),
+}
 
-        buf.resize(len, 0);
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+type DcompassRouter = Router<Guarded<Views<Script>>>;
+// TSIG needs ring, which doesn't build on MIPS
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+type DcompassRouter = Router<Views<Script>>;
 
-        let router = router.clone();
-        let socket = socket.clone();
-        let mut shutdown = tx.subscribe();
-        #[rustfmt::skip]
-        tokio::spawn(async move {
-            tokio::select! {
-                biased; res = worker(router, socket, buf.freeze(), src) => {
-                    match res {
-                        Ok(_) => (),
-                        Err(e) => warn!("handling query failed: {}", e),
-                    }
-                }
-                _ = shutdown.recv() => {
-                    // If a shutdown signal is received, return from the spawned task.
-                    // This will result in the task terminating.
-                    log::warn!("worker shut down");
-                }
+async fn init(
+    p: Parsed,
+) -> StdResult<(DcompassRouter, SocketAddr, LevelFilter, Offline), ScriptError> {
+    for (name, list) in p.lists {
+        list.build(&name).await?;
+    }
+    let mut views = ViewsBuilder::new(p.script);
+    for view in p.views {
+        let mut clients = IpCidr::new();
+        for c in view.clients {
+            clients.add_cidr(c)?;
+        }
+        views = views.add_view(view.name, clients, view.script);
+    }
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+    let views = {
+        let mut script = GuardedBuilder::new(views);
+        if let Some(tsig) = p.tsig {
+            for key in tsig.keys {
+                script = script.add_key(key);
             }
-        });
+            for zone in tsig.zones {
+                script = script.add_zone(
+                    Dname::from_str(&zone).map_err(|e| ScriptError::from(MessageError::from(e)))?,
+                );
+            }
+        }
+        script
+    };
+    // Shared with the control API to flip it at runtime
+    let offline = Offline::default();
+    let mut builder = RouterBuilder::new(views, p.upstreams.with_offline_switch(offline.clone()));
+    if let Some(t) = p.query_timeout {
+        builder = builder.with_timeout(Duration::from_millis(t));
+    }
+    if p.minimal_any {
+        builder = builder.with_minimal_any();
+    }
+    if p.minimal_responses {
+        builder = builder.with_minimal_responses();
     }
+    if p.normalize_qname {
+        builder = builder.with_normalize_qname();
+    }
+    if let Some(v) = p.chaos_version {
+        builder = builder.with_version(v);
+    }
+    if let Some(a) = p.annotate {
+        builder = builder.with_annotation(a);
+    }
+    Ok((
+        builder.async_try_into().await?,
+        p.address,
+        p.verbosity,
+        offline,
+    ))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // console_subscriber::init();
-
-    let args: DcompassOpts = DcompassOpts::from_args();
-
-    // If the config path is manually specified with `-c` flag, we use it and any error should fail early.
-    // If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
-    let config = if let Some(config_path) = args.config {
+// If the config path is manually specified with `-c` flag, we use it and any error should fail early.
+// If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
+fn read_config(config: Option<PathBuf>) -> Result<String> {
+    Ok(if let Some(config_path) = config {
         let display_path = config_path.as_path().display();
-        let mut file = File::open(config_path.clone())
-            .await
-            .with_context(|| format!("Failed to open the file specified: {}", display_path))?;
-        let mut config = String::new();
-        file.read_to_string(&mut config)
-            .await
-            .with_context(|| format!("Failed to read from the file specified: {}", display_path))?;
+        let content = std::fs::read(&config_path)
+            .with_context(|| format!("Failed to read the file specified: {}", display_path))?;
+        if bundle::is_bundle(&content) {
+            println!("Using the bundle specified: {}", display_path);
+            return bundle::open(&content);
+        }
         println!("Using the config file specified: {}", display_path);
-        config
+        String::from_utf8(content)
+            .with_context(|| format!("Failed to read the file specified: {}", display_path))?
     } else {
         let mut config_path = std::env::current_dir()?;
         config_path.push("config.yaml");
         let display_path = config_path.as_path().display();
-        match File::open(config_path.clone()).await {
-            // We have found the config and successfully opened it.
-            Ok(mut file) => {
-                let mut config = String::new();
-                file.read_to_string(&mut config).await.with_context(|| {
-                    format!("Failed to read from the file found: {}", display_path)
-                })?;
+        match std::fs::read_to_string(&config_path) {
+            // We have found the config and successfully read it.
+            Ok(config) => {
                 println!("Using the config under current path: {}", display_path);
                 config
             }
@@ -147,43 +192,117 @@ async fn main() -> Result<()> {
                 println!("No config found or specified, using built-in config.");
                 include_str!("../../configs/default.json").to_owned()
             }
-            // Found but unable to open. We shall exit as this is intended.
+            // Found but unable to read. We shall exit as this is intended.
             Err(e) => {
                 return Err(e).with_context(|| {
-                    format!("`config.yaml` found, but failed to open: {}", display_path)
+                    format!("`config.yaml` found, but failed to read: {}", display_path)
                 })
             }
         }
-    };
+    })
+}
 
-    // Create whatever we need for get dcompass up and running.
-    let (router, addr, verbosity) = init(
-        serde_yaml::from_str(&config)
-            .with_context(|| "Failed to parse the configuration file".to_string())?,
-    )
-    .await?;
+// Parsed apart from loading, as the runtime is built from the configuration before anything is loaded.
+fn parse(config: &str) -> Result<Parsed> {
+    serde_yaml::from_str(config)
+        .with_context(|| "Failed to parse the configuration file".to_string())
+}
 
-    // If we are only required to validate the config, we shall be safe to exit now.
-    if args.validate {
-        println!("The configuration provided is valid.");
-        return Ok(());
+// Everything needed to serve queries, loaded from the configuration
+struct Server {
+    router: DcompassRouter,
+    addr: SocketAddr,
+    verbosity: LevelFilter,
+    offline: Offline,
+    udp_sockets: usize,
+    cpu_affinity: Vec<usize>,
+    max_udp_size: Option<u16>,
+    doh: Option<DohServer>,
+    dot: Option<DotServer>,
+    otlp: Option<Otlp>,
+    query_log: Option<QueryLog>,
+    control: Option<ControlServer>,
+}
+
+// Create whatever we need for get dcompass up and running.
+async fn load(parsed: Parsed) -> Result<Server> {
+    let doh = parsed.doh.clone();
+    let dot = parsed.dot.clone();
+    let otlp = parsed.otlp.clone();
+    let query_log = parsed.query_log.clone();
+    let control = parsed.control.clone();
+    let max_udp_size = parsed.max_udp_size;
+    let cpu_affinity = parsed.runtime.cpu_affinity.clone();
+    let udp_sockets = match parsed.udp_sockets {
+        // One per pinned core if the workers are pinned
+        0 if !cpu_affinity.is_empty() => cpu_affinity.len(),
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let (router, addr, verbosity, offline) = init(parsed).await?;
+    Ok(Server {
+        router,
+        addr,
+        verbosity,
+        offline,
+        udp_sockets,
+        cpu_affinity,
+        max_udp_size,
+        doh,
+        dot,
+        otlp,
+        query_log,
+        control,
+    })
+}
+
+// Serve queries until `shutdown` completes. Logging has to be set up by the caller.
+async fn serve(server: Server, shutdown: impl Future<Output = ()>) -> Result<()> {
+    if let Some(c) = server.otlp {
+        info!("exporting traces to {}", c.endpoint);
+        telemetry::init(c)?;
     }
 
-    // Start logging
-    SimpleLogger::new()
-        // These modules are quite chatty, we want to disable it.
-        .with_level(verbosity)
-        .init()?;
+    if let Some(c) = server.query_log {
+        info!("logging queries to {}", c.path.display());
+        query_log::init(c)?;
+    }
 
     info!("dcompass ready!");
 
-    let router = Arc::new(router);
-    // Bind an UDP socket
-    let socket = Arc::new(
-        UdpSocket::bind(addr)
-            .await
-            .with_context(|| format!("failed to bind to {}", addr))?,
-    );
+    let router = Arc::new(server.router);
+    // Bind UDP sockets, among which the kernel balances the queries if there are more than one
+    let sockets = udp::bind(server.addr, server.udp_sockets, &server.cpu_affinity)?;
+
+    if let Some(c) = server.doh {
+        info!("serving DNS over HTTPS at {}{}", c.address, c.path);
+        let server = doh::bind(c, router.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("DNS over HTTPS server failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(c) = server.dot {
+        info!("serving DNS over TLS at {}", c.address);
+        let server = dot::bind(c, router.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("DNS over TLS server failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(c) = server.control {
+        info!("serving the control API at {}", c.address);
+        let server = control::bind(c, server.offline)?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("control API server failed: {}", e);
+            }
+        });
+    }
 
     // Create a shutdown broadcast channel
     let (tx, _) = broadcast::channel::<()>(10);
@@ -191,9 +310,8 @@ async fn main() -> Result<()> {
     // We don't have to worry about incoming requests when shutting down, because when we initiate shutdown, the loop was already terminated
     #[rustfmt::skip]
     tokio::select! {
-        _ = serve(socket, router, &tx) => (),
-        _ = signal::ctrl_c() => {
-            log::warn!("Ctrl-C received, shutting down");
+        _ = futures::future::join_all(sockets.into_iter().map(|s| udp::serve(s, router.clone(), server.max_udp_size, &tx))) => (),
+        _ = shutdown => {
 	    sleep(Duration::from_millis(500)).await;
             // Error implies that there is no receiver/active worker, we are done
             if tx.send(()).is_ok() {
@@ -205,5 +323,82 @@ async fn main() -> Result<()> {
             log::warn!("gracefully shut down!");
         }
     };
+    telemetry::shutdown();
     Ok(())
 }
+
+fn main() -> Result<()> {
+    // console_subscriber::init();
+
+    let args: DcompassOpts = DcompassOpts::from_args();
+
+    // The service manager sets up everything itself
+    if let Some(Command::Service(cmd)) = args.cmd {
+        return service::handle(cmd, args.config);
+    }
+
+    if let Some(Command::Bundle(opts)) = &args.cmd {
+        let config = read_config(args.config)?;
+        // Rather fail now than on the routers
+        parse(&config)?;
+        let packed = bundle::export(&config, opts)?;
+        for path in &packed {
+            println!("packed `{}`", path);
+        }
+        println!(
+            "The configuration and {} files referenced are bundled into {}",
+            packed.len(),
+            opts.output().display()
+        );
+        return Ok(());
+    }
+
+    let parsed = parse(&read_config(args.config)?)?;
+    runtime::build(&parsed.runtime)?.block_on(run(parsed, args.validate, args.cmd))
+}
+
+async fn run(parsed: Parsed, validate: bool, cmd: Option<Command>) -> Result<()> {
+    let server = load(parsed).await?;
+
+    // If we are only required to validate the config, we shall be safe to exit now.
+    if validate {
+        println!("The configuration provided is valid.");
+        return Ok(());
+    }
+
+    if let Some(Command::Bench(opts)) = cmd {
+        SimpleLogger::new().with_level(server.verbosity).init()?;
+        // Queries are sent to the target directly, the router is not used.
+        let router = if opts.needs_router() {
+            Some(server.router)
+        } else {
+            None
+        };
+        println!("{}", bench::run(opts, router).await?);
+        return Ok(());
+    }
+
+    if let Some(Command::Replay(opts)) = cmd {
+        // Rule logs and failures in dry runs would bury the report.
+        SimpleLogger::new()
+            .with_level(server.verbosity.min(LevelFilter::Error))
+            .init()?;
+        server.offline.set(!opts.live());
+        println!("{}", replay::run(opts, server.router).await?);
+        return Ok(());
+    }
+
+    // Start logging
+    SimpleLogger::new()
+        // These modules are quite chatty, we want to disable it.
+        .with_level(server.verbosity)
+        // Rule logs have their own verbosity set in the script
+        .with_module_level(droute::utils::RULE_LOG_TARGET, LevelFilter::Trace)
+        .init()?;
+
+    serve(server, async {
+        let _ = signal::ctrl_c().await;
+        log::warn!("Ctrl-C received, shutting down");
+    })
+    .await
+}
@@ -13,10 +13,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use droute::builders::*;
+use crate::script::ScriptBuilder;
+use droute::{builders::*, trace::Annotation, utils::SubscriptionBuilder};
 use log::LevelFilter;
 use serde::Deserialize;
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -30,14 +31,214 @@ enum LevelFilterDef {
     Trace,
 }
 
+// A set of clients routed with their own script
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct View {
+    pub name: String,
+    // IP CIDRs or addresses of the clients
+    pub clients: Vec<String>,
+    pub script: ScriptBuilder,
+}
+
+fn default_udp_sockets() -> usize {
+    1
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+// TLS terminated by the encrypted frontends
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServer {
+    // PEM encoded certificate chain and private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Require client certificates issued by the CAs in this PEM file
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+// DNS over HTTPS frontend
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DohServer {
+    pub address: SocketAddr,
+    // The URL path queries are served at
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+    // Serve over plain HTTP if not set
+    #[serde(default)]
+    pub tls: Option<TlsServer>,
+}
+
+// DNS over TLS frontend
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DotServer {
+    pub address: SocketAddr,
+    pub tls: TlsServer,
+}
+
+fn default_service_name() -> String {
+    "dcompass".to_string()
+}
+
+// Export of tracing spans to an OpenTelemetry collector
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Otlp {
+    // gRPC endpoint of the collector
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_top_size() -> usize {
+    100
+}
+
+fn default_top_window() -> u64 {
+    600
+}
+
+// HTTP API to inspect the running instance
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ControlServer {
+    pub address: SocketAddr,
+    // Number of the most queried domains and the busiest clients tracked
+    #[serde(default = "default_top_size")]
+    pub top_size: usize,
+    // Counts are halved every this many seconds
+    #[serde(default = "default_top_window")]
+    pub top_window: u64,
+}
+
+// How client addresses are written to the query log
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIp {
+    Full,
+    // Keep the /24 of IPv4 and the /48 of IPv6 addresses
+    Truncate,
+    // Keyed hash, whose key is renewed on every rotation
+    Hash,
+    None,
+}
+
+impl Default for ClientIp {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
+// A log of every query answered
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueryLog {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub client_ip: ClientIp,
+    // Queries for names asked fewer times than this since the last rotation are not logged
+    #[serde(default)]
+    pub min_popularity: u64,
+    // Rotate once the log exceeds this many bytes
+    #[serde(default)]
+    pub rotate_size: Option<u64>,
+    // Rotate once the log is older than this many seconds
+    #[serde(default)]
+    pub rotate_interval: Option<u64>,
+    // Number of rotated logs kept, all of them are kept if not set
+    #[serde(default)]
+    pub keep: Option<usize>,
+    // Compress rotated logs with gzip
+    #[serde(default)]
+    pub compress: bool,
+}
+
+// The async runtime everything runs on
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Runtime {
+    // Number of worker threads, 0 for one per CPU core. With 1, everything runs on the main thread.
+    #[serde(default)]
+    pub worker_threads: usize,
+    // Maximum number of threads spawned for blocking operations like file I/O
+    #[serde(default)]
+    pub max_blocking_threads: Option<usize>,
+    // CPU cores the worker threads are pinned to in turn (Linux only)
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+}
+
+// TSIG verification of incoming queries. Not available on MIPS, where configurations with it are rejected as unknown fields.
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tsig {
+    // Keys queries can be signed with
+    pub keys: Vec<TsigKeyBuilder>,
+    // Zones for which unsigned queries are refused
+    #[serde(default)]
+    pub zones: Vec<String>,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Parsed {
-    pub script: RuneScriptBuilder,
+    pub script: ScriptBuilder,
     // We are not using UpstreamsBuilder because flatten ruins error location.
     #[serde(flatten)]
     pub upstreams: UpstreamsBuilder<UpstreamBuilder>,
     pub address: SocketAddr,
+    // Number of UDP sockets bound to `address` with `SO_REUSEPORT`, 0 for one per CPU core
+    #[serde(default = "default_udp_sockets")]
+    pub udp_sockets: usize,
+    #[serde(default)]
+    pub runtime: Runtime,
     #[serde(with = "LevelFilterDef")]
     pub verbosity: LevelFilter,
+    // The end-to-end time budget in milliseconds for every query
+    #[serde(default)]
+    pub query_timeout: Option<u64>,
+    // Answer ANY queries with a single HINFO record (RFC 8482)
+    #[serde(default)]
+    pub minimal_any: bool,
+    // Strip the authority and additional records not needed from the responses
+    #[serde(default)]
+    pub minimal_responses: bool,
+    // Lowercase the names queried before routing them
+    #[serde(default)]
+    pub normalize_qname: bool,
+    // The maximum size of responses over UDP, beyond which they are truncated with the TC bit set
+    #[serde(default)]
+    pub max_udp_size: Option<u16>,
+    // Answer CHAOS TXT queries for `version.bind` with this string
+    #[serde(default)]
+    pub chaos_version: Option<String>,
+    // Put the rules matched and the upstreams tried in the responses for debugging
+    #[serde(default)]
+    pub annotate: Option<Annotation>,
+    // Views tried in order before falling back to `script`
+    #[serde(default)]
+    pub views: Vec<View>,
+    // Domain lists subscribed by their names, which are loaded before the script
+    #[serde(default)]
+    pub lists: HashMap<String, SubscriptionBuilder>,
+    #[serde(default)]
+    pub doh: Option<DohServer>,
+    #[serde(default)]
+    pub dot: Option<DotServer>,
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+    #[serde(default)]
+    pub tsig: Option<Tsig>,
+    #[serde(default)]
+    pub otlp: Option<Otlp>,
+    #[serde(default)]
+    pub query_log: Option<QueryLog>,
+    #[serde(default)]
+    pub control: Option<ControlServer>,
 }
@@ -0,0 +1,257 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A log of every query answered, written as JSON lines by a dedicated thread so that file I/O never blocks the workers.
+
+use super::parser::{ClientIp, QueryLog};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use domain::base::{iana::Rcode, Message, Rtype};
+use flate2::{write::GzEncoder, Compression};
+use log::*;
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hash, Hasher},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Entries queued beyond this are dropped instead of slowing the workers down
+const QUEUE_LEN: usize = 4096;
+
+// Popularity counts are reset once this many names are tracked, so that memory stays bounded without rotation
+const MAX_TRACKED: usize = 1 << 16;
+
+static SENDER: OnceCell<SyncSender<Entry>> = OnceCell::new();
+
+struct Entry {
+    time: SystemTime,
+    client: IpAddr,
+    qname: String,
+    qtype: Rtype,
+    rcode: Rcode,
+    elapsed: Duration,
+}
+
+/// Start writing the query log. Queries are only logged after this is called.
+pub fn init(config: QueryLog) -> Result<()> {
+    let writer = Writer::new(config)?;
+    let (tx, rx) = sync_channel(QUEUE_LEN);
+    std::thread::Builder::new()
+        .name("query-log".into())
+        .spawn(move || writer.run(rx))?;
+    SENDER
+        .set(tx)
+        .map_err(|_| anyhow::anyhow!("query log is already started"))
+}
+
+/// Log the query answered for the client. This is a no-op if the query log is not enabled.
+pub fn record(client: IpAddr, query: &Message<Bytes>, resp: &Message<Bytes>, elapsed: Duration) {
+    let tx = match SENDER.get() {
+        Some(tx) => tx,
+        None => return,
+    };
+    let question = match query.first_question() {
+        Some(q) => q,
+        None => return,
+    };
+    let entry = Entry {
+        time: SystemTime::now(),
+        client,
+        qname: question.qname().to_string(),
+        qtype: question.qtype(),
+        rcode: resp.header().rcode(),
+        elapsed,
+    };
+    if tx.try_send(entry).is_err() {
+        debug!("query log is falling behind, dropping the entry");
+    }
+}
+
+/// Keep the /24 of an IPv4 address or the /48 of an IPv6 address.
+pub fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & 0xffff_ff00).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !((1u128 << 80) - 1)).into(),
+    }
+}
+
+fn open(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+struct Writer {
+    config: QueryLog,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    // Number of queries for every name since the last rotation
+    counts: HashMap<String, u64>,
+    // Renewed on every rotation, so that hashed addresses can't be linked across logs
+    key: RandomState,
+}
+
+impl Writer {
+    fn new(config: QueryLog) -> Result<Self> {
+        let (file, size) = open(&config.path)
+            .with_context(|| format!("failed to open the query log: {}", config.path.display()))?;
+        Ok(Self {
+            config,
+            file,
+            size,
+            opened: Instant::now(),
+            counts: HashMap::new(),
+            key: RandomState::new(),
+        })
+    }
+
+    fn run(mut self, rx: Receiver<Entry>) {
+        // Write out whatever is queued before flushing
+        while let Ok(entry) = rx.recv() {
+            for entry in std::iter::once(entry).chain(rx.try_iter()) {
+                if let Err(e) = self.write(entry) {
+                    warn!("failed to write the query log: {}", e);
+                }
+            }
+            if let Err(e) = self.file.flush() {
+                warn!("failed to write the query log: {}", e);
+            }
+        }
+    }
+
+    fn client(&self, ip: IpAddr) -> Option<String> {
+        match self.config.client_ip {
+            ClientIp::Full => Some(ip.to_string()),
+            ClientIp::Truncate => Some(truncate(ip).to_string()),
+            ClientIp::Hash => {
+                let mut hasher = self.key.build_hasher();
+                ip.hash(&mut hasher);
+                Some(format!("{:016x}", hasher.finish()))
+            }
+            ClientIp::None => None,
+        }
+    }
+
+    // Whether the name has been asked often enough to be logged
+    fn popular(&mut self, qname: &str) -> bool {
+        if self.config.min_popularity == 0 {
+            return true;
+        }
+        if self.counts.len() >= MAX_TRACKED && !self.counts.contains_key(qname) {
+            self.counts.clear();
+        }
+        let count = self.counts.entry(qname.to_owned()).or_default();
+        *count += 1;
+        *count >= self.config.min_popularity
+    }
+
+    fn write(&mut self, entry: Entry) -> io::Result<()> {
+        if !self.popular(&entry.qname) {
+            return Ok(());
+        }
+        // Rotate before the entry is formatted, so that it is hashed with the key of the log it goes to
+        let oversized = self.config.rotate_size.map_or(false, |s| self.size >= s);
+        let expired = self
+            .config
+            .rotate_interval
+            .map_or(false, |i| self.opened.elapsed() >= Duration::from_secs(i));
+        if (oversized && self.size > 0) || expired {
+            self.rotate()?;
+        }
+
+        let line = json!({
+            "time": entry.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "client": self.client(entry.client),
+            "qname": entry.qname,
+            "qtype": entry.qtype.to_string(),
+            "rcode": entry.rcode.to_string(),
+            "elapsed_ms": entry.elapsed.as_secs_f64() * 1000.0,
+        })
+        .to_string();
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let rotated = PathBuf::from(format!("{}.{}", self.config.path.display(), stamp));
+        fs::rename(&self.config.path, &rotated)?;
+        let (file, size) = open(&self.config.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened = Instant::now();
+        self.counts.clear();
+        self.key = RandomState::new();
+
+        if self.config.compress {
+            let mut gz = GzEncoder::new(
+                File::create(format!("{}.gz", rotated.display()))?,
+                Compression::default(),
+            );
+            io::copy(&mut File::open(&rotated)?, &mut gz)?;
+            gz.finish()?;
+            fs::remove_file(&rotated)?;
+        }
+        if let Some(keep) = self.config.keep {
+            self.prune(keep)?;
+        }
+        Ok(())
+    }
+
+    // Remove the oldest rotated logs so that only `keep` of them are left
+    fn prune(&self, keep: usize) -> io::Result<()> {
+        let path = &self.config.path;
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let mut rotated: Vec<(u128, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let stamp = name
+                    .strip_prefix(&prefix)?
+                    .trim_end_matches(".gz")
+                    .parse()
+                    .ok()?;
+                Some((stamp, e.path()))
+            })
+            .collect();
+        rotated.sort_unstable();
+        let excess = rotated.len().saturating_sub(keep);
+        for (_, path) in rotated.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
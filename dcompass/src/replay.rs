@@ -0,0 +1,317 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Replay captured queries against the configuration, and report the rules they match and the upstreams they are sent to.
+
+use super::{bench::parse_pcap_clients, DcompassRouter};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use domain::base::Message;
+use droute::{trace, QueryContext};
+use std::{collections::BTreeMap, fmt, net::IpAddr, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ReplayOpts {
+    /// Path to a pcap file, from which DNS queries sent over UDP port 53 are extracted.
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless = "dnstap",
+        conflicts_with = "dnstap"
+    )]
+    pcap: Option<PathBuf>,
+
+    /// Path to a dnstap file written in Frame Streams, from which the `CLIENT_QUERY` messages are extracted.
+    #[structopt(short, long, parse(from_os_str))]
+    dnstap: Option<PathBuf>,
+
+    /// Send the queries to the upstreams. By default nothing is sent, and queries routed to upstreams other than zones are answered with SERVFAIL.
+    #[structopt(long)]
+    live: bool,
+
+    /// Only print the summary instead of every query.
+    #[structopt(short, long)]
+    summary: bool,
+}
+
+impl ReplayOpts {
+    /// Whether the queries are sent to the upstreams.
+    pub fn live(&self) -> bool {
+        self.live
+    }
+}
+
+// A field of a protobuf message, with the payload of length-delimited ones.
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    for i in 0..10 {
+        let (b, rest) = buf.split_first()?;
+        *buf = rest;
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+// Decode the fields of a protobuf message with their numbers. `None` if it is malformed.
+fn fields(mut buf: &[u8]) -> Option<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let field = match key & 0x07 {
+            0 => Field::Varint(varint(&mut buf)?),
+            1 => {
+                buf = buf.get(8..)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                let (bytes, rest) = (buf.get(..len)?, buf.get(len..)?);
+                buf = rest;
+                Field::Bytes(bytes)
+            }
+            5 => {
+                buf = buf.get(4..)?;
+                Field::Fixed
+            }
+            _ => return None,
+        };
+        fields.push((key >> 3, field));
+    }
+    Some(fields)
+}
+
+// Type of the dnstap message for queries received from clients
+const CLIENT_QUERY: u64 = 5;
+
+// Get the client and the query out of a dnstap `Dnstap` message, if it is a `CLIENT_QUERY` one.
+fn dnstap_query(frame: &[u8]) -> Option<(Option<IpAddr>, Message<Bytes>)> {
+    // `message` of `Dnstap`
+    let msg = fields(frame)?.into_iter().find_map(|(n, f)| match (n, f) {
+        (14, Field::Bytes(b)) => Some(b),
+        _ => None,
+    })?;
+    let (mut client, mut query, mut is_query) = (None, None, false);
+    for (n, f) in fields(msg)? {
+        match (n, f) {
+            (1, Field::Varint(t)) => is_query = t == CLIENT_QUERY,
+            (4, Field::Bytes(b)) => {
+                client = match b.len() {
+                    4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).ok()?)),
+                    16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).ok()?)),
+                    _ => None,
+                }
+            }
+            (10, Field::Bytes(b)) => query = Some(b),
+            _ => (),
+        }
+    }
+    if !is_query {
+        return None;
+    }
+    let query = Message::from_octets(Bytes::copy_from_slice(query?)).ok()?;
+    query.first_question()?;
+    Some((client, query))
+}
+
+// Read a big-endian u32 off the front of `buf`.
+fn be_u32(buf: &mut &[u8]) -> Option<usize> {
+    let (len, rest) = (buf.get(..4)?, &buf[4..]);
+    *buf = rest;
+    Some(u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+}
+
+/// Extract the queries received from clients in a dnstap file, which is a sequence of Frame Streams frames. Control frames are skipped.
+pub fn parse_dnstap(content: &[u8]) -> Result<Vec<(Option<IpAddr>, Message<Bytes>)>> {
+    let mut queries = Vec::new();
+    let mut buf = content;
+    while !buf.is_empty() {
+        let offset = content.len() - buf.len();
+        let truncated = || anyhow!("truncated frame at offset {}", offset);
+        let (len, control) = match be_u32(&mut buf).ok_or_else(truncated)? {
+            // Control frames are escaped with a zero length
+            0 => (be_u32(&mut buf).ok_or_else(truncated)?, true),
+            len => (len, false),
+        };
+        let frame = buf.get(..len).ok_or_else(truncated)?;
+        buf = &buf[len..];
+        if !control {
+            queries.extend(dnstap_query(frame));
+        }
+    }
+    Ok(queries)
+}
+
+/// Counts of the outcomes of the queries replayed.
+#[derive(Default)]
+pub struct Summary {
+    total: usize,
+    rcodes: BTreeMap<String, usize>,
+    upstreams: BTreeMap<String, usize>,
+    rules: BTreeMap<String, usize>,
+    // Queries answered without any upstream, e.g. blackholed
+    local: usize,
+    live: bool,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} queries replayed, {} answered without any upstream",
+            self.total, self.local
+        )?;
+        for (title, counts) in [
+            ("response codes", &self.rcodes),
+            ("upstreams", &self.upstreams),
+            ("rules matched", &self.rules),
+        ] {
+            writeln!(f, "{}:", title)?;
+            for (name, n) in counts {
+                writeln!(f, "  {}: {}", name, n)?;
+            }
+        }
+        if !self.live {
+            write!(
+                f,
+                "nothing was sent to the upstreams, queries routed to them are answered with SERVFAIL"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay the queries captured in the file given through the router, printing the decisions made for each of them unless only the summary is asked for.
+pub async fn run(opts: ReplayOpts, router: DcompassRouter) -> Result<Summary> {
+    let queries = if let Some(path) = &opts.pcap {
+        parse_pcap_clients(
+            &tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read the pcap file: {}", path.display()))?,
+        )?
+        .into_iter()
+        .map(|(ip, query)| (Some(ip), query))
+        .collect()
+    } else if let Some(path) = &opts.dnstap {
+        parse_dnstap(
+            &tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read the dnstap file: {}", path.display()))?,
+        )?
+    } else {
+        unreachable!("either `pcap` or `dnstap` is required")
+    };
+    if queries.is_empty() {
+        bail!("no query found to replay");
+    }
+
+    let mut summary = Summary {
+        live: opts.live,
+        ..Default::default()
+    };
+    // One at a time, so that the decisions are told apart and the upstreams are not flooded in live runs
+    for (client, query) in queries {
+        let q = query.first_question().unwrap();
+        let (resp, trace) =
+            trace::scope(router.resolve(query.clone(), client.map(|ip| QueryContext { ip }))).await;
+        let outcome = match resp {
+            Ok(resp) => resp.header().rcode().to_string(),
+            Err(e) => format!("error ({})", e),
+        };
+        if !opts.summary {
+            println!(
+                "{} {} {} -> {}, upstreams: [{}], rules: [{}]",
+                client.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+                q.qname(),
+                q.qtype(),
+                outcome,
+                trace.upstreams.join(", "),
+                trace.rules.join(", ")
+            );
+        }
+
+        summary.total += 1;
+        *summary.rcodes.entry(outcome).or_default() += 1;
+        if trace.upstreams.is_empty() {
+            summary.local += 1;
+        }
+        for tag in trace.upstreams {
+            *summary.upstreams.entry(tag.to_string()).or_default() += 1;
+        }
+        for rule in trace.rules {
+            *summary.rules.entry(rule).or_default() += 1;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_dnstap;
+    use crate::bench::parse_qnames;
+    use std::net::IpAddr;
+
+    fn field(n: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut v = vec![n << 3 | 2, bytes.len() as u8];
+        v.extend(bytes);
+        v
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut v = (payload.len() as u32).to_be_bytes().to_vec();
+        v.extend(payload);
+        v
+    }
+
+    #[test]
+    fn dnstap() {
+        let query = &parse_qnames("example.com").unwrap()[0];
+
+        // `Message` with the type, the client, and the query
+        let mut message = vec![1 << 3, 5];
+        message.extend(field(4, &[10, 0, 0, 1]));
+        message.extend(field(10, query.as_slice()));
+        // `Dnstap` with the identity and the message
+        let mut dnstap = field(1, b"unbound");
+        dnstap.extend(field(14, &message));
+
+        // `CLIENT_RESPONSE` messages are skipped
+        let mut response = vec![1 << 3, 6];
+        response.extend(field(10, query.as_slice()));
+
+        // Start control frame followed by the data frames
+        let mut content = vec![0, 0, 0, 0];
+        content.extend(frame(&[0, 0, 0, 2]));
+        content.extend(frame(&dnstap));
+        content.extend(frame(&field(14, &response)));
+
+        let queries = parse_dnstap(&content).unwrap();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].0, Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(queries[0].1.as_slice(), query.as_slice());
+
+        assert!(parse_dnstap(&content[..content.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The async runtime, which is built from the configuration before anything else runs.
+
+use super::parser::Runtime;
+use anyhow::{bail, Result};
+use tokio::runtime::Builder;
+
+/// The number of worker threads the runtime runs, 0 if everything runs on the main thread.
+pub fn workers(config: &Runtime) -> usize {
+    match config.worker_threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        1 => 0,
+        n => n,
+    }
+}
+
+/// Build the runtime as configured.
+pub fn build(config: &Runtime) -> Result<tokio::runtime::Runtime> {
+    let mut builder = match workers(config) {
+        0 => Builder::new_current_thread(),
+        n => {
+            let mut builder = Builder::new_multi_thread();
+            builder.worker_threads(n);
+            builder
+        }
+    };
+    builder.enable_all();
+    match config.max_blocking_threads {
+        Some(0) => bail!("`max_blocking_threads` must be at least 1"),
+        Some(n) => {
+            builder.max_blocking_threads(n);
+        }
+        None => (),
+    }
+    if !config.cpu_affinity.is_empty() {
+        affinity::pin(&mut builder, config.cpu_affinity.clone(), workers(config))?;
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+mod affinity {
+    use anyhow::{bail, Result};
+    use std::{
+        io, mem,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::runtime::Builder;
+
+    // Restrict the calling thread to the cores.
+    fn set(cores: &[usize]) -> io::Result<()> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &core in cores {
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+        match unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Pin the `workers` worker threads to the cores in turn. Other threads may run on any of the cores.
+    pub fn pin(builder: &mut Builder, cores: Vec<usize>, workers: usize) -> Result<()> {
+        if let Some(core) = cores.iter().find(|&&c| c >= libc::CPU_SETSIZE as usize) {
+            bail!("CPU core {} is out of range", core);
+        }
+        // Fails early if none of the cores is available to us. Threads spawned later inherit it.
+        if let Err(e) = set(&cores) {
+            bail!("failed to pin to CPU cores {:?}: {}", cores, e);
+        }
+        let started = AtomicUsize::new(0);
+        builder.on_thread_start(move || {
+            // Worker threads are all started as the runtime is built, while threads for blocking operations are only started later on demand.
+            let i = started.fetch_add(1, Ordering::Relaxed);
+            let _ = if i < workers {
+                set(&cores[i % cores.len()..][..1])
+            } else {
+                // They may be spawned from a pinned worker thread
+                set(&cores)
+            };
+        });
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod affinity {
+    use anyhow::{bail, Result};
+    use tokio::runtime::Builder;
+
+    pub fn pin(_: &mut Builder, _: Vec<usize>, _: usize) -> Result<()> {
+        bail!("pinning threads to CPU cores is only supported on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, workers};
+    use crate::parser::Runtime;
+
+    #[test]
+    fn flavors() {
+        let single = Runtime {
+            worker_threads: 1,
+            ..Default::default()
+        };
+        assert_eq!(workers(&single), 0);
+        assert_eq!(build(&single).unwrap().block_on(async { 42 }), 42);
+
+        let config = Runtime {
+            worker_threads: 2,
+            max_blocking_threads: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(workers(&config), 2);
+        let rt = build(&config).unwrap();
+        assert_eq!(
+            rt.block_on(async { tokio::task::spawn_blocking(|| 42).await })
+                .unwrap(),
+            42
+        );
+
+        assert!(build(&Runtime {
+            max_blocking_threads: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The routing script, written either in Rune or as routing tables.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use droute::{
+    builders::{RuneScript, RuneScriptBuilder, TableBuilder},
+    errors::ScriptError,
+    Label, QueryContext, ScriptBackend, Table, Upstreams, Validatable,
+};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ScriptBuilder {
+    Rune(RuneScriptBuilder),
+    Table { table: TableBuilder },
+}
+
+pub enum Script {
+    Rune(RuneScript),
+    Table(Table),
+}
+
+#[async_trait]
+impl ScriptBackend for Script {
+    async fn route(
+        &self,
+        query: Message<Bytes>,
+        ctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        match self {
+            Self::Rune(s) => s.route(query, ctx).await,
+            Self::Table(s) => s.route(query, ctx).await,
+        }
+    }
+}
+
+impl Validatable for Script {
+    type Error = ScriptError;
+
+    fn validate(&self, used: Option<&Vec<Label>>) -> Result<(), ScriptError> {
+        match self {
+            Self::Rune(s) => s.validate(used),
+            Self::Table(s) => s.validate(used),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl droute::ScriptBuilder<Script> for ScriptBuilder {
+    async fn build(self, upstreams: Upstreams) -> Result<Script, ScriptError> {
+        Ok(match self {
+            Self::Rune(s) => Script::Rune(s.build(upstreams).await?),
+            Self::Table { table } => Script::Table(table.build(upstreams).await?),
+        })
+    }
+}
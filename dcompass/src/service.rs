@@ -0,0 +1,294 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+// Name of the service registered
+#[cfg(windows)]
+const SERVICE_NAME: &str = "dcompass";
+
+// Label of the launchd job
+const LAUNCHD_LABEL: &str = "com.compassd.dcompass";
+
+#[derive(Debug, StructOpt)]
+pub enum ServiceCommand {
+    /// Register dcompass as a service started on boot, serving with the configuration file given by `-c`.
+    Install,
+    /// Stop and remove the service registered.
+    Uninstall,
+    /// Run as the service. This is invoked by the Windows Service Control Manager, not by hand.
+    Run,
+    /// Print the launchd property list used on macOS, for those managing the job themselves.
+    Plist,
+}
+
+pub fn handle(cmd: ServiceCommand, config: Option<PathBuf>) -> Result<()> {
+    // The service is started from elsewhere, so the path must not be relative to where we are now.
+    let config = || -> Result<PathBuf> {
+        let path = config
+            .clone()
+            .context("the configuration file must be given with `-c`")?;
+        std::fs::canonicalize(&path)
+            .with_context(|| format!("Failed to locate the file specified: {}", path.display()))
+    };
+    match cmd {
+        ServiceCommand::Install => imp::install(config()?),
+        ServiceCommand::Uninstall => imp::uninstall(),
+        ServiceCommand::Run => imp::run(config()?),
+        ServiceCommand::Plist => {
+            print!("{}", plist(&std::env::current_exe()?, &config()?));
+            Ok(())
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The launchd job running `exe` with the configuration file at `config`, restarted whenever it exits.
+pub fn plist(exe: &Path, config: &Path) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>-c</string>
+        <string>{config}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>/var/log/dcompass.log</string>
+    <key>StandardErrorPath</key>
+    <string>/var/log/dcompass.log</string>
+</dict>
+</plist>
+"#,
+        label = LAUNCHD_LABEL,
+        exe = escape(&exe.to_string_lossy()),
+        config = escape(&config.to_string_lossy()),
+    )
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::SERVICE_NAME;
+    use crate::{load, parse, read_config, runtime, serve};
+    use anyhow::Result;
+    use log::Level;
+    use std::{ffi::OsString, path::PathBuf, sync::Mutex, time::Duration};
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+            ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+    // The service entry is called by the dispatcher without our arguments, so the path is passed through here.
+    static CONFIG: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    pub fn install(config: PathBuf) -> Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "dcompass DNS server".into(),
+            service_type: SERVICE_TYPE,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: vec![
+                "-c".into(),
+                config.into_os_string(),
+                "service".into(),
+                "run".into(),
+            ],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description("Rule-based DNS server")?;
+        // Logs are written to the Application event log under our name
+        eventlog::register(SERVICE_NAME)?;
+        println!("Service `{}` installed.", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        eventlog::deregister(SERVICE_NAME)?;
+        println!("Service `{}` uninstalled.", SERVICE_NAME);
+        Ok(())
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn run(config: PathBuf) -> Result<()> {
+        *CONFIG.lock().unwrap() = Some(config);
+        // Blocks until the service is stopped
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+        Ok(())
+    }
+
+    fn service_main(_: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            log::error!("dcompass service failed: {:#}", e);
+        }
+    }
+
+    fn run_service() -> Result<()> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let mut tx = Some(tx);
+        let status =
+            service_control_handler::register(SERVICE_NAME, move |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    if let Some(tx) = tx.take() {
+                        let _ = tx.send(());
+                    }
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+        let set_status = |state, controls_accepted, exit_code| {
+            status.set_service_status(ServiceStatus {
+                service_type: SERVICE_TYPE,
+                current_state: state,
+                controls_accepted,
+                exit_code,
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+        };
+
+        set_status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            ServiceExitCode::Win32(0),
+        )?;
+        let config = CONFIG.lock().unwrap().take();
+        let res = parse(&read_config(config)?).and_then(|parsed| {
+            runtime::build(&parsed.runtime)?.block_on(async {
+                let server = load(parsed).await?;
+                eventlog::init(
+                    SERVICE_NAME,
+                    server.verbosity.to_level().unwrap_or(Level::Error),
+                )?;
+                log::set_max_level(server.verbosity);
+                serve(server, async {
+                    let _ = rx.await;
+                    log::warn!("stop requested by the service manager, shutting down");
+                })
+                .await
+            })
+        });
+        set_status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            // Tell the service manager we failed, so that it can be recovered or reported
+            ServiceExitCode::ServiceSpecific(res.is_err() as u32),
+        )?;
+        res
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use super::{plist, LAUNCHD_LABEL};
+    use anyhow::{bail, Result};
+    use std::{path::PathBuf, process::Command};
+
+    fn plist_path() -> PathBuf {
+        PathBuf::from(format!("/Library/LaunchDaemons/{}.plist", LAUNCHD_LABEL))
+    }
+
+    fn launchctl(action: &str) -> Result<()> {
+        let status = Command::new("launchctl")
+            .args([action, "-w"])
+            .arg(plist_path())
+            .status()?;
+        if !status.success() {
+            bail!("`launchctl {}` failed: {}", action, status);
+        }
+        Ok(())
+    }
+
+    pub fn install(config: PathBuf) -> Result<()> {
+        std::fs::write(plist_path(), plist(&std::env::current_exe()?, &config))?;
+        launchctl("load")?;
+        println!("launchd job `{}` installed and loaded.", LAUNCHD_LABEL);
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        launchctl("unload")?;
+        std::fs::remove_file(plist_path())?;
+        println!("launchd job `{}` unloaded and removed.", LAUNCHD_LABEL);
+        Ok(())
+    }
+
+    pub fn run(_: PathBuf) -> Result<()> {
+        bail!("launchd runs dcompass directly, `service run` is only used on Windows")
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod imp {
+    use anyhow::{bail, Result};
+    use std::path::PathBuf;
+
+    pub fn install(_: PathBuf) -> Result<()> {
+        bail!("services are only supported on Windows and macOS, use your init system (e.g. systemd) instead")
+    }
+
+    pub fn uninstall() -> Result<()> {
+        install(PathBuf::new())
+    }
+
+    pub fn run(_: PathBuf) -> Result<()> {
+        install(PathBuf::new())
+    }
+}
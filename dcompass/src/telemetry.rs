@@ -0,0 +1,84 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::parser::Otlp;
+use anyhow::Result;
+
+/// Export the spans of every query to the OpenTelemetry collector given.
+#[cfg(feature = "otlp")]
+pub fn init(config: Otlp) -> Result<()> {
+    use opentelemetry::{
+        sdk::{trace, Resource},
+        KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(config.endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name,
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    // Not using `try_init` as it would hijack `log`, which is already taken by `SimpleLogger`.
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+    )?;
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init(_: Otlp) -> Result<()> {
+    anyhow::bail!("`otlp` is set, but dcompass is built without the `otlp` feature")
+}
+
+/// Flush the spans not yet exported.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Otlp;
+
+    #[test]
+    fn config() {
+        let otlp: Otlp = serde_yaml::from_str("endpoint: http://127.0.0.1:4317").unwrap();
+        assert_eq!(otlp.endpoint, "http://127.0.0.1:4317");
+        assert_eq!(otlp.service_name, "dcompass");
+
+        let otlp: Otlp =
+            serde_yaml::from_str("endpoint: http://127.0.0.1:4317\nservice_name: edge").unwrap();
+        assert_eq!(otlp.service_name, "edge");
+
+        assert!(serde_yaml::from_str::<Otlp>("endpoint: a\nprotocol: grpc").is_err());
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    #[test]
+    fn unsupported() {
+        let otlp: Otlp = serde_yaml::from_str("endpoint: http://127.0.0.1:4317").unwrap();
+        assert!(super::init(otlp).is_err());
+    }
+}
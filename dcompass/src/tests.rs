@@ -13,8 +13,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::init;
-use droute::errors::*;
+use super::{
+    doh::{json_query, to_json},
+    init,
+    query_log::truncate,
+    service::plist,
+    top::TopK,
+};
+use domain::base::Rtype;
+use droute::{errors::*, trace::Annotation, utils::blackhole};
+use std::{net::IpAddr, path::Path, time::Duration};
 
 #[tokio::test]
 async fn check_default() {
@@ -34,22 +42,20 @@ async fn check_success_ipcidr() {
 #[cfg(all(feature = "geoip-maxmind", not(feature = "geoip-cn")))]
 #[tokio::test]
 async fn check_example_maxmind() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/example.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[cfg(all(feature = "geoip-cn", not(feature = "geoip-maxmind")))]
 #[tokio::test]
 async fn check_example_cn() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/example.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
@@ -58,31 +64,28 @@ async fn check_success_query_cache_mode() {
     init(serde_yaml::from_str(include_str!("../../configs/query_cache_policy.yaml")).unwrap())
         .await
         .unwrap();
-    assert_eq!(
-        init(serde_yaml::from_str(include_str!("../../configs/query_cache_policy.yaml")).unwrap())
-            .await
-            .is_ok(),
-        true
-    );
+    assert!(init(
+        serde_yaml::from_str(include_str!("../../configs/query_cache_policy.yaml")).unwrap()
+    )
+    .await
+    .is_ok());
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn check_success_geoip() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_geoip.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[tokio::test]
 async fn check_success_header_yaml() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_header.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
@@ -97,3 +100,227 @@ async fn check_fail_recursion() {
         e => panic!("Not the right error type: {}", e),
     };
 }
+
+#[tokio::test]
+async fn check_success_zone() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_zone.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_pinned() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_pinned.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_views() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_views.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_doh() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_doh.yaml")).unwrap();
+    assert_eq!(parsed.doh.as_ref().unwrap().path, "/resolve");
+    init(parsed).await.unwrap();
+}
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[tokio::test]
+async fn check_success_dot() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_dot.yaml")).unwrap();
+    let dot = parsed.dot.as_ref().unwrap();
+    assert!(dot.tls.client_ca.is_some());
+    super::tls::Acceptor::new(&dot.tls, &[b"dot"]).unwrap();
+    super::tls::Acceptor::new(parsed.doh.as_ref().unwrap().tls.as_ref().unwrap(), &[]).unwrap();
+    init(parsed).await.unwrap();
+}
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[test]
+fn fail_tls_key() {
+    let mut tls = super::parser::TlsServer {
+        cert: "../data/tls/server.pem".into(),
+        key: "../data/tls/ca.pem".into(),
+        client_ca: None,
+    };
+    assert!(super::tls::Acceptor::new(&tls, &[]).is_err());
+    tls.key = "../data/tls/server.key".into();
+    tls.client_ca = Some("../data/tls/server.key".into());
+    assert!(super::tls::Acceptor::new(&tls, &[]).is_err());
+}
+
+#[test]
+fn doh_json() {
+    let query = json_query("example.com", Some("28")).unwrap();
+    assert_eq!(query.first_question().unwrap().qtype(), Rtype::Aaaa);
+    assert!(json_query("example.com", Some("NOTATYPE")).is_none());
+
+    let json = to_json(&blackhole(&json_query("example.com", None).unwrap()).unwrap()).unwrap();
+    assert_eq!(json["Status"], 0);
+    assert_eq!(json["Question"][0]["name"], "example.com.");
+    assert_eq!(json["Question"][0]["type"], 1);
+    assert_eq!(json["Additional"][0]["type"], 6);
+    assert!(json.get("Answer").is_none());
+}
+
+#[tokio::test]
+async fn check_success_padding() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_padding.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_happy_eyeballs() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_happy_eyeballs.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_lazy() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_lazy.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_split() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_split.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_annotate() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_annotate.yaml")).unwrap();
+    assert_eq!(parsed.annotate, Some(Annotation::Ede));
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_minimal() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_minimal.yaml")).unwrap();
+    assert_eq!(parsed.max_udp_size, Some(1232));
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_normalize() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_normalize.yaml")).unwrap();
+    assert!(parsed.normalize_qname);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_runtime() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_runtime.yaml")).unwrap();
+    assert_eq!(super::runtime::workers(&parsed.runtime), 4);
+    assert_eq!(parsed.runtime.max_blocking_threads, Some(64));
+    assert_eq!(parsed.runtime.cpu_affinity, vec![0, 1, 2, 3]);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_offline() {
+    let (_, _, _, offline) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_offline.yaml")).unwrap())
+            .await
+            .unwrap();
+    assert!(offline.get());
+}
+
+#[tokio::test]
+async fn check_success_pipeline() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_pipeline.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_table() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_table.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_cache_eviction() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_cache_eviction.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_doh_method() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_doh_method.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[tokio::test]
+async fn check_success_tsig() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_tsig.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn check_plist() {
+    let plist = plist(
+        Path::new("/usr/local/bin/dcompass"),
+        Path::new("/etc/a&b.yaml"),
+    );
+    assert!(plist.contains("<string>/usr/local/bin/dcompass</string>"));
+    assert!(plist.contains("<string>/etc/a&amp;b.yaml</string>"));
+}
+
+#[tokio::test]
+async fn check_success_query_log() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_query_log.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn check_truncate() {
+    let ip: IpAddr = "192.168.31.77".parse().unwrap();
+    assert_eq!(truncate(ip), "192.168.31.0".parse::<IpAddr>().unwrap());
+    let ip: IpAddr = "2001:db8:1234:5678::1".parse().unwrap();
+    assert_eq!(truncate(ip), "2001:db8:1234::".parse::<IpAddr>().unwrap());
+}
+
+#[tokio::test]
+async fn check_success_control() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_control.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn check_top_k() {
+    let mut top = TopK::new(4, Duration::from_secs(600));
+    for i in 0..200 {
+        top.add(&format!("{}.example.com", i));
+        if i % 10 == 0 {
+            top.add("popular.com");
+        }
+        if i % 20 == 0 {
+            top.add("less.popular.com");
+        }
+    }
+    let names: Vec<_> = top.top(2).into_iter().map(|(k, _)| k).collect();
+    assert_eq!(names, vec!["popular.com", "less.popular.com"]);
+}
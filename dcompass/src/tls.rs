@@ -0,0 +1,163 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! TLS termination shared by the encrypted frontends, optionally authenticating clients by their certificates.
+
+use super::parser::TlsServer;
+use anyhow::{Context, Result};
+use log::*;
+use std::{future::Future, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+// Connections not finishing the handshake in time are dropped, so that they can't hold on to the sockets.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+mod acceptor {
+    use super::TlsServer;
+    use anyhow::{anyhow, Context, Result};
+    use rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    };
+    use rustls_pemfile::Item;
+    use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsAcceptor;
+
+    pub type Stream = tokio_rustls::server::TlsStream<TcpStream>;
+
+    fn certs(path: &Path) -> Result<Vec<Certificate>> {
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        );
+        let certs = rustls_pemfile::certs(&mut reader)
+            .with_context(|| format!("failed to read certificates from {}", path.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate found in {}", path.display()));
+        }
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+
+    fn key(path: &Path) -> Result<PrivateKey> {
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        );
+        rustls_pemfile::read_all(&mut reader)
+            .with_context(|| format!("failed to read the private key from {}", path.display()))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .map(PrivateKey)
+            .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+    }
+
+    #[derive(Clone)]
+    pub struct Acceptor(TlsAcceptor);
+
+    impl Acceptor {
+        pub fn new(config: &TlsServer, alpn: &[&[u8]]) -> Result<Self> {
+            let builder = ServerConfig::builder().with_safe_defaults();
+            let builder = match &config.client_ca {
+                Some(path) => {
+                    let mut roots = RootCertStore::empty();
+                    for cert in certs(path)? {
+                        roots.add(&cert).with_context(|| {
+                            format!("invalid CA certificate in {}", path.display())
+                        })?;
+                    }
+                    builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                }
+                None => builder.with_no_client_auth(),
+            };
+            let mut server = builder
+                .with_single_cert(certs(&config.cert)?, key(&config.key)?)
+                .context("the certificate doesn't match the private key")?;
+            server.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+            Ok(Self(TlsAcceptor::from(Arc::new(server))))
+        }
+
+        pub async fn accept(&self, stream: TcpStream) -> std::io::Result<Stream> {
+            self.0.accept(stream).await
+        }
+    }
+}
+
+// rustls doesn't build on MIPS, and native-tls can't ask clients for certificates.
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+mod acceptor {
+    use super::TlsServer;
+    use anyhow::{bail, Result};
+    use std::convert::Infallible;
+    use tokio::net::TcpStream;
+
+    pub type Stream = TcpStream;
+
+    #[derive(Clone)]
+    pub struct Acceptor(Infallible);
+
+    impl Acceptor {
+        pub fn new(_: &TlsServer, _: &[&[u8]]) -> Result<Self> {
+            bail!("serving over TLS is not supported on this platform")
+        }
+
+        pub async fn accept(&self, _: TcpStream) -> std::io::Result<Stream> {
+            match self.0 {}
+        }
+    }
+}
+
+pub use acceptor::{Acceptor, Stream};
+
+/// Bind a TCP listener to the address.
+pub fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    let listener =
+        std::net::TcpListener::bind(addr).with_context(|| format!("failed to bind to {}", addr))?;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// Accept connections on the listener, and hand each of them over to `handle` on its own task once the handshake completes. Clients failing to authenticate are rejected during the handshake.
+pub async fn serve<F, Fut>(listener: TcpListener, acceptor: Acceptor, handle: F) -> Result<()>
+where
+    F: Fn(Stream, SocketAddr) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Errors like running out of file descriptors are transient
+                warn!("failed to accept the connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    // Not holding `handle` across the await, which is not `Sync`
+                    let conn = handle(stream, src);
+                    conn.await
+                }
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", src, e),
+                Err(_) => debug!("TLS handshake with {} timed out", src),
+            }
+        });
+    }
+}
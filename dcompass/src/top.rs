@@ -0,0 +1,166 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Approximate rolling counts of the most queried domains and the busiest clients, in memory bounded regardless of the traffic.
+
+use bytes::Bytes;
+use domain::base::Message;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Number of rows of the Count-Min Sketch
+const DEPTH: usize = 4;
+
+static TOP: OnceCell<Top> = OnceCell::new();
+
+struct Top {
+    domains: Mutex<TopK>,
+    clients: Mutex<TopK>,
+}
+
+/// Start counting the queries. `capacity` is the number of heavy hitters tracked, and counts are halved every `window`.
+pub fn init(capacity: usize, window: Duration) {
+    let _ = TOP.set(Top {
+        domains: Mutex::new(TopK::new(capacity, window)),
+        clients: Mutex::new(TopK::new(capacity, window)),
+    });
+}
+
+/// Count the query from the client. This is a no-op if counting is not started.
+pub fn record(client: IpAddr, query: &Message<Bytes>) {
+    let top = match TOP.get() {
+        Some(top) => top,
+        None => return,
+    };
+    if let Some(q) = query.first_question() {
+        let qname = q.qname().to_string().to_ascii_lowercase();
+        top.domains.lock().unwrap().add(&qname);
+    }
+    top.clients.lock().unwrap().add(&client.to_string());
+}
+
+/// The `n` most queried domains with their approximate counts, in descending order.
+pub fn domains(n: usize) -> Vec<(String, u32)> {
+    TOP.get()
+        .map(|t| t.domains.lock().unwrap().top(n))
+        .unwrap_or_default()
+}
+
+/// The `n` busiest clients with their approximate counts, in descending order.
+pub fn clients(n: usize) -> Vec<(String, u32)> {
+    TOP.get()
+        .map(|t| t.clients.lock().unwrap().top(n))
+        .unwrap_or_default()
+}
+
+/// Heavy hitters of a stream, estimated with a Count-Min Sketch. Counts decay exponentially so that they reflect the recent traffic.
+pub struct TopK {
+    width: usize,
+    counters: Vec<u32>,
+    hasher: RandomState,
+    capacity: usize,
+    // Keys with the largest counts seen
+    top: HashMap<String, u32>,
+    // A lower bound of the least count in `top` once it is full, below which keys are not candidates
+    floor: u32,
+    window: Duration,
+    decayed: Instant,
+}
+
+impl TopK {
+    /// Track the `capacity` keys with the largest counts, which are halved every `window`.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        // Wide enough that the overestimation is negligible for the heavy hitters
+        let width = (capacity * 64).next_power_of_two().max(1024);
+        Self {
+            width,
+            counters: vec![0; width * DEPTH],
+            hasher: RandomState::new(),
+            capacity,
+            top: HashMap::with_capacity(capacity),
+            floor: 0,
+            window,
+            decayed: Instant::now(),
+        }
+    }
+
+    fn index(&self, key: &str, row: usize) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        row * self.width + (hasher.finish() as usize) % self.width
+    }
+
+    fn decay(&mut self) {
+        self.counters.iter_mut().for_each(|c| *c /= 2);
+        self.top.values_mut().for_each(|c| *c /= 2);
+        self.top.retain(|_, c| *c > 0);
+        self.floor /= 2;
+        self.decayed = Instant::now();
+    }
+
+    /// Count one occurrence of the key.
+    pub fn add(&mut self, key: &str) {
+        if self.decayed.elapsed() >= self.window {
+            self.decay();
+        }
+
+        let indices: [usize; DEPTH] = std::array::from_fn(|row| self.index(key, row));
+        let count = indices
+            .iter()
+            .map(|&i| self.counters[i])
+            .min()
+            .unwrap_or_default()
+            .saturating_add(1);
+        // Conservative update: counters already above the estimate are left as they are, which reduces the overestimation.
+        for i in indices {
+            self.counters[i] = self.counters[i].max(count);
+        }
+
+        if let Some(c) = self.top.get_mut(key) {
+            *c = count;
+        } else if self.top.len() < self.capacity {
+            self.top.insert(key.to_owned(), count);
+        } else if count > self.floor {
+            // Counts only grow between decays, so the floor may be stale and has to be checked against.
+            if let Some((min_key, min)) = self
+                .top
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(k, c)| (k.clone(), *c))
+            {
+                if count > min {
+                    self.top.remove(&min_key);
+                    self.top.insert(key.to_owned(), count);
+                }
+            }
+            self.floor = self.top.values().copied().min().unwrap_or_default();
+        }
+    }
+
+    /// The `n` keys with the largest counts, in descending order.
+    pub fn top(&self, n: usize) -> Vec<(String, u32)> {
+        let mut top: Vec<_> = self.top.iter().map(|(k, c)| (k.clone(), *c)).collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Plain DNS over UDP frontend, which can be sharded across several sockets bound with `SO_REUSEPORT` so that the kernel balances the packets among them.
+
+use super::{worker::worker, DcompassRouter};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::*;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::UdpSocket, sync::broadcast::Sender};
+
+// Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
+const BUF_LEN: usize = 1024;
+
+/// Bind `n` sockets to the address. `SO_REUSEPORT` is set if there is more than one socket. If `cores` is not empty, the sockets are assigned to the cores in turn with `SO_INCOMING_CPU`, so that packets are received on the core the worker threads pinned to it run on.
+pub fn bind(addr: SocketAddr, n: usize, cores: &[usize]) -> Result<Vec<Arc<UdpSocket>>> {
+    #[cfg(not(unix))]
+    if n > 1 {
+        anyhow::bail!("sharding UDP sockets relies on `SO_REUSEPORT`, which is only available on Unix-like systems");
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if !cores.is_empty() {
+        anyhow::bail!("`SO_INCOMING_CPU` is only available on Linux");
+    }
+
+    let sockets: Vec<Arc<UdpSocket>> = (0..n)
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            #[cfg(unix)]
+            if n > 1 {
+                socket.set_reuse_port(true)?;
+            }
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            Ok(Arc::new(UdpSocket::from_std(socket.into())?))
+        })
+        .collect::<std::io::Result<_>>()
+        .with_context(|| format!("failed to bind to {}", addr))?;
+
+    #[cfg(target_os = "linux")]
+    if n > 1 {
+        for (socket, core) in sockets.iter().zip(cores.iter().cycle()) {
+            socket2::SockRef::from(socket.as_ref())
+                .set_cpu_affinity(*core)
+                .with_context(|| format!("failed to assign the socket to CPU core {}", core))?;
+        }
+    }
+    Ok(sockets)
+}
+
+/// The way responses are sent back to the clients.
+#[derive(Clone)]
+pub enum Reply {
+    /// Send the response on the socket right away.
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    Direct(Arc<UdpSocket>),
+    /// Hand the response over to the task sending responses in batches.
+    #[cfg(target_os = "linux")]
+    Batched(tokio::sync::mpsc::Sender<(Bytes, SocketAddr)>),
+}
+
+impl Reply {
+    /// Send the response to `dst`.
+    pub async fn send(&self, resp: Bytes, dst: SocketAddr) -> std::io::Result<()> {
+        match self {
+            Self::Direct(socket) => socket.send_to(&resp, dst).await.map(|_| ()),
+            #[cfg(target_os = "linux")]
+            Self::Batched(tx) => tx.send((resp, dst)).await.map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "sending task exited")
+            }),
+        }
+    }
+}
+
+fn dispatch(
+    router: &Arc<DcompassRouter>,
+    reply: Reply,
+    buf: Bytes,
+    src: SocketAddr,
+    max_size: Option<u16>,
+    tx: &Sender<()>,
+) {
+    let router = router.clone();
+    let mut shutdown = tx.subscribe();
+    #[rustfmt::skip]
+    tokio::spawn(async move {
+        tokio::select! {
+            biased; res = worker(router, reply, buf, src, max_size) => {
+                match res {
+                    Ok(_) => (),
+                    Err(e) => warn!("handling query failed: {}", e),
+                }
+            }
+            _ = shutdown.recv() => {
+                // If a shutdown signal is received, return from the spawned task.
+                // This will result in the task terminating.
+                log::warn!("worker shut down");
+            }
+        }
+    });
+}
+
+/// Serve the queries received on the socket until an unrecoverable error occurs. Responses are truncated to `max_size` if it is set.
+#[cfg(not(target_os = "linux"))]
+pub async fn serve(
+    socket: Arc<UdpSocket>,
+    router: Arc<DcompassRouter>,
+    max_size: Option<u16>,
+    tx: &Sender<()>,
+) {
+    // Queries are received into the same allocation, which is reused once all of them are dropped.
+    let mut buf = bytes::BytesMut::with_capacity(BUF_LEN * 64);
+    loop {
+        buf.resize(BUF_LEN, 0);
+        // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to receive query: {}", e);
+                continue;
+            }
+        };
+        buf.truncate(len);
+        dispatch(
+            &router,
+            Reply::Direct(socket.clone()),
+            buf.split().freeze(),
+            src,
+            max_size,
+            tx,
+        );
+    }
+}
+
+/// Serve the queries received on the socket until an unrecoverable error occurs. Queries are received and responses are sent in batches with `recvmmsg` and `sendmmsg`. Responses are truncated to `max_size` if it is set.
+#[cfg(target_os = "linux")]
+pub async fn serve(
+    socket: Arc<UdpSocket>,
+    router: Arc<DcompassRouter>,
+    max_size: Option<u16>,
+    tx: &Sender<()>,
+) {
+    let (reply, rx) = tokio::sync::mpsc::channel(mmsg::BATCH * 4);
+    tokio::spawn(mmsg::send_loop(socket.clone(), rx));
+    // Batches are received into the same allocation, which is reused once all the queries in it are dropped.
+    let mut buf = bytes::BytesMut::new();
+    loop {
+        let packets = match mmsg::recv(&socket, &mut buf).await {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("failed to receive query: {}", e);
+                continue;
+            }
+        };
+        for (buf, src) in packets {
+            dispatch(
+                &router,
+                Reply::Batched(reply.clone()),
+                buf,
+                src,
+                max_size,
+                tx,
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+mod mmsg {
+    use super::BUF_LEN;
+    use bytes::{Bytes, BytesMut};
+    use log::*;
+    use socket2::SockAddr;
+    use std::{
+        io, mem,
+        net::SocketAddr,
+        os::unix::io::{AsRawFd, RawFd},
+        sync::Arc,
+    };
+    use tokio::{io::Interest, net::UdpSocket, sync::mpsc::Receiver};
+
+    // Maximum number of packets received or sent with a single system call
+    pub const BATCH: usize = 32;
+
+    fn recvmmsg(fd: RawFd, buf: &mut BytesMut) -> io::Result<Vec<(usize, SocketAddr)>> {
+        // SAFETY: all-zero is a valid representation of these C structs.
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; BATCH];
+        let mut iovecs: Vec<libc::iovec> = buf
+            .chunks_mut(BUF_LEN)
+            .map(|chunk| libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            })
+            .collect();
+        let mut hdrs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, addr)| {
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                hdr.msg_hdr.msg_iov = iov;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+
+        // SAFETY: every header points to a buffer and an address storage that outlive the call.
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                hdrs.as_mut_ptr(),
+                hdrs.len() as _,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        hdrs.iter()
+            .zip(addrs)
+            .take(n as usize)
+            .map(|(hdr, addr)| {
+                // SAFETY: the address is filled in by the kernel with its length given.
+                let addr = unsafe { SockAddr::new(addr, hdr.msg_hdr.msg_namelen) };
+                addr.as_socket()
+                    .map(|addr| (hdr.msg_len as usize, addr))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "unknown address family")
+                    })
+            })
+            .collect()
+    }
+
+    fn sendmmsg(fd: RawFd, msgs: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+        let addrs: Vec<SockAddr> = msgs.iter().map(|(_, addr)| (*addr).into()).collect();
+        let mut iovecs: Vec<libc::iovec> = msgs
+            .iter()
+            .map(|(buf, _)| libc::iovec {
+                // The buffer is only read by the kernel
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut hdrs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter())
+            .map(|(iov, addr)| {
+                // SAFETY: all-zero is a valid representation of the C struct.
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = addr.len();
+                hdr.msg_hdr.msg_iov = iov;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+
+        // SAFETY: every header points to a buffer and an address that outlive the call.
+        let n =
+            unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, libc::MSG_DONTWAIT) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// Receive a batch of packets into `buf`.
+    pub async fn recv(
+        socket: &UdpSocket,
+        buf: &mut BytesMut,
+    ) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        // A single allocation shared by the whole batch
+        buf.clear();
+        buf.reserve(BUF_LEN * BATCH);
+        buf.resize(BUF_LEN * BATCH, 0);
+        let received = loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || recvmmsg(socket.as_raw_fd(), buf)) {
+                Ok(r) => break r,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(received
+            .into_iter()
+            .map(|(len, src)| {
+                let mut packet = buf.split_to(BUF_LEN);
+                packet.truncate(len);
+                (packet.freeze(), src)
+            })
+            .collect())
+    }
+
+    async fn send(socket: &UdpSocket, msgs: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || sendmmsg(socket.as_raw_fd(), msgs)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Send the responses queued in batches until all the senders are dropped.
+    pub async fn send_loop(socket: Arc<UdpSocket>, mut rx: Receiver<(Bytes, SocketAddr)>) {
+        let mut batch = Vec::with_capacity(BATCH);
+        while let Some(msg) = rx.recv().await {
+            batch.push(msg);
+            while batch.len() < BATCH {
+                match rx.try_recv() {
+                    Ok(msg) => batch.push(msg),
+                    Err(_) => break,
+                }
+            }
+
+            let mut sent = 0;
+            while sent < batch.len() {
+                match send(&socket, &batch[sent..]).await {
+                    Ok(n) => sent += n,
+                    // The first message in the remaining batch failed, skip it.
+                    Err(e) => {
+                        warn!("failed to send back response to {}: {}", batch[sent].1, e);
+                        sent += 1;
+                    }
+                }
+            }
+            batch.clear();
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{bind, serve};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
+    use tokio::{net::UdpSocket, sync::broadcast, time::timeout};
+
+    const CONFIG: &str = r#"
+verbosity: "off"
+address: 127.0.0.1:2053
+script:
+  table:
+    start:
+      - then: [blackhole, end]
+upstreams: {}
+"#;
+
+    // Sent by every client, several batches in total
+    const QUERIES: u16 = 24;
+    const CLIENTS: usize = 8;
+
+    fn query(client: usize, id: u16) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder
+            .push((
+                Dname::<Bytes>::from_str(&format!("c{}.example", client)).unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    async fn check(n: usize) {
+        // Sockets bound to port 0 would each get a port of their own
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (router, ..) = crate::init(serde_yaml::from_str(CONFIG).unwrap())
+            .await
+            .unwrap();
+        let router = Arc::new(router);
+        let (tx, _) = broadcast::channel(1);
+        for socket in bind(addr, n, &[]).unwrap() {
+            let (router, tx) = (router.clone(), tx.clone());
+            tokio::spawn(async move { serve(socket, router, None, &tx).await });
+        }
+
+        let mut clients = Vec::new();
+        for c in 0..CLIENTS {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for id in 0..QUERIES {
+                socket.send_to(query(c, id).as_slice(), addr).await.unwrap();
+            }
+            clients.push(socket);
+        }
+
+        // Every client gets the responses to its own queries only, all from the address it sent them to
+        for (c, socket) in clients.iter().enumerate() {
+            let mut ids = HashSet::new();
+            let mut buf = [0; 512];
+            while ids.len() < QUERIES as usize {
+                let (len, src) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+                    .await
+                    .expect("responses missing")
+                    .unwrap();
+                assert_eq!(src, addr);
+                let resp = Message::from_octets(&buf[..len]).unwrap();
+                assert_eq!(
+                    resp.first_question().unwrap().qname().to_string(),
+                    format!("c{}.example", c)
+                );
+                assert!(ids.insert(resp.header().id()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        check(1).await;
+    }
+
+    #[tokio::test]
+    async fn reuse_port() {
+        check(4).await;
+    }
+}
@@ -13,37 +13,48 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use super::{query_log, top, udp::Reply, DcompassRouter};
 use anyhow::Result;
 use bytes::Bytes;
-use domain::base::Message;
-use droute::{builders::RuneScript, QueryContext, Router};
+use droute::{
+    inbound::{self, Inbound},
+    utils::{truncate, udp_limit},
+    QueryContext,
+};
 use log::*;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+use tracing::Instrument;
 
 /// Handle a single incoming packet
 pub async fn worker(
-    router: Arc<Router<RuneScript>>,
-    socket: Arc<UdpSocket>,
+    router: Arc<DcompassRouter>,
+    reply: Reply,
     buf: Bytes,
     src: SocketAddr,
+    max_size: Option<u16>,
 ) -> Result<()> {
-    socket
-        .send_to(
-            router
-                .resolve(
-                    Message::from_octets(buf)?,
-                    Some(QueryContext { ip: src.ip() }),
-                )
-                .await?
-                .as_slice(),
-            src,
-        )
-        .await
-        .unwrap_or_else(|e| {
-            warn!("failed to send back response: {}", e);
-            0
-        });
+    let start = Instant::now();
+    let query = match inbound::parse(buf) {
+        Inbound::Query(query) => query,
+        Inbound::FormErr(resp) => {
+            reply.send(resp.into_octets(), src).await?;
+            return Ok(());
+        }
+        Inbound::Drop => return Ok(()),
+    };
+    top::record(src.ip(), &query);
+    let resp = router
+        .resolve(query.clone(), Some(QueryContext { ip: src.ip() }))
+        .instrument(tracing::info_span!("query", protocol = "udp", client = %src))
+        .await?;
+    query_log::record(src.ip(), &query, &resp, start.elapsed());
+    let resp = match max_size {
+        Some(max) => truncate(&resp, udp_limit(&query, max))?,
+        None => resp,
+    };
+    if let Err(e) = reply.send(resp.into_octets(), src).await {
+        warn!("failed to send back response: {}", e);
+    }
 
     info!("response completed. Sent back to {} successfully.", src);
 
@@ -15,7 +15,7 @@
 
 use bytes::Bytes;
 use criterion::{criterion_group, criterion_main, Criterion};
-use dmatcher::domain::Domain;
+use dmatcher::{compact::Compact, domain::Domain};
 use domain::base::Dname;
 use std::{fs::File, io::Read, str::FromStr};
 
@@ -33,7 +33,13 @@ fn bench_match(c: &mut Criterion) {
     let test = Dname::from_str("store.www.baidu.com").unwrap();
     matcher.insert_multi(&domains);
     c.bench_function("match", |b| {
-        b.iter(|| assert_eq!(matcher.matches(&test), true))
+        b.iter(|| assert!(matcher.matches(&test)))
+    });
+
+    let mut compact = Compact::new();
+    compact.insert_multi(&domains);
+    c.bench_function("match_compact", |b| {
+        b.iter(|| assert!(compact.matches(&test)))
     });
 }
 
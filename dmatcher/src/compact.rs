@@ -0,0 +1,211 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A compact variant of the domain matching algorithm, intended for rule sets with millions of domains.
+//!
+//! Rules are kept as a sorted array of encoded names in a single buffer instead of a trie of labels, which takes several times less memory. In exchange, every insertion rebuilds the array, so rules should be inserted in bulk with `insert_multi`.
+
+use bytes::Bytes;
+use domain::base::Dname;
+
+// Maximum length of an encoded name, which is never longer than the name on the wire.
+const MAX_KEY_LEN: usize = 255;
+
+// Encode the name into `buf` and return the length. Labels are ordered from the root, each prefixed with its length and lowercased, so that a rule matches a name if and only if its key is a prefix of the name's key.
+fn encode(name: &Dname<Bytes>, buf: &mut [u8; MAX_KEY_LEN]) -> usize {
+    let mut len = 0;
+    for label in name.iter().rev().filter(|l| !l.is_root()) {
+        buf[len] = label.len() as u8;
+        for (i, c) in label.as_slice().iter().enumerate() {
+            buf[len + 1 + i] = c.to_ascii_lowercase();
+        }
+        len += 1 + label.len();
+    }
+    len
+}
+
+// The `i`-th key in the keys concatenated
+fn key<'a>(keys: &'a [u8], offsets: &[u32], i: usize) -> &'a [u8] {
+    let start = offsets[i] as usize;
+    let end = offsets
+        .get(i + 1)
+        .map(|&o| o as usize)
+        .unwrap_or(keys.len());
+    &keys[start..end]
+}
+
+/// Compact domain matcher algorithm
+#[derive(Clone, Default)]
+pub struct Compact {
+    // Encoded rules concatenated in ascending order. No rule is a prefix of another.
+    keys: Vec<u8>,
+    // Start of each rule in `keys`
+    offsets: Vec<u32>,
+}
+
+impl Compact {
+    /// Create a matcher.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of rules in the matcher, excluding the ones covered by others.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether there is no rule in the matcher.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        key(&self.keys, &self.offsets, i)
+    }
+
+    /// Insert all the domains yielded, rebuilding the matcher once. Domains are encoded as soon as they are yielded, so they can be streamed without being held in memory.
+    pub fn extend(&mut self, domains: impl IntoIterator<Item = Dname<Bytes>>) {
+        let (mut keys, mut offsets) = (
+            std::mem::take(&mut self.keys),
+            std::mem::take(&mut self.offsets),
+        );
+        let mut buf = [0; MAX_KEY_LEN];
+        for domain in domains {
+            offsets.push(keys.len() as u32);
+            let len = encode(&domain, &mut buf);
+            keys.extend_from_slice(&buf[..len]);
+        }
+        let mut order: Vec<usize> = (0..offsets.len()).collect();
+        order.sort_unstable_by(|&a, &b| key(&keys, &offsets, a).cmp(key(&keys, &offsets, b)));
+
+        for i in order {
+            let key = key(&keys, &offsets, i);
+            // A rule comes right after the ones covering it once sorted. Both the duplicates and the rules covered are redundant.
+            if !self.is_empty() && key.starts_with(self.key(self.len() - 1)) {
+                continue;
+            }
+            self.offsets.push(self.keys.len() as u32);
+            self.keys.extend_from_slice(key);
+        }
+        self.keys.shrink_to_fit();
+        self.offsets.shrink_to_fit();
+    }
+
+    /// Insert all the domains given, rebuilding the matcher once.
+    pub fn insert_multi(&mut self, domain: &[Dname<Bytes>]) {
+        self.extend(domain.iter().cloned())
+    }
+
+    /// Insert a domain into the matcher. This rebuilds the whole matcher, use `insert_multi` or `extend` to insert many at once.
+    pub fn insert(&mut self, domain: &Dname<Bytes>) {
+        self.insert_multi(std::slice::from_ref(domain))
+    }
+
+    /// Match the domain against inserted domain rules. If `apple.com` is inserted, then `www.apple.com` and `stores.www.apple.com` is considered as matched while `apple.cn` is not. Unlike [`Domain`](crate::domain::Domain), this holds even if `www.apple.com` is inserted as well.
+    pub fn matches(&self, domain: &Dname<Bytes>) -> bool {
+        // Same as `Domain`, a matcher without any rule matches everything
+        if self.is_empty() {
+            return true;
+        }
+        let mut buf = [0; MAX_KEY_LEN];
+        let len = encode(domain, &mut buf);
+        let key = &buf[..len];
+
+        // As rules never cover each other, the only rule possibly covering the domain is the greatest one not greater than it.
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.key(mid) <= key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo > 0 && key.starts_with(self.key(lo - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compact;
+    use crate::domain::Domain;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    macro_rules! dname {
+        ($s:expr) => {
+            Dname::from_str($s).unwrap()
+        };
+    }
+
+    #[test]
+    fn matches() {
+        let mut matcher = Compact::new();
+        matcher.insert_multi(&[
+            dname!("tejia.taobao.com"),
+            dname!("temai.m.taobao.com"),
+            dname!("tui.taobao.com"),
+            dname!("apple.com"),
+            dname!("apple.cn"),
+        ]);
+        assert!(matcher.matches(&dname!("a.tui.taobao.com")));
+        assert!(matcher.matches(&dname!("tejia.taobao.com")));
+        assert!(!matcher.matches(&dname!("m.taobao.com")));
+        assert!(!matcher.matches(&dname!("taobao.com")));
+        assert!(matcher.matches(&dname!("store.apple.com.")));
+        assert!(matcher.matches(&dname!("STORE.Apple.CN")));
+        assert!(!matcher.matches(&dname!("apple.co")));
+        assert!(!matcher.matches(&dname!("baidu.com")));
+    }
+
+    #[test]
+    fn redundant() {
+        let mut matcher = Compact::new();
+        matcher.insert_multi(&[dname!("www.apple.com"), dname!("apple.com")]);
+        matcher.insert(&dname!("apple.com"));
+        matcher.insert(&dname!("store.apple.com"));
+        assert_eq!(matcher.len(), 1);
+        assert!(matcher.matches(&dname!("www.apple.com")));
+    }
+
+    #[test]
+    fn same_as_trie() {
+        // Rules covering each other are left out, where the trie only honors the most specific one
+        let rules = [
+            dname!("a.b.c"),
+            dname!("ab.c"),
+            dname!("c.b"),
+            dname!("x-y.z"),
+        ];
+        let tests = [
+            "c", "b.c", "a.b.c", "ab.c", "a.ab.c", "b.b.c", "c.b", "b", "z", "x-y.z", "x.z",
+        ];
+        let (mut trie, mut compact) = (Domain::new(), Compact::new());
+        // Both match everything if empty
+        for t in tests {
+            assert_eq!(trie.matches(&dname!(t)), compact.matches(&dname!(t)));
+        }
+        trie.insert_multi(&rules);
+        compact.insert_multi(&rules);
+        for t in tests {
+            assert_eq!(
+                trie.matches(&dname!(t)),
+                compact.matches(&dname!(t)),
+                "{}",
+                t
+            );
+        }
+    }
+}
@@ -17,4 +17,5 @@
 #![deny(unsafe_code)]
 //! This is a library providing a set of domain and IP address matching algorithms.
 
+pub mod compact;
 pub mod domain;
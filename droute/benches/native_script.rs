@@ -62,6 +62,11 @@ async fn create_router<T: ScriptBackend>(script_builder: impl ScriptBuilder<T>)
                 max_pool_size: 256,
                 timeout: 1,
                 ratelimit: None,
+                bind_addr: None,
+                bind_interface: None,
+                #[cfg(feature = "tsig")]
+                tsig: None,
+                max_reuse: 1,
             }),
         ),
     )
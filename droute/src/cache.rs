@@ -16,17 +16,169 @@
 use self::RecordStatus::*;
 use crate::{Label, MAX_TTL};
 use bytes::Bytes;
-use clru::CLruCache;
-use domain::base::{name::ToDname, Message};
+use clru::{CLruCache, CLruCacheConfig, WeightScale};
+use domain::base::Message;
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::{
     borrow::Borrow,
-    hash::{Hash, Hasher},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
     num::NonZeroUsize,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
+// Approximate memory taken by an entry besides the key and the message, which are the hash table slot, the LRU list node, and the record itself.
+const ENTRY_OVERHEAD: usize = 128;
+
+// Approximate size of an entry, used to estimate the number of entries a cache bounded by bytes holds.
+const AVERAGE_ENTRY_SIZE: usize = 256;
+
+/// How the size of the response cache is bounded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheCapacity {
+    /// Bound the cache by the number of responses
+    Entries(NonZeroUsize),
+    /// Bound the cache by the approximate memory taken by the responses, in bytes
+    Bytes(NonZeroUsize),
+}
+
+/// The policy to decide which response to evict once the cache is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// Evict the least recently used response
+    Lru,
+    /// Evict the least recently used response, but only if the new response is requested more frequently than it. Otherwise, the new response is not cached at all. This keeps the popular responses from being flushed by the ones requested only once.
+    TinyLfu,
+}
+
+impl Default for Eviction {
+    fn default() -> Self {
+        Self::Lru
+    }
+}
+
+type Key = (Label, Bytes);
+
+// Approximate memory taken by the entries
+#[derive(Clone, Copy)]
+struct Weight;
+
+impl WeightScale<Key, CacheRecord<Message<Bytes>>> for Weight {
+    fn weight(&self, key: &Key, value: &CacheRecord<Message<Bytes>>) -> usize {
+        key.0.len() + key.1.len() + value.content.as_slice().len() + ENTRY_OVERHEAD
+    }
+}
+
+type Record = CacheRecord<Message<Bytes>>;
+
+enum Store {
+    Entries(CLruCache<Key, Record>),
+    Bytes(CLruCache<Key, Record, RandomState, Weight>),
+}
+
+impl Store {
+    // Whether putting the entry may evict others
+    fn full(&self, key: &Key, value: &Record) -> bool {
+        match self {
+            Self::Entries(c) => c.peek(key).is_none() && c.len() >= c.capacity(),
+            Self::Bytes(c) => {
+                c.peek(key).is_none()
+                    && c.len() + c.weight() + Weight.weight(key, value) >= c.capacity()
+            }
+        }
+    }
+
+    // The least recently used entry
+    fn back(&self) -> Option<&Key> {
+        match self {
+            Self::Entries(c) => c.back().map(|(k, _)| k),
+            Self::Bytes(c) => c.back().map(|(k, _)| k),
+        }
+    }
+
+    fn put(&mut self, key: Key, value: Record) {
+        match self {
+            Self::Entries(c) => {
+                c.put(key, value);
+            }
+            Self::Bytes(c) => {
+                if c.put_with_weight(key, value).is_err() {
+                    info!("response too large to be cached.");
+                }
+            }
+        }
+    }
+
+    fn get(&mut self, key: &dyn KeyPair<Label, Bytes>) -> Option<&Record> {
+        match self {
+            Self::Entries(c) => c.get(key),
+            Self::Bytes(c) => c.get(key),
+        }
+    }
+}
+
+// Number of hash functions of the sketch
+const DEPTH: usize = 4;
+
+// Odd constants to derive the hash functions from one hash
+const SEEDS: [u64; DEPTH] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x85EB_CA77_C2B2_AE63,
+];
+
+// Maximum of the counters. 4 bits are enough to tell the popular responses from the rest.
+const MAX_COUNT: u8 = 15;
+
+// Count-min sketch estimating how frequently each query is requested, which TinyLFU admission is based on. Counters are halved periodically, so that the responses once popular are forgotten eventually.
+struct Sketch {
+    counters: Vec<u8>,
+    // Number of counters for each hash function, which is a power of two.
+    width: usize,
+    additions: usize,
+    // Number of additions after which the counters are halved
+    sample: usize,
+}
+
+impl Sketch {
+    fn new(entries: usize) -> Self {
+        let width = entries.next_power_of_two().max(64);
+        Self {
+            counters: vec![0; width * DEPTH],
+            width,
+            additions: 0,
+            sample: width * 10,
+        }
+    }
+
+    fn index(&self, hash: u64, i: usize) -> usize {
+        i * self.width + ((hash.wrapping_mul(SEEDS[i]) >> 32) as usize & (self.width - 1))
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for i in 0..DEPTH {
+            let index = self.index(hash, i);
+            self.counters[index] = (self.counters[index] + 1).min(MAX_COUNT);
+        }
+        self.additions += 1;
+        if self.additions >= self.sample {
+            self.counters.iter_mut().for_each(|c| *c /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        (0..DEPTH)
+            .map(|i| self.counters[self.index(hash, i)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
 // Code to use (&A, &B) for accessing HashMap, clipped from https://stackoverflow.com/questions/45786717/how-to-implement-hashmap-with-two-keys/45795699#45795699.
 trait KeyPair<A: ?Sized, B: ?Sized> {
     /// Obtains the first element of the pair.
@@ -105,17 +257,65 @@ pub enum RecordStatus<T> {
     Expired(T),
 }
 
-// A LRU cache for responses
+struct Inner {
+    cache: Store,
+    // Only present with TinyLFU eviction
+    sketch: Option<Sketch>,
+    hasher: RandomState,
+}
+
+impl Inner {
+    fn hash(&self, tag: &Label, query: &[u8]) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        tag.hash(&mut hasher);
+        query.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Whether to admit the new entry. Only TinyLFU may reject entries, which it does if the entry would evict a more frequently requested one.
+    fn admit(&self, key: &Key, value: &Record) -> bool {
+        let sketch = match &self.sketch {
+            Some(sketch) => sketch,
+            None => return true,
+        };
+        if !self.cache.full(key, value) {
+            return true;
+        }
+        match self.cache.back() {
+            Some((tag, query)) => {
+                sketch.estimate(self.hash(&key.0, &key.1)) > sketch.estimate(self.hash(tag, query))
+            }
+            None => true,
+        }
+    }
+}
+
+// A cache for responses
 #[derive(Clone)]
 pub struct RespCache {
-    #[allow(clippy::type_complexity)]
-    cache: Arc<Mutex<CLruCache<(Label, Bytes), CacheRecord<Message<Bytes>>>>>,
+    cache: Arc<Mutex<Inner>>,
 }
 
 impl RespCache {
-    pub fn new(size: NonZeroUsize) -> Self {
+    pub fn new(capacity: CacheCapacity, eviction: Eviction) -> Self {
+        let (cache, entries) = match capacity {
+            CacheCapacity::Entries(n) => (Store::Entries(CLruCache::new(n)), n.get()),
+            CacheCapacity::Bytes(n) => (
+                Store::Bytes(CLruCache::with_config(
+                    CLruCacheConfig::new(n).with_scale(Weight),
+                )),
+                n.get() / AVERAGE_ENTRY_SIZE,
+            ),
+        };
         Self {
-            cache: Arc::new(Mutex::new(CLruCache::new(size))),
+            cache: Arc::new(Mutex::new(Inner {
+                cache,
+                sketch: match eviction {
+                    Eviction::Lru => None,
+                    Eviction::TinyLfu => Some(Sketch::new(entries)),
+                },
+                hasher: RandomState::new(),
+            })),
         }
     }
 
@@ -134,12 +334,19 @@ impl RespCache {
                     })
                     .unwrap_or(MAX_TTL),
             ));
-            self.cache.lock().unwrap().put(
-                // We discard the first two bytes which are the places for ID
-                (tag, query.as_octets().slice(2..)),
-                // Clone should be cheap here
-                CacheRecord::new(msg, ttl),
-            );
+            // Messages are copied out so that they don't pin the pooled buffers they are encoded in.
+            let msg = Message::from_octets(Bytes::copy_from_slice(msg.as_slice()))
+                .expect("copied from a valid message");
+            // We discard the first two bytes which are the places for ID
+            let key = (tag, Bytes::copy_from_slice(&query.as_slice()[2..]));
+            let record = CacheRecord::new(msg, ttl);
+
+            let mut inner = self.cache.lock().unwrap();
+            if inner.admit(&key, &record) {
+                inner.cache.put(key, record);
+            } else {
+                debug!("response not admitted into the cache as it is requested less frequently than the ones cached.");
+            }
         } else {
             info!("response errored, not caching erroneous upstream response.");
         };
@@ -147,14 +354,16 @@ impl RespCache {
 
     pub fn get(&self, tag: &Label, msg: &Message<Bytes>) -> Option<RecordStatus<Message<Bytes>>> {
         let question = msg.first_question().unwrap();
-        let qname = question.qname().to_bytes();
-
-        match self
-            .cache
-            .lock()
-            .unwrap()
-            .get(&(tag, msg.as_octets().slice(2..)) as &dyn KeyPair<Label, Bytes>)
-        {
+        let qname = question.qname();
+
+        let mut inner = self.cache.lock().unwrap();
+        if inner.sketch.is_some() {
+            let hash = inner.hash(tag, &msg.as_slice()[2..]);
+            if let Some(sketch) = &mut inner.sketch {
+                sketch.increment(hash);
+            }
+        }
+        match inner.cache.get(&(tag, msg.as_octets().slice(2..))) {
             Some(r) => {
                 // Get record only once.
                 if r.validate() {
@@ -215,3 +424,71 @@ impl RespCache {
 //         Self::new()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheCapacity, Eviction, RecordStatus, RespCache};
+    use crate::{mock::query, Label};
+    use domain::base::Rtype;
+    use std::num::NonZeroUsize;
+
+    fn cached(cache: &RespCache, name: &str) -> bool {
+        matches!(
+            cache.get(&Label::from("tag"), &query(name, Rtype::A)),
+            Some(RecordStatus::Alive(_))
+        )
+    }
+
+    fn put(cache: &RespCache, name: &str) {
+        cache.put(
+            Label::from("tag"),
+            &query(name, Rtype::A),
+            query(name, Rtype::A),
+        );
+    }
+
+    #[test]
+    fn bytes() {
+        // Each entry takes around 160 bytes
+        let cache = RespCache::new(
+            CacheCapacity::Bytes(NonZeroUsize::new(1024).unwrap()),
+            Eviction::Lru,
+        );
+        for i in 0..16 {
+            put(&cache, &format!("{}.example.com", i));
+        }
+        assert!(cached(&cache, "15.example.com"));
+        assert!(!cached(&cache, "0.example.com"));
+    }
+
+    #[test]
+    fn tiny_lfu() {
+        let cache = RespCache::new(
+            CacheCapacity::Entries(NonZeroUsize::new(2).unwrap()),
+            Eviction::TinyLfu,
+        );
+        for name in ["a.com", "b.com"] {
+            for _ in 0..4 {
+                cached(&cache, name);
+            }
+            put(&cache, name);
+        }
+        // Requested only once, so it doesn't evict the popular ones
+        assert!(!cached(&cache, "c.com"));
+        put(&cache, "c.com");
+        assert!(!cached(&cache, "c.com"));
+        assert!(cached(&cache, "a.com"));
+        assert!(cached(&cache, "b.com"));
+
+        // Plain LRU evicts the least recently used one instead
+        let cache = RespCache::new(
+            CacheCapacity::Entries(NonZeroUsize::new(2).unwrap()),
+            Eviction::Lru,
+        );
+        for name in ["a.com", "b.com", "c.com"] {
+            put(&cache, name);
+        }
+        assert!(!cached(&cache, "a.com"));
+        assert!(cached(&cache, "c.com"));
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Sanity checks on the packets received from clients before they are routed. The whole message is walked once with hard limits, so that nothing routing it later has to deal with malformed or abusive input.
+
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Message, MessageBuilder};
+use log::debug;
+
+/// The largest query accepted. Queries hardly exceed a few hundred bytes even with EDNS options and TSIG signatures.
+pub const MAX_QUERY_LEN: usize = 4096;
+
+// Queries carry no answers, and only a few additional records like OPT and TSIG.
+const MAX_RECORDS: usize = 16;
+
+// Compression pointers followed at most for a single name. Pointers have to point backwards, so they can't loop, but chains of them still cost time to follow.
+const MAX_POINTERS: usize = 16;
+
+// Length of a name in the wire format, including the root label (RFC 1035).
+const MAX_NAME_LEN: usize = 255;
+
+const HEADER_LEN: usize = 12;
+
+/// The verdict on a packet received from a client.
+pub enum Inbound {
+    /// A well-formed query, which can be routed
+    Query(Message<Bytes>),
+    /// A malformed query, which should be answered with the `FORMERR` response given
+    FormErr(Message<Bytes>),
+    /// A packet too short to carry a header or a response rather than a query. Nothing should be sent back, or it could be used to reflect traffic.
+    Drop,
+}
+
+// Skip the name at `pos` and return the position right after it.
+pub(crate) fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, &'static str> {
+    let (mut end, mut len, mut pointers) = (None, 0, 0);
+    loop {
+        let b = *msg.get(pos).ok_or("truncated name")?;
+        match b & 0xc0 {
+            0x00 => {
+                len += b as usize + 1;
+                if len > MAX_NAME_LEN {
+                    return Err("name too long");
+                }
+                if b == 0 {
+                    return Ok(end.unwrap_or(pos + 1));
+                }
+                pos += b as usize + 1;
+            }
+            0xc0 => {
+                let target = ((b & 0x3f) as usize) << 8
+                    | *msg.get(pos + 1).ok_or("truncated name")? as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err("too many compression pointers");
+                }
+                if target < HEADER_LEN || target >= pos {
+                    return Err("compression pointer not pointing backwards into the body");
+                }
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            _ => return Err("unknown label type"),
+        }
+    }
+}
+
+// Walk the sections of the query. Only the framing is checked, not the content of the records.
+fn check(msg: &[u8]) -> Result<(), &'static str> {
+    if msg.len() > MAX_QUERY_LEN {
+        return Err("query too long");
+    }
+    let count = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]) as usize;
+    if count(4) != 1 {
+        return Err("not exactly one question");
+    }
+    let records = count(6) + count(8) + count(10);
+    if records > MAX_RECORDS {
+        return Err("too many records");
+    }
+
+    // Type and class
+    let mut pos = skip_name(msg, HEADER_LEN)? + 4;
+    for _ in 0..records {
+        // Type, class, TTL, and the length of the data
+        pos = skip_name(msg, pos)? + 10;
+        let len = msg.get(pos - 2..pos).ok_or("truncated record")?;
+        pos += u16::from_be_bytes([len[0], len[1]]) as usize;
+    }
+    if pos > msg.len() {
+        return Err("truncated message");
+    }
+    Ok(())
+}
+
+// The FORMERR response, which echoes the header only as the question may not be parsed.
+fn formerr(query: &Message<Bytes>) -> Option<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(HEADER_LEN)).ok()?;
+    let header = builder.header_mut();
+    header.set_id(query.header().id());
+    header.set_qr(true);
+    header.set_opcode(query.header().opcode());
+    header.set_rd(query.header().rd());
+    header.set_rcode(Rcode::FormErr);
+    Some(builder.into_message())
+}
+
+/// Check the packet received from a client.
+pub fn parse(buf: Bytes) -> Inbound {
+    let msg = match Message::from_octets(buf) {
+        Ok(msg) if !msg.header().qr() => msg,
+        _ => {
+            debug!("dropping a packet which is not a query");
+            return Inbound::Drop;
+        }
+    };
+    match check(msg.as_slice()) {
+        Ok(()) => Inbound::Query(msg),
+        Err(e) => {
+            debug!("malformed query: {}, answering with FORMERR", e);
+            formerr(&msg).map_or(Inbound::Drop, Inbound::FormErr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Inbound, MAX_QUERY_LEN};
+    use crate::{mock, utils::rebuild_with};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Message, Rtype};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // A query with RD set and an OPT record
+    fn query() -> Message<Bytes> {
+        let query = mock::query("www.example.com", Rtype::A);
+        let mut builder = rebuild_with(&query, BytesMut::new(), |_, _| Ok(vec![])).unwrap();
+        builder.header_mut().set_rd(true);
+        builder.opt(|_| Ok(())).unwrap();
+        builder.into_message()
+    }
+
+    fn rcode(buf: &[u8]) -> Option<Rcode> {
+        match parse(Bytes::copy_from_slice(buf)) {
+            Inbound::Query(_) => None,
+            Inbound::FormErr(resp) => Some(resp.header().rcode()),
+            Inbound::Drop => Some(Rcode::Refused),
+        }
+    }
+
+    #[test]
+    fn wellformed() {
+        assert_eq!(rcode(query().as_slice()), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let query = query();
+        let buf = query.as_slice();
+
+        // Too short to answer
+        assert!(matches!(
+            parse(Bytes::copy_from_slice(&buf[..11])),
+            Inbound::Drop
+        ));
+        // Responses are never answered
+        let mut resp = buf.to_vec();
+        resp[2] |= 0x80;
+        assert!(matches!(parse(Bytes::from(resp)), Inbound::Drop));
+
+        // Truncated anywhere after the header
+        for len in 12..buf.len() {
+            match parse(Bytes::copy_from_slice(&buf[..len])) {
+                Inbound::FormErr(resp) => {
+                    assert_eq!(resp.header().id(), 42);
+                    assert!(resp.header().qr() && resp.header().rd());
+                    assert_eq!(resp.header().rcode(), Rcode::FormErr);
+                }
+                _ => panic!("truncated query at {} accepted", len),
+            }
+        }
+
+        // No question
+        let mut v = buf.to_vec();
+        v[5] = 0;
+        assert_eq!(rcode(&v), Some(Rcode::FormErr));
+
+        // Compression pointer to itself
+        let mut v = buf[..12].to_vec();
+        v.extend([0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(rcode(&v), Some(Rcode::FormErr));
+
+        // Name longer than 255 bytes
+        let mut v = buf[..12].to_vec();
+        for _ in 0..5 {
+            v.push(63);
+            v.extend([b'a'; 63]);
+        }
+        v.extend([0, 0, 1, 0, 1]);
+        assert_eq!(rcode(&v), Some(Rcode::FormErr));
+
+        // Oversized
+        let mut v = buf.to_vec();
+        v.resize(MAX_QUERY_LEN + 1, 0);
+        assert_eq!(rcode(&v), Some(Rcode::FormErr));
+    }
+
+    // Random packets and random mutations of a valid query must never panic, and whatever is accepted must be parsable all the way through.
+    #[test]
+    fn fuzz() {
+        let mut rng = StdRng::seed_from_u64(0x0d17);
+        let query = query();
+        for _ in 0..20000 {
+            let buf: Vec<u8> = if rng.gen_bool(0.5) {
+                let len = rng.gen_range(0..128);
+                (0..len).map(|_| rng.gen()).collect()
+            } else {
+                let mut v = query.as_slice().to_vec();
+                for _ in 0..rng.gen_range(1..4) {
+                    let i = rng.gen_range(0..v.len());
+                    v[i] = rng.gen();
+                }
+                v
+            };
+            if let Inbound::Query(msg) = parse(Bytes::from(buf)) {
+                let q = msg.sole_question().unwrap();
+                let _ = q.qname().to_string();
+                for section in [msg.answer(), msg.authority(), msg.additional()] {
+                    for record in section.unwrap() {
+                        record.unwrap();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -26,9 +26,15 @@
 // Documentation
 //! This is the core library for dcompass. It implements configuration parsing scheme, DNS query routing rules, and upstream managements.
 pub(crate) mod cache;
+pub mod inbound;
 #[doc(hidden)]
 pub mod mock;
+pub mod padding;
+pub(crate) mod pool;
 mod router;
+pub mod trace;
+#[cfg(feature = "tsig")]
+pub mod tsig;
 
 #[cfg(all(feature = "doh-native-tls", feature = "doh-rustls"))]
 compile_error!("You should only choose one TLS backend for DNS over HTTPS implementation");
@@ -43,23 +49,48 @@ use compact_str::CompactString;
 // API guideline: when we are exporting, make sure we aggregate builders by pub using them in parent builder(s) modules.
 pub mod builders {
     pub use super::router::{script::builders::*, upstreams::builder::*, RouterBuilder};
+    #[cfg(feature = "tsig")]
+    pub use super::tsig::{GuardedBuilder, KeyBuilder as TsigKeyBuilder};
 }
 
 /// A collection of all errors in `droute`
 pub mod errors {
     pub use super::router::{
-        script::{MessageError, ScriptError},
-        upstreams::error::UpstreamError,
+        script::{utils::UtilsError, MessageError, ScriptError},
+        upstreams::{error::UpstreamError, QHandleError},
     };
+
+    /// The general category of an error, which helps to decide how to react to it programmatically.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        /// The configuration is invalid, e.g. a missing upstream tag or an unreadable rule file. Retrying won't help.
+        Config,
+        /// Failed to reach the upstream, e.g. connection failures and timeouts. It may succeed later.
+        Network,
+        /// Malformed or unexpected DNS or HTTP data.
+        Protocol,
+        /// The query is rejected on purpose, e.g. by the ratelimiter.
+        Policy,
+        /// The routing script failed at runtime.
+        Script,
+    }
 }
 
 // All the major components
+pub use self::cache::{CacheCapacity, Eviction};
 pub use self::router::{
-    script::{native::NativeScript, utils, QueryContext, ScriptBackend, ScriptBuilder},
-    upstreams::{CacheMode, Upstream, Upstreams},
+    script::{
+        native::NativeScript, table::Table, utils, views::Views, QueryContext, ScriptBackend,
+        ScriptBuilder,
+    },
+    upstreams::{CacheMode, Offline, Upstream, Upstreams},
     Router,
 };
 
+#[cfg(feature = "tower")]
+pub use self::router::service::RouterService;
+
 // Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
 //   Setting this to a value of 1 day, in seconds
 const MAX_TTL: u32 = 86400_u32;
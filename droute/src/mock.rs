@@ -14,8 +14,12 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 //! This module is NOT intended to be used by regular users. It is used for mocking purpose only.
+#[cfg(test)]
+use bytes::Bytes;
 use bytes::BytesMut;
 use domain::base::Message;
+#[cfg(test)]
+use domain::base::{Dname, MessageBuilder, Rtype};
 use std::net::SocketAddr;
 use tokio::net::UdpSocket;
 
@@ -63,3 +67,14 @@ impl Server {
         }
     }
 }
+
+/// A query of `rtype` for `name` with the ID 42 and no EDNS, shared by the tests.
+#[cfg(test)]
+pub(crate) fn query(name: &str, rtype: Rtype) -> Message<Bytes> {
+    let name: Dname<Bytes> = name.parse().unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+    builder.header_mut().set_id(42);
+    let mut builder = builder.question();
+    builder.push((&name, rtype)).unwrap();
+    builder.into_message()
+}
@@ -0,0 +1,191 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! EDNS(0) padding (RFC 7830), which hides the actual length of the messages sent over encrypted transports.
+
+use crate::{
+    errors::QHandleError,
+    pool,
+    router::upstreams::QHandle,
+    utils::{rebuild_with, records},
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::{
+    opt::{AllOptData, Padding as PaddingOpt},
+    Message, Rtype,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, QHandleError>;
+
+// UDP payload size advertised if the message doesn't carry an OPT record. Same as the one recommended by DNS flag day 2020.
+const DEFAULT_PAYLOAD_SIZE: u16 = 1232;
+
+/// The padding policy
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Padding {
+    /// Pad the message to the closest multiple of the block length. RFC 8467 recommends 128 for queries and 468 for responses.
+    Block(u16),
+    /// Pad the message with a random number of bytes, up to the length given.
+    Random(u16),
+}
+
+impl Padding {
+    /// The block-length padding recommended by RFC 8467 for queries
+    pub const QUERY: Self = Self::Block(128);
+
+    /// The block-length padding recommended by RFC 8467 for responses
+    pub const RESPONSE: Self = Self::Block(468);
+
+    // Length of the padding option data needed for a message of length `unpadded`, which already includes an empty padding option.
+    fn len(self, unpadded: usize) -> u16 {
+        match self {
+            Self::Block(0) => 0,
+            Self::Block(block) => {
+                let block = block as usize;
+                ((block - unpadded % block) % block) as u16
+            }
+            Self::Random(max) => rand::thread_rng().gen_range(0..=max),
+        }
+    }
+
+    /// Pad the message, replacing any padding option it already has. Other EDNS options are kept as they are.
+    pub fn pad(self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let unpadded = rebuild(msg, 0)?.as_slice().len();
+        let padded = rebuild(msg, self.len(unpadded))?;
+        // Never exceed the maximum length of a DNS message
+        Ok(if padded.as_slice().len() <= u16::MAX as usize {
+            padded
+        } else {
+            msg.clone()
+        })
+    }
+}
+
+/// Whether the message asks for padding. Per RFC 7830, responses should only be padded if the query carries a padding option.
+pub fn requested(msg: &Message<Bytes>) -> bool {
+    msg.opt()
+        .map(|opt| {
+            opt.iter::<AllOptData<_>>()
+                .any(|o| matches!(o, Ok(AllOptData::Padding(_))))
+        })
+        .unwrap_or(false)
+}
+
+/// Whether the message is signed with TSIG, in which case padding it would invalidate the signature.
+pub fn signed(msg: &Message<Bytes>) -> bool {
+    msg.additional()
+        .map(|section| section.flatten().any(|r| r.rtype() == Rtype::Tsig))
+        .unwrap_or(false)
+}
+
+// Copy the message with its OPT record replaced by one carrying `len` bytes of padding.
+fn rebuild(msg: &Message<Bytes>, len: u16) -> Result<Message<Bytes>> {
+    // The OPT record is rebuilt below
+    let mut builder = rebuild_with(msg, pool::buffer(), |s, section| {
+        records(s, section, &|_, rtype, _| rtype != Rtype::Opt)
+    })?;
+
+    let opt = msg.opt();
+    builder.opt(|builder| {
+        match &opt {
+            Some(opt) => {
+                builder.set_udp_payload_size(opt.udp_payload_size());
+                builder.set_version(opt.version());
+                builder.set_dnssec_ok(opt.dnssec_ok());
+                for option in opt.iter::<AllOptData<_>>().flatten() {
+                    if !matches!(option, AllOptData::Padding(_)) {
+                        builder.push(&option)?;
+                    }
+                }
+            }
+            None => builder.set_udp_payload_size(DEFAULT_PAYLOAD_SIZE),
+        }
+        builder.push(&PaddingOpt::new(len))
+    })?;
+
+    Ok(builder.into_message())
+}
+
+/// A query handle which pads every query sent through it.
+pub struct Padded {
+    inner: Arc<dyn QHandle>,
+    padding: Padding,
+}
+
+impl Padded {
+    /// Pad the queries sent through `inner`
+    pub fn new(inner: Arc<dyn QHandle>, padding: Padding) -> Self {
+        Self { inner, padding }
+    }
+}
+
+#[async_trait]
+impl QHandle for Padded {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.inner.query(&self.padding.pad(msg)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{requested, Padding};
+    use crate::mock::query;
+
+    use domain::base::{opt::AllOptData, Rtype};
+
+    #[test]
+    fn block() {
+        let msg = query("example.com", Rtype::A);
+        assert!(!requested(&msg));
+
+        let padded = Padding::QUERY.pad(&msg).unwrap();
+        assert_eq!(padded.as_slice().len(), 128);
+        assert_eq!(padded.header().id(), 42);
+        assert!(requested(&padded));
+        assert_eq!(
+            padded.first_question().unwrap().qname().to_string(),
+            "example.com"
+        );
+
+        // Padding again replaces the existing padding instead of adding another
+        let repadded = Padding::Block(64).pad(&padded).unwrap();
+        assert_eq!(repadded.as_slice().len(), 64);
+        assert_eq!(
+            repadded
+                .opt()
+                .unwrap()
+                .iter::<AllOptData<_>>()
+                .filter(|o| matches!(o, Ok(AllOptData::Padding(_))))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn random() {
+        let msg = query("example.com", Rtype::A);
+        // An empty OPT record (11 bytes) with an empty padding option (4 bytes)
+        let base = msg.as_slice().len() + 15;
+        for _ in 0..32 {
+            let len = Padding::Random(16).pad(&msg).unwrap().as_slice().len();
+            assert!((base..=base + 16).contains(&len));
+        }
+    }
+}
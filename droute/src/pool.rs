@@ -0,0 +1,63 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Buffers messages are encoded into on the hot path. Instead of allocating a buffer for every message, buffers are carved out of a larger per-thread allocation, which is reclaimed in place once all the messages encoded into it are dropped.
+// Messages kept for long (e.g. cached ones) should be copied out so that they don't pin the whole allocation.
+
+use crate::MAX_LEN;
+use bytes::BytesMut;
+use std::cell::RefCell;
+
+// Number of buffers carved out of a single allocation
+const CHUNK: usize = 64;
+
+thread_local! {
+    static ARENA: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+// Get an empty buffer with the capacity of `MAX_LEN`, which grows on its own if needed.
+pub fn buffer() -> BytesMut {
+    ARENA.with(|arena| {
+        let mut arena = arena.borrow_mut();
+        if arena.capacity() < MAX_LEN {
+            // This reuses the previous allocation if nothing carved out of it is alive anymore.
+            arena.reserve(MAX_LEN * CHUNK);
+        }
+        let rest = arena.split_off(MAX_LEN);
+        std::mem::replace(&mut *arena, rest)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buffer, CHUNK};
+    use crate::MAX_LEN;
+
+    #[test]
+    fn reuse() {
+        let first = buffer();
+        assert!(first.is_empty());
+        assert_eq!(first.capacity(), MAX_LEN);
+        let ptr = first.as_ptr();
+        drop(first);
+
+        // Drain the rest of the allocation
+        let bufs: Vec<_> = (1..CHUNK).map(|_| buffer()).collect();
+        drop(bufs);
+
+        // All the buffers are dropped, so the allocation is reclaimed
+        assert_eq!(buffer().as_ptr(), ptr);
+    }
+}
@@ -16,25 +16,105 @@
 //! Router is the core concept of `droute`.
 
 pub mod script;
+#[cfg(feature = "tower")]
+pub mod service;
 pub mod upstreams;
 
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
 use self::{
-    script::QueryContext,
+    script::{
+        utils::{lowercase_qname, minimize, restore_qname},
+        QueryContext,
+    },
     upstreams::{error::UpstreamError, Upstreams},
 };
 use crate::{
-    errors::ScriptError, AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
+    errors::ScriptError,
+    pool,
+    trace::{self, Annotation},
+    AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable,
 };
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
-use domain::base::{iana::rcode::Rcode, Message, MessageBuilder};
+use bytes::Bytes;
+use domain::{
+    base::{
+        iana::{rcode::Rcode, Class, Opcode, Rtype},
+        CharStr, Dname, Message, MessageBuilder, ToDname,
+    },
+    rdata::{Hinfo, Txt},
+};
 use log::warn;
+use tokio::time::timeout;
+use tracing::{field, Instrument, Span};
 
 /// Router implementation.
 pub struct Router<T: ScriptBackend> {
     script: T,
+    // The time budget for a query across all the upstreams tried
+    timeout: Option<Duration>,
+    // Answer ANY queries with a synthesized HINFO record instead of routing them
+    minimal_any: bool,
+    // Strip the authority and additional records not needed to answer the queries
+    minimal_responses: bool,
+    // Lowercase the names queried before routing them
+    normalize_qname: bool,
+    // The string CHAOS TXT queries for the server version are answered with
+    version: Option<Bytes>,
+    // Put the routing decisions in the responses for debugging
+    annotation: Option<Annotation>,
+}
+
+// TTL of the HINFO record answering ANY queries, same as the one used by Cloudflare.
+const MINIMAL_ANY_TTL: u32 = 3789;
+
+// Answer the ANY query with a single synthesized HINFO record as suggested by RFC 8482.
+fn minimal_any(msg: &Message<Bytes>) -> Result<Message<Bytes>, ScriptError> {
+    let qname = msg.first_question().unwrap().qname().to_bytes();
+    let mut builder =
+        MessageBuilder::from_target(pool::buffer())?.start_answer(msg, Rcode::NoError)?;
+    builder.push((
+        qname,
+        MINIMAL_ANY_TTL,
+        // Both are shorter than 255 bytes
+        Hinfo::new(
+            CharStr::from_octets(Bytes::from_static(b"RFC8482")).unwrap(),
+            CharStr::from_octets(Bytes::new()).unwrap(),
+        ),
+    ))?;
+    Ok(builder.into_message())
+}
+
+// Names of the CHAOS TXT queries for the version of the server, the latter from RFC 4892.
+const VERSION_NAMES: [&[u8]; 2] = [b"\x07version\x04bind\x00", b"\x07version\x06server\x00"];
+
+// Answer the query with the response code only.
+fn reply(msg: &Message<Bytes>, rcode: Rcode) -> Result<Message<Bytes>, ScriptError> {
+    Ok(MessageBuilder::from_target(pool::buffer())?
+        .start_answer(msg, rcode)?
+        .into_message())
+}
+
+// Answer the CHAOS query for the server version, refusing any other.
+fn chaos(msg: &Message<Bytes>, version: Option<&Bytes>) -> Result<Message<Bytes>, ScriptError> {
+    let q = msg.first_question().unwrap();
+    let version = match version {
+        Some(v)
+            if q.qclass() == Class::Ch
+                && matches!(q.qtype(), Rtype::Txt | Rtype::Any)
+                && VERSION_NAMES
+                    .iter()
+                    .any(|n| Dname::from_slice(n).unwrap() == q.qname()) =>
+        {
+            v
+        }
+        _ => return reply(msg, Rcode::Refused),
+    };
+    let qname = q.qname().to_bytes();
+    let mut builder =
+        MessageBuilder::from_target(pool::buffer())?.start_answer(msg, Rcode::NoError)?;
+    builder.push((qname, Class::Ch, 0, Txt::<Bytes>::from_slice(version)?))?;
+    Ok(builder.into_message())
 }
 
 impl<T: ScriptBackend> Validatable for Router<T> {
@@ -48,38 +128,126 @@ impl<T: ScriptBackend> Validatable for Router<T> {
 impl<T: ScriptBackend> Router<T> {
     /// Create a new `Router` from raw
     pub fn new(script: T) -> Result<Self, ScriptError> {
-        let router = Self { script };
+        let router = Self {
+            script,
+            timeout: None,
+            minimal_any: false,
+            minimal_responses: false,
+            normalize_qname: false,
+            version: None,
+            annotation: None,
+        };
         router.validate(None)?;
         Ok(router)
     }
 
-    /// Resolve the DNS query with routing rules defined.
+    /// Set the end-to-end time budget of every query, after which the query is answered with SERVFAIL.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Answer queries of type ANY with a single HINFO record (RFC 8482) instead of routing them, so that the router can't be abused for ANY amplification.
+    pub fn with_minimal_any(mut self) -> Self {
+        self.minimal_any = true;
+        self
+    }
+
+    /// Strip the authority and additional records from the responses routed, except for those needed, to save bandwidth. See also [`utils::minimize`](script::utils::minimize).
+    pub fn with_minimal_responses(mut self) -> Self {
+        self.minimal_responses = true;
+        self
+    }
+
+    /// Route the queries with their names lowercased, so that mixed-case names, e.g. those sent with DNS 0x20 encoding, can't slip past the matchers and scripts comparing names as strings. The names are restored to the spelling of the clients in the responses. See also [`utils::lowercase_qname`](script::utils::lowercase_qname).
+    pub fn with_normalize_qname(mut self) -> Self {
+        self.normalize_qname = true;
+        self
+    }
+
+    /// Answer CHAOS TXT queries for `version.bind` and `version.server` with the string given. Queries of classes other than `IN` are refused otherwise.
+    pub fn with_version(mut self, version: impl Into<Bytes>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Annotate the responses routed with the rules matched and the upstreams tried as `annotation`, so that it can be told from the client why a name resolves as it does. Responses not routed through the script, e.g. those to `CHAOS` queries, are left alone.
+    pub fn with_annotation(mut self, annotation: Annotation) -> Self {
+        self.annotation = Some(annotation);
+        self
+    }
+
+    /// Resolve the DNS query with routing rules defined. `qctx` is the context of the client sending the query, if any.
+    /// This can be used to embed the routing engine in other programs. See also `RouterService` (available with feature `tower`).
+    #[tracing::instrument(name = "router", skip_all, fields(qname = field::Empty, qtype = field::Empty))]
     pub async fn resolve(
         &self,
         msg: Message<Bytes>,
         qctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>, ScriptError> {
+        // Only standard queries are routed. UPDATE, NOTIFY and the like are meant for the authoritative servers, not us.
+        if msg.header().opcode() != Opcode::Query {
+            return reply(&msg, Rcode::NotImp);
+        }
         // We have to ensure the number of queries is larger than 0 as it is a gurantee for actions/matchers.
         // Not using `query_count()` because it is manually set, and may not be correct.
         Ok(match msg.sole_question() {
-            Ok(_) => {
+            Ok(q) if q.qclass() != Class::In => chaos(&msg, self.version.as_ref())?,
+            Ok(q) if self.minimal_any && q.qtype() == Rtype::Any => minimal_any(&msg)?,
+            Ok(q) => {
+                Span::current()
+                    .record("qname", field::display(q.qname()))
+                    .record("qtype", field::display(q.qtype()));
+                // `None` if the name is not rewritten
+                let normalized = if self.normalize_qname {
+                    lowercase_qname(&msg)
+                } else {
+                    None
+                };
+                let restore = normalized.is_some();
                 // Clone should be cheap here guaranteed by Bytes
-                match self.script.route(msg.clone(), qctx).await {
+                let route = self
+                    .script
+                    .route(normalized.unwrap_or_else(|| msg.clone()), qctx)
+                    .instrument(tracing::info_span!("script"));
+                let route = async {
+                    match self.timeout {
+                        Some(t) => timeout(t, route)
+                            .await
+                            .unwrap_or_else(|_| Err(ScriptError::Timeout(t))),
+                        None => route.await,
+                    }
+                };
+                // Decisions are only recorded if they are put in the responses
+                let (res, trace) = match self.annotation {
+                    Some(_) => {
+                        let (res, trace) = trace::scope(route).await;
+                        (res, Some(trace))
+                    }
+                    None => (route.await, None),
+                };
+                let res = match res {
+                    Ok(m) if restore => Ok(restore_qname(&m, &msg)),
+                    res => res,
+                };
+                let resp = match res {
+                    Ok(m) if self.minimal_responses => minimize(&m)?,
                     Ok(m) => m,
                     Err(e) => {
                         // Catch all server failure here and return server fail
                         warn!("upstream encountered error: {}, returning SERVFAIL", e);
-                        MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                            .start_answer(&msg, Rcode::ServFail)?
-                            .into_message()
+                        reply(&msg, Rcode::ServFail)?
                     }
+                };
+                match (self.annotation, trace) {
+                    (Some(a), Some(trace)) => a.annotate(&msg, &resp, &trace).unwrap_or(resp),
+                    _ => resp,
                 }
             }
             Err(e) => {
+                // The query is at fault rather than us
                 warn!("DNS message parsing errored: {}.", e);
-                MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                    .start_answer(&msg, Rcode::ServFail)?
-                    .into_message()
+                reply(&msg, Rcode::FormErr)?
             }
         })
     }
@@ -94,6 +262,12 @@ where
 {
     script: S,
     upstreams: U,
+    timeout: Option<Duration>,
+    minimal_any: bool,
+    minimal_responses: bool,
+    normalize_qname: bool,
+    version: Option<Bytes>,
+    annotation: Option<Annotation>,
     _phantom: PhantomData<T>,
 }
 
@@ -108,9 +282,51 @@ where
         Self {
             script,
             upstreams,
-            _phantom: PhantomData::default(),
+            timeout: None,
+            minimal_any: false,
+            minimal_responses: false,
+            normalize_qname: false,
+            version: None,
+            annotation: None,
+            _phantom: PhantomData,
         }
     }
+
+    /// Set the end-to-end time budget of every query. See also [`Router::with_timeout`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Answer queries of type ANY with a single HINFO record. See also [`Router::with_minimal_any`].
+    pub fn with_minimal_any(mut self) -> Self {
+        self.minimal_any = true;
+        self
+    }
+
+    /// Strip the records not needed from the responses. See also [`Router::with_minimal_responses`].
+    pub fn with_minimal_responses(mut self) -> Self {
+        self.minimal_responses = true;
+        self
+    }
+
+    /// Route the queries with their names lowercased. See also [`Router::with_normalize_qname`].
+    pub fn with_normalize_qname(mut self) -> Self {
+        self.normalize_qname = true;
+        self
+    }
+
+    /// Answer CHAOS TXT queries for the server version. See also [`Router::with_version`].
+    pub fn with_version(mut self, version: impl Into<Bytes>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Annotate the responses with the routing decisions. See also [`Router::with_annotation`].
+    pub fn with_annotation(mut self, annotation: Annotation) -> Self {
+        self.annotation = Some(annotation);
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -125,6 +341,33 @@ where
     /// Build a new `Router` from configuration and check the validity. `data` is the content of the configuration file.
     async fn async_try_into(self) -> Result<Router<T>, ScriptError> {
         let upstreams = self.upstreams.async_try_into().await?;
-        Router::new(self.script.build(upstreams).await?)
+        let router = Router::new(self.script.build(upstreams).await?)?;
+        let router = match self.timeout {
+            Some(t) => router.with_timeout(t),
+            None => router,
+        };
+        let router = if self.minimal_any {
+            router.with_minimal_any()
+        } else {
+            router
+        };
+        let router = if self.minimal_responses {
+            router.with_minimal_responses()
+        } else {
+            router
+        };
+        let router = if self.normalize_qname {
+            router.with_normalize_qname()
+        } else {
+            router
+        };
+        let router = match self.version {
+            Some(v) => router.with_version(v),
+            None => router,
+        };
+        Ok(match self.annotation {
+            Some(a) => router.with_annotation(a),
+            None => router,
+        })
     }
 }
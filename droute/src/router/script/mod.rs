@@ -16,8 +16,10 @@
 pub mod native;
 #[cfg(feature = "rune-scripting")]
 pub mod rune_scripting;
+pub mod table;
 /// Useful utils to route a query
 pub mod utils;
+pub mod views;
 
 pub mod builders {
     #[cfg(feature = "rhai-scripting")]
@@ -27,9 +29,11 @@ pub mod builders {
     pub use super::rune_scripting::{RuneScript, RuneScriptBuilder};
 
     pub use super::native::NativeScriptBuilder;
+    pub use super::table::{Action, MatcherBuilder, StepBuilder, TableBuilder};
+    pub use super::views::ViewsBuilder;
 }
 
-use crate::{Upstreams, Validatable};
+use crate::{errors::ErrorKind, Upstreams, Validatable};
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::{
@@ -41,12 +45,14 @@ use domain::base::{
 use std::{
     net::{AddrParseError, IpAddr},
     string::FromUtf8Error,
+    time::Duration,
 };
 use thiserror::Error;
 
 type Result<T> = std::result::Result<T, ScriptError>;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 /// All possible errors that may incur when using message.
 pub enum MessageError {
     /// The record data indicated is currently not supported or mismatched on conversion.
@@ -108,6 +114,7 @@ pub enum MessageError {
 
 /// Errors generated by the `script` module.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub enum ScriptError {
     /// Buf is too short
@@ -126,6 +133,14 @@ pub enum ScriptError {
     #[error(transparent)]
     UpstreamError(#[from] crate::errors::UpstreamError),
 
+    /// The query didn't finish within the time budget
+    #[error("query didn't finish within the time budget of {0:?}")]
+    Timeout(Duration),
+
+    /// The routing tables are invalid
+    #[error("invalid routing table: {0}")]
+    TableError(String),
+
     /// Rune Emit Error
     #[cfg(feature = "rune-scripting")]
     #[error(transparent)]
@@ -147,6 +162,33 @@ pub enum ScriptError {
     RuneVmError(#[from] rune::runtime::VmError),
 }
 
+impl MessageError {
+    /// The general category of the error, which is always `ErrorKind::Protocol`.
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Protocol
+    }
+}
+
+impl ScriptError {
+    /// The general category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ShortBuf(_) => ErrorKind::Protocol,
+            Self::MessageError(e) => e.kind(),
+            Self::UtilsError(e) => e.kind(),
+            Self::UpstreamError(e) => e.kind(),
+            Self::Timeout(_) => ErrorKind::Network,
+            Self::TableError(_) => ErrorKind::Config,
+            #[cfg(feature = "rune-scripting")]
+            Self::RuneEmitError(_) | Self::RuneBuildError(_) | Self::RuneContextError(_) => {
+                ErrorKind::Config
+            }
+            #[cfg(feature = "rune-scripting")]
+            Self::RuneVmError(_) => ErrorKind::Script,
+        }
+    }
+}
+
 /// Query Context
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
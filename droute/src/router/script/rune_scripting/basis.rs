@@ -14,9 +14,10 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::types::*;
-use crate::{errors::ScriptError, CacheMode, QueryContext, Upstreams};
+use crate::{errors::ScriptError, utils::Family, CacheMode, QueryContext, Upstreams};
 use once_cell::sync::Lazy;
 use rune::{runtime::Protocol, Module};
+use std::{str::FromStr, time::Duration};
 
 // A module containing upstreams methods and query context
 pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
@@ -45,9 +46,54 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
             .into())
     }
 
+    async fn send_timeout(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+        timeout: u64,
+    ) -> Result<Message, ScriptError> {
+        Ok(upstreams
+            .send_with_timeout(
+                &tag.into(),
+                &cache_mode,
+                &msg.into(),
+                Duration::from_millis(timeout),
+            )
+            .await?
+            .into())
+    }
+
+    async fn send_prefer(
+        upstreams: &Upstreams,
+        tag: &str,
+        family: &str,
+        msg: &Message,
+    ) -> Result<Message, ScriptError> {
+        let family = Family::from_str(family)?;
+        Ok(upstreams
+            .send_prefer(&tag.into(), &CacheMode::default(), family, &msg.into())
+            .await?
+            .into())
+    }
+
+    fn lookup(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+    ) -> Result<Option<Message>, ScriptError> {
+        Ok(upstreams
+            .lookup(&tag.into(), &cache_mode, &msg.into())?
+            .map(Into::into))
+    }
+
     m.ty::<Upstreams>().unwrap();
     m.async_inst_fn("send", send).unwrap();
+    m.async_inst_fn("send_timeout", send_timeout).unwrap();
     m.async_inst_fn("send_default", send_default).unwrap();
+    m.async_inst_fn("send_prefer", send_prefer).unwrap();
+    m.inst_fn("lookup", lookup).unwrap();
 
     m.ty::<CacheMode>().unwrap();
 
@@ -16,8 +16,8 @@
 type MessageResult<T> = std::result::Result<T, MessageError>;
 
 use super::super::types::{DnsRecord, OptRecordData};
-use crate::errors::MessageError;
-use bytes::{Bytes, BytesMut};
+use crate::{errors::MessageError, pool};
+use bytes::Bytes;
 use domain::{
     base::{opt::AllOptData, Dname, Message, MessageBuilder, ParsedDname, Record, Rtype, ToDname},
     rdata::{
@@ -159,7 +159,7 @@ pub fn modify_opt(
     msg: &Message<Bytes>,
     opt: Option<OptRecordsIter>,
 ) -> MessageResult<Message<Bytes>> {
-    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+    let mut builder = MessageBuilder::from_target(pool::buffer())?;
     // Copy header
     *builder.header_mut() = msg.header();
 
@@ -243,7 +243,7 @@ pub fn modify_message(
     msg: &Message<Bytes>,
     section_modified: SectionPayload,
 ) -> MessageResult<Message<Bytes>> {
-    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+    let mut builder = MessageBuilder::from_target(pool::buffer())?;
     // Copy header
     *builder.header_mut() = msg.header();
 
@@ -15,12 +15,16 @@
 
 use super::types::*;
 use crate::{
-    errors::ScriptError,
-    utils::{blackhole, Domain, GeoIp, IpCidr},
+    errors::{MessageError, ScriptError, UtilsError},
+    utils::{
+        blackhole, prefer_family, sanitize, strip_additional, strip_records, strip_svc_params,
+        svc_param_key, BlockPage, Domain, DomainList, Family, GeoIp, IpCidr, RuleLog, SafeSearch,
+    },
+    Upstreams,
 };
 use once_cell::sync::Lazy;
 use rune::Module;
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 
 #[derive(rune::Any, Clone)]
 pub enum Utils {
@@ -30,10 +34,23 @@ pub enum Utils {
     GeoIp(#[rune(get)] SealedGeoIp),
     #[rune(constructor)]
     IpCidr(#[rune(get)] SealedIpCidr),
+    #[rune(constructor)]
+    SafeSearch(#[rune(get)] SealedSafeSearch),
+    #[rune(constructor)]
+    BlockPage(#[rune(get)] SealedBlockPage),
+    #[rune(constructor)]
+    RuleLog(#[rune(get)] SealedRuleLog),
+}
+
+#[derive(Clone)]
+enum DomainRef {
+    Static(Arc<Domain>),
+    // Registered under a name and updatable at runtime
+    List(Arc<DomainList>),
 }
 
 #[derive(rune::Any, Clone)]
-pub struct SealedDomain(Arc<Domain>);
+pub struct SealedDomain(DomainRef);
 
 #[derive(rune::Any, Clone)]
 pub struct SealedGeoIp(Arc<GeoIp>);
@@ -41,6 +58,15 @@ pub struct SealedGeoIp(Arc<GeoIp>);
 #[derive(rune::Any, Clone)]
 pub struct SealedIpCidr(Arc<IpCidr>);
 
+#[derive(rune::Any, Clone)]
+pub struct SealedSafeSearch(Arc<SafeSearch>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedBlockPage(Arc<BlockPage>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedRuleLog(Arc<RuleLog>);
+
 pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
     let mut m = Module::new();
 
@@ -55,12 +81,63 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         .unwrap();
     }
 
+    // Sanitizer
+    {
+        m.function(
+            &["sanitize"],
+            |query: &Message, resp: &Message, reject_bogus: bool| -> Result<Message, ScriptError> {
+                Ok(sanitize(&query.into(), &resp.into(), reject_bogus)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["strip_svc_params"],
+            |resp: &Message, keys: Vec<String>| -> Result<Message, ScriptError> {
+                let keys = keys
+                    .iter()
+                    .map(|k| svc_param_key(k))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(strip_svc_params(&resp.into(), &keys)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["strip_records"],
+            |resp: &Message,
+             types: Vec<String>,
+             min_len: Option<usize>|
+             -> Result<Message, ScriptError> {
+                let types = types
+                    .iter()
+                    .map(|t| domain::base::Rtype::from_str(t).map_err(MessageError::from))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(strip_records(&resp.into(), &types, min_len)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["prefer_family"],
+            |resp: &Message, family: &str| -> Result<Message, ScriptError> {
+                Ok(prefer_family(&resp.into(), Family::from_str(family)?)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["strip_additional"],
+            |resp: &Message| -> Result<Message, ScriptError> {
+                Ok(strip_additional(&resp.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
     // Domain list
     {
         m.ty::<Domain>().unwrap();
         m.ty::<SealedDomain>().unwrap();
 
         m.function(&["Domain", "new"], Domain::new).unwrap();
+        m.function(&["Domain", "compact"], Domain::compact).unwrap();
         m.inst_fn(
             "add_qname",
             |mut domain: Domain, qname: &str| -> Result<Domain, ScriptError> {
@@ -77,14 +154,65 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
             },
         )
         .unwrap();
+        m.inst_fn(
+            "add_files",
+            |mut domain: Domain, paths: Vec<String>| -> Result<Domain, ScriptError> {
+                domain.add_files(&paths)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn(
+            "add_except_qname",
+            |mut domain: Domain, qname: &str| -> Result<Domain, ScriptError> {
+                domain.add_except_qname(qname)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_except_file",
+            |mut domain: Domain, path: &str| -> Result<Domain, ScriptError> {
+                domain.add_except_file(path)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
 
         m.inst_fn("seal", |domain: Domain| -> SealedDomain {
-            SealedDomain(Arc::new(domain))
+            SealedDomain(DomainRef::Static(Arc::new(domain)))
         })
         .unwrap();
 
+        m.inst_fn(
+            "seal_list",
+            |domain: Domain, name: &str, file: Option<String>| -> SealedDomain {
+                SealedDomain(DomainRef::List(DomainList::register(
+                    name,
+                    domain,
+                    file.map(Into::into),
+                )))
+            },
+        )
+        .unwrap();
+
+        m.function(
+            &["Domain", "list"],
+            |name: &str| -> Result<SealedDomain, ScriptError> {
+                Ok(SealedDomain(DomainRef::List(
+                    DomainList::get(name)
+                        .ok_or_else(|| UtilsError::MissingList(name.to_string()))?,
+                )))
+            },
+        )
+        .unwrap();
+
         m.inst_fn("contains", |domain: &SealedDomain, qname: &Dname| -> bool {
-            domain.0.contains(&qname.into())
+            match &domain.0 {
+                DomainRef::Static(d) => d.contains(&qname.into()),
+                DomainRef::List(l) => l.contains(&qname.into()),
+            }
         })
         .unwrap();
     }
@@ -132,6 +260,14 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
             },
         )
         .unwrap();
+        m.inst_fn(
+            "add_files",
+            |mut ipcidr: IpCidr, paths: Vec<String>| -> Result<IpCidr, ScriptError> {
+                ipcidr.add_files(&paths)?;
+                Ok(ipcidr)
+            },
+        )
+        .unwrap();
 
         m.inst_fn("seal", |cidr: IpCidr| -> SealedIpCidr {
             SealedIpCidr(Arc::new(cidr))
@@ -144,5 +280,156 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         .unwrap();
     }
 
+    // Safe search
+    {
+        m.ty::<SafeSearch>().unwrap();
+        m.ty::<SealedSafeSearch>().unwrap();
+
+        m.function(&["SafeSearch", "new"], SafeSearch::new).unwrap();
+        m.function(&["SafeSearch", "empty"], SafeSearch::empty)
+            .unwrap();
+        m.inst_fn(
+            "add_cname",
+            |mut safe: SafeSearch, name: &str, target: &str| -> Result<SafeSearch, ScriptError> {
+                safe.add_cname(name, target)?;
+                Ok(safe)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_ip",
+            |mut safe: SafeSearch, name: &str, ip: &IpAddr| -> Result<SafeSearch, ScriptError> {
+                safe.add_ip(name, ip.into())?;
+                Ok(safe)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "remove",
+            |mut safe: SafeSearch, name: &str| -> Result<SafeSearch, ScriptError> {
+                safe.remove(name)?;
+                Ok(safe)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |safe: SafeSearch| -> SealedSafeSearch {
+            SealedSafeSearch(Arc::new(safe))
+        })
+        .unwrap();
+
+        async fn enforce(
+            safe: &SealedSafeSearch,
+            upstreams: &Upstreams,
+            tag: &str,
+            query: &Message,
+        ) -> Result<Option<Message>, ScriptError> {
+            Ok(safe
+                .0
+                .enforce(upstreams, &tag.into(), &query.into())
+                .await?
+                .map(Into::into))
+        }
+
+        m.async_inst_fn("enforce", enforce).unwrap();
+    }
+
+    // Block page
+    {
+        m.ty::<BlockPage>().unwrap();
+        m.ty::<SealedBlockPage>().unwrap();
+
+        m.function(&["BlockPage", "new"], |ip: &IpAddr| -> BlockPage {
+            BlockPage::new(ip.into())
+        })
+        .unwrap();
+        m.inst_fn("add_ip", |mut page: BlockPage, ip: &IpAddr| -> BlockPage {
+            page.add_ip(ip.into());
+            page
+        })
+        .unwrap();
+        m.inst_fn("set_ttl", |mut page: BlockPage, ttl: u32| -> BlockPage {
+            page.set_ttl(ttl);
+            page
+        })
+        .unwrap();
+        m.inst_fn(
+            "add_exempt_qname",
+            |mut page: BlockPage, qname: &str| -> Result<BlockPage, ScriptError> {
+                page.add_exempt_qname(qname)?;
+                Ok(page)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_exempt_file",
+            |mut page: BlockPage, path: &str| -> Result<BlockPage, ScriptError> {
+                page.add_exempt_file(path)?;
+                Ok(page)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |page: BlockPage| -> SealedBlockPage {
+            SealedBlockPage(Arc::new(page))
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "redirect",
+            |page: &SealedBlockPage, query: &Message| -> Result<Message, ScriptError> {
+                Ok(page.0.redirect(&query.into())?.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "rewrite",
+            |page: &SealedBlockPage, resp: &Message| -> Result<Message, ScriptError> {
+                Ok(page.0.rewrite(resp.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Rule log
+    {
+        m.ty::<RuleLog>().unwrap();
+        m.ty::<SealedRuleLog>().unwrap();
+
+        m.function(
+            &["RuleLog", "new"],
+            |name: &str, level: &str| -> Result<RuleLog, ScriptError> {
+                Ok(RuleLog::new(name, level)?)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "sample",
+            |mut log: RuleLog, rate: f64| -> Result<RuleLog, ScriptError> {
+                log.set_sample(rate)?;
+                Ok(log)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |log: RuleLog| -> SealedRuleLog {
+            SealedRuleLog(Arc::new(log))
+        })
+        .unwrap();
+
+        m.inst_fn("log", |log: &SealedRuleLog, query: &Message| {
+            log.0.log(&query.into())
+        })
+        .unwrap();
+        m.inst_fn(
+            "log_response",
+            |log: &SealedRuleLog, query: &Message, resp: Message| -> Message {
+                log.0.log_response(&query.into(), &(&resp).into());
+                resp
+            },
+        )
+        .unwrap();
+    }
+
     m
 });
@@ -0,0 +1,609 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A declarative script backend: named tables of steps run top to bottom, each applying actions depending on whether its matcher holds, and moving between the tables with `goto` and `jump`.
+
+use super::{
+    utils::{addrs, blackhole, Domain, DomainList, Family, GeoIp, IpCidr, UtilsError},
+    MessageError, QueryContext, Result, ScriptBackend, ScriptBuilder, ScriptError,
+};
+use crate::{CacheMode, Label, Upstreams, Validatable};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::{Message, Rtype, ToDname};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+/// The table the routing starts from.
+pub const START: &str = "start";
+
+// Number of steps run for a single query before giving up, which is only reached if the tables go to each other in circle.
+const MAX_STEPS: usize = 1024;
+
+/// A condition on the query, the client, or the response got so far.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum MatcherBuilder {
+    /// The query name is any of the domains or their subdomains, given directly or in the files
+    Domain {
+        /// Domains to match
+        #[serde(default)]
+        qnames: Vec<String>,
+        /// Files of the domains to match, one per line
+        #[serde(default)]
+        files: Vec<String>,
+    },
+    /// The query name is in the domain list registered under the name, e.g. a list subscribed
+    List(String),
+    /// The query type is any of the types, e.g. `AAAA`
+    Qtype(Vec<String>),
+    /// The client is within any of the IP CIDRs
+    Client(Vec<String>),
+    /// Any address answered in the response got so far belongs to any of the countries, e.g. `CN`
+    GeoIp {
+        /// Country codes to match
+        codes: Vec<String>,
+        /// Path to the GeoIP database. The builtin one is used if not given.
+        #[serde(default)]
+        path: Option<String>,
+    },
+    /// Any address answered in the response got so far is within any of the IP CIDRs in the files
+    IpCidr(Vec<String>),
+    /// The matcher doesn't hold
+    Not(Box<MatcherBuilder>),
+}
+
+enum Matcher {
+    Domain(Domain),
+    List(Arc<DomainList>),
+    Qtype(Vec<Rtype>),
+    Client(IpCidr),
+    GeoIp(GeoIp, Vec<String>),
+    IpCidr(IpCidr),
+    Not(Box<Matcher>),
+}
+
+impl MatcherBuilder {
+    // Recursion of async functions needs boxing
+    fn build(self) -> futures::future::LocalBoxFuture<'static, Result<Matcher>> {
+        Box::pin(async move {
+            Ok(match self {
+                Self::Domain { qnames, files } => {
+                    let mut domain = Domain::new();
+                    for qname in qnames {
+                        domain.add_qname(qname)?;
+                    }
+                    domain.add_files(&files)?;
+                    Matcher::Domain(domain)
+                }
+                Self::List(name) => {
+                    Matcher::List(DomainList::get(&name).ok_or(UtilsError::MissingList(name))?)
+                }
+                Self::Qtype(types) => Matcher::Qtype(
+                    types
+                        .iter()
+                        .map(|t| Rtype::from_str(t).map_err(MessageError::from))
+                        .collect::<std::result::Result<_, _>>()?,
+                ),
+                Self::Client(cidrs) => {
+                    let mut clients = IpCidr::new();
+                    for cidr in cidrs {
+                        clients.add_cidr(cidr)?;
+                    }
+                    Matcher::Client(clients)
+                }
+                Self::GeoIp { codes, path } => Matcher::GeoIp(
+                    match path {
+                        Some(path) => GeoIp::from_path(path).await?,
+                        None => GeoIp::create_default()?,
+                    },
+                    codes,
+                ),
+                Self::IpCidr(files) => {
+                    let mut cidrs = IpCidr::new();
+                    cidrs.add_files(&files)?;
+                    Matcher::IpCidr(cidrs)
+                }
+                Self::Not(m) => Matcher::Not(Box::new(m.build().await?)),
+            })
+        })
+    }
+}
+
+impl Matcher {
+    fn matches(
+        &self,
+        query: &Message<Bytes>,
+        ctx: Option<&QueryContext>,
+        resp: Option<&Message<Bytes>>,
+    ) -> bool {
+        match self {
+            Self::Domain(domain) => query
+                .first_question()
+                .map_or(false, |q| domain.contains(&q.qname().to_bytes())),
+            Self::List(list) => query
+                .first_question()
+                .map_or(false, |q| list.contains(&q.qname().to_bytes())),
+            Self::Qtype(types) => query
+                .first_question()
+                .map_or(false, |q| types.contains(&q.qtype())),
+            Self::Client(clients) => ctx.map_or(false, |ctx| clients.contains(ctx.ip)),
+            Self::GeoIp(geoip, codes) => resp.map_or(false, |resp| {
+                addrs(resp)
+                    .into_iter()
+                    .any(|ip| codes.iter().any(|code| geoip.contains(ip, code)))
+            }),
+            Self::IpCidr(cidrs) => resp.map_or(false, |resp| {
+                addrs(resp).into_iter().any(|ip| cidrs.contains(ip))
+            }),
+            Self::Not(m) => !m.matches(query, ctx, resp),
+        }
+    }
+}
+
+// The cache policy of upstreams given by their tags only
+const DEFAULT_CACHE: CacheMode = CacheMode::Standard;
+
+/// An upstream addressed by an action, either by its tag only, e.g. `domestic`, or along with the cache policy, e.g. `{upstream: domestic, cache: persistent}`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Target {
+    /// The tag of the upstream, whose cache is used within the TTL
+    Tag(Label),
+    /// The upstream with the cache policy
+    Cached {
+        /// The tag of the upstream
+        upstream: Label,
+        /// The cache policy, `disabled`, `standard`, or `persistent`
+        cache: CacheMode,
+    },
+}
+
+impl Target {
+    fn tag(&self) -> &Label {
+        match self {
+            Self::Tag(tag) | Self::Cached { upstream: tag, .. } => tag,
+        }
+    }
+
+    fn cache(&self) -> &CacheMode {
+        match self {
+            Self::Tag(_) => &DEFAULT_CACHE,
+            Self::Cached { cache, .. } => cache,
+        }
+    }
+}
+
+impl From<&str> for Target {
+    fn from(tag: &str) -> Self {
+        Self::Tag(tag.into())
+    }
+}
+
+/// What a step does.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Send the query to the upstream given, whose response replaces the one got so far
+    Query(Target),
+    /// Answer with the response cached for the query sent to the upstream given and stop routing if there is one, see `Upstreams::lookup`. Nothing is sent, and the next action is applied otherwise.
+    Cache(Target),
+    /// Send the query to the upstream with the tag given like `query`, preferring the addresses of the family given, see `Upstreams::send_prefer`
+    Prefer {
+        /// The tag of the upstream
+        query: Label,
+        /// The family preferred, `ipv4` or `ipv6`
+        family: Family,
+        /// The cache policy
+        #[serde(default)]
+        cache: CacheMode,
+    },
+    /// Answer with a SOA record to curb further queries
+    Blackhole,
+    /// Stop routing and answer with the response got so far
+    End,
+    /// Continue with the table given, never coming back
+    Goto(Label),
+    /// Run the table given and come back to the next step once it returns or runs out of steps
+    Jump(Label),
+    /// Leave the current table for the one jumping to it, or stop routing if there is none
+    Return,
+}
+
+/// A step of a table: the actions in `then` are applied if the matcher in `if` holds (or there is none), and those in `else` otherwise. Actions after `end`, `goto`, `jump`, or `return` are never applied.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StepBuilder {
+    /// The condition of the step
+    #[serde(rename = "if", default)]
+    pub cond: Option<MatcherBuilder>,
+    /// Actions applied if the condition holds
+    #[serde(default)]
+    pub then: Vec<Action>,
+    /// Actions applied otherwise
+    #[serde(rename = "else", default)]
+    pub otherwise: Vec<Action>,
+}
+
+struct Step {
+    cond: Option<Matcher>,
+    then: Vec<Action>,
+    otherwise: Vec<Action>,
+}
+
+/// A builder for `Table`: the steps of every table by its name. Routing starts from the table named `start`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TableBuilder(pub HashMap<Label, Vec<StepBuilder>>);
+
+/// The script backend routing queries with the tables.
+pub struct Table {
+    upstreams: Upstreams,
+    tables: HashMap<Label, Vec<Step>>,
+}
+
+// Where we are in the tables
+type Position<'a> = (&'a Label, usize);
+
+fn invalid(msg: impl Into<String>) -> ScriptError {
+    ScriptError::TableError(msg.into())
+}
+
+#[async_trait]
+impl ScriptBackend for Table {
+    async fn route(
+        &self,
+        query: Message<Bytes>,
+        ctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>> {
+        let start = Label::from(START);
+        let mut resp = None;
+        // Positions to come back to after `jump`s
+        let mut stack: Vec<Position<'_>> = Vec::new();
+        let mut pos: Position<'_> = (&start, 0);
+
+        let mut count = 0;
+        'steps: loop {
+            count += 1;
+            if count > MAX_STEPS {
+                return Err(invalid(format!(
+                    "routing didn't end within {} steps, check if the tables go to each other in circle",
+                    MAX_STEPS
+                )));
+            }
+            let step = match self.tables[pos.0].get(pos.1) {
+                Some(step) => step,
+                // Running out of steps is the same as `return`
+                None => match stack.pop() {
+                    Some(p) => {
+                        pos = p;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            pos.1 += 1;
+
+            let actions = match &step.cond {
+                Some(m) if !m.matches(&query, ctx.as_ref(), resp.as_ref()) => &step.otherwise,
+                _ => &step.then,
+            };
+            for action in actions {
+                match action {
+                    Action::Query(target) => {
+                        resp = Some(
+                            self.upstreams
+                                .send(target.tag(), target.cache(), &query)
+                                .await?,
+                        )
+                    }
+                    Action::Cache(target) => {
+                        if let Some(r) =
+                            self.upstreams
+                                .lookup(target.tag(), target.cache(), &query)?
+                        {
+                            resp = Some(r);
+                            break 'steps;
+                        }
+                    }
+                    Action::Prefer {
+                        query: tag,
+                        family,
+                        cache,
+                    } => {
+                        resp = Some(
+                            self.upstreams
+                                .send_prefer(tag, cache, *family, &query)
+                                .await?,
+                        )
+                    }
+                    Action::Blackhole => resp = Some(blackhole(&query)?),
+                    Action::End => break 'steps,
+                    Action::Goto(table) => {
+                        pos = (table, 0);
+                        continue 'steps;
+                    }
+                    Action::Jump(table) => {
+                        stack.push(pos);
+                        pos = (table, 0);
+                        continue 'steps;
+                    }
+                    Action::Return => match stack.pop() {
+                        Some(p) => {
+                            pos = p;
+                            continue 'steps;
+                        }
+                        None => break 'steps,
+                    },
+                }
+            }
+        }
+
+        resp.ok_or_else(|| invalid("no response is got when the routing ends"))
+    }
+}
+
+impl Validatable for Table {
+    type Error = ScriptError;
+
+    fn validate(&self, _: Option<&Vec<Label>>) -> Result<()> {
+        self.upstreams.validate(None)?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl ScriptBuilder<Table> for TableBuilder {
+    async fn build(self, upstreams: Upstreams) -> Result<Table> {
+        if !self.0.contains_key(START) {
+            return Err(invalid(format!("table `{}` is missing", START)));
+        }
+        let tags = upstreams.tags();
+        for steps in self.0.values() {
+            for action in steps.iter().flat_map(|s| s.then.iter().chain(&s.otherwise)) {
+                match action {
+                    Action::Goto(t) | Action::Jump(t) if !self.0.contains_key(t) => {
+                        return Err(invalid(format!("table `{}` is not defined", t)))
+                    }
+                    Action::Query(target) | Action::Cache(target)
+                        if !tags.contains(target.tag()) =>
+                    {
+                        return Err(invalid(format!(
+                            "upstream `{}` is not defined",
+                            target.tag()
+                        )))
+                    }
+                    Action::Prefer { query: tag, .. } if !tags.contains(tag) => {
+                        return Err(invalid(format!("upstream `{}` is not defined", tag)))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut tables = HashMap::new();
+        for (name, steps) in self.0 {
+            let mut built = Vec::new();
+            for step in steps {
+                built.push(Step {
+                    cond: match step.cond {
+                        Some(m) => Some(m.build().await?),
+                        None => None,
+                    },
+                    then: step.then,
+                    otherwise: step.otherwise,
+                });
+            }
+            tables.insert(name, built);
+        }
+        Ok(Table { upstreams, tables })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, MatcherBuilder, StepBuilder, TableBuilder, Target};
+    use crate::{
+        builders::{UpstreamBuilder, UpstreamsBuilder},
+        errors::QHandleError,
+        mock::query,
+        router::upstreams::QHandle,
+        AsyncTryInto, CacheMode, ScriptBackend, ScriptBuilder, Upstream, Upstreams,
+    };
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Message, MessageBuilder, Rtype};
+    use std::{
+        collections::HashMap,
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn step(cond: Option<MatcherBuilder>, then: Vec<Action>) -> StepBuilder {
+        StepBuilder {
+            cond,
+            then,
+            otherwise: vec![],
+        }
+    }
+
+    async fn build(tables: Vec<(&str, Vec<StepBuilder>)>) -> super::Result<super::Table> {
+        TableBuilder(
+            tables
+                .into_iter()
+                .map(|(k, v)| (k.into(), v))
+                .collect::<HashMap<_, _>>(),
+        )
+        .build(
+            UpstreamsBuilder::<UpstreamBuilder>::new(1)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn flow() {
+        let table = build(vec![
+            (
+                "start",
+                vec![
+                    step(
+                        Some(MatcherBuilder::Qtype(vec!["AAAA".into()])),
+                        vec![Action::Jump("ads".into())],
+                    ),
+                    // Only reached by AAAA queries not blocked
+                    step(None, vec![Action::Return]),
+                ],
+            ),
+            (
+                "ads",
+                vec![
+                    step(
+                        Some(MatcherBuilder::Domain {
+                            qnames: vec!["ads.example".into()],
+                            files: vec![],
+                        }),
+                        vec![Action::Blackhole, Action::End],
+                    ),
+                    step(
+                        Some(MatcherBuilder::Not(Box::new(MatcherBuilder::Domain {
+                            qnames: vec!["example.com".into()],
+                            files: vec![],
+                        }))),
+                        vec![Action::Goto("start".into())],
+                    ),
+                    step(None, vec![Action::Blackhole]),
+                ],
+            ),
+        ])
+        .await
+        .unwrap();
+
+        let resp = table
+            .route(query("x.ads.example", Rtype::Aaaa), None)
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(resp.header().qr());
+
+        // Comes back from `ads` to `return` with the response
+        assert!(table
+            .route(query("example.com", Rtype::Aaaa), None)
+            .await
+            .is_ok());
+        // Nothing answers the query
+        assert!(table
+            .route(query("ads.example", Rtype::A), None)
+            .await
+            .is_err());
+        // `goto` back and forth in circle
+        assert!(table
+            .route(query("example.org", Rtype::Aaaa), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn undefined() {
+        assert!(build(vec![("main", vec![])]).await.is_err());
+        assert!(build(vec![(
+            "start",
+            vec![step(None, vec![Action::Goto("foo".into())])]
+        )])
+        .await
+        .is_err());
+        assert!(build(vec![(
+            "start",
+            vec![step(None, vec![Action::Query("foo".into())])]
+        )])
+        .await
+        .is_err());
+        assert!(build(vec![(
+            "start",
+            vec![step(None, vec![Action::Cache("foo".into())])]
+        )])
+        .await
+        .is_err());
+    }
+
+    // Answer every query, counting the queries received
+    struct Counted(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl QHandle for Counted {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(MessageBuilder::from_target(BytesMut::new())?
+                .start_answer(msg, Rcode::NoError)?
+                .into_message())
+        }
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let upstreams = Upstreams::new(
+            HashMap::from([(
+                "up".into(),
+                Upstream::Others(Arc::new(Counted(count.clone()))),
+            )]),
+            NonZeroUsize::new(8).unwrap(),
+        )
+        .unwrap();
+        let fresh = Target::Cached {
+            upstream: "up".into(),
+            cache: CacheMode::Disabled,
+        };
+        let table = TableBuilder(HashMap::from([(
+            "start".into(),
+            vec![
+                step(
+                    Some(MatcherBuilder::Qtype(vec!["AAAA".into()])),
+                    vec![Action::Query(fresh), Action::End],
+                ),
+                step(
+                    None,
+                    vec![Action::Cache("up".into()), Action::Query("up".into())],
+                ),
+            ],
+        )]))
+        .build(upstreams)
+        .await
+        .unwrap();
+
+        // Only the first query is sent, the rest are answered from the cache
+        for _ in 0..3 {
+            table
+                .route(query("example.com", Rtype::A), None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // Always sent with the cache disabled
+        for _ in 0..3 {
+            table
+                .route(query("example.com", Rtype::Aaaa), None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+    }
+}
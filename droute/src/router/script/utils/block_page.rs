@@ -0,0 +1,181 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{blackhole, Domain, Result, UtilsError};
+use crate::pool;
+use bytes::Bytes;
+use domain::{
+    base::{iana::Rcode, Message, MessageBuilder, Rtype, ToDname},
+    rdata::{Aaaa, A},
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Keep it short so that clients recover soon after the name gets unblocked or registered.
+const DEFAULT_TTL: u32 = 10;
+
+/// Point blocked or nonexistent names at a landing page explaining why they are unavailable.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct BlockPage {
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+    ttl: u32,
+    // Names (and their subdomains) never redirected
+    exempt: Domain,
+}
+
+impl BlockPage {
+    /// Create a redirector pointing at the block page served on `ip`.
+    pub fn new(ip: IpAddr) -> Self {
+        let mut page = Self {
+            v4: None,
+            v6: None,
+            ttl: DEFAULT_TTL,
+            exempt: Domain::new(),
+        };
+        page.add_ip(ip);
+        page
+    }
+
+    /// Serve the block page on `ip` as well. Each address family takes at most one address, and the latest one wins.
+    pub fn add_ip(&mut self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => self.v4 = Some(ip),
+            IpAddr::V6(ip) => self.v6 = Some(ip),
+        }
+    }
+
+    /// Set the TTL of the records pointing at the block page (default to 10 seconds).
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+
+    /// Never redirect the given names and their subdomains.
+    pub fn add_exempt_qname(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.exempt.add_qname(s)
+    }
+
+    /// Never redirect the names in the file and their subdomains.
+    pub fn add_exempt_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.exempt.add_file(path)
+    }
+
+    // Answer the message with the block page address, `None` if the message is exempted or no address is available for the type queried.
+    fn answer(&self, msg: &Message<Bytes>) -> Result<Option<Message<Bytes>>> {
+        let question = msg.first_question().ok_or(UtilsError::NoQuestion)?;
+        let qname = question.qname().to_bytes();
+        if self.exempt.contains(&qname) {
+            return Ok(None);
+        }
+
+        let mut builder =
+            MessageBuilder::from_target(pool::buffer())?.start_answer(msg, Rcode::NoError)?;
+        builder.header_mut().set_ra(msg.header().ra());
+        match (question.qtype(), self.v4, self.v6) {
+            (Rtype::A, Some(ip), _) => builder.push((qname, self.ttl, A::new(ip)))?,
+            (Rtype::Aaaa, _, Some(ip)) => builder.push((qname, self.ttl, Aaaa::new(ip)))?,
+            _ => return Ok(None),
+        }
+        Ok(Some(builder.into_message()))
+    }
+
+    /// Answer a blocked query with the block page. Queries exempted or of types other than `A` and `AAAA` are blackholed instead.
+    pub fn redirect(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.answer(query)? {
+            Some(resp) => Ok(resp),
+            None => blackhole(query),
+        }
+    }
+
+    /// Rewrite an `NXDOMAIN` response to point at the block page. Other responses, as well as those exempted, are returned untouched.
+    pub fn rewrite(&self, resp: Message<Bytes>) -> Result<Message<Bytes>> {
+        if resp.header().rcode() != Rcode::NXDomain {
+            return Ok(resp);
+        }
+        Ok(self.answer(&resp)?.unwrap_or(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockPage;
+    use crate::mock::query;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Message, MessageBuilder, ParsedDname, Rtype},
+        rdata::AllRecordData,
+    };
+
+    fn nxdomain(name: &str, rtype: Rtype) -> Message<Bytes> {
+        MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query(name, rtype), Rcode::NXDomain)
+            .unwrap()
+            .into_message()
+    }
+
+    fn answer(msg: &Message<Bytes>) -> Vec<(Rtype, u32, String)> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.rtype(), r.ttl(), r.data().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn redirect() {
+        let mut page = BlockPage::new("10.0.0.1".parse().unwrap());
+        page.add_exempt_qname("example.org").unwrap();
+
+        let resp = page.redirect(&query("ads.example.com", Rtype::A)).unwrap();
+        assert_eq!(resp.header().id(), 42);
+        assert_eq!(answer(&resp), vec![(Rtype::A, 10, "10.0.0.1".to_string())]);
+        // No IPv6 address configured
+        assert!(answer(
+            &page
+                .redirect(&query("ads.example.com", Rtype::Aaaa))
+                .unwrap()
+        )
+        .is_empty());
+        // Exempted names are blackholed
+        assert!(answer(&page.redirect(&query("www.example.org", Rtype::A)).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut page = BlockPage::new("::1".parse().unwrap());
+        page.set_ttl(1);
+        page.add_exempt_qname("example.org").unwrap();
+
+        let resp = page
+            .rewrite(nxdomain("typo.example.com", Rtype::Aaaa))
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(answer(&resp), vec![(Rtype::Aaaa, 1, "::1".to_string())]);
+
+        let resp = page
+            .rewrite(nxdomain("typo.example.org", Rtype::Aaaa))
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+
+        // Successful responses are left untouched
+        let resp = page.rewrite(query("example.com", Rtype::Aaaa)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(answer(&resp).is_empty());
+    }
+}
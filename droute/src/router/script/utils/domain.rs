@@ -13,30 +13,160 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::Result;
+use super::{load_files, Result};
 use bytes::Bytes;
-use dmatcher::domain::Domain as DomainAlg;
+use dmatcher::{compact::Compact, domain::Domain as DomainAlg};
 use domain::base::{name::FromStrError, Dname};
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    borrow::Cow,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    str::FromStr,
+};
+
+// The matching algorithm backing the matcher
+#[derive(Clone)]
+enum Alg {
+    Trie(DomainAlg),
+    Compact(Compact),
+}
+
+impl Alg {
+    // An empty matcher with the same algorithm
+    fn empty(&self) -> Self {
+        match self {
+            Self::Trie(_) => Self::Trie(DomainAlg::new()),
+            Self::Compact(_) => Self::Compact(Compact::new()),
+        }
+    }
+
+    fn insert_multi(&mut self, domain: &[Dname<Bytes>]) {
+        match self {
+            Self::Trie(alg) => alg.insert_multi(domain),
+            Self::Compact(alg) => alg.insert_multi(domain),
+        }
+    }
+
+    fn matches(&self, domain: &Dname<Bytes>) -> bool {
+        match self {
+            Self::Trie(alg) => alg.matches(domain),
+            Self::Compact(alg) => alg.matches(domain),
+        }
+    }
+}
+
+// Domains matching themselves along with their subdomains, and wildcards, e.g. `*.example.com`, matching the subdomains only.
+#[derive(Clone)]
+struct Rules {
+    domains: Alg,
+    // Matched against the parents of the names, so that the apexes never match
+    wildcards: Alg,
+    // Empty matchers match everything, so we have to tell them apart
+    has_domains: bool,
+    has_wildcards: bool,
+}
+
+impl Rules {
+    fn new(alg: Alg) -> Self {
+        Self {
+            wildcards: alg.empty(),
+            domains: alg,
+            has_domains: false,
+            has_wildcards: false,
+        }
+    }
+
+    // An empty set of rules with the same algorithm
+    fn empty(&self) -> Self {
+        Self::new(self.domains.empty())
+    }
+
+    fn insert_multi(&mut self, rules: &[(Dname<Bytes>, bool)]) {
+        let (wildcards, domains): (Vec<_>, Vec<_>) = rules.iter().partition(|(_, w)| *w);
+        // The compact matcher is rebuilt on every insertion
+        for (alg, has, rules) in [
+            (&mut self.domains, &mut self.has_domains, domains),
+            (&mut self.wildcards, &mut self.has_wildcards, wildcards),
+        ] {
+            if !rules.is_empty() {
+                *has = true;
+                alg.insert_multi(
+                    &rules
+                        .into_iter()
+                        .map(|(d, _)| d.clone())
+                        .collect::<Vec<_>>(),
+                );
+            }
+        }
+    }
+
+    // Insert the domains in the file as they are read.
+    fn insert_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        let mut err = None;
+        let mut wildcards = Vec::new();
+        let has_domains = &mut self.has_domains;
+        let domains = read_file(path)?
+            .map_while(|r| r.map_err(|e| err = Some(e)).ok())
+            .filter_map(|(d, w)| {
+                if w {
+                    wildcards.push(d);
+                    None
+                } else {
+                    *has_domains = true;
+                    Some(d)
+                }
+            });
+        match &mut self.domains {
+            Alg::Trie(alg) => domains.for_each(|d| alg.insert(&d)),
+            Alg::Compact(alg) => alg.extend(domains),
+        }
+        if !wildcards.is_empty() {
+            self.has_wildcards = true;
+            self.wildcards.insert_multi(&wildcards);
+        }
+        err.map_or(Ok(()), Err)
+    }
+
+    fn matches(&self, domain: &Dname<Bytes>) -> bool {
+        if !self.has_wildcards {
+            return self.domains.matches(domain);
+        }
+        (self.has_domains && self.domains.matches(domain))
+            || matches!(domain.iter_suffixes().nth(1), Some(p) if self.wildcards.matches(&p))
+    }
+}
 
 /// The domain matcher
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
-pub struct Domain(DomainAlg);
-
-fn into_dnames(list: &str) -> std::result::Result<Vec<Dname<Bytes>>, FromStrError> {
-    list.split('\n')
-        .filter(|&x| {
-            (!x.is_empty())
-                && (x.chars().all(|c| {
-                    char::is_ascii_alphabetic(&c)
-                        | char::is_ascii_digit(&c)
-                        | (c == '-')
-                        | (c == '.')
-                }))
-        })
-        .map(Dname::from_str)
-        .collect()
+pub struct Domain {
+    rules: Rules,
+    // Exceptions take precedence over rules. `None` if there is no exception.
+    except: Option<Rules>,
+}
+
+// Parse a line into a domain along with whether it is a wildcard, e.g. `*.example.com`, which matches the subdomains only. `None` if the line is not a domain.
+pub(super) fn parse_rule(
+    line: &str,
+) -> Option<std::result::Result<(Dname<Bytes>, bool), FromStrError>> {
+    let line = line.trim();
+    let wildcard = line.starts_with("*.");
+    let line = line.trim_start_matches("*.").trim_end_matches('.');
+    // Internationalized names are matched in their punycode form, which is how they are queried
+    let line = if line.is_ascii() {
+        Cow::Borrowed(line)
+    } else {
+        Cow::Owned(idna::domain_to_ascii(line).ok()?)
+    };
+    ((!line.is_empty())
+        && (line.chars().all(|c| {
+            char::is_ascii_alphabetic(&c) | char::is_ascii_digit(&c) | (c == '-') | (c == '.')
+        })))
+    .then(|| Dname::from_str(&line).map(|d| (d, wildcard)))
+}
+
+fn into_rules(list: &str) -> std::result::Result<Vec<(Dname<Bytes>, bool)>, FromStrError> {
+    list.split('\n').filter_map(parse_rule).collect()
 }
 
 impl Default for Domain {
@@ -45,30 +175,171 @@ impl Default for Domain {
     }
 }
 
+// Stream the domains in the file line by line, so that the file is never held in memory as a whole.
+fn read_file(path: impl AsRef<str>) -> Result<impl Iterator<Item = Result<(Dname<Bytes>, bool)>>> {
+    // from_str is Infallible
+    let (file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
+    Ok(BufReader::new(file).lines().filter_map(|line| match line {
+        Ok(line) => parse_rule(&line).map(|r| r.map_err(Into::into)),
+        Err(e) => Some(Err(e.into())),
+    }))
+}
+
 impl Domain {
     /// Create an empty `domain` matcher
     pub fn new() -> Self {
-        Self(DomainAlg::new())
+        Self {
+            rules: Rules::new(Alg::Trie(DomainAlg::new())),
+            except: None,
+        }
+    }
+
+    /// Create an empty `domain` matcher backed by a sorted array instead of a trie. It takes several times less memory for large lists, but every list added rebuilds the matcher.
+    pub fn compact() -> Self {
+        Self {
+            rules: Rules::new(Alg::Compact(Compact::new())),
+            except: None,
+        }
     }
 
     /// Add a question name to the domain matcher's list
     pub fn add_qname(&mut self, s: impl AsRef<str>) -> Result<()> {
-        self.0.insert_multi(&into_dnames(s.as_ref())?);
+        self.rules.insert_multi(&into_rules(s.as_ref())?);
         Ok(())
     }
 
     /// Add all question names in a file to the domain matcher's list
     pub fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
-        // from_str is Infallible
-        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        self.0.insert_multi(&into_dnames(&data)?);
+        self.rules.insert_file(path)
+    }
+
+    /// Add all question names in the files to the domain matcher's list. Files are read and parsed in parallel, and the matcher is only built once, which is considerably faster than adding them one by one.
+    pub fn add_files(&mut self, paths: &[impl AsRef<str> + Sync]) -> Result<()> {
+        self.rules
+            .insert_multi(&load_files(paths, |p| read_file(p)?.collect())?);
+        Ok(())
+    }
+
+    /// Add a question name to the exception list. Question names matching any exception never match the matcher.
+    pub fn add_except_qname(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.except
+            .get_or_insert_with(|| self.rules.empty())
+            .insert_multi(&into_rules(s.as_ref())?);
         Ok(())
     }
 
-    /// Check if the question name matches any in the matcher.
+    /// Add all question names in a file to the exception list
+    pub fn add_except_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.except
+            .get_or_insert_with(|| self.rules.empty())
+            .insert_file(path)
+    }
+
+    /// Check if the question name matches any in the matcher and none in the exception list.
+    #[tracing::instrument(name = "domain", level = "debug", skip_all)]
     pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
-        self.0.matches(qname)
+        self.rules.matches(qname)
+            && !self
+                .except
+                .as_ref()
+                .map(|e| e.matches(qname))
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Domain;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    #[test]
+    fn except() {
+        let mut domain = Domain::new();
+        domain.add_qname("*.doubleclick.net\nexample.com").unwrap();
+        domain.add_except_qname("safe.doubleclick.net").unwrap();
+
+        assert!(domain.contains(&Dname::from_str("ad.doubleclick.net").unwrap()));
+        assert!(domain.contains(&Dname::from_str("example.com").unwrap()));
+        assert!(!domain.contains(&Dname::from_str("safe.doubleclick.net").unwrap()));
+        assert!(!domain.contains(&Dname::from_str("www.safe.doubleclick.net").unwrap()));
+        assert!(!domain.contains(&Dname::from_str("example.org").unwrap()));
+    }
+
+    #[test]
+    fn wildcard() {
+        let path = std::env::temp_dir().join(format!("dcompass-wildcard-{}", std::process::id()));
+        std::fs::write(&path, "*.example.net\nexample.edu\n").unwrap();
+        for mut domain in [Domain::new(), Domain::compact()] {
+            domain.add_qname("*.example.com\nexample.org").unwrap();
+            domain.add_file(path.to_str().unwrap()).unwrap();
+            assert!(domain.contains(&Dname::from_str("www.example.com").unwrap()));
+            assert!(domain.contains(&Dname::from_str("a.b.example.com").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("example.com").unwrap()));
+            // Plain domains keep matching themselves
+            assert!(domain.contains(&Dname::from_str("example.org").unwrap()));
+            assert!(domain.contains(&Dname::from_str("www.example.net").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("example.net").unwrap()));
+            assert!(domain.contains(&Dname::from_str("example.edu").unwrap()));
+
+            // Exceptions given as wildcards leave the apex alone as well
+            domain.add_except_qname("*.example.org").unwrap();
+            assert!(domain.contains(&Dname::from_str("example.org").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("www.example.org").unwrap()));
+        }
+        std::fs::remove_file(path).unwrap();
+
+        for mut domain in [Domain::new(), Domain::compact()] {
+            domain.add_qname("*.example.com").unwrap();
+            assert!(domain.contains(&Dname::from_str("www.example.com").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("example.org").unwrap()));
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        for mut domain in [Domain::new(), Domain::compact()] {
+            domain
+                .add_qname("Example.COM.\r\n*.bÜcher.de\n中国")
+                .unwrap();
+            assert!(domain.contains(&Dname::from_str("www.EXAMPLE.com").unwrap()));
+            assert!(domain.contains(&Dname::from_str("www.xn--bcher-kva.de").unwrap()));
+            assert!(domain.contains(&Dname::from_str("xn--fiqs8s").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("example.org").unwrap()));
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let mut domain = Domain::compact();
+        domain.add_qname("*.doubleclick.net\nexample.com").unwrap();
+        domain.add_except_qname("safe.doubleclick.net").unwrap();
+
+        assert!(domain.contains(&Dname::from_str("ad.doubleclick.net").unwrap()));
+        assert!(domain.contains(&Dname::from_str("example.com").unwrap()));
+        assert!(!domain.contains(&Dname::from_str("www.safe.doubleclick.net").unwrap()));
+        assert!(!domain.contains(&Dname::from_str("example.org").unwrap()));
+    }
+
+    #[test]
+    fn add_files() {
+        let mut domain = Domain::compact();
+        domain
+            .add_files(&["../data/apple.txt", "../data/china.txt.gz"])
+            .unwrap();
+        assert!(domain.contains(&Dname::from_str("a1.mzstatic.com").unwrap()));
+        assert!(domain.contains(&Dname::from_str("www.baidu.com").unwrap()));
+
+        assert!(Domain::new().add_files(&["../data/nonexist.txt"]).is_err());
+    }
+
+    #[test]
+    fn add_file() {
+        for mut domain in [Domain::new(), Domain::compact()] {
+            domain.add_file("../data/china.txt.gz").unwrap();
+            domain.add_except_file("../data/apple.txt").unwrap();
+            assert!(domain.contains(&Dname::from_str("www.baidu.com").unwrap()));
+            assert!(!domain.contains(&Dname::from_str("example.com").unwrap()));
+        }
     }
 }
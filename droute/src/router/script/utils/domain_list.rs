@@ -0,0 +1,251 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{domain::parse_rule, Domain, Result, UtilsError};
+use bytes::Bytes;
+use domain::base::Dname;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+// Lists registered, looked up by whoever updates them at runtime
+static LISTS: Lazy<RwLock<HashMap<String, Arc<DomainList>>>> = Lazy::new(Default::default);
+
+// A domain along with whether it is a wildcard, e.g. `*.example.com`, matching the subdomains only
+type Rule = (Dname<Bytes>, bool);
+
+// Changes made at runtime on top of the matcher built
+#[derive(Default)]
+struct Edits {
+    added: HashSet<Rule>,
+    removed: HashSet<Rule>,
+}
+
+impl Edits {
+    // Whether the rule changed at runtime matches. `None` if it is not changed.
+    fn get(&self, rule: &Rule) -> Option<bool> {
+        if self.added.contains(rule) {
+            Some(true)
+        } else if self.removed.contains(rule) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// A domain matcher registered under a name, to which domains can be added or from which they can be removed while the router is running.
+pub struct DomainList {
+    // Swapped as a whole when the list is updated
+    domain: RwLock<Arc<Domain>>,
+    edits: RwLock<Edits>,
+    // Plain text file the changes are written back to
+    file: Option<PathBuf>,
+}
+
+fn parse_one(s: &str) -> Result<Rule> {
+    Ok(parse_rule(s).ok_or_else(|| UtilsError::InvalidDomain(s.to_string()))??)
+}
+
+// Write the rule back as it is parsed
+fn display((name, wildcard): &Rule) -> String {
+    if *wildcard {
+        format!("*.{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// Lines of the file, which is empty if it doesn't exist yet
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Whether the file is compressed, which can't be appended to as plain text
+fn compressed(path: &Path) -> bool {
+    let sniffed = fs::File::open(path).map(|f| niffler::sniff(Box::new(f)));
+    matches!(sniffed, Ok(Ok((_, format))) if format != niffler::Format::No)
+}
+
+impl DomainList {
+    /// Register the matcher under `name`, replacing the one registered under the same name if any. If `file` is given, changes are written back to it, so that they persist if the matcher is built from it. Changes to compressed files are kept in memory only.
+    pub fn register(name: impl Into<String>, domain: Domain, file: Option<PathBuf>) -> Arc<Self> {
+        let name = name.into();
+        let file = file.filter(|path| {
+            let plain = !compressed(path);
+            if !plain {
+                warn!(
+                    "`{}` is compressed, changes to list `{}` are not written back to it",
+                    path.display(),
+                    name
+                );
+            }
+            plain
+        });
+        let list = Arc::new(Self {
+            domain: RwLock::new(Arc::new(domain)),
+            edits: RwLock::new(Edits::default()),
+            file,
+        });
+        LISTS.write().unwrap().insert(name, list.clone());
+        list
+    }
+
+    /// Get the matcher registered under `name`.
+    pub fn get(name: &str) -> Option<Arc<Self>> {
+        LISTS.read().unwrap().get(name).cloned()
+    }
+
+    /// Replace the rules the matcher is built with, e.g. with a newer version of the list. Changes made at runtime are kept.
+    pub fn swap(&self, domain: Domain) {
+        *self.domain.write().unwrap() = Arc::new(domain);
+    }
+
+    /// Make the domain and its subdomains, or only the subdomains for wildcards like `*.example.com`, match from now on. Domains already listed in the file are not written again.
+    pub fn add(&self, s: &str) -> Result<()> {
+        let rule = parse_one(s)?;
+        if let Some(path) = &self.file {
+            let listed = read_lines(path)?
+                .iter()
+                .any(|l| matches!(parse_rule(l), Some(Ok(r)) if r == rule));
+            if !listed {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", display(&rule))?;
+            }
+        }
+        let mut edits = self.edits.write().unwrap();
+        edits.removed.remove(&rule);
+        edits.added.insert(rule);
+        Ok(())
+    }
+
+    /// Make the domain and its subdomains, or only the subdomains for wildcards like `*.example.com`, no longer match from now on, regardless of the rules the matcher is built with. If the changes are written back to a file, only the rules listed in it can be removed, as exceptions to the broader rules listed can't be written back.
+    pub fn remove(&self, s: &str) -> Result<()> {
+        let rule = parse_one(s)?;
+        if let Some(path) = &self.file {
+            let lines = read_lines(path)?;
+            let len = lines.len();
+            let kept: Vec<_> = lines
+                .into_iter()
+                .filter(|l| !matches!(parse_rule(l), Some(Ok(r)) if r == rule))
+                .collect();
+            if kept.len() == len {
+                return Err(UtilsError::NotListed(display(&rule)));
+            }
+            // Replace the file as a whole so that it is never left half written
+            let tmp = path.with_extension("tmp");
+            fs::write(
+                &tmp,
+                kept.iter().map(|l| format!("{}\n", l)).collect::<String>(),
+            )?;
+            fs::rename(tmp, path)?;
+        }
+        let mut edits = self.edits.write().unwrap();
+        edits.added.remove(&rule);
+        edits.removed.insert(rule);
+        Ok(())
+    }
+
+    /// Check if the question name matches. The most specific domain changed at runtime takes precedence over the rules the matcher is built with.
+    pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
+        let edits = self.edits.read().unwrap();
+        if !edits.added.is_empty() || !edits.removed.is_empty() {
+            let mut rule = (qname.clone(), false);
+            // Wildcards only apply to the names strictly under them
+            let mut strict = false;
+            loop {
+                if let Some(m) = edits.get(&rule) {
+                    return m;
+                }
+                if strict {
+                    rule.1 = true;
+                    if let Some(m) = edits.get(&rule) {
+                        return m;
+                    }
+                }
+                match rule.0.iter_suffixes().nth(1) {
+                    Some(parent) => rule = (parent, false),
+                    None => break,
+                }
+                strict = true;
+            }
+        }
+        // Don't hold the lock while matching, so that swapping is never blocked for long
+        let domain = self.domain.read().unwrap().clone();
+        domain.contains(qname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Domain, DomainList};
+    use domain::base::Dname;
+    use std::{fs, str::FromStr};
+
+    #[test]
+    fn edits() {
+        let mut domain = Domain::new();
+        domain.add_qname("doubleclick.net").unwrap();
+        DomainList::register("ads", domain, None);
+
+        let list = DomainList::get("ads").unwrap();
+        assert!(list.contains(&Dname::from_str("ad.doubleclick.net").unwrap()));
+        assert!(!list.contains(&Dname::from_str("bad.example").unwrap()));
+
+        list.add("bad.example").unwrap();
+        list.remove("safe.doubleclick.net").unwrap();
+        assert!(list.contains(&Dname::from_str("www.bad.example").unwrap()));
+        assert!(list.contains(&Dname::from_str("ad.doubleclick.net").unwrap()));
+        assert!(!list.contains(&Dname::from_str("safe.doubleclick.net").unwrap()));
+
+        list.remove("bad.example").unwrap();
+        assert!(!list.contains(&Dname::from_str("bad.example").unwrap()));
+
+        // Runtime changes survive swapping the rules
+        let mut domain = Domain::new();
+        domain.add_qname("ads.example").unwrap();
+        list.swap(domain);
+        assert!(list.contains(&Dname::from_str("ads.example").unwrap()));
+        assert!(!list.contains(&Dname::from_str("ad.doubleclick.net").unwrap()));
+        assert!(!list.contains(&Dname::from_str("safe.doubleclick.net").unwrap()));
+        assert!(list.add("not a domain").is_err());
+        assert!(DomainList::get("nonexist").is_none());
+    }
+
+    #[test]
+    fn compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ads.txt.gz");
+        // Magic number of gzip
+        let content = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        fs::write(&path, content).unwrap();
+
+        let list = DomainList::register("compressed", Domain::new(), Some(path.clone()));
+        list.add("bad.example").unwrap();
+        list.remove("ads.example").unwrap();
+        assert!(list.contains(&Dname::from_str("bad.example").unwrap()));
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+}
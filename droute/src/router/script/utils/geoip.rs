@@ -64,6 +64,7 @@ impl GeoIp {
     }
 
     /// Whether the given country code contains the given IP address
+    #[tracing::instrument(name = "geoip", level = "debug", skip_all)]
     pub fn contains(&self, ip: IpAddr, code: &str) -> bool {
         let r = if let Ok(r) = self.db.lookup::<Country>(ip) {
             r
@@ -93,28 +94,22 @@ mod tests {
 
     #[tokio::test]
     async fn builtin_db_not_china() {
-        assert_eq!(
-            GeoIp::from_buf(DB.clone())
-                .unwrap()
-                .contains("1.1.1.1".parse().unwrap(), "CN"),
-            false
-        )
+        assert!(!GeoIp::from_buf(DB.clone())
+            .unwrap()
+            .contains("1.1.1.1".parse().unwrap(), "CN"))
     }
 
     #[tokio::test]
     async fn not_china() {
-        assert_eq!(
-            GeoIp::from_buf(DB.clone())
-                .unwrap()
-                .contains("1.1.1.1".parse().unwrap(), "CN"),
-            false
-        )
+        assert!(!GeoIp::from_buf(DB.clone())
+            .unwrap()
+            .contains("1.1.1.1".parse().unwrap(), "CN"))
     }
 
     #[tokio::test]
     async fn mixed() {
         let geoip = GeoIp::from_buf(DB.clone()).unwrap();
-        assert_eq!(geoip.contains("180.101.49.12".parse().unwrap(), "CN"), true);
-        assert_eq!(geoip.contains("69.162.81.155".parse().unwrap(), "US"), true)
+        assert!(geoip.contains("180.101.49.12".parse().unwrap(), "CN"));
+        assert!(geoip.contains("69.162.81.155".parse().unwrap(), "US"))
     }
 }
@@ -1,9 +1,10 @@
-use super::Result;
-use cidr_utils::{
-    cidr::{IpCidr as Cidr, IpCidrError},
-    utils::IpCidrCombiner as CidrCombiner,
+use super::{load_files, Result};
+use cidr_utils::{cidr::IpCidr as Cidr, utils::IpCidrCombiner as CidrCombiner};
+use std::{
+    io::{BufRead, BufReader},
+    net::IpAddr,
+    path::Path,
 };
-use std::{net::IpAddr, path::Path};
 
 /// IP CIDR matcher.
 #[derive(Clone)]
@@ -20,22 +21,41 @@ impl IpCidr {
         }
     }
 
+    // Stream the IP CIDRs in the file line by line, so that the file is never held in memory as a whole.
+    fn read_file(path: impl AsRef<Path>) -> Result<impl Iterator<Item = Result<Cidr>>> {
+        let (file, _) = niffler::from_path(path)?;
+        // This gets rid of empty lines for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
+        Ok(BufReader::new(file).lines().filter_map(|line| match line {
+            Ok(line) if line.is_empty() => None,
+            Ok(line) => Some(Cidr::from_str(line).map_err(Into::into)),
+            Err(e) => Some(Err(e.into())),
+        }))
+    }
+
     /// Add IP CIDRs from a files where each IP CIDR is seperated from one another by `\n`.
     pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
-        let (mut file, _) = niffler::from_path(path)?;
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        // This gets rid of empty substrings for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
-        data.split('\n').filter(|&x| !x.is_empty()).try_for_each(
-            |x| -> std::result::Result<(), IpCidrError> {
-                self.matcher.push(Cidr::from_str(x)?);
-                Ok(())
-            },
-        )?;
+        for cidr in Self::read_file(path)? {
+            self.matcher.push(cidr?);
+        }
+        Ok(())
+    }
+
+    /// Add IP CIDRs from all the files given, which are read and parsed in parallel.
+    pub fn add_files(&mut self, paths: &[impl AsRef<Path> + Sync]) -> Result<()> {
+        load_files(paths, |p| Self::read_file(p)?.collect())?
+            .into_iter()
+            .for_each(|cidr| self.matcher.push(cidr));
+        Ok(())
+    }
+
+    /// Add an IP CIDR like `192.168.1.0/24` or a single IP address.
+    pub fn add_cidr(&mut self, cidr: impl AsRef<str>) -> Result<()> {
+        self.matcher.push(Cidr::from_str(cidr.as_ref())?);
         Ok(())
     }
 
     /// Check if IP CIDR set contains the given IP address.
+    #[tracing::instrument(name = "ipcidr", level = "debug", skip_all)]
     pub fn contains(&self, ip: IpAddr) -> bool {
         self.matcher.contains(ip)
     }
@@ -16,23 +16,106 @@
 // proc-macro on non-inline modules are unstable
 
 mod blackhole;
+mod block_page;
 mod domain;
+mod domain_list;
 mod geoip;
 mod ipcidr;
+mod normalize;
+mod prefer;
+mod rule_log;
+mod safe_search;
+mod sanitize;
+mod strip;
+mod subscription;
+mod svcb;
 
 pub use self::domain::Domain;
 pub use blackhole::blackhole;
+pub use block_page::BlockPage;
+pub use domain_list::DomainList;
 pub use geoip::GeoIp;
 pub use ipcidr::IpCidr;
+pub use normalize::{lowercase_qname, restore_qname};
+pub use prefer::{family_query, filter_family, prefer_family, Family};
+pub use rule_log::{RuleLog, RULE_LOG_TARGET};
+pub use safe_search::SafeSearch;
+pub use sanitize::sanitize;
+pub use strip::{minimize, strip_additional, strip_records, truncate, udp_limit};
+pub(crate) use strip::{rebuild_with, records};
+pub use subscription::{ListFormat, SubscriptionBuilder};
+pub use svcb::{strip_svc_params, svc_param_key};
 
-use ::domain::base::{name::FromStrError, octets::ParseError};
+use crate::errors::ErrorKind;
+use ::domain::{
+    base::{name::FromStrError, octets::ParseError, Message},
+    rdata::{Aaaa, A},
+};
+use bytes::Bytes;
 use maxminddb::MaxMindDBError;
+use std::{net::IpAddr, num::NonZeroUsize};
 use thiserror::Error;
 
 /// A shorthand for returning utils error.
 pub type Result<T> = std::result::Result<T, UtilsError>;
 
+// Addresses answered in the response
+pub(crate) fn addrs(resp: &Message<Bytes>) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    if let Ok(answer) = resp.answer() {
+        addrs.extend(
+            answer
+                .limit_to::<A>()
+                .flatten()
+                .map(|r| IpAddr::from(r.data().addr())),
+        );
+    }
+    if let Ok(answer) = resp.answer() {
+        addrs.extend(
+            answer
+                .limit_to::<Aaaa>()
+                .flatten()
+                .map(|r| IpAddr::from(r.data().addr())),
+        );
+    }
+    addrs
+}
+
+// Load the files concurrently on no more threads than the available parallelism, and concatenate the items loaded in the order of the paths given.
+fn load_files<P: Sync, T: Send>(
+    paths: &[P],
+    load: impl Fn(&P) -> Result<Vec<T>> + Sync,
+) -> Result<Vec<T>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    // Each thread loads a run of consecutive files, which keeps the items in order
+    let chunk = (paths.len() + threads - 1) / threads;
+    let load = &load;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = paths
+            .chunks(chunk)
+            .map(|paths| {
+                s.spawn(move || -> Result<Vec<T>> {
+                    let mut items = Vec::new();
+                    for p in paths {
+                        items.extend(load(p)?);
+                    }
+                    Ok(items)
+                })
+            })
+            .collect();
+        let mut items = Vec::new();
+        for handle in handles {
+            items.extend(handle.join().expect("thread loading the files panicked")?);
+        }
+        Ok(items)
+    })
+}
+
 #[derive(Error, Debug)]
+#[non_exhaustive]
 /// All possible errors that may incur when using utils.
 pub enum UtilsError {
     /// Error forwarded from `std::io::Error`.
@@ -67,4 +150,97 @@ pub enum UtilsError {
     /// Short Buf
     #[error(transparent)]
     ShortBuf(#[from] ::domain::base::ShortBuf),
+
+    /// The query has no question to act on
+    #[error("the query contains no question")]
+    NoQuestion,
+
+    /// The response doesn't answer the query
+    #[error("the {0} of the response doesn't match the query")]
+    Mismatch(&'static str),
+
+    /// The response answers with an address public resolvers never return
+    #[error("bogus address {0} found in the answer")]
+    BogusAnswer(std::net::IpAddr),
+
+    /// The name of the SvcParamKey is unknown
+    #[error("unknown SvcParamKey `{0}`")]
+    UnknownSvcParam(String),
+
+    /// The string given is not a domain
+    #[error("`{0}` is not a valid domain")]
+    InvalidDomain(String),
+
+    /// The name of the address family is unknown
+    #[error("unknown address family `{0}`, expecting `ipv4` or `ipv6`")]
+    UnknownFamily(String),
+
+    /// Invalid options of the rule log
+    #[error("invalid rule log option: {0}")]
+    RuleLog(String),
+
+    /// Failed to download the list subscribed
+    #[error("failed to download {0}")]
+    FetchError(String),
+
+    /// No file to cache the list in is set while the user has no cache directory
+    #[error("no cache directory found for list `{0}`, please set its `cache`")]
+    NoCacheDir(String),
+
+    /// The rule to remove is not listed in the file the list writes its changes back to
+    #[error("`{0}` is not listed in the file of the list")]
+    NotListed(String),
+
+    /// No list is registered under the name
+    #[error("no list named `{0}` found")]
+    MissingList(String),
+
+    /// The list downloaded doesn't match its checksum
+    #[error("the SHA256 checksum of {0} doesn't match")]
+    ChecksumMismatch(String),
+
+    /// Checksums of lists can't be verified without the `checksum` feature.
+    #[cfg(not(feature = "checksum"))]
+    #[error("This build can't verify the checksums of lists, please remove `sha256_url` or use other builds.")]
+    NoChecksum,
+}
+
+impl UtilsError {
+    /// The general category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ParseError(_) | Self::ShortBuf(_) | Self::NoQuestion | Self::Mismatch(_) => {
+                ErrorKind::Protocol
+            }
+            Self::BogusAnswer(_) => ErrorKind::Policy,
+            Self::FetchError(_) => ErrorKind::Network,
+            Self::ChecksumMismatch(_) => ErrorKind::Protocol,
+            _ => ErrorKind::Config,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_files, UtilsError};
+
+    #[test]
+    fn load_files_in_order() {
+        let paths: Vec<usize> = (0..100).collect();
+        assert_eq!(
+            load_files(&paths, |&p| Ok(vec![p, p])).unwrap(),
+            paths.iter().flat_map(|&p| [p, p]).collect::<Vec<_>>()
+        );
+        assert!(load_files(&[0_usize; 0], |_| Ok(vec![()]))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            load_files(&paths, |&p| if p == 42 {
+                Err(UtilsError::NoQuestion)
+            } else {
+                Ok(vec![p])
+            }),
+            Err(UtilsError::NoQuestion)
+        ));
+    }
 }
@@ -0,0 +1,124 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use std::ops::Range;
+
+// Offset of the question section, right after the header.
+const QUESTION: usize = 12;
+
+// The bytes of the name in the first question. `None` if there is no question or it is malformed. The first name in a message can't be compressed as nothing precedes it.
+fn qname_span(msg: &[u8]) -> Option<Range<usize>> {
+    if msg.len() < QUESTION || u16::from_be_bytes([msg[4], msg[5]]) == 0 {
+        return None;
+    }
+    let mut pos = QUESTION;
+    loop {
+        match *msg.get(pos)? as usize {
+            0 => return Some(QUESTION..pos + 1),
+            len if len < 64 => pos += len + 1,
+            // Compression pointers and extended label types
+            _ => return None,
+        }
+    }
+}
+
+fn rewrite(msg: &Message<Bytes>, f: impl FnOnce(&mut [u8])) -> Option<Message<Bytes>> {
+    let mut buf = BytesMut::from(msg.as_slice());
+    f(&mut buf);
+    Message::from_octets(buf.freeze()).ok()
+}
+
+/// Lowercase the name in the question of `query`, so that the matchers and the scripts see the names in the same form regardless of how clients spell them, e.g. with DNS 0x20 encoding. `None` if it is lowercase already.
+pub fn lowercase_qname(query: &Message<Bytes>) -> Option<Message<Bytes>> {
+    let span = qname_span(query.as_slice())?;
+    if !query.as_slice()[span.clone()]
+        .iter()
+        .any(u8::is_ascii_uppercase)
+    {
+        return None;
+    }
+    rewrite(query, |buf| buf[span].make_ascii_lowercase())
+}
+
+/// Restore the name in the question of `resp` to the spelling in `query`, as clients expect the question to be echoed as is. Names compressed to point to the question are restored as well. `resp` is returned as is if the names differ other than in case.
+pub fn restore_qname(resp: &Message<Bytes>, query: &Message<Bytes>) -> Message<Bytes> {
+    let (from, to) = match (qname_span(query.as_slice()), qname_span(resp.as_slice())) {
+        (Some(from), Some(to)) => (&query.as_slice()[from], to),
+        _ => return resp.clone(),
+    };
+    if !from.eq_ignore_ascii_case(&resp.as_slice()[to.clone()]) {
+        return resp.clone();
+    }
+    rewrite(resp, |buf| buf[to].copy_from_slice(from)).unwrap_or_else(|| resp.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lowercase_qname, restore_qname};
+    use crate::mock::query;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype, ToDname},
+        rdata::A,
+    };
+    use std::str::FromStr;
+
+    // Names are compared in the wire format as `Dname` ignores the case
+    fn qname(msg: &Message<Bytes>) -> Bytes {
+        msg.first_question()
+            .unwrap()
+            .qname()
+            .to_bytes()
+            .into_octets()
+    }
+
+    fn wire(name: &str) -> Bytes {
+        Dname::<Bytes>::from_str(name).unwrap().into_octets()
+    }
+
+    #[test]
+    fn lowercase() {
+        let query = query("WwW.ExAmple.COM", Rtype::A);
+        let lower = lowercase_qname(&query).unwrap();
+        assert_eq!(qname(&lower), wire("www.example.com"));
+        assert!(lowercase_qname(&lower).is_none());
+    }
+
+    #[test]
+    fn restore() {
+        let original = query("WwW.ExAmple.COM", Rtype::A);
+        let lower = lowercase_qname(&original).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&lower, Rcode::NoError)
+            .unwrap();
+        let name = lower.first_question().unwrap().qname().to_bytes();
+        builder
+            .push((&name, 300, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+        let resp = restore_qname(&builder.into_message(), &original);
+        assert_eq!(qname(&resp), wire("WwW.ExAmple.COM"));
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        // Different names are left alone
+        let other = query("example.org", Rtype::A);
+        assert_eq!(
+            qname(&restore_qname(&other, &original)),
+            wire("example.org")
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    addrs,
+    strip::{rebuild_sorted, records, Section},
+    strip_records, Result, UtilsError,
+};
+use crate::pool;
+use bytes::Bytes;
+use domain::base::{Message, MessageBuilder, Rtype};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, str::FromStr};
+
+/// The family of addresses.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Family {
+    /// IPv4 addresses, answered in `A` records
+    #[serde(rename = "ipv4")]
+    V4,
+    /// IPv6 addresses, answered in `AAAA` records
+    #[serde(rename = "ipv6")]
+    V6,
+}
+
+impl FromStr for Family {
+    type Err = UtilsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ipv4" => Ok(Self::V4),
+            "ipv6" => Ok(Self::V6),
+            _ => Err(UtilsError::UnknownFamily(s.to_string())),
+        }
+    }
+}
+
+impl Family {
+    /// The type of the records answering the addresses of the family.
+    pub fn rtype(self) -> Rtype {
+        match self {
+            Self::V4 => Rtype::A,
+            Self::V6 => Rtype::Aaaa,
+        }
+    }
+
+    /// The other family.
+    pub fn other(self) -> Self {
+        match self {
+            Self::V4 => Self::V6,
+            Self::V6 => Self::V4,
+        }
+    }
+
+    fn contains(self, ip: &IpAddr) -> bool {
+        match self {
+            Self::V4 => ip.is_ipv4(),
+            Self::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Reorder the answer section so that addresses of `family` come before those of the other family, which helps clients connecting to the first address answered. Other records, e.g. `CNAME`, are put before the addresses in their original order.
+pub fn prefer_family(resp: &Message<Bytes>, family: Family) -> Result<Message<Bytes>> {
+    rebuild_sorted(
+        resp,
+        |_, _, _| true,
+        |rtype| match rtype {
+            _ if rtype == family.rtype() => 1,
+            _ if rtype == family.other().rtype() => 2,
+            _ => 0,
+        },
+    )
+}
+
+/// The query for the addresses of `family` under the same name, ID, and EDNS options as `query`, e.g. the `A` query alongside an `AAAA` one.
+pub fn family_query(query: &Message<Bytes>, family: Family) -> Result<Message<Bytes>> {
+    let question = query.first_question().ok_or(UtilsError::NoQuestion)?;
+    let mut builder = MessageBuilder::from_target(pool::buffer())?;
+    *builder.header_mut() = query.header();
+    let mut builder = builder.question();
+    builder.push((question.qname(), family.rtype(), question.qclass()))?;
+    let mut builder = builder.additional();
+    // TSIG signatures don't cover the new query
+    for record in records(Section::Additional, query.additional(), &|_, rtype, _| {
+        rtype == Rtype::Opt
+    })? {
+        builder.push(record)?;
+    }
+    Ok(builder.into_message())
+}
+
+/// Strip the addresses of the family other than `family` from `resp` if `preferred`, the response to the query of `family` under the same name, answers any address of `family`, so that clients have to connect over `family`. Names with addresses of the other family only are left untouched.
+pub fn filter_family(
+    resp: &Message<Bytes>,
+    preferred: &Message<Bytes>,
+    family: Family,
+) -> Result<Message<Bytes>> {
+    if addrs(preferred).iter().any(|ip| family.contains(ip)) {
+        strip_records(resp, &[family.other().rtype()], None)
+    } else {
+        Ok(resp.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{family_query, filter_family, prefer_family, Family};
+    use crate::{mock::query, utils::rebuild_with};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, Cname, A},
+    };
+    use std::str::FromStr;
+
+    fn resp(rtype: Rtype, v4: bool, v6: bool) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query("example.com", rtype), Rcode::NoError)
+            .unwrap();
+        if v6 {
+            builder
+                .push((&name, 300, Aaaa::new("2001:db8::1".parse().unwrap())))
+                .unwrap();
+        }
+        builder
+            .push((
+                &name,
+                300,
+                Cname::new(Dname::<Bytes>::from_str("cdn.example.com").unwrap()),
+            ))
+            .unwrap();
+        if v4 {
+            builder
+                .push((&name, 300, A::from_octets(1, 1, 1, 1)))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn types(msg: &Message<Bytes>) -> Vec<Rtype> {
+        msg.answer().unwrap().map(|r| r.unwrap().rtype()).collect()
+    }
+
+    #[test]
+    fn reorder() {
+        let resp = resp(Rtype::Any, true, true);
+        assert_eq!(
+            types(&prefer_family(&resp, Family::V4).unwrap()),
+            vec![Rtype::Cname, Rtype::A, Rtype::Aaaa]
+        );
+        assert_eq!(
+            types(&prefer_family(&resp, Family::V6).unwrap()),
+            vec![Rtype::Cname, Rtype::Aaaa, Rtype::A]
+        );
+    }
+
+    #[test]
+    fn filter() {
+        let resp = resp(Rtype::Aaaa, false, true);
+        // The name has IPv4 addresses
+        assert_eq!(
+            types(&filter_family(&resp, &self::resp(Rtype::A, true, false), Family::V4).unwrap()),
+            vec![Rtype::Cname]
+        );
+        // The name is IPv6 only
+        assert_eq!(
+            types(&filter_family(&resp, &self::resp(Rtype::A, false, false), Family::V4).unwrap()),
+            vec![Rtype::Aaaa, Rtype::Cname]
+        );
+    }
+
+    #[test]
+    fn sibling() {
+        let mut edns = rebuild_with(
+            &query("example.com", Rtype::Aaaa),
+            BytesMut::new(),
+            |_, _| Ok(vec![]),
+        )
+        .unwrap();
+        edns.opt(|_| Ok(())).unwrap();
+        let query = family_query(&edns.into_message(), Family::V4).unwrap();
+        assert_eq!(query.header().id(), 42);
+        assert_eq!(query.first_question().unwrap().qtype(), Rtype::A);
+        assert!(query.opt().is_some());
+        assert_eq!(Family::from_str("ipv6").unwrap(), Family::V6);
+        assert!(Family::from_str("ipv5").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Result, UtilsError};
+use bytes::Bytes;
+use domain::{
+    base::{Message, ParsedDname},
+    rdata::AllRecordData,
+};
+use log::{Level, LevelFilter};
+use std::{fmt::Write, str::FromStr};
+
+/// The target of the logs emitted by [`RuleLog`]. Loggers should let it through regardless of the global verbosity, as the level of each rule log is already chosen on its own.
+pub const RULE_LOG_TARGET: &str = "droute::rule";
+
+/// Logger for the queries matching a single rule in the routing script, with its own verbosity and sample rate.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct RuleLog {
+    name: String,
+    // `None` if it is turned off
+    level: Option<Level>,
+    sample: f64,
+}
+
+fn question(msg: &Message<Bytes>) -> String {
+    msg.first_question()
+        .map(|q| q.to_string())
+        .unwrap_or_default()
+}
+
+// Describe every section of the message, one record per line.
+fn describe(msg: &Message<Bytes>) -> String {
+    let header = msg.header();
+    let mut s = format!(
+        "id: {}, opcode: {}, rcode: {}",
+        header.id(),
+        header.opcode(),
+        header.rcode()
+    );
+    for question in msg.question().flatten() {
+        let _ = write!(s, "\n  question: {}", question);
+    }
+    for (name, section) in [
+        ("answer", msg.answer()),
+        ("authority", msg.authority()),
+        ("additional", msg.additional()),
+    ] {
+        for record in section
+            .into_iter()
+            .flat_map(|s| s.limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>())
+            .flatten()
+        {
+            let _ = write!(s, "\n  {}: {}", name, record);
+        }
+    }
+    s
+}
+
+impl RuleLog {
+    /// Create a logger for the rule `name`. `level` is one of `off`, `info`, `debug`, and `trace`. With `info`, the question of every query matched is logged. With `debug` or `trace`, the whole query and response are logged.
+    pub fn new(name: impl Into<String>, level: impl AsRef<str>) -> Result<Self> {
+        let level = LevelFilter::from_str(level.as_ref())
+            .map_err(|_| UtilsError::RuleLog(format!("unknown level `{}`", level.as_ref())))?;
+        Ok(Self {
+            name: name.into(),
+            level: level.to_level(),
+            sample: 1.0,
+        })
+    }
+
+    /// Only log the given proportion of the queries matched, which is between 0 and 1.
+    pub fn set_sample(&mut self, rate: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(UtilsError::RuleLog(format!(
+                "sample rate {} is not between 0 and 1",
+                rate
+            )));
+        }
+        self.sample = rate;
+        Ok(())
+    }
+
+    // The level to log at if this query is sampled
+    fn sampled(&self) -> Option<Level> {
+        self.level
+            .filter(|_| self.sample >= 1.0 || rand::random::<f64>() < self.sample)
+    }
+
+    /// Log the query matching the rule.
+    pub fn log(&self, query: &Message<Bytes>) {
+        crate::trace::rule(&self.name);
+        let level = match self.sampled() {
+            Some(level) => level,
+            None => return,
+        };
+        let msg = if level <= Level::Info {
+            question(query)
+        } else {
+            describe(query)
+        };
+        log::log!(target: RULE_LOG_TARGET, level, "rule `{}` matched {}", self.name, msg);
+    }
+
+    /// Log the query matching the rule along with its response.
+    pub fn log_response(&self, query: &Message<Bytes>, resp: &Message<Bytes>) {
+        crate::trace::rule(&self.name);
+        let level = match self.sampled() {
+            Some(level) => level,
+            None => return,
+        };
+        let msg = if level <= Level::Info {
+            format!(
+                "{}, answered with {}",
+                question(query),
+                resp.header().rcode()
+            )
+        } else {
+            format!("{}\nanswered with {}", describe(query), describe(resp))
+        };
+        log::log!(target: RULE_LOG_TARGET, level, "rule `{}` matched {}", self.name, msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuleLog;
+
+    #[test]
+    fn options() {
+        assert!(RuleLog::new("ads", "debug").is_ok());
+        assert!(RuleLog::new("ads", "OFF").unwrap().sampled().is_none());
+        assert!(RuleLog::new("ads", "loud").is_err());
+
+        let mut log = RuleLog::new("ads", "info").unwrap();
+        assert!(log.set_sample(1.5).is_err());
+        log.set_sample(0.0).unwrap();
+        assert!(log.sampled().is_none());
+        log.set_sample(1.0).unwrap();
+        assert!(log.sampled().is_some());
+    }
+}
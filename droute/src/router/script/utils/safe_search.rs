@@ -0,0 +1,265 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Result, UtilsError};
+use crate::{errors::ScriptError, pool, CacheMode, Label, Upstreams};
+use bytes::Bytes;
+use domain::{
+    base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype, ToDname},
+    rdata::{Aaaa, AllRecordData, Cname, A},
+};
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+// TTL of the records synthesized
+const SAFE_SEARCH_TTL: u32 = 300;
+
+// Safe search endpoints documented by the search engines.
+const DEFAULT_RULES: &[(&str, &str)] = &[
+    ("google.com", "forcesafesearch.google.com"),
+    ("www.google.com", "forcesafesearch.google.com"),
+    ("bing.com", "strict.bing.com"),
+    ("www.bing.com", "strict.bing.com"),
+    ("duckduckgo.com", "safe.duckduckgo.com"),
+    ("www.duckduckgo.com", "safe.duckduckgo.com"),
+    ("youtube.com", "restrict.youtube.com"),
+    ("www.youtube.com", "restrict.youtube.com"),
+    ("m.youtube.com", "restrict.youtube.com"),
+    ("youtubei.googleapis.com", "restrict.youtube.com"),
+    ("youtube.googleapis.com", "restrict.youtube.com"),
+    ("www.youtube-nocookie.com", "restrict.youtube.com"),
+];
+
+#[derive(Clone)]
+enum Target {
+    // Alias to the safe endpoint
+    Cname(Dname<Bytes>),
+    // Answer with the pinned addresses
+    Addrs(Vec<IpAddr>),
+}
+
+/// Rewrite queries for search engines to their safe search endpoints.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct SafeSearch {
+    // Only the exact names are rewritten, subdomains like `mail.google.com` are left intact.
+    rules: HashMap<Dname<Bytes>, Target>,
+}
+
+impl Default for SafeSearch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SafeSearch {
+    /// Create a rewriter enforcing safe search on Google, Bing, DuckDuckGo, and YouTube.
+    pub fn new() -> Self {
+        Self {
+            rules: DEFAULT_RULES
+                .iter()
+                .map(|(name, target)| {
+                    (
+                        Dname::from_str(name).unwrap(),
+                        Target::Cname(Dname::from_str(target).unwrap()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Create a rewriter without any rule.
+    pub fn empty() -> Self {
+        Self {
+            rules: HashMap::new(),
+        }
+    }
+
+    /// Alias `name` to `target`, which replaces any existing rule for `name`.
+    pub fn add_cname(&mut self, name: &str, target: &str) -> Result<()> {
+        self.rules.insert(
+            Dname::from_str(name)?,
+            Target::Cname(Dname::from_str(target)?),
+        );
+        Ok(())
+    }
+
+    /// Answer queries for `name` with the pinned address. Addresses added for the same name accumulate, replacing any alias rule for `name`.
+    pub fn add_ip(&mut self, name: &str, ip: IpAddr) -> Result<()> {
+        let target = self
+            .rules
+            .entry(Dname::from_str(name)?)
+            .or_insert(Target::Addrs(Vec::new()));
+        match target {
+            Target::Addrs(addrs) => addrs.push(ip),
+            Target::Cname(_) => *target = Target::Addrs(vec![ip]),
+        }
+        Ok(())
+    }
+
+    /// Remove the rule for `name`, so that it is no longer rewritten.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.rules.remove(&Dname::<Bytes>::from_str(name)?);
+        Ok(())
+    }
+
+    /// Whether the qname of the query is to be rewritten.
+    pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
+        self.rules.contains_key(qname)
+    }
+
+    // Answer with the pinned addresses of the queried type.
+    fn answer_addrs(query: &Message<Bytes>, addrs: &[IpAddr]) -> Result<Message<Bytes>> {
+        let question = query.first_question().ok_or(UtilsError::NoQuestion)?;
+        let qname = question.qname().to_bytes();
+        let mut builder =
+            MessageBuilder::from_target(pool::buffer())?.start_answer(query, Rcode::NoError)?;
+        for ip in addrs {
+            match (ip, question.qtype()) {
+                (IpAddr::V4(ip), Rtype::A) => {
+                    builder.push((qname.clone(), SAFE_SEARCH_TTL, A::new(*ip)))?
+                }
+                (IpAddr::V6(ip), Rtype::Aaaa) => {
+                    builder.push((qname.clone(), SAFE_SEARCH_TTL, Aaaa::new(*ip)))?
+                }
+                _ => (),
+            }
+        }
+        Ok(builder.into_message())
+    }
+
+    /// Rewrite the query if its qname is covered by the rules. Aliased names are resolved with the upstream tagged `tag`, and the answer is returned along with the CNAME record. `None` if the query is not covered.
+    pub async fn enforce(
+        &self,
+        upstreams: &Upstreams,
+        tag: &Label,
+        query: &Message<Bytes>,
+    ) -> std::result::Result<Option<Message<Bytes>>, ScriptError> {
+        let question = query.first_question().ok_or(UtilsError::NoQuestion)?;
+        let qname = question.qname().to_bytes();
+        let target = match self.rules.get(&qname) {
+            Some(Target::Cname(target)) => target,
+            Some(Target::Addrs(addrs)) => return Ok(Some(Self::answer_addrs(query, addrs)?)),
+            None => return Ok(None),
+        };
+        log::debug!("enforcing safe search for `{}` with `{}`", qname, target);
+
+        let mut builder = MessageBuilder::from_target(pool::buffer())?;
+        *builder.header_mut() = query.header();
+        let mut builder = builder.question();
+        builder.push((target, question.qtype(), question.qclass()))?;
+        let resp = upstreams
+            .send(tag, &CacheMode::default(), &builder.into_message())
+            .await?;
+
+        let mut builder = MessageBuilder::from_target(pool::buffer())?
+            .start_answer(query, resp.header().rcode())?;
+        builder.push((qname, SAFE_SEARCH_TTL, Cname::new(target.clone())))?;
+        for item in resp.answer().map_err(UtilsError::from)? {
+            if let Some(record) = item
+                .map_err(UtilsError::from)?
+                .into_record::<AllRecordData<_, _>>()
+                .map_err(UtilsError::from)?
+            {
+                builder.push(record)?;
+            }
+        }
+        Ok(Some(builder.into_message()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SafeSearch;
+    use crate::{
+        builders::{UpstreamBuilder, UpstreamsBuilder, ZoneBuilder},
+        mock::query,
+        AsyncTryInto,
+    };
+    use bytes::Bytes;
+    use domain::{
+        base::{Dname, Message, ParsedDname, Rtype},
+        rdata::AllRecordData,
+    };
+    use std::str::FromStr;
+
+    fn answer(msg: &Message<Bytes>) -> Vec<(Rtype, String)> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.rtype(), r.data().to_string())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn enforce() {
+        let upstreams = UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "zone",
+                UpstreamBuilder::Zone(ZoneBuilder {
+                    origin: "a.cn".to_string(),
+                    path: "../data/a.cn.zone".into(),
+                }),
+            )
+            .async_try_into()
+            .await
+            .unwrap();
+        let tag = "zone".into();
+
+        let mut safe = SafeSearch::empty();
+        safe.add_cname("www.google.com", "www.a.cn").unwrap();
+        safe.add_ip("www.bing.com", "204.79.197.220".parse().unwrap())
+            .unwrap();
+
+        let resp = safe
+            .enforce(&upstreams, &tag, &query("www.google.com", Rtype::A))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.header().id(), 42);
+        let records = answer(&resp);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, Rtype::Cname);
+        assert_eq!(records[1], (Rtype::A, "127.0.0.1".to_string()));
+
+        let resp = safe
+            .enforce(&upstreams, &tag, &query("www.bing.com", Rtype::A))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            answer(&resp),
+            vec![(Rtype::A, "204.79.197.220".to_string())]
+        );
+        // No IPv6 address pinned
+        let resp = safe
+            .enforce(&upstreams, &tag, &query("www.bing.com", Rtype::Aaaa))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(answer(&resp).is_empty());
+
+        // Subdomains are not covered
+        assert!(safe
+            .enforce(&upstreams, &tag, &query("mail.google.com", Rtype::A))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(SafeSearch::new().contains(&Dname::from_str("www.youtube.com").unwrap()));
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    strip::{rebuild_with, records, Section},
+    Result, UtilsError,
+};
+use crate::pool;
+use bytes::Bytes;
+use domain::{
+    base::{Dname, Message, Rtype, ToDname},
+    rdata::AllRecordData,
+};
+use std::net::IpAddr;
+
+// Addresses never legitimately returned by a public resolver, which are commonly used by DNS hijackers.
+fn bogus(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_unspecified() || ip.is_loopback() || ip.octets()[0] == 0,
+        IpAddr::V6(ip) => ip.is_unspecified() || ip.is_loopback(),
+    }
+}
+
+// Check that the response is the answer to the query.
+fn check(query: &Message<Bytes>, resp: &Message<Bytes>) -> Result<()> {
+    if query.header().id() != resp.header().id() {
+        return Err(UtilsError::Mismatch("ID"));
+    }
+    let question = query.first_question().ok_or(UtilsError::NoQuestion)?;
+    match resp.first_question() {
+        // Name comparison is case-insensitive
+        Some(q) if q == question => Ok(()),
+        _ => Err(UtilsError::Mismatch("question")),
+    }
+}
+
+/// Sanitize the response to the query.
+///
+/// The response is rejected if its ID or question doesn't match the query's. Answers not belonging to the query name or the CNAME chain it leads to are dropped, so are authority records outside the zones of these names, and additional records outside both these zones and the zones in the authority section, which are the common vectors of cache poisoning. If `reject_bogus` is set, responses answering with addresses like `0.0.0.0` or `127.0.0.1`, which public resolvers never return, are rejected as well.
+pub fn sanitize(
+    query: &Message<Bytes>,
+    resp: &Message<Bytes>,
+    reject_bogus: bool,
+) -> Result<Message<Bytes>> {
+    check(query, resp)?;
+    let qname = query
+        .first_question()
+        .ok_or(UtilsError::NoQuestion)?
+        .qname()
+        .to_bytes();
+    let all = |_, _, _| true;
+    let answer = records(Section::Answer, resp.answer(), &all)?;
+    let authority = records(Section::Authority, resp.authority(), &all)?;
+    let additional = records(Section::Additional, resp.additional(), &all)?;
+
+    // Names the response is allowed to answer for: the query name and the CNAME chain it leads to, whichever order the CNAME records are in.
+    let mut chain: Vec<Dname<Bytes>> = vec![qname];
+    loop {
+        let len = chain.len();
+        for record in &answer {
+            if let AllRecordData::Cname(cname) = record.data() {
+                let target = cname.cname().to_bytes();
+                if chain.contains(&record.owner().to_bytes()) && !chain.contains(&target) {
+                    chain.push(target);
+                }
+            }
+        }
+        if chain.len() == len {
+            break;
+        }
+    }
+
+    let answer: Vec<_> = answer
+        .into_iter()
+        .filter(|r| {
+            let owner = r.owner().to_bytes();
+            match r.rtype() {
+                // DNAME applies to the subdomains of its owner
+                Rtype::Dname => chain.iter().any(|n| n.ends_with(&owner)),
+                _ => chain.contains(&owner),
+            }
+        })
+        .collect();
+
+    if reject_bogus {
+        for record in &answer {
+            match record.data() {
+                AllRecordData::A(a) if bogus(a.addr().into()) => {
+                    return Err(UtilsError::BogusAnswer(a.addr().into()))
+                }
+                AllRecordData::Aaaa(aaaa) if bogus(aaaa.addr().into()) => {
+                    return Err(UtilsError::BogusAnswer(aaaa.addr().into()))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Authority records must be about the zones enclosing the names answered
+    let authority: Vec<_> = authority
+        .into_iter()
+        .filter(|r| {
+            let owner = r.owner().to_bytes();
+            chain.iter().any(|n| n.ends_with(&owner))
+        })
+        .collect();
+
+    // Glue records must be within the zones enclosing the names answered or the zones delegated to
+    let zones: Vec<Dname<Bytes>> = authority
+        .iter()
+        .map(|r| r.owner().to_bytes())
+        .chain(chain.iter().cloned())
+        .collect();
+    let additional: Vec<_> = additional
+        .into_iter()
+        .filter(|r| match r.rtype() {
+            // Not real records
+            Rtype::Opt | Rtype::Tsig => true,
+            _ => {
+                let owner = r.owner().to_bytes();
+                zones.iter().any(|z| owner.ends_with(z))
+            }
+        })
+        .collect();
+
+    let mut sections = (answer, authority, additional);
+    Ok(rebuild_with(resp, pool::buffer(), |s, _| {
+        Ok(std::mem::take(match s {
+            Section::Answer => &mut sections.0,
+            Section::Authority => &mut sections.1,
+            Section::Additional => &mut sections.2,
+        }))
+    })?
+    .into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+    use crate::{errors::UtilsError, mock::query, utils::rebuild_with};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, ParsedDname, Rtype},
+        rdata::{AllRecordData, Cname, Ns, A},
+    };
+    use std::str::FromStr;
+
+    fn name(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    fn owners(msg: &Message<Bytes>) -> Vec<String> {
+        msg.answer()
+            .unwrap()
+            .chain(msg.authority().unwrap())
+            .chain(msg.additional().unwrap())
+            .map(|r| r.unwrap().owner().to_string())
+            .collect()
+    }
+
+    fn response(query: &Message<Bytes>, ip: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(query, Rcode::NoError)
+            .unwrap();
+        builder
+            .push((
+                name("www.example.com"),
+                300,
+                Cname::new(name("cdn.example.net")),
+            ))
+            .unwrap();
+        builder
+            .push((name("cdn.example.net"), 300, A::new(ip.parse().unwrap())))
+            .unwrap();
+        // Poisoned answer
+        builder
+            .push((name("bank.com"), 300, A::new("6.6.6.6".parse().unwrap())))
+            .unwrap();
+        let mut builder = builder.authority();
+        builder
+            .push((name("example.net"), 300, Ns::new(name("ns.example.net"))))
+            .unwrap();
+        builder
+            .push((name("bank.com"), 300, Ns::new(name("ns.evil.org"))))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder
+            .push((
+                name("ns.example.net"),
+                300,
+                A::new("1.2.3.4".parse().unwrap()),
+            ))
+            .unwrap();
+        builder
+            .push((name("ns.evil.org"), 300, A::new("6.6.6.6".parse().unwrap())))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn out_of_bailiwick() {
+        let msg = query("www.example.com", Rtype::A);
+        let resp = sanitize(&msg, &response(&msg, "1.1.1.1"), true).unwrap();
+        assert_eq!(
+            owners(&resp),
+            vec![
+                "www.example.com",
+                "cdn.example.net",
+                "example.net",
+                "ns.example.net"
+            ]
+        );
+        let answer: Vec<_> = resp
+            .answer()
+            .unwrap()
+            .limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()
+            .map(|r| r.unwrap().data().to_string())
+            .collect();
+        assert_eq!(answer[1], "1.1.1.1");
+    }
+
+    #[test]
+    fn reject() {
+        let msg = query("www.example.com", Rtype::A);
+        let bogus = response(&msg, "127.0.0.1");
+        assert!(matches!(
+            sanitize(&msg, &bogus, true),
+            Err(UtilsError::BogusAnswer(_))
+        ));
+        assert!(sanitize(&msg, &bogus, false).is_ok());
+
+        let mut other = rebuild_with(&msg, BytesMut::new(), |_, _| Ok(vec![])).unwrap();
+        other.header_mut().set_id(43);
+        let other = other.into_message();
+        assert!(matches!(
+            sanitize(&other, &bogus, false),
+            Err(UtilsError::Mismatch("ID"))
+        ));
+        let other = query("www.example.org", Rtype::A);
+        assert!(matches!(
+            sanitize(&other, &bogus, false),
+            Err(UtilsError::Mismatch("question"))
+        ));
+    }
+}
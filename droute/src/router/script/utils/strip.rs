@@ -0,0 +1,314 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use crate::pool;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        message_builder::AdditionalBuilder, octets::ParseError, Message, MessageBuilder,
+        ParsedDname, Record, RecordSection, Rtype,
+    },
+    rdata::AllRecordData,
+};
+
+pub(crate) type ParsedRecord<'a> =
+    Record<ParsedDname<&'a Bytes>, AllRecordData<Bytes, ParsedDname<&'a Bytes>>>;
+
+type RawRecord<'a> = domain::base::record::ParsedRecord<&'a Bytes>;
+
+type ParsedSection<'a> = std::result::Result<RecordSection<&'a Bytes>, ParseError>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+// Records which are not data but about the message itself
+pub(super) fn pseudo(rtype: Rtype) -> bool {
+    matches!(rtype, Rtype::Opt | Rtype::Tsig)
+}
+
+// Parse the records of the section into what `map` gives for each of them, which drops the record by giving `None`.
+pub(super) fn parse<'a>(
+    s: Section,
+    section: ParsedSection<'a>,
+    map: &mut impl FnMut(Section, RawRecord<'a>) -> Result<Option<ParsedRecord<'a>>>,
+) -> Result<Vec<ParsedRecord<'a>>> {
+    let mut records = Vec::new();
+    for item in section? {
+        if let Some(record) = map(s, item?)? {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+// Parse the records of the section for which `keep` holds, given the section, the type, and the RDATA length.
+pub(crate) fn records<'a>(
+    s: Section,
+    section: ParsedSection<'a>,
+    keep: &impl Fn(Section, Rtype, usize) -> bool,
+) -> Result<Vec<ParsedRecord<'a>>> {
+    parse(s, section, &mut |s, item| {
+        Ok(if keep(s, item.rtype(), item.rdlen().into()) {
+            item.into_record::<AllRecordData<_, _>>()?
+        } else {
+            None
+        })
+    })
+}
+
+// Copy the header and the question of the message into `target`, followed by the records `section` gives for each section out of the one in the message. The builder is left at the additional section, so that records like OPT and TSIG can be appended.
+pub(crate) fn rebuild_with<'a>(
+    msg: &'a Message<Bytes>,
+    target: BytesMut,
+    mut section: impl FnMut(Section, ParsedSection<'a>) -> Result<Vec<ParsedRecord<'a>>>,
+) -> Result<AdditionalBuilder<BytesMut>> {
+    let mut builder = MessageBuilder::from_target(target)?;
+    *builder.header_mut() = msg.header();
+    let mut builder = builder.question();
+    for item in msg.question() {
+        builder.push(item?)?;
+    }
+    let mut builder = builder.answer();
+    for record in section(Section::Answer, msg.answer())? {
+        builder.push(record)?;
+    }
+    let mut builder = builder.authority();
+    for record in section(Section::Authority, msg.authority())? {
+        builder.push(record)?;
+    }
+    let mut builder = builder.additional();
+    for record in section(Section::Additional, msg.additional())? {
+        builder.push(record)?;
+    }
+    Ok(builder)
+}
+
+// Rebuild the response with only the records for which `keep` holds, given the section, the type, and the RDATA length.
+fn rebuild(
+    resp: &Message<Bytes>,
+    keep: impl Fn(Section, Rtype, usize) -> bool,
+) -> Result<Message<Bytes>> {
+    rebuild_sorted(resp, keep, |_| 0)
+}
+
+// Same as `rebuild`, with the answers sorted stably by the rank of their types, lower first.
+pub(super) fn rebuild_sorted(
+    resp: &Message<Bytes>,
+    keep: impl Fn(Section, Rtype, usize) -> bool,
+    rank: impl Fn(Rtype) -> u8,
+) -> Result<Message<Bytes>> {
+    Ok(rebuild_with(resp, pool::buffer(), |s, section| {
+        let mut records = records(s, section, &keep)?;
+        if s == Section::Answer {
+            records.sort_by_key(|r| rank(r.rtype()));
+        }
+        Ok(records)
+    })?
+    .into_message())
+}
+
+/// Strip the records of the types given from all the sections of the response, e.g. `AAAA` for domains unreachable over IPv6. If `min_len` is given, only the records with RDATA of at least `min_len` bytes are stripped, e.g. oversized `TXT` records. OPT and TSIG records are always kept.
+pub fn strip_records(
+    resp: &Message<Bytes>,
+    types: &[Rtype],
+    min_len: Option<usize>,
+) -> Result<Message<Bytes>> {
+    rebuild(resp, |_, rtype, len| {
+        pseudo(rtype) || !types.contains(&rtype) || min_len.map_or(false, |min| len < min)
+    })
+}
+
+/// Strip the additional section from the response, except for the OPT and TSIG records.
+pub fn strip_additional(resp: &Message<Bytes>) -> Result<Message<Bytes>> {
+    rebuild(resp, |section, rtype, _| {
+        section != Section::Additional || pseudo(rtype)
+    })
+}
+
+/// Strip the records not needed to answer the query like `minimal-responses` of BIND: the authority section of responses answering the name, and the additional section except for the OPT and TSIG records. The authority section of negative responses is kept, whose SOA record is needed for negative caching.
+pub fn minimize(resp: &Message<Bytes>) -> Result<Message<Bytes>> {
+    let answered = resp.header_counts().ancount() > 0;
+    rebuild(resp, |section, rtype, _| match section {
+        Section::Answer => true,
+        Section::Authority => !answered,
+        Section::Additional => pseudo(rtype),
+    })
+}
+
+/// The maximum length of the response to the query over UDP: the payload size advertised by the client in its OPT record, 512 bytes without one (RFC 1035), and no more than `max_len`.
+pub fn udp_limit(query: &Message<Bytes>, max_len: u16) -> usize {
+    let advertised = query
+        .opt()
+        .map_or(512, |opt| opt.udp_payload_size().max(512));
+    advertised.min(max_len) as usize
+}
+
+/// Truncate the response to fit in `max_len` bytes. An oversized response is replaced by an empty one with the TC bit set, so that the client retries over TCP (RFC 2181). The question and the OPT record are kept.
+pub fn truncate(resp: &Message<Bytes>, max_len: usize) -> Result<Message<Bytes>> {
+    if resp.as_slice().len() <= max_len {
+        return Ok(resp.clone());
+    }
+    // TSIG signatures don't cover the truncated response
+    let truncated = rebuild(resp, |section, rtype, _| {
+        section == Section::Additional && rtype == Rtype::Opt
+    })?;
+    let mut truncated = Message::from_octets(BytesMut::from(truncated.as_slice()))?;
+    truncated.header_mut().set_tc(true);
+    Ok(Message::from_octets(truncated.into_octets().freeze())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{minimize, strip_additional, strip_records, truncate, udp_limit};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, Txt, A},
+    };
+    use std::str::FromStr;
+
+    fn resp() -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::Any)).unwrap();
+        let query = builder.into_message();
+
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap();
+        builder
+            .push((&name, 300, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+        builder
+            .push((&name, 300, Aaaa::new("::1".parse().unwrap())))
+            .unwrap();
+        builder
+            .push((&name, 300, Txt::<Bytes>::from_slice(b"short").unwrap()))
+            .unwrap();
+        builder
+            .push((&name, 300, Txt::<Bytes>::from_slice(&[b'a'; 200]).unwrap()))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder
+            .push((&name, 300, A::from_octets(1, 0, 0, 1)))
+            .unwrap();
+        builder.opt(|_| Ok(())).unwrap();
+        builder.into_message()
+    }
+
+    fn types(msg: &Message<Bytes>) -> (Vec<Rtype>, Vec<Rtype>) {
+        let rtypes = |section: domain::base::RecordSection<&'_ Bytes>| {
+            section.map(|r| r.unwrap().rtype()).collect::<Vec<_>>()
+        };
+        (
+            rtypes(msg.answer().unwrap()),
+            rtypes(msg.additional().unwrap()),
+        )
+    }
+
+    #[test]
+    fn records() {
+        let resp = resp();
+        let stripped = strip_records(&resp, &[Rtype::Aaaa, Rtype::Opt], None).unwrap();
+        assert_eq!(
+            types(&stripped),
+            (
+                vec![Rtype::A, Rtype::Txt, Rtype::Txt],
+                vec![Rtype::A, Rtype::Opt]
+            )
+        );
+        assert_eq!(stripped.header().id(), resp.header().id());
+
+        // Only the long TXT record is stripped
+        let stripped = strip_records(&resp, &[Rtype::Txt], Some(100)).unwrap();
+        assert_eq!(types(&stripped).0, vec![Rtype::A, Rtype::Aaaa, Rtype::Txt]);
+    }
+
+    #[test]
+    fn minimal() {
+        let resp = resp();
+        let minimal = minimize(&resp).unwrap();
+        assert_eq!(
+            types(&minimal),
+            (
+                vec![Rtype::A, Rtype::Aaaa, Rtype::Txt, Rtype::Txt],
+                vec![Rtype::Opt]
+            )
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let resp = resp();
+        let len = resp.as_slice().len();
+        assert_eq!(truncate(&resp, len).unwrap().as_slice(), resp.as_slice());
+
+        let truncated = truncate(&resp, len - 1).unwrap();
+        assert!(truncated.header().tc());
+        assert_eq!(truncated.header().id(), resp.header().id());
+        assert_eq!(types(&truncated), (vec![], vec![Rtype::Opt]));
+        assert_eq!(
+            truncated.first_question().unwrap().qname(),
+            resp.first_question().unwrap().qname()
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let query = |size: Option<u16>| {
+            let mut builder = MessageBuilder::from_target(BytesMut::new())
+                .unwrap()
+                .question();
+            builder
+                .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+                .unwrap();
+            let mut builder = builder.additional();
+            if let Some(size) = size {
+                builder
+                    .opt(|opt| {
+                        opt.set_udp_payload_size(size);
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+            builder.into_message()
+        };
+        assert_eq!(udp_limit(&query(None), 4096), 512);
+        assert_eq!(udp_limit(&query(Some(1232)), 4096), 1232);
+        assert_eq!(udp_limit(&query(Some(1232)), 1000), 1000);
+        // Sizes below 512 are taken as 512
+        assert_eq!(udp_limit(&query(Some(100)), 4096), 512);
+    }
+
+    #[test]
+    fn additional() {
+        let stripped = strip_additional(&resp()).unwrap();
+        assert_eq!(
+            types(&stripped),
+            (
+                vec![Rtype::A, Rtype::Aaaa, Rtype::Txt, Rtype::Txt],
+                vec![Rtype::Opt]
+            )
+        );
+    }
+}
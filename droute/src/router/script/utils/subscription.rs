@@ -0,0 +1,342 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Domain lists downloaded from a URL and updated on schedule.
+
+use super::{Domain, DomainList, Result, UtilsError};
+#[cfg(feature = "checksum")]
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{DirBuilder, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+// Update the lists once a day
+const fn default_refresh() -> u64 {
+    86400
+}
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The format of the list downloaded
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    /// One domain per line
+    Domains,
+    /// Hosts file, e.g. `0.0.0.0 ads.example`
+    Hosts,
+    /// Domain rules of Adblock Plus filters, e.g. `||ads.example^`. Other rules are ignored.
+    Adblock,
+}
+
+impl Default for ListFormat {
+    fn default() -> Self {
+        Self::Domains
+    }
+}
+
+impl ListFormat {
+    // Convert the list into one domain per line
+    fn normalize(self, list: &str) -> Vec<&str> {
+        let lines = list.lines().map(|l| match l.find('#') {
+            Some(i) => l[..i].trim(),
+            None => l.trim(),
+        });
+        match self {
+            Self::Domains => lines.filter(|l| !l.is_empty()).collect(),
+            Self::Hosts => lines
+                .flat_map(|l| l.split_whitespace().skip(1))
+                .filter(|d| {
+                    !matches!(
+                        *d,
+                        "localhost" | "localhost.localdomain" | "local" | "broadcasthost"
+                    )
+                })
+                .collect(),
+            Self::Adblock => list
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix("||"))
+                .filter_map(|l| l.split(|c| c == '^' || c == '$').next())
+                // Rules with paths or wildcards are not about a domain
+                .filter(|d| !d.is_empty() && !d.contains(|c| c == '/' || c == '*'))
+                .collect(),
+        }
+    }
+}
+
+/// A list of domains subscribed from a URL
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub struct SubscriptionBuilder {
+    /// The URL the list is downloaded from
+    pub url: String,
+    /// The format of the list
+    #[serde(default)]
+    pub format: ListFormat,
+    /// Interval in seconds between two updates
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+    /// The category of the list, e.g. `ads`, which is shown in the logs
+    #[serde(default)]
+    pub category: Option<String>,
+    /// The URL of the SHA256 checksum of the list, e.g. `https://example.com/list.txt.sha256`. The list is rejected if it doesn't match.
+    #[serde(default)]
+    pub sha256_url: Option<String>,
+    /// The file the list is cached in, which is used if the list can't be downloaded on start. Defaults to `cache_path(name)`.
+    #[serde(default)]
+    pub cache: Option<PathBuf>,
+}
+
+impl SubscriptionBuilder {
+    /// Create a subscription to the list at `url` with the default options.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            format: ListFormat::default(),
+            refresh: default_refresh(),
+            category: None,
+            sha256_url: None,
+            cache: None,
+        }
+    }
+
+    /// The file the list registered under `name` is cached in if `cache` is not set, which is under the cache directory of the user, e.g. `~/.cache/dcompass/list-ads.txt`. `None` if the user has no such directory.
+    pub fn cache_path(name: &str) -> Option<PathBuf> {
+        let var = |key| {
+            std::env::var_os(key)
+                .map(PathBuf::from)
+                .filter(|p| p.is_absolute())
+        };
+        let dir = var("XDG_CACHE_HOME")
+            .or_else(|| var("HOME").map(|h| h.join(".cache")))
+            .or_else(|| var("LOCALAPPDATA"))?;
+        Some(dir.join("dcompass").join(format!("list-{}.txt", name)))
+    }
+
+    /// Download the list, falling back to the cached one if it fails, and register it as a `DomainList` under `name`, which is then updated every `refresh` seconds until another list is registered under the same name.
+    pub async fn build(self, name: &str) -> Result<Arc<DomainList>> {
+        let cache = match &self.cache {
+            Some(cache) => cache.clone(),
+            None => {
+                let cache = Self::cache_path(name)
+                    .ok_or_else(|| UtilsError::NoCacheDir(name.to_string()))?;
+                if let Some(dir) = cache.parent() {
+                    private_dir(dir)?;
+                }
+                cache
+            }
+        };
+        let sub = Subscription {
+            cache,
+            name: name.to_string(),
+            builder: self,
+        };
+        let domain = match sub.fetch().await {
+            Ok(domain) => domain,
+            Err(e) if sub.cache.exists() => {
+                log::warn!(
+                    "failed to update list `{}`: {}, using the cached one",
+                    sub.name,
+                    e
+                );
+                sub.load()?
+            }
+            Err(e) => return Err(e),
+        };
+        let list = DomainList::register(name, domain, None);
+        tokio::spawn(sub.refresh(Arc::downgrade(&list)));
+        Ok(list)
+    }
+}
+
+struct Subscription {
+    name: String,
+    builder: SubscriptionBuilder,
+    cache: PathBuf,
+}
+
+async fn get(url: &str) -> Result<String> {
+    let fetch = || async {
+        reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    };
+    fetch()
+        .await
+        .map_err(|e| UtilsError::FetchError(format!("{}: {}", url, e)))
+}
+
+// The SHA256 checksum of the content in hex.
+#[cfg(feature = "checksum")]
+fn sha256(content: &str) -> Result<String> {
+    Ok(hex::encode(digest(&SHA256, content.as_bytes())))
+}
+
+#[cfg(not(feature = "checksum"))]
+fn sha256(_: &str) -> Result<String> {
+    Err(UtilsError::NoChecksum)
+}
+
+impl Subscription {
+    // Download and verify the list, and cache it as domains one per line.
+    async fn fetch(&self) -> Result<Domain> {
+        let list = get(&self.builder.url).await?;
+        if let Some(url) = &self.builder.sha256_url {
+            let actual = sha256(&list)?;
+            let expected = get(url).await?;
+            // Checksum files may come with the file name after the checksum
+            let expected = expected.split_whitespace().next().unwrap_or_default();
+            if !expected.eq_ignore_ascii_case(&actual) {
+                return Err(UtilsError::ChecksumMismatch(self.builder.url.clone()));
+            }
+        }
+        let domains = self.builder.format.normalize(&list);
+        write(&self.cache, &domains.join("\n"))?;
+        let domain = self.load()?;
+        log::info!(
+            "list `{}`{} updated with {} domains",
+            self.name,
+            self.builder
+                .category
+                .as_ref()
+                .map(|c| format!(" ({})", c))
+                .unwrap_or_default(),
+            domains.len()
+        );
+        Ok(domain)
+    }
+
+    fn load(&self) -> Result<Domain> {
+        let mut domain = Domain::new();
+        domain.add_file(self.cache.to_string_lossy())?;
+        Ok(domain)
+    }
+
+    async fn refresh(self, list: std::sync::Weak<DomainList>) {
+        let interval = Duration::from_secs(self.builder.refresh);
+        loop {
+            tokio::time::sleep(interval).await;
+            // Stop once the list is replaced, e.g. on reloading the configuration
+            match (list.upgrade(), DomainList::get(&self.name)) {
+                (Some(l), Some(r)) if Arc::ptr_eq(&l, &r) => (),
+                _ => return,
+            }
+            match self.fetch().await {
+                Ok(domain) => {
+                    if let Some(list) = list.upgrade() {
+                        list.swap(domain)
+                    }
+                }
+                Err(e) => log::warn!("failed to update list `{}`: {}", self.name, e),
+            }
+        }
+    }
+}
+
+// Create the directory, which only the user may access, along with its parents
+fn private_dir(dir: &Path) -> Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+    Ok(())
+}
+
+// Replace the file as a whole so that it is never left half written
+fn write(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    // A stale temporary file, or a link planted in its place, is removed rather than followed
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)?
+        .write_all(content.as_bytes())?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write, ListFormat, SubscriptionBuilder};
+    use crate::utils::DomainList;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cached.txt");
+        let mut builder = SubscriptionBuilder::new("http://127.0.0.1:1/list.txt");
+        builder.cache = Some(path.clone());
+
+        // Neither reachable nor cached
+        assert!(builder.clone().build("cached").await.is_err());
+        assert!(DomainList::get("cached").is_none());
+
+        std::fs::write(&path, "ads.example\n").unwrap();
+        let list = builder.build("cached").await.unwrap();
+        assert!(list.contains(&Dname::from_str("www.ads.example").unwrap()));
+        assert!(DomainList::get("cached").is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_replaces_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, target) = (dir.path().join("list.txt"), dir.path().join("target"));
+        std::fs::write(&target, "kept").unwrap();
+        std::os::unix::fs::symlink(&target, dir.path().join("list.tmp")).unwrap();
+
+        write(&path, "ads.example").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "ads.example");
+        assert_eq!(std::fs::read_to_string(target).unwrap(), "kept");
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            ListFormat::Domains.normalize("# comment\nads.example\n\n tracker.example # inline\n"),
+            vec!["ads.example", "tracker.example"]
+        );
+        assert_eq!(
+            ListFormat::Hosts.normalize(
+                "127.0.0.1 localhost\n0.0.0.0 ads.example tracker.example\n# 0.0.0.0 ignored.example\n"
+            ),
+            vec!["ads.example", "tracker.example"]
+        );
+        assert_eq!(
+            ListFormat::Adblock.normalize(
+                "[Adblock Plus 2.0]\n! comment\n||ads.example^\n||tracker.example^$third-party\n@@||allowed.example^\n||cdn.example/ads/*\n/banner/\n"
+            ),
+            vec!["ads.example", "tracker.example"]
+        );
+    }
+}
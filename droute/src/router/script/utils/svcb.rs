@@ -0,0 +1,211 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    strip::{parse, rebuild_with},
+    Result, UtilsError,
+};
+use crate::pool;
+use bytes::Bytes;
+use domain::{
+    base::{Message, Record, Rtype, UnknownRecordData},
+    rdata::AllRecordData,
+};
+
+// RR types of SVCB and HTTPS records
+const SVCB: Rtype = Rtype::Svcb;
+const HTTPS: Rtype = Rtype::Https;
+
+// The SvcParamKey listing the keys clients must understand
+const MANDATORY: u16 = 0;
+
+/// Parse the name of an SvcParamKey as presented in zone files (RFC 9460), e.g. `ech`, `ipv6hint`, or `key65000`.
+pub fn svc_param_key(name: &str) -> Result<u16> {
+    Ok(match name {
+        "mandatory" => MANDATORY,
+        "alpn" => 1,
+        "no-default-alpn" => 2,
+        "port" => 3,
+        "ipv4hint" => 4,
+        "ech" => 5,
+        "ipv6hint" => 6,
+        _ => name
+            .strip_prefix("key")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| UtilsError::UnknownSvcParam(name.to_string()))?,
+    })
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+// Rewrite the SVCB RDATA without the params of the keys given. `None` if the RDATA is malformed.
+fn strip(data: &[u8], keys: &[u16]) -> Option<Vec<u8>> {
+    // SvcPriority, followed by TargetName, which is never compressed
+    let mut pos = 2;
+    loop {
+        let len = *data.get(pos)? as usize;
+        if len > 63 {
+            return None;
+        }
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let mut stripped = data.get(..pos)?.to_vec();
+
+    while pos < data.len() {
+        let key = read_u16(data, pos)?;
+        let len = read_u16(data, pos + 2)? as usize;
+        let value = data.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+
+        let value = match key {
+            _ if keys.contains(&key) => continue,
+            // Keys stripped must not be mandatory anymore, otherwise clients would drop the record.
+            MANDATORY => {
+                if len % 2 != 0 {
+                    return None;
+                }
+                let value: Vec<u8> = value
+                    .chunks_exact(2)
+                    .filter(|k| !keys.contains(&u16::from_be_bytes([k[0], k[1]])))
+                    .flatten()
+                    .copied()
+                    .collect();
+                if value.is_empty() {
+                    continue;
+                }
+                value
+            }
+            _ => value.to_vec(),
+        };
+        stripped.extend_from_slice(&key.to_be_bytes());
+        stripped.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        stripped.extend_from_slice(&value);
+    }
+    Some(stripped)
+}
+
+/// Strip the SvcParams of the keys given from the SVCB and HTTPS records in the response, e.g. `ech` and `ipv6hint` on networks where they break connectivity. Other records are kept as they are, and so are the malformed SVCB and HTTPS records.
+pub fn strip_svc_params(resp: &Message<Bytes>, keys: &[u16]) -> Result<Message<Bytes>> {
+    Ok(rebuild_with(resp, pool::buffer(), |s, section| {
+        parse(s, section, &mut |_, item| {
+            // Read SVCB and HTTPS records as raw RDATA to rewrite their params
+            if [SVCB, HTTPS].contains(&item.rtype()) {
+                if let Some(record) = item.clone().into_record::<UnknownRecordData<Bytes>>()? {
+                    if let Some(stripped) = strip(record.data().data(), keys) {
+                        return Ok(Some(Record::new(
+                            *record.owner(),
+                            record.class(),
+                            record.ttl(),
+                            AllRecordData::Other(UnknownRecordData::from_octets(
+                                record.rtype(),
+                                Bytes::from(stripped),
+                            )),
+                        )));
+                    }
+                }
+            }
+            Ok(item.into_record::<AllRecordData<_, _>>()?)
+        })
+    })?
+    .into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip, strip_svc_params, svc_param_key, HTTPS};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Record, UnknownRecordData};
+    use std::str::FromStr;
+
+    // `1 . alpn=h2 ech=AA ipv6hint=::1 mandatory=alpn,ech`
+    fn rdata() -> Vec<u8> {
+        let mut data = vec![0, 1, 0];
+        // mandatory
+        data.extend_from_slice(&[0, 0, 0, 4, 0, 1, 0, 5]);
+        // alpn
+        data.extend_from_slice(&[0, 1, 0, 3, 2, b'h', b'2']);
+        // ech
+        data.extend_from_slice(&[0, 5, 0, 1, 0xAA]);
+        // ipv6hint
+        data.extend_from_slice(&[0, 6, 0, 16]);
+        data.extend_from_slice(&[0; 15]);
+        data.push(1);
+        data
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(svc_param_key("ech").unwrap(), 5);
+        assert_eq!(svc_param_key("key65000").unwrap(), 65000);
+        assert!(svc_param_key("foo").is_err());
+    }
+
+    #[test]
+    fn strip_params() {
+        let stripped = strip(&rdata(), &[5, 6]).unwrap();
+        assert_eq!(
+            stripped,
+            vec![0, 1, 0, 0, 0, 0, 2, 0, 1, 0, 1, 0, 3, 2, b'h', b'2']
+        );
+        // Nothing to strip
+        assert_eq!(strip(&rdata(), &[3]).unwrap(), rdata());
+        // Truncated
+        assert!(strip(&rdata()[..10], &[5]).is_none());
+    }
+
+    #[test]
+    fn rewrite() {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, HTTPS)).unwrap();
+        let query = builder.into_message();
+
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap();
+        builder
+            .push(Record::new(
+                name,
+                domain::base::iana::Class::In,
+                300,
+                UnknownRecordData::from_octets(HTTPS, Bytes::from(rdata())),
+            ))
+            .unwrap();
+        let resp: Message<Bytes> = builder.into_message();
+
+        let stripped = strip_svc_params(&resp, &[5]).unwrap();
+        let record = stripped
+            .answer()
+            .unwrap()
+            .limit_to::<UnknownRecordData<Bytes>>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.rtype(), HTTPS);
+        assert_eq!(
+            record.data().data().as_ref(),
+            strip(&rdata(), &[5]).unwrap()
+        );
+        assert_eq!(stripped.header().id(), resp.header().id());
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{utils::IpCidr, QueryContext, Result, ScriptBackend, ScriptBuilder, ScriptError};
+use crate::{Label, Upstreams, Validatable};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+
+struct View<T> {
+    name: Label,
+    clients: IpCidr,
+    script: T,
+}
+
+/// A script backend that routes queries with the script of the first view whose clients contain the query sender, or with the default script if there is none.
+pub struct Views<T: ScriptBackend> {
+    views: Vec<View<T>>,
+    default: T,
+}
+
+impl<T: ScriptBackend> Views<T> {
+    fn select(&self, ctx: &Option<QueryContext>) -> &T {
+        ctx.as_ref()
+            .and_then(|ctx| self.views.iter().find(|v| v.clients.contains(ctx.ip)))
+            .map(|v| {
+                log::debug!("query is routed with view `{}`", v.name);
+                &v.script
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+#[async_trait]
+impl<T: ScriptBackend + Send + Sync> ScriptBackend for Views<T> {
+    async fn route(
+        &self,
+        query: Message<Bytes>,
+        ctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>> {
+        self.select(&ctx).route(query, ctx).await
+    }
+}
+
+impl<T: ScriptBackend> Validatable for Views<T> {
+    type Error = ScriptError;
+
+    fn validate(&self, _: Option<&Vec<Label>>) -> Result<()> {
+        for v in &self.views {
+            v.script.validate(None)?;
+        }
+        self.default.validate(None)
+    }
+}
+
+/// The builder for `Views`
+pub struct ViewsBuilder<S> {
+    views: Vec<(Label, IpCidr, S)>,
+    default: S,
+}
+
+impl<S> ViewsBuilder<S> {
+    /// Create a builder with the script used for queries from clients not covered by any view.
+    pub fn new(default: S) -> Self {
+        Self {
+            views: Vec::new(),
+            default,
+        }
+    }
+
+    /// Add a view named `name` applying `script` to queries from `clients`. Views are tried in the order they are added.
+    pub fn add_view(mut self, name: impl Into<Label>, clients: IpCidr, script: S) -> Self {
+        self.views.push((name.into(), clients, script));
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, T> ScriptBuilder<Views<T>> for ViewsBuilder<S>
+where
+    S: ScriptBuilder<T>,
+    T: ScriptBackend + Send + Sync,
+{
+    async fn build(self, upstreams: Upstreams) -> Result<Views<T>> {
+        let mut views = Vec::new();
+        for (name, clients, script) in self.views {
+            views.push(View {
+                name,
+                clients,
+                script: script.build(upstreams.clone()).await?,
+            });
+        }
+        Ok(Views {
+            views,
+            default: self.default.build(upstreams).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ViewsBuilder;
+    use crate::{
+        builders::{NativeScriptBuilder, UpstreamBuilder, UpstreamsBuilder},
+        errors::ScriptError,
+        mock::query,
+        utils::{blackhole, IpCidr},
+        AsyncTryInto, QueryContext, ScriptBackend, ScriptBuilder, Upstreams,
+    };
+    use bytes::Bytes;
+    use domain::base::{Message, Rtype};
+    use futures::future::{ready, Ready};
+    use std::{net::IpAddr, str::FromStr};
+
+    type Resp = Ready<Result<Message<Bytes>, ScriptError>>;
+
+    // Either echo back the query or blackhole it
+    fn script(
+        block: bool,
+    ) -> impl Fn(Upstreams, Message<Bytes>, Option<QueryContext>) -> Resp + Send + Sync {
+        move |_, query, _| {
+            ready(if block {
+                blackhole(&query).map_err(|e| e.into())
+            } else {
+                Ok(query)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn select_by_client() {
+        let mut clients = IpCidr::new();
+        clients.add_cidr("192.168.1.0/24").unwrap();
+
+        let views = ViewsBuilder::new(NativeScriptBuilder::new(script(false)))
+            .add_view("lan", clients, NativeScriptBuilder::new(script(true)))
+            .build(
+                UpstreamsBuilder::<UpstreamBuilder>::new(1)
+                    .unwrap()
+                    .async_try_into()
+                    .await
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let query = query("example.com", Rtype::A);
+
+        let blocked = |ip: &str| {
+            let ctx = QueryContext {
+                ip: IpAddr::from_str(ip).unwrap(),
+            };
+            let resp = views.route(query.clone(), Some(ctx));
+            async move { resp.await.unwrap().header().qr() }
+        };
+        assert!(blocked("192.168.1.10").await);
+        assert!(!blocked("10.0.0.1").await);
+        assert!(!views
+            .route(query.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .qr());
+    }
+}
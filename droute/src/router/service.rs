@@ -0,0 +1,107 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{script::QueryContext, Router};
+use crate::{errors::ScriptError, ScriptBackend};
+use bytes::Bytes;
+use domain::base::Message;
+use futures::future::BoxFuture;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower_service::Service;
+
+/// A cheaply cloneable handle to a `Router`, which implements `tower::Service`.
+/// The request is the query along with the optional context of the client sending it, and the response is the answer.
+pub struct RouterService<T: ScriptBackend>(Arc<Router<T>>);
+
+impl<T: ScriptBackend> RouterService<T> {
+    /// Create a service from the router.
+    pub fn new(router: Router<T>) -> Self {
+        Self(Arc::new(router))
+    }
+}
+
+impl<T: ScriptBackend> From<Arc<Router<T>>> for RouterService<T> {
+    fn from(router: Arc<Router<T>>) -> Self {
+        Self(router)
+    }
+}
+
+// `derive(Clone)` would require `T: Clone`
+impl<T: ScriptBackend> Clone for RouterService<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Service<(Message<Bytes>, Option<QueryContext>)> for RouterService<T>
+where
+    T: ScriptBackend + Send + Sync + 'static,
+{
+    type Response = Message<Bytes>;
+    type Error = ScriptError;
+    type Future = BoxFuture<'static, Result<Message<Bytes>, ScriptError>>;
+
+    // Router is always ready as the backpressure is handled by the connection pools of upstreams.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, (query, ctx): (Message<Bytes>, Option<QueryContext>)) -> Self::Future {
+        let router = self.0.clone();
+        Box::pin(async move { router.resolve(query, ctx).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RouterService;
+    use crate::{
+        builders::{NativeScriptBuilder, UpstreamBuilder, UpstreamsBuilder},
+        mock::query,
+        AsyncTryInto, QueryContext, Router, ScriptBuilder, Upstreams,
+    };
+    use bytes::Bytes;
+    use domain::base::{Message, Rtype};
+
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn call() {
+        let script = NativeScriptBuilder::new(
+            |_: Upstreams, query: Message<Bytes>, _: Option<QueryContext>| async move { Ok(query) },
+        )
+        .build(
+            UpstreamsBuilder::<UpstreamBuilder>::new(1)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut service = RouterService::new(Router::new(script).unwrap());
+
+        let query = query("example.com", Rtype::A);
+
+        let ctx = QueryContext {
+            ip: "127.0.0.1".parse().unwrap(),
+        };
+        let resp = service.call((query.clone(), Some(ctx))).await.unwrap();
+        assert_eq!(resp.as_slice(), query.as_slice());
+    }
+}
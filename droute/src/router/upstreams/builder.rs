@@ -17,12 +17,12 @@ pub use super::upstream::builder::*;
 
 use super::{
     error::{Result, UpstreamError},
-    QHandleError, Upstreams,
+    Offline, QHandleError, Upstreams,
 };
-use crate::{AsyncTryInto, Label, Upstream};
+use crate::{AsyncTryInto, CacheCapacity, Eviction, Label, Upstream};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, num::NonZeroUsize};
+use std::{collections::HashMap, num::NonZeroUsize, time::Duration};
 
 fn default_cache_size() -> NonZeroUsize {
     NonZeroUsize::new(2048).unwrap()
@@ -35,6 +35,21 @@ pub struct UpstreamsBuilder<U: AsyncTryInto<Upstream, Error = QHandleError>> {
     upstreams: HashMap<Label, U>,
     #[serde(default = "default_cache_size")]
     cache_size: NonZeroUsize,
+    /// Bound the cache by the approximate memory taken in bytes instead. `cache_size` is ignored if set.
+    #[serde(default)]
+    cache_bytes: Option<NonZeroUsize>,
+    /// The policy to evict responses once the cache is full
+    #[serde(default)]
+    cache_eviction: Eviction,
+    /// Interval in seconds to log the statistics of each upstream. No statistics are logged if not set.
+    #[serde(default)]
+    stats_interval: Option<u64>,
+    /// Start in offline mode, where queries are answered only from the cache and local zones.
+    #[serde(default)]
+    offline: bool,
+    // The switch of offline mode, which may be shared to flip it at runtime
+    #[serde(skip)]
+    offline_switch: Offline,
 }
 
 impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
@@ -43,6 +58,11 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         Self {
             upstreams: upstreams.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             cache_size,
+            cache_bytes: None,
+            cache_eviction: Eviction::default(),
+            stats_interval: None,
+            offline: false,
+            offline_switch: Offline::default(),
         }
     }
 
@@ -51,9 +71,44 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         std::num::NonZeroUsize::new(cache_size).map(|c| Self {
             upstreams: HashMap::new(),
             cache_size: c,
+            cache_bytes: None,
+            cache_eviction: Eviction::default(),
+            stats_interval: None,
+            offline: false,
+            offline_switch: Offline::default(),
         })
     }
 
+    /// Bound the cache by the approximate memory taken in bytes instead of the number of responses.
+    pub fn with_cache_bytes(mut self, bytes: NonZeroUsize) -> Self {
+        self.cache_bytes = Some(bytes);
+        self
+    }
+
+    /// Set the policy to evict responses once the cache is full.
+    pub fn with_cache_eviction(mut self, eviction: Eviction) -> Self {
+        self.cache_eviction = eviction;
+        self
+    }
+
+    /// Log the query counts, error rates, and latencies of each upstream every `interval` seconds.
+    pub fn with_stats_interval(mut self, interval: u64) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    /// Start in offline mode, where queries are answered only from the cache and local zones.
+    pub fn with_offline(mut self) -> Self {
+        self.offline = true;
+        self
+    }
+
+    /// Control offline mode with the switch given, so that it can be flipped at runtime.
+    pub fn with_offline_switch(mut self, switch: Offline) -> Self {
+        self.offline_switch = switch;
+        self
+    }
+
     /// Add an upstream builder
     pub fn add_upstream(mut self, tag: impl Into<Label>, upstream: U) -> Self {
         self.upstreams.insert(tag.into(), upstream);
@@ -73,6 +128,18 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> AsyncTryInto<Upstreams>
         for (tag, u) in self.upstreams {
             v.insert(tag, u.async_try_into().await?);
         }
-        Upstreams::new(v, self.cache_size)
+        let capacity = match self.cache_bytes {
+            Some(bytes) => CacheCapacity::Bytes(bytes),
+            None => CacheCapacity::Entries(self.cache_size),
+        };
+        if self.offline {
+            self.offline_switch.set(true);
+        }
+        let upstreams = Upstreams::with_cache(v, capacity, self.cache_eviction)?
+            .with_offline(self.offline_switch);
+        if let Some(i) = self.stats_interval {
+            upstreams.report_stats(Duration::from_secs(i));
+        }
+        Ok(upstreams)
     }
 }
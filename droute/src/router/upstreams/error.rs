@@ -14,7 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::upstream::QHandleError;
-use crate::Label;
+use crate::{errors::ErrorKind, Label};
 use std::{collections::HashSet, fmt::Debug};
 use thiserror::Error;
 
@@ -22,17 +22,20 @@ pub type Result<T> = std::result::Result<T, UpstreamError>;
 
 /// Error generated by the `upstreams` section.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum UpstreamError {
     /// Tag missing in upstream definition for either the destination of a rule or the `default_tag`
     #[error("No upstream with tag `{0}` found")]
     MissingTag(Label),
 
-    /// Hybrid definition forms a chain, which is prohibited
-    #[error("You cannot recursively define `hybrid` method. The `hybrid` method that contains the destination to be recursively called: {0}")]
+    /// Hybrid or fastest definition forms a chain, which is prohibited
+    #[error("You cannot recursively define `hybrid` or `fastest` method. The method that contains the destination to be recursively called: {0}")]
     HybridRecursion(Label),
 
-    /// There is no destinations in hybrid's destination list.
-    #[error("`hybrid` upstream method with tag `{0}` contains no upstreams to race")]
+    /// There is no destinations in hybrid's or fastest's destination list.
+    #[error(
+        "`hybrid` or `fastest` upstream method with tag `{0}` contains no upstreams to choose from"
+    )]
     EmptyHybrid(Label),
 
     /// Error forwarded from `QHandle`.
@@ -47,3 +50,17 @@ pub enum UpstreamError {
     #[error("Some of the upstreams are not used: {0:?}")]
     UnusedUpstreams(HashSet<Label>),
 }
+
+impl UpstreamError {
+    /// The general category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::QHandleError(e) => e.kind(),
+            Self::ShortBuf(_) => ErrorKind::Protocol,
+            Self::MissingTag(_)
+            | Self::HybridRecursion(_)
+            | Self::EmptyHybrid(_)
+            | Self::UnusedUpstreams(_) => ErrorKind::Config,
+        }
+    }
+}
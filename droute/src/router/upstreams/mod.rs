@@ -20,15 +20,34 @@
 pub mod builder;
 /// Module which contains the error type for the `upstreams` section.
 pub mod error;
+mod stats;
 mod upstream;
 
-use self::error::{Result, UpstreamError};
-use crate::{cache::RespCache, Label, Validatable, ValidateCell};
-use bytes::{Bytes, BytesMut};
+use self::{
+    error::{Result, UpstreamError},
+    stats::Stats,
+};
+use crate::{
+    cache::{CacheCapacity, Eviction, RecordStatus::*, RespCache},
+    pool,
+    router::script::utils::{family_query, filter_family, prefer_family, Family},
+    trace, Label, Validatable, ValidateCell,
+};
+use bytes::Bytes;
 use domain::base::Message;
-use futures::future::{select_ok, BoxFuture, FutureExt};
+use futures::future::{join_all, select, select_ok, BoxFuture, Either, FutureExt};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, num::NonZeroUsize, str::FromStr};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
 pub use upstream::*;
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -73,6 +92,24 @@ pub struct Upstreams {
     upstreams: HashMap<Label, Upstream>,
     // All the responses are cached together, however, they are seperately tagged, so there should be no contamination in place.
     cache: RespCache,
+    stats: Arc<Stats>,
+    offline: Offline,
+}
+
+/// A switch to put upstreams into offline mode, where queries are answered only from the cache and local zones. It can be flipped at runtime, and the clones share the same state.
+#[derive(Clone, Default)]
+pub struct Offline(Arc<AtomicBool>);
+
+impl Offline {
+    /// Turn offline mode on or off.
+    pub fn set(&self, offline: bool) {
+        self.0.store(offline, Ordering::Relaxed);
+    }
+
+    /// Whether offline mode is on.
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
 impl Validatable for Upstreams {
@@ -94,15 +131,37 @@ impl Validatable for Upstreams {
 impl Upstreams {
     /// Create a new `Upstreams` by passing a bunch of `Upstream`s, with their respective labels, and cache capacity.
     pub fn new(upstreams: HashMap<Label, Upstream>, cache_size: NonZeroUsize) -> Result<Self> {
+        Self::with_cache(upstreams, CacheCapacity::Entries(cache_size), Eviction::Lru)
+    }
+
+    /// Create a new `Upstreams` with the cache bounded by `capacity` and evicted with the `eviction` policy.
+    pub fn with_cache(
+        upstreams: HashMap<Label, Upstream>,
+        capacity: CacheCapacity,
+        eviction: Eviction,
+    ) -> Result<Self> {
         let u = Self {
             upstreams,
-            cache: RespCache::new(cache_size),
+            cache: RespCache::new(capacity, eviction),
+            stats: Arc::new(Stats::default()),
+            offline: Offline::default(),
         };
         // Validate on the assumption that every upstream is gonna be used.
         u.validate(Some(&u.tags()))?;
         Ok(u)
     }
 
+    /// Control offline mode with the switch given, which can be shared with others to flip it at runtime.
+    pub fn with_offline(mut self, offline: Offline) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Log query counts, error rates, and latencies of each upstream every `interval`, until all the clones of `self` are dropped.
+    pub fn report_stats(&self, interval: Duration) {
+        Stats::report(Arc::downgrade(&self.stats), interval);
+    }
+
     /// Return the tags of all the upstreams.
     pub fn tags(&self) -> Vec<Label> {
         self.upstreams.keys().cloned().collect()
@@ -114,11 +173,11 @@ impl Upstreams {
         tag: &Label,
     ) -> Result<()> {
         let (val, u) = if let Some((c, u)) = bucket.get_mut(tag) {
-            (c.val(), u.try_hybrid())
+            (c.val(), u.members())
         } else {
             return Err(UpstreamError::MissingTag(tag.clone()));
         };
-        // The following code is based on the assumption that only hybrid upstream would recurse into the next level and increment the counter
+        // The following code is based on the assumption that only hybrid-like upstreams would recurse into the next level and increment the counter
         // Therefore, if the counter for the same upstream is already one, that means recursion.
         if val < &1 {
             // We have checked that tag exists.
@@ -144,6 +203,96 @@ impl Upstreams {
         Ok(())
     }
 
+    // Measure the latencies of all the members of a fastest upstream in background.
+    fn probe(&self, fastest: Fastest) {
+        let upstreams = self.clone();
+        tokio::spawn(async move {
+            let latencies = join_all(fastest.tags().iter().map(|t| {
+                let upstreams = &upstreams;
+                async move {
+                    let start = Instant::now();
+                    upstreams
+                        .send(t, &CacheMode::Disabled, &upstream::DUMMY_QUERY)
+                        .await
+                        .ok()
+                        .map(|_| start.elapsed())
+                }
+            }))
+            .await;
+            fastest.update(latencies);
+        });
+    }
+
+    // Query both members of a split upstream at once. The domestic response is taken as soon as it arrives if it is trusted, otherwise we wait for the foreign one. Whichever succeeds is taken if the other fails.
+    async fn split(
+        &self,
+        split: &Split,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let domestic = self.send(split.domestic(), cache_mode, msg);
+        let foreign = self.send(split.foreign(), cache_mode, msg);
+        let (domestic, foreign) = match select(domestic, foreign).await {
+            Either::Left((Ok(r), _)) if split.accepts(&r) => return Ok(r),
+            Either::Left((domestic, foreign)) => (domestic, foreign.await),
+            Either::Right((foreign, domestic)) => (domestic.await, foreign),
+        };
+        match (domestic, foreign) {
+            (Ok(r), _) if split.accepts(&r) => Ok(r),
+            (_, Ok(r)) => Ok(r),
+            (Ok(r), Err(e)) => {
+                log::warn!(
+                    "foreign upstream `{}` failed: {}, taking the untrusted domestic response",
+                    split.foreign(),
+                    e
+                );
+                Ok(r)
+            }
+            (Err(_), Err(e)) => Err(e),
+        }
+    }
+
+    /// Send the query to a tagged upstream within the time budget given, which covers every member tried by the upstream.
+    pub async fn send_with_timeout(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+        budget: Duration,
+    ) -> Result<Message<Bytes>> {
+        match tokio::time::timeout(budget, self.send(tag, cache_mode, msg)).await {
+            Ok(r) => r,
+            Err(e) => Err(QHandleError::from(e).into()),
+        }
+    }
+
+    /// Send the query to a tagged upstream, preferring the addresses of `family`. Queries for the other family are sent together with the query for `family` under the same name, and are answered without any address if the name has addresses of `family`. Addresses of `family` are put first in the responses to other queries.
+    pub async fn send_prefer(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        family: Family,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let qtype = msg.first_question().map(|q| q.qtype());
+        if qtype != Some(family.other().rtype()) {
+            let resp = self.send(tag, cache_mode, msg).await?;
+            return Ok(prefer_family(&resp, family).map_err(QHandleError::from)?);
+        }
+        let sibling = family_query(msg, family).map_err(QHandleError::from)?;
+        let (resp, preferred) = futures::join!(
+            self.send(tag, cache_mode, msg),
+            self.send(tag, cache_mode, &sibling)
+        );
+        match preferred {
+            Ok(preferred) => {
+                Ok(filter_family(&resp?, &preferred, family).map_err(QHandleError::from)?)
+            }
+            // Not knowing whether the name has addresses of `family`, we keep the others.
+            Err(_) => resp,
+        }
+    }
+
     // Write out in this way to allow recursion for async functions
     /// Send the query to a tagged upstream and a given cache mode.
     pub fn send<'a>(
@@ -157,33 +306,136 @@ impl Upstreams {
                 .upstreams
                 .get(tag)
                 .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
-            let resp = if let Some(v) = u.try_hybrid() {
-                // Hybrid will never call `u.send_internal()`
-                let v = v.iter().map(|t| self.send(t, cache_mode, msg));
-                let (r, _) = select_ok(v).await?;
-                r
-            } else {
-                u.resolve(tag, &self.cache, cache_mode, msg).await?
-            };
-
-            // Set back the message ID
-            let mut resp = Message::from_octets(BytesMut::from(resp.as_slice()))?;
-            resp.header_mut().set_id(msg.header().id());
-
-            Ok(Message::from_octets(resp.into_octets().freeze())?)
+            let start = Instant::now();
+            let resp = async {
+                let resp = if let Some(v) = u.try_hybrid() {
+                    // Hybrid will never call `u.send_internal()`
+                    let v = v.iter().map(|t| self.send(t, cache_mode, msg));
+                    let (r, _) = select_ok(v).await?;
+                    r
+                } else if let Some(f) = u.try_fastest() {
+                    let (index, probe) = f.pick();
+                    // Probes would only measure the cache in offline mode
+                    if probe && !self.offline.get() {
+                        self.probe(f.clone());
+                    }
+                    match self.send(&f.tags()[index], cache_mode, msg).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!(
+                                "upstream `{}` of fastest upstream `{}` failed: {}, falling back to the others",
+                                f.tags()[index],
+                                tag,
+                                e
+                            );
+                            f.demote(index);
+                            let v: Vec<_> = f
+                                .tags()
+                                .iter()
+                                .enumerate()
+                                .filter(|(i, _)| *i != index)
+                                .map(|(_, t)| self.send(t, cache_mode, msg))
+                                .collect();
+                            if v.is_empty() {
+                                return Err(e);
+                            }
+                            let (r, _) = select_ok(v).await?;
+                            r
+                        }
+                    }
+                } else if let Some(s) = u.try_split() {
+                    self.split(s, cache_mode, msg).await?
+                } else {
+                    u.resolve(tag, &self.cache, cache_mode, self.offline.get(), msg)
+                        .await?
+                };
+                Ok::<_, UpstreamError>(resp)
+            }
+            .await;
+            self.stats
+                .record(tag, resp.as_ref().ok().map(|_| start.elapsed()));
+            with_id(resp?, msg)
         }
+        .instrument(tracing::info_span!("upstream", %tag))
         .boxed()
     }
+
+    /// Look up the response cached for the query sent to a tagged upstream, without sending it. The members of upstreams dispatching queries to others, e.g. `hybrid`, are looked up in order. Responses within the TTL are answered with `CacheMode::Standard`, and so are the expired ones with `CacheMode::Persistent`, which are refreshed in the background. Nothing is answered with `CacheMode::Disabled`.
+    pub fn lookup(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Option<Message<Bytes>>> {
+        let u = self
+            .upstreams
+            .get(tag)
+            .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
+        // Untrusted domestic responses are cached as well, which must not be answered
+        if let Some(s) = u.try_split() {
+            return Ok(match self.lookup(s.domestic(), cache_mode, msg)? {
+                Some(r) if s.accepts(&r) => Some(r),
+                _ => self.lookup(s.foreign(), cache_mode, msg)?,
+            });
+        }
+        if let Some(members) = u.members() {
+            for t in members {
+                if let Some(r) = self.lookup(t, cache_mode, msg)? {
+                    return Ok(Some(r));
+                }
+            }
+            return Ok(None);
+        }
+        let resp = match (cache_mode, self.cache.get(tag, msg)) {
+            (CacheMode::Disabled, _) | (_, None) => return Ok(None),
+            (_, Some(Alive(r))) => r,
+            // Nothing can be refreshed anyway
+            (_, Some(Expired(r))) if self.offline.get() => r,
+            (CacheMode::Persistent, Some(Expired(r))) => {
+                // Expired responses are fetched again and put into the cache with the standard policy
+                let (upstreams, tag, msg) = (self.clone(), tag.clone(), msg.clone());
+                tokio::spawn(async move {
+                    let _ = upstreams.send(&tag, &CacheMode::Standard, &msg).await;
+                });
+                r
+            }
+            (CacheMode::Standard, Some(Expired(_))) => return Ok(None),
+        };
+        trace::upstream(tag);
+        with_id(resp, msg).map(Some)
+    }
+}
+
+// Set back the message ID of the query, which saves a copy if it is already the same
+fn with_id(resp: Message<Bytes>, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    if resp.header().id() == msg.header().id() {
+        return Ok(resp);
+    }
+    let mut buf = pool::buffer();
+    buf.extend_from_slice(resp.as_slice());
+    let mut resp = Message::from_octets(buf)?;
+    resp.header_mut().set_id(msg.header().id());
+
+    Ok(Message::from_octets(resp.into_octets().freeze())?)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::AsyncTryInto;
+    use crate::{errors::ErrorKind, mock::query, AsyncTryInto};
 
     use super::{
-        builder::{HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
-        UpstreamError,
+        builder::{FastestBuilder, HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
+        upstream::DUMMY_QUERY,
+        CacheMode, Offline, QHandle, QHandleError, Split, Upstream, UpstreamError, Upstreams,
     };
+    use crate::router::script::utils::{addrs, Family, IpCidr};
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, A},
+    };
+    use std::{collections::HashMap, net::IpAddr, num::NonZeroUsize, sync::Arc, time::Duration};
 
     #[tokio::test]
     async fn should_not_fail_recursion() {
@@ -198,6 +450,11 @@ mod tests {
                     max_pool_size: 32,
                     timeout: 1,
                     ratelimit: None,
+                    bind_addr: None,
+                    bind_interface: None,
+                    #[cfg(feature = "tsig")]
+                    tsig: None,
+                    max_reuse: 1,
                 }),
             )
             .add_upstream(
@@ -225,6 +482,11 @@ mod tests {
                     max_pool_size: 256,
                     timeout: 1,
                     ratelimit: None,
+                    bind_addr: None,
+                    bind_interface: None,
+                    #[cfg(feature = "tsig")]
+                    tsig: None,
+                    max_reuse: 1,
                 }),
             )
             .add_upstream(
@@ -239,6 +501,320 @@ mod tests {
             .await
             .err()
             .unwrap()
+        {
+            e @ UpstreamError::HybridRecursion(_) => assert_eq!(e.kind(), ErrorKind::Config),
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
+    #[test]
+    fn error_kind() {
+        assert_eq!(
+            UpstreamError::from(QHandleError::Throttled).kind(),
+            ErrorKind::Policy
+        );
+        assert_eq!(
+            UpstreamError::from(QHandleError::from(std::io::Error::from(
+                std::io::ErrorKind::ConnectionRefused
+            )))
+            .kind(),
+            ErrorKind::Network
+        );
+    }
+
+    // Answer every query with the address given after the delay, or fail if there is none
+    struct Fixed(Option<[u8; 4]>, Duration);
+
+    #[async_trait]
+    impl QHandle for Fixed {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+            tokio::time::sleep(self.1).await;
+            let ip = self.0.ok_or(QHandleError::Throttled)?;
+            let mut builder =
+                MessageBuilder::from_target(BytesMut::new())?.start_answer(msg, Rcode::NoError)?;
+            builder.push((
+                msg.first_question().unwrap().qname(),
+                300,
+                A::from_octets(ip[0], ip[1], ip[2], ip[3]),
+            ))?;
+            Ok(builder.into_message())
+        }
+    }
+
+    #[tokio::test]
+    async fn split() {
+        let mut cidrs = IpCidr::new();
+        cidrs.add_cidr("114.114.0.0/16").unwrap();
+        let upstreams = |domestic: Option<[u8; 4]>| {
+            Upstreams::new(
+                HashMap::from([
+                    (
+                        "domestic".into(),
+                        Upstream::Others(Arc::new(Fixed(domestic, Duration::from_millis(50)))),
+                    ),
+                    (
+                        "foreign".into(),
+                        Upstream::Others(Arc::new(Fixed(Some([8, 8, 8, 8]), Duration::ZERO))),
+                    ),
+                    (
+                        "split".into(),
+                        Upstream::Split(Split::new(
+                            "domestic".into(),
+                            "foreign".into(),
+                            cidrs.clone(),
+                        )),
+                    ),
+                ]),
+                NonZeroUsize::new(1).unwrap(),
+            )
+            .unwrap()
+        };
+        let ip = |domestic| async move {
+            let resp = upstreams(domestic)
+                .send(&"split".into(), &CacheMode::Disabled, &DUMMY_QUERY)
+                .await
+                .unwrap();
+            addrs(&resp)
+        };
+
+        // The domestic response is waited for although the foreign one comes first
+        assert_eq!(
+            ip(Some([114, 114, 114, 114])).await,
+            vec![IpAddr::from([114, 114, 114, 114])]
+        );
+        // Poisoned
+        assert_eq!(
+            ip(Some([1, 2, 3, 4])).await,
+            vec![IpAddr::from([8, 8, 8, 8])]
+        );
+        assert_eq!(ip(None).await, vec![IpAddr::from([8, 8, 8, 8])]);
+    }
+
+    #[tokio::test]
+    async fn send_with_timeout() {
+        let upstreams = Upstreams::new(
+            HashMap::from([
+                (
+                    "slow".into(),
+                    Upstream::Others(Arc::new(Fixed(Some([1, 1, 1, 1]), Duration::from_secs(60)))),
+                ),
+                (
+                    "fast".into(),
+                    Upstream::Others(Arc::new(Fixed(Some([8, 8, 8, 8]), Duration::ZERO))),
+                ),
+                ("hybrid".into(), Upstream::Hybrid(vec!["slow".into()])),
+            ]),
+            NonZeroUsize::new(8).unwrap(),
+        )
+        .unwrap();
+        let send = |tag: &'static str| {
+            let upstreams = &upstreams;
+            async move {
+                upstreams
+                    .send_with_timeout(
+                        &tag.into(),
+                        &CacheMode::Disabled,
+                        &DUMMY_QUERY,
+                        Duration::from_millis(100),
+                    )
+                    .await
+            }
+        };
+
+        let resp = send("fast").await.unwrap();
+        assert_eq!(addrs(&resp), vec![IpAddr::from([8, 8, 8, 8])]);
+        // The budget covers every member tried
+        for tag in ["slow", "hybrid"] {
+            let e = send(tag).await.err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::Network);
+        }
+    }
+
+    #[tokio::test]
+    async fn offline() {
+        let offline = Offline::default();
+        let upstreams = Upstreams::new(
+            HashMap::from([(
+                "up".into(),
+                Upstream::Others(Arc::new(Fixed(Some([8, 8, 8, 8]), Duration::ZERO))),
+            )]),
+            NonZeroUsize::new(8).unwrap(),
+        )
+        .unwrap()
+        .with_offline(offline.clone());
+        upstreams
+            .send(
+                &"up".into(),
+                &CacheMode::Standard,
+                &query("cached.example", Rtype::A),
+            )
+            .await
+            .unwrap();
+        offline.set(true);
+        // Cached responses are answered even if the cache is meant to be skipped
+        upstreams
+            .send(
+                &"up".into(),
+                &CacheMode::Disabled,
+                &query("cached.example", Rtype::A),
+            )
+            .await
+            .unwrap();
+        let e = upstreams
+            .send(
+                &"up".into(),
+                &CacheMode::Standard,
+                &query("new.example", Rtype::A),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::Policy);
+
+        offline.set(false);
+        upstreams
+            .send(
+                &"up".into(),
+                &CacheMode::Standard,
+                &query("new.example", Rtype::A),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lookup() {
+        let upstreams = Upstreams::new(
+            HashMap::from([
+                (
+                    "up".into(),
+                    Upstream::Others(Arc::new(Fixed(Some([8, 8, 8, 8]), Duration::ZERO))),
+                ),
+                ("hybrid".into(), Upstream::Hybrid(vec!["up".into()])),
+            ]),
+            NonZeroUsize::new(8).unwrap(),
+        )
+        .unwrap();
+        let query = query("example.com", Rtype::A);
+
+        assert!(upstreams
+            .lookup(&"up".into(), &CacheMode::Standard, &query)
+            .unwrap()
+            .is_none());
+        upstreams
+            .send(&"up".into(), &CacheMode::Standard, &query)
+            .await
+            .unwrap();
+        for tag in ["up", "hybrid"] {
+            let resp = upstreams
+                .lookup(&tag.into(), &CacheMode::Standard, &query)
+                .unwrap()
+                .unwrap();
+            assert_eq!(addrs(&resp), vec![IpAddr::from([8, 8, 8, 8])]);
+            assert_eq!(resp.header().id(), 42);
+        }
+        // The cache is never used with the policy
+        assert!(upstreams
+            .lookup(&"up".into(), &CacheMode::Disabled, &query)
+            .unwrap()
+            .is_none());
+        assert!(upstreams
+            .lookup(&"foo".into(), &CacheMode::Standard, &query)
+            .is_err());
+    }
+
+    // Answer `AAAA` queries with an IPv6 address, and `A` queries with an IPv4 address if the name has one
+    struct DualStack(bool);
+
+    #[async_trait]
+    impl QHandle for DualStack {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+            let question = msg.first_question().unwrap();
+            let mut builder =
+                MessageBuilder::from_target(BytesMut::new())?.start_answer(msg, Rcode::NoError)?;
+            match question.qtype() {
+                Rtype::A if self.0 => {
+                    builder.push((question.qname(), 300, A::from_octets(1, 1, 1, 1)))?
+                }
+                Rtype::Aaaa => builder.push((
+                    question.qname(),
+                    300,
+                    Aaaa::new("2001:db8::1".parse().unwrap()),
+                ))?,
+                _ => (),
+            }
+            Ok(builder.into_message())
+        }
+    }
+
+    #[tokio::test]
+    async fn prefer() {
+        let upstreams = Upstreams::new(
+            HashMap::from([
+                ("dual".into(), Upstream::Others(Arc::new(DualStack(true)))),
+                (
+                    "v6only".into(),
+                    Upstream::Others(Arc::new(DualStack(false))),
+                ),
+            ]),
+            NonZeroUsize::new(8).unwrap(),
+        )
+        .unwrap();
+        let ip = |tag: &'static str, rtype| {
+            let upstreams = &upstreams;
+            async move {
+                let resp = upstreams
+                    .send_prefer(
+                        &tag.into(),
+                        &CacheMode::Standard,
+                        Family::V4,
+                        &query("example.com", rtype),
+                    )
+                    .await
+                    .unwrap();
+                addrs(&resp)
+            }
+        };
+
+        assert!(ip("dual", Rtype::Aaaa).await.is_empty());
+        assert_eq!(ip("dual", Rtype::A).await, vec![IpAddr::from([1, 1, 1, 1])]);
+        // Names without IPv4 addresses keep resolving
+        assert_eq!(
+            ip("v6only", Rtype::Aaaa).await,
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_fastest_recursion() {
+        match UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 256,
+                    timeout: 1,
+                    ratelimit: None,
+                    bind_addr: None,
+                    bind_interface: None,
+                    #[cfg(feature = "tsig")]
+                    tsig: None,
+                    max_reuse: 1,
+                }),
+            )
+            .add_upstream(
+                "fastest",
+                UpstreamBuilder::Fastest(FastestBuilder::new().add_tag("udp").add_tag("hybrid")),
+            )
+            .add_upstream(
+                "hybrid",
+                UpstreamBuilder::Hybrid(HybridBuilder::new().add_tag("fastest")),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
         {
             UpstreamError::HybridRecursion(_) => (),
             e => panic!("Not the right error type: {}", e),
@@ -0,0 +1,150 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::Label;
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, Weak},
+    time::Duration,
+};
+
+// Maximum number of latency samples kept per upstream within an interval. Older samples are overwritten once exceeded.
+const MAX_SAMPLES: usize = 4096;
+
+#[derive(Default)]
+struct Sample {
+    queries: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+/// Summary of the queries sent to an upstream within an interval.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of queries sent
+    pub queries: u64,
+    /// Number of queries failed
+    pub errors: u64,
+    /// Median latency of the successful queries
+    pub p50: Option<Duration>,
+    /// 95th percentile latency of the successful queries
+    pub p95: Option<Duration>,
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Per-upstream query counts, errors, and latencies collected since the last report.
+#[derive(Default)]
+pub struct Stats(Mutex<BTreeMap<Label, Sample>>);
+
+impl Stats {
+    /// Record the outcome of a query sent to the upstream tagged `tag`. `latency` is `None` if the query failed.
+    pub fn record(&self, tag: &Label, latency: Option<Duration>) {
+        let mut samples = self.0.lock().unwrap();
+        let sample = samples.entry(tag.clone()).or_default();
+        sample.queries += 1;
+        match latency {
+            Some(l) if sample.latencies.len() < MAX_SAMPLES => sample.latencies.push(l),
+            Some(l) => sample.latencies[sample.queries as usize % MAX_SAMPLES] = l,
+            None => sample.errors += 1,
+        }
+    }
+
+    /// Summarize and reset the statistics collected.
+    pub fn take(&self) -> BTreeMap<Label, Summary> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+            .into_iter()
+            .map(|(tag, mut s)| {
+                s.latencies.sort_unstable();
+                let summary = Summary {
+                    queries: s.queries,
+                    errors: s.errors,
+                    p50: percentile(&s.latencies, 50),
+                    p95: percentile(&s.latencies, 95),
+                };
+                (tag, summary)
+            })
+            .collect()
+    }
+
+    /// Log the statistics every `interval` until the statistics are dropped.
+    pub fn report(stats: Weak<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let stats = match stats.upgrade() {
+                    Some(s) => s,
+                    None => break,
+                };
+                Self::log(&stats.take(), interval);
+            }
+        });
+    }
+
+    fn log(summaries: &BTreeMap<Label, Summary>, interval: Duration) {
+        for (tag, s) in summaries {
+            log::info!(
+                "upstream `{}` in the last {:?}: {} queries, {:.2}% errors, p50 {}, p95 {}",
+                tag,
+                interval,
+                s.queries,
+                s.errors as f64 * 100.0 / s.queries as f64,
+                s.p50
+                    .map_or_else(|| "n/a".to_string(), |d| format!("{:.2?}", d)),
+                s.p95
+                    .map_or_else(|| "n/a".to_string(), |d| format!("{:.2?}", d)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stats, Summary};
+    use std::time::Duration;
+
+    #[test]
+    fn summarize() {
+        let stats = Stats::default();
+        let tag = "udp".into();
+        for i in 1..=100 {
+            stats.record(&tag, Some(Duration::from_millis(i)));
+        }
+        stats.record(&tag, None);
+
+        let summaries = stats.take();
+        assert_eq!(
+            summaries[&tag],
+            Summary {
+                queries: 101,
+                errors: 1,
+                p50: Some(Duration::from_millis(50)),
+                p95: Some(Duration::from_millis(95)),
+            }
+        );
+        // Statistics are reset after taken
+        assert!(stats.take().is_empty());
+    }
+}
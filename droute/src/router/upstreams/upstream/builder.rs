@@ -13,20 +13,62 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+pub use super::pinned::HealthCheck;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use super::qhandle::https::Https;
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+pub use super::qhandle::https::{DohFormat, DohMethod};
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
-use super::qhandle::tls::Tls;
+use super::qhandle::tls::{Pipeline, Tls};
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+use super::Lazy;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls",
+    feature = "tsig"
+))]
+use super::QHandle;
 use super::{
-    qhandle::{udp::Udp, ConnPool, Result},
-    QHandleError, Upstream,
+    qhandle::{udp::Udp, BindOpts, ConnPool, Result},
+    Fastest, Pinned, QHandleError, Split, Upstream, Zone,
 };
-use crate::{AsyncTryInto, Label};
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+use crate::padding::{Padded, Padding};
+#[cfg(feature = "tsig")]
+use crate::tsig::{KeyBuilder as TsigKeyBuilder, Signed};
+use crate::{router::script::utils::IpCidr, AsyncTryInto, Label};
 use async_trait::async_trait;
+use domain::base::Dname;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+use futures::future::{BoxFuture, FutureExt};
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
-use std::net::IpAddr;
-use std::{net::SocketAddr, num::NonZeroU32, sync::Arc, time::Duration};
+use std::collections::HashMap;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 // Default value for timeout
 const fn default_timeout() -> u64 {
@@ -47,6 +89,34 @@ const fn default_udp_max_pool_size() -> usize {
     43
 }
 
+const fn default_udp_max_reuse() -> usize {
+    1
+}
+
+// Probe the latencies of the members of fastest upstream every 5 minutes
+const fn default_fastest_interval() -> u64 {
+    300
+}
+
+// Switch to another member only if it is faster by at least 20 milliseconds
+const fn default_fastest_tolerance() -> u64 {
+    20
+}
+
+// Check the pinned addresses every 10 seconds, failing those not passing within 2 seconds
+const fn default_pinned_interval() -> u64 {
+    10
+}
+
+const fn default_pinned_timeout() -> u64 {
+    2
+}
+
+// Keep the TTL short so that clients follow the failover quickly
+const fn default_pinned_ttl() -> u32 {
+    10
+}
+
 // We do cache TLS connections. However, they expire quite soon.
 // Therefore, pool size is not of problems.
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -71,6 +141,45 @@ const fn default_https_max_pool_size() -> usize {
     1024
 }
 
+// Wrap the query handle to sign the queries if a TSIG key is given
+#[cfg(feature = "tsig")]
+fn signed(inner: Arc<dyn QHandle>, tsig: Option<TsigKeyBuilder>) -> Result<Arc<dyn QHandle>> {
+    Ok(match tsig {
+        Some(key) => Arc::new(Signed::new(inner, key.build()?)),
+        None => inner,
+    })
+}
+
+// Wrap the query handle to pad the queries if a padding policy is given. Queries are padded before signed so that signatures stay valid.
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+fn padded(inner: Arc<dyn QHandle>, padding: Option<Padding>) -> Arc<dyn QHandle> {
+    match padding {
+        Some(padding) => Arc::new(Padded::new(inner, padding)),
+        None => inner,
+    }
+}
+
+// Build the query handle in the background with a fresh copy of the builder on every attempt
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+#[allow(clippy::type_complexity)]
+fn lazy<B: Clone + Send + Sync + 'static>(
+    name: String,
+    builder: B,
+    build: fn(B) -> BoxFuture<'static, Result<Arc<dyn QHandle>>>,
+) -> Arc<dyn QHandle> {
+    Arc::new(Lazy::new(name, Box::new(move || build(builder.clone()))))
+}
+
 /// A builder for hybrid upstream
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HybridBuilder(Vec<Label>);
@@ -103,6 +212,83 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
     }
 }
 
+/// A builder for fastest upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct FastestBuilder {
+    /// Tags of the upstreams to choose from
+    pub tags: Vec<Label>,
+    /// Interval in seconds between two rounds of latency probes
+    #[serde(default = "default_fastest_interval")]
+    pub interval: u64,
+    /// The time in millisecond another upstream has to be faster than the current one before we switch to it
+    #[serde(default = "default_fastest_tolerance")]
+    pub tolerance: u64,
+}
+
+impl Default for FastestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FastestBuilder {
+    /// Create an empty fastest builder with default probe interval and tolerance
+    pub fn new() -> Self {
+        Self {
+            tags: Vec::new(),
+            interval: default_fastest_interval(),
+            tolerance: default_fastest_tolerance(),
+        }
+    }
+
+    /// Add another upstream to the fastest upstream about to build
+    pub fn add_tag(mut self, tag: impl Into<Label>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for FastestBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Fastest(Fastest::new(
+            self.tags,
+            Duration::from_secs(self.interval),
+            Duration::from_millis(self.tolerance),
+        )))
+    }
+}
+
+/// A builder for split upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct SplitBuilder {
+    /// Tag of the domestic upstream, e.g. the resolver of the local ISP
+    pub domestic: Label,
+    /// Tag of the foreign upstream, e.g. an encrypted public resolver
+    pub foreign: Label,
+    /// Paths to the files of domestic IP CIDRs. The domestic response is taken if all the addresses answered are within them.
+    pub cidrs: Vec<PathBuf>,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for SplitBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let mut cidrs = IpCidr::new();
+        cidrs.add_files(&self.cidrs)?;
+        Ok(Upstream::Split(Split::new(
+            self.domestic,
+            self.foreign,
+            cidrs,
+        )))
+    }
+}
+
 /// A builder for DNS over HTTPS upstream
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 #[derive(Serialize, Deserialize, Clone)]
@@ -112,6 +298,9 @@ pub struct HttpsBuilder {
     pub uri: String,
     /// The address of the server. e.g. `1.1.1.1` for Cloudflare DNS.
     pub addr: IpAddr,
+    /// More addresses of the server, e.g. `2606:4700:4700::1111` besides `1.1.1.1`. Connections are attempted with Happy Eyeballs (RFC 8305) among all of them.
+    #[serde(default)]
+    pub extra_addrs: Vec<IpAddr>,
     /// The Proxy URL used to connect the upstream server. Supporting HTTP and SOCKS5 proxy formats.
     pub proxy: Option<String>,
     /// Timeout length
@@ -126,6 +315,36 @@ pub struct HttpsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// The local address to send queries from. e.g. `::` to force IPv6.
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    /// The network interface to send queries through, e.g. the one of a VPN. Connections are made from the address of the interface picked for `addr` (Linux only).
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// Extra HTTP headers sent along with every query. e.g. `Authorization`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Path to the PEM encoded certificate chain used for TLS client authentication
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// Path to the PEM encoded private key of `client_cert`
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// Pad the queries with EDNS(0) padding to hide their lengths
+    #[serde(default)]
+    pub padding: Option<Padding>,
+    /// The HTTP method queries are sent with
+    #[serde(default)]
+    pub method: DohMethod,
+    /// The format of queries and responses
+    #[serde(default)]
+    pub format: DohFormat,
+    /// Path to the file TLS sessions are saved to, so that connections can be resumed with abbreviated handshakes even after restarts (rustls builds only)
+    #[serde(default)]
+    pub session_cache: Option<PathBuf>,
+    /// Initialize the upstream in the background, retrying until it responds, instead of failing the router if it is unreachable on start. Queries to it fail until then.
+    #[serde(default)]
+    pub lazy: bool,
 }
 
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -134,12 +353,48 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
+        Ok(Upstream::Others(if self.lazy {
+            lazy(self.uri.clone(), self, |b| b.handle().boxed())
+        } else {
+            self.handle().await?
+        }))
+    }
+}
+
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+impl HttpsBuilder {
+    async fn handle(self) -> Result<Arc<dyn QHandle>> {
+        let client_auth = match (self.client_cert, self.client_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(QHandleError::InvalidClientCert(
+                    "`client_cert` and `client_key` must be specified together".to_string(),
+                ))
+            }
+        };
+        let pool = Arc::new(ConnPool::new(
+            Https::new(
+                self.uri,
+                std::iter::once(self.addr).chain(self.extra_addrs).collect(),
+                BindOpts {
+                    addr: self.bind_addr,
+                    interface: self.bind_interface,
+                },
+                self.proxy,
+                self.sni,
+                self.headers,
+                client_auth,
+                self.method,
+                self.format,
+                self.session_cache,
+            )
+            .await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
             self.ratelimit.into(),
-        )?)))
+        )?);
+        Ok(padded(pool, self.padding))
     }
 }
 
@@ -152,6 +407,9 @@ pub struct TlsBuilder {
     pub domain: String,
     /// The address of the server. e.g. `1.1.1.1:853` for Cloudflare DNS.
     pub addr: SocketAddr,
+    /// More addresses of the server on the same port, e.g. `2606:4700:4700::1111` besides `1.1.1.1`. Connections are attempted with Happy Eyeballs (RFC 8305) among all of them.
+    #[serde(default)]
+    pub extra_addrs: Vec<IpAddr>,
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
@@ -170,6 +428,28 @@ pub struct TlsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// The local address to send queries from. e.g. `::` to force IPv6.
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    /// The network interface to send queries from (Linux only). e.g. `eth0`
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// Sign queries with the TSIG key and verify the responses.
+    #[cfg(feature = "tsig")]
+    #[serde(default)]
+    pub tsig: Option<TsigKeyBuilder>,
+    /// Pad the queries with EDNS(0) padding to hide their lengths
+    #[serde(default)]
+    pub padding: Option<Padding>,
+    /// Send all the queries over a single connection without waiting for the previous responses (RFC 7766), instead of one query at a time on each connection in the pool. `max_pool_size` is ignored if set.
+    #[serde(default)]
+    pub pipeline: bool,
+    /// Path to the file TLS sessions are saved to, so that connections can be resumed with abbreviated handshakes even after restarts (rustls builds only)
+    #[serde(default)]
+    pub session_cache: Option<PathBuf>,
+    /// Initialize the upstream in the background, retrying until it responds, instead of failing the router if it is unreachable on start. Queries to it fail until then.
+    #[serde(default)]
+    pub lazy: bool,
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -178,18 +458,56 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Tls::new(
-                self.domain,
-                self.addr,
-                self.sni,
-                self.reuse_timeout,
+        Ok(Upstream::Others(if self.lazy {
+            lazy(self.domain.clone(), self, |b| {
+                async move { b.handle() }.boxed()
+            })
+        } else {
+            self.handle()?
+        }))
+    }
+}
+
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+impl TlsBuilder {
+    fn handle(self) -> Result<Arc<dyn QHandle>> {
+        let tls = Tls::new(
+            self.domain,
+            std::iter::once(self.addr)
+                .chain(
+                    self.extra_addrs
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip, self.addr.port())),
+                )
+                .collect(),
+            BindOpts {
+                addr: self.bind_addr,
+                interface: self.bind_interface,
+            },
+            self.sni,
+            self.reuse_timeout,
+            self.max_reuse,
+            self.session_cache,
+        )?;
+        let pool: Arc<dyn QHandle> = if self.pipeline {
+            Arc::new(Pipeline::new(
+                tls,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+                Duration::from_millis(self.reuse_timeout),
                 self.max_reuse,
-            )?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+            ))
+        } else {
+            Arc::new(ConnPool::new(
+                tls,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?)
+        };
+        #[cfg(feature = "tsig")]
+        let pool = signed(pool, self.tsig)?;
+        Ok(padded(pool, self.padding))
     }
 }
 
@@ -208,6 +526,19 @@ pub struct UdpBuilder {
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    /// The local address to send queries from. e.g. `::` to force IPv6.
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    /// The network interface to send queries from (Linux only). e.g. `eth0`
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// Sign queries with the TSIG key and verify the responses.
+    #[cfg(feature = "tsig")]
+    #[serde(default)]
+    pub tsig: Option<TsigKeyBuilder>,
+    /// Number of queries sent from a socket before it is replaced by one on a fresh random port. The default, 1, uses a new port for every query.
+    #[serde(default = "default_udp_max_reuse")]
+    pub max_reuse: usize,
 }
 
 #[async_trait(?Send)]
@@ -215,11 +546,90 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Udp::new(self.addr).await?,
+        let pool = Arc::new(ConnPool::new(
+            Udp::new(
+                self.addr,
+                BindOpts {
+                    addr: self.bind_addr,
+                    interface: self.bind_interface,
+                },
+                self.max_reuse,
+            )
+            .await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
             self.ratelimit.into(),
+        )?);
+        #[cfg(feature = "tsig")]
+        let pool = signed(pool, self.tsig)?;
+        Ok(Upstream::Others(pool))
+    }
+}
+
+/// A builder for local authoritative zone
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ZoneBuilder {
+    /// The origin of the zone. e.g. `example.com`
+    pub origin: String,
+    /// Path to the zone file in RFC 1035 master file format
+    pub path: PathBuf,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for ZoneBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let origin = Dname::from_str(&self.origin).map_err(|e| {
+            QHandleError::InvalidZone(format!("invalid origin `{}`: {}", self.origin, e))
+        })?;
+        Ok(Upstream::Others(Arc::new(
+            Zone::from_file(origin, self.path).await?,
+        )))
+    }
+}
+
+/// A builder for pinned answers with health checks
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct PinnedBuilder {
+    /// The name answered. e.g. `nas.home.arpa`
+    pub name: String,
+    /// The addresses of the name, in the order of preference
+    pub addrs: Vec<IpAddr>,
+    /// How the health of the addresses is checked
+    pub check: HealthCheck,
+    /// Interval in seconds between two rounds of health checks
+    #[serde(default = "default_pinned_interval")]
+    pub interval: u64,
+    /// Timeout in seconds of a single health check
+    #[serde(default = "default_pinned_timeout")]
+    pub timeout: u64,
+    /// TTL of the records answered
+    #[serde(default = "default_pinned_ttl")]
+    pub ttl: u32,
+    /// Only answer the first healthy address of each family instead of all the healthy ones, for primary/backup setups.
+    #[serde(default)]
+    pub failover: bool,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for PinnedBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let name = Dname::from_str(&self.name).map_err(|e| {
+            QHandleError::InvalidPinned(format!("invalid name `{}`: {}", self.name, e))
+        })?;
+        Ok(Upstream::Others(Arc::new(Pinned::new(
+            name,
+            self.addrs,
+            self.check,
+            Duration::from_secs(self.interval),
+            Duration::from_secs(self.timeout),
+            self.ttl,
+            self.failover,
         )?)))
     }
 }
@@ -230,8 +640,17 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
 pub enum UpstreamBuilder {
     /// Race various different upstreams concurrently. You can use it recursively, meaning Hybrid over (Hybrid over (DoH + UDP) + UDP) is legal.
     Hybrid(HybridBuilder),
+    /// Send queries to the upstream with the lowest latency, which is probed periodically. Unlike `Hybrid`, only one upstream is queried at a time.
+    Fastest(FastestBuilder),
+    /// Query a domestic and a foreign upstream concurrently, and take the domestic response only if it answers with domestic addresses (the ChinaDNS algorithm).
+    Split(SplitBuilder),
     /// UDP connection.
     Udp(UdpBuilder),
+    /// Local zone served authoritatively.
+    #[serde(alias = "file")]
+    Zone(ZoneBuilder),
+    /// Addresses pinned to a name, answered only while they pass the health checks.
+    Pinned(PinnedBuilder),
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     /// HTTPS connection.
     Https(HttpsBuilder),
@@ -247,8 +666,14 @@ impl AsyncTryInto<Upstream> for UpstreamBuilder {
         Ok(match self {
             Self::Hybrid(v) => v.async_try_into().await?,
 
+            Self::Fastest(f) => f.async_try_into().await?,
+
+            Self::Split(s) => s.async_try_into().await?,
+
             // UDP Upstream
             Self::Udp(u) => u.async_try_into().await?,
+            Self::Zone(z) => z.async_try_into().await?,
+            Self::Pinned(p) => p.async_try_into().await?,
 
             #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
             Self::Https(h) => h.async_try_into().await?,
@@ -260,3 +685,53 @@ impl AsyncTryInto<Upstream> for UpstreamBuilder {
 
     type Error = QHandleError;
 }
+
+#[cfg(all(test, any(feature = "doh-rustls", feature = "doh-native-tls")))]
+mod tests {
+    use super::{HttpsBuilder, QHandleError};
+    use crate::{errors::ErrorKind, AsyncTryInto};
+    use serde_json::{json, Value};
+
+    fn https(extra: Value) -> HttpsBuilder {
+        let mut builder = json!({
+            "uri": "https://cloudflare-dns.com/dns-query",
+            "addr": "1.1.1.1",
+        });
+        builder
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(builder).unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        for headers in [
+            json!({ "bad header": "value" }),
+            json!({ "X-Header": "bad\nvalue" }),
+        ] {
+            match https(json!({ "headers": headers }))
+                .async_try_into()
+                .await
+                .err()
+                .unwrap()
+            {
+                e @ QHandleError::InvalidHeader(_) => assert_eq!(e.kind(), ErrorKind::Config),
+                e => panic!("Not the right error type: {}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn incomplete_client_auth() {
+        for auth in [
+            json!({ "client_cert": "client.pem" }),
+            json!({ "client_key": "client.key" }),
+        ] {
+            match https(auth).async_try_into().await.err().unwrap() {
+                e @ QHandleError::InvalidClientCert(_) => assert_eq!(e.kind(), ErrorKind::Config),
+                e => panic!("Not the right error type: {}", e),
+            }
+        }
+    }
+}
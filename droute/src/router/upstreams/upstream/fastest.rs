@@ -0,0 +1,168 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::Label;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct State {
+    // Index of the member currently serving the normal traffic
+    current: usize,
+    // Latencies measured in the last probe round. `None` means the member failed to respond.
+    latencies: Vec<Option<Duration>>,
+    // The instant the last probe round was started
+    last_probe: Option<Instant>,
+}
+
+/// An upstream that sends the normal traffic to the member with the lowest latency, which is measured periodically.
+#[derive(Clone)]
+pub struct Fastest {
+    tags: Vec<Label>,
+    interval: Duration,
+    tolerance: Duration,
+    // Shared among all the clones of `Upstreams`
+    state: Arc<Mutex<State>>,
+}
+
+impl Fastest {
+    /// Create a new `Fastest` upstream over the given tags.
+    /// `interval` is the time between two probe rounds, `tolerance` is how much faster another member has to be before we switch to it.
+    pub fn new(tags: Vec<Label>, interval: Duration, tolerance: Duration) -> Self {
+        let len = tags.len();
+        Self {
+            tags,
+            interval,
+            tolerance,
+            state: Arc::new(Mutex::new(State {
+                current: 0,
+                latencies: vec![None; len],
+                last_probe: None,
+            })),
+        }
+    }
+
+    /// Tags of all the members.
+    pub fn tags(&self) -> &Vec<Label> {
+        &self.tags
+    }
+
+    // Get the index of the member that should serve the query, and whether a new probe round should be started.
+    pub(crate) fn pick(&self) -> (usize, bool) {
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .last_probe
+            .map(|t| t.elapsed() >= self.interval)
+            .unwrap_or(true);
+        if due {
+            // Mark it now so that concurrent queries won't start another probe round
+            state.last_probe = Some(Instant::now());
+        }
+        (state.current, due)
+    }
+
+    // Record the result of a probe round and switch to a faster member if necessary.
+    pub(crate) fn update(&self, latencies: Vec<Option<Duration>>) {
+        let mut state = self.state.lock().unwrap();
+        let best = latencies
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| l.map(|l| (i, l)))
+            .min_by_key(|(_, l)| *l);
+
+        if let Some((i, l)) = best {
+            // Hysteresis: only switch if the current one is down or the best one is significantly faster.
+            let switch = match latencies[state.current] {
+                Some(current) => l + self.tolerance < current,
+                None => true,
+            };
+            if switch && i != state.current {
+                log::info!(
+                    "switching the fastest upstream from `{}` to `{}` ({:?})",
+                    self.tags[state.current],
+                    self.tags[i],
+                    l
+                );
+                state.current = i;
+            }
+        } else {
+            log::warn!("all the members of the fastest upstream failed to respond on probe");
+        }
+        state.latencies = latencies;
+    }
+
+    // Mark the member as failed, the next query will go to the fastest member that is still alive.
+    pub(crate) fn demote(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.latencies[index] = None;
+        if state.current == index {
+            if let Some((i, _)) = state
+                .latencies
+                .iter()
+                .enumerate()
+                .filter_map(|(i, l)| l.map(|l| (i, l)))
+                .min_by_key(|(_, l)| *l)
+            {
+                state.current = i;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fastest;
+    use std::time::Duration;
+
+    fn ms(v: u64) -> Option<Duration> {
+        Some(Duration::from_millis(v))
+    }
+
+    #[test]
+    fn switch_with_hysteresis() {
+        let f = Fastest::new(
+            vec!["a".into(), "b".into(), "c".into()],
+            Duration::from_secs(60),
+            Duration::from_millis(20),
+        );
+        assert_eq!(f.pick(), (0, true));
+        // Probe round just started, no new round is due
+        assert_eq!(f.pick(), (0, false));
+
+        f.update(vec![ms(50), ms(40), None]);
+        // 40 + 20 is not less than 50, stay
+        assert_eq!(f.pick().0, 0);
+
+        f.update(vec![ms(50), ms(20), None]);
+        assert_eq!(f.pick().0, 1);
+
+        // Current one is down, switch to whatever is the fastest
+        f.update(vec![ms(50), None, ms(60)]);
+        assert_eq!(f.pick().0, 0);
+    }
+
+    #[test]
+    fn demote_current() {
+        let f = Fastest::new(
+            vec!["a".into(), "b".into()],
+            Duration::from_secs(60),
+            Duration::from_millis(20),
+        );
+        f.update(vec![ms(10), ms(50)]);
+        f.demote(0);
+        assert_eq!(f.pick().0, 1);
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    qhandle::{QHandle, QHandleError, Result},
+    DUMMY_QUERY,
+};
+use crate::errors::ErrorKind;
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use futures::future::BoxFuture;
+use once_cell::sync::OnceCell;
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+// Backoff between two initialization attempts
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The function initializing the query handle, which is called on every attempt.
+pub type Init = Box<dyn Fn() -> BoxFuture<'static, Result<Arc<dyn QHandle>>> + Send + Sync>;
+
+/// A query handle initialized in the background, retrying with exponential backoff until the upstream is reachable. Queries fail with `QHandleError::Pending` until then.
+pub struct Lazy {
+    inner: Arc<OnceCell<Arc<dyn QHandle>>>,
+    task: JoinHandle<()>,
+}
+
+impl Lazy {
+    /// Start initializing the query handle with `init`. The upstream is taken as reachable once it responds to a probe query.
+    pub fn new(name: String, init: Init) -> Self {
+        Self::with_backoff(name, init, MIN_BACKOFF)
+    }
+
+    fn with_backoff(name: String, init: Init, mut backoff: Duration) -> Self {
+        let inner = Arc::new(OnceCell::new());
+        let cell = inner.clone();
+        let task = tokio::spawn(async move {
+            let mut handle = None;
+            loop {
+                let r = match &handle {
+                    Some(h) => Ok(Arc::clone(h)),
+                    None => init().await,
+                };
+                match r {
+                    Ok(h) => match h.query(&DUMMY_QUERY).await {
+                        Ok(_) => {
+                            log::info!("upstream `{}` is ready", name);
+                            let _ = cell.set(h);
+                            return;
+                        }
+                        Err(e) => {
+                            log::warn!("upstream `{}` failed to respond: {}", name, e);
+                            handle = Some(h);
+                        }
+                    },
+                    // Retrying won't fix the configuration
+                    Err(e) if e.kind() == ErrorKind::Config => {
+                        log::error!("failed to initialize upstream `{}`: {}", name, e);
+                        return;
+                    }
+                    Err(e) => log::warn!("failed to initialize upstream `{}`: {}", name, e),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        });
+        Self { inner, task }
+    }
+}
+
+impl Drop for Lazy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl QHandle for Lazy {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.inner.get() {
+            Some(h) => h.query(msg).await,
+            None => Err(QHandleError::Pending),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lazy, QHandle, QHandleError, Result};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use domain::base::Message;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    struct Echo;
+
+    #[async_trait]
+    impl QHandle for Echo {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+            Ok(msg.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let lazy = Lazy::with_backoff(
+            "test".to_string(),
+            Box::new(move || {
+                let n = counter.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async move {
+                    if n < 2 {
+                        Err(io::Error::new(io::ErrorKind::ConnectionRefused, "unreachable").into())
+                    } else {
+                        Ok(Arc::new(Echo) as Arc<dyn QHandle>)
+                    }
+                })
+            }),
+            Duration::from_millis(10),
+        );
+
+        let query = super::DUMMY_QUERY.clone();
+        assert!(matches!(
+            lazy.query(&query).await,
+            Err(QHandleError::Pending)
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            lazy.query(&query).await.unwrap().as_slice(),
+            query.as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn config_error() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let lazy = Lazy::with_backoff(
+            "test".to_string(),
+            Box::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Err(QHandleError::InvalidZone("invalid".to_string())) })
+            }),
+            Duration::from_millis(10),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Given up at once
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            lazy.query(&super::DUMMY_QUERY).await,
+            Err(QHandleError::Pending)
+        ));
+    }
+}
@@ -14,19 +14,46 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod builder;
+mod fastest;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+mod lazy;
+mod pinned;
 mod qhandle;
+mod split;
+mod zone;
 
 use std::sync::Arc;
 
 use bytes::Bytes;
+pub use fastest::Fastest;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+pub use lazy::{Init, Lazy};
+pub use pinned::Pinned;
+pub(crate) use qhandle::DUMMY_QUERY;
 pub use qhandle::{QHandle, QHandleError};
+pub use split::Split;
+pub use zone::Zone;
 
-use super::{error::Result, CacheMode};
+use super::{
+    error::{Result, UpstreamError},
+    CacheMode,
+};
 use crate::{
     cache::{RecordStatus::*, RespCache},
-    Label,
+    trace, Label,
 };
 use domain::base::Message;
+use tracing::Instrument;
 
 /// A single upstream. Opposite to the `Upstreams`.
 #[derive(Clone)]
@@ -34,6 +61,10 @@ pub enum Upstream {
     /// Hybrid upstream type
     // We don't use HashSet because we don't need to look up
     Hybrid(Vec<Label>),
+    /// Fastest upstream type
+    Fastest(Fastest),
+    /// Split upstream type
+    Split(Split),
     /// Other upstream types, like Zone or ClientPool.
     Others(Arc<dyn QHandle>),
 }
@@ -46,26 +77,67 @@ impl Upstream {
         }
     }
 
+    pub(super) fn try_fastest(&self) -> Option<&Fastest> {
+        match &self {
+            Self::Fastest(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub(super) fn try_split(&self) -> Option<&Split> {
+        match &self {
+            Self::Split(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Tags of the upstreams this upstream dispatches queries to, if any.
+    pub(super) fn members(&self) -> Option<Vec<&Label>> {
+        match &self {
+            Self::Hybrid(v) => Some(v.iter().collect()),
+            Self::Fastest(f) => Some(f.tags().iter().collect()),
+            Self::Split(s) => Some(s.tags().iter().collect()),
+            _ => None,
+        }
+    }
+
     /// Resolve the query into a response.
     pub async fn resolve(
         &self,
         tag: &Label,
         cache: &RespCache,
         cache_mode: &CacheMode,
+        offline: bool,
         msg: &Message<Bytes>,
     ) -> Result<Message<Bytes>> {
         if let Self::Others(inner) = &self {
             log::info!("querying with upstream: {}", tag);
+            trace::upstream(tag);
+            // Only fresh responses are put into the cache, records hit are kept as they are.
+            let fetch = || async {
+                let r = inner
+                    .query(msg)
+                    .instrument(tracing::info_span!("query"))
+                    .await?;
+                cache.put(tag.clone(), msg, r.clone());
+                Ok::<_, UpstreamError>(r)
+            };
+            let lookup = || tracing::info_span!("cache").in_scope(|| cache.get(tag, msg));
             // Manage cache with caching policies
             let r = match cache_mode {
+                // Answer from the cache regardless of TTL, and never update it
+                _ if offline && !inner.is_local() => match lookup() {
+                    Some(Alive(r)) | Some(Expired(r)) => r,
+                    None => return Err(QHandleError::Offline.into()),
+                },
                 CacheMode::Disabled => inner.query(msg).await?,
-                CacheMode::Standard => match cache.get(tag, msg) {
+                CacheMode::Standard => match lookup() {
                     // Cache available within TTL constraints
                     Some(Alive(r)) => r,
                     // No cache or cache expired
-                    Some(Expired(_)) | None => inner.query(msg).await?,
+                    Some(Expired(_)) | None => fetch().await?,
                 },
-                CacheMode::Persistent => match cache.get(tag, msg) {
+                CacheMode::Persistent => match lookup() {
                     // Cache available within TTL constraints
                     Some(Alive(r)) => r,
                     Some(Expired(r)) => {
@@ -85,12 +157,9 @@ impl Upstream {
                         });
                         r
                     }
-                    None => inner.query(msg).await?,
+                    None => fetch().await?,
                 },
             };
-            if cache_mode != &CacheMode::Disabled {
-                cache.put(tag.clone(), msg, r.clone());
-            }
             log::info!("query successfully completed.");
             Ok(r)
         } else {
@@ -0,0 +1,361 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::qhandle::{QHandle, QHandleError, Result};
+use crate::pool;
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, Rtype, ToDname,
+    },
+    rdata::{Aaaa, A},
+};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinHandle,
+    time::timeout,
+};
+
+fn default_http_path() -> String {
+    "/".to_string()
+}
+
+/// How the health of the addresses is checked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// Healthy if a TCP connection to the port can be established
+    Tcp {
+        /// The port connected to
+        port: u16,
+    },
+    /// Healthy if a `GET` request to the path answers with a 2xx or 3xx status code. Only plain HTTP is supported.
+    Http {
+        /// The port connected to
+        port: u16,
+        /// The path requested. e.g. `/health`
+        #[serde(default = "default_http_path")]
+        path: String,
+        /// The `Host` header sent, default to the name answered
+        #[serde(default)]
+        host: Option<String>,
+    },
+}
+
+impl HealthCheck {
+    async fn check(&self, ip: IpAddr, host: &str) -> std::io::Result<bool> {
+        match self {
+            Self::Tcp { port } => {
+                TcpStream::connect(SocketAddr::new(ip, *port)).await?;
+                Ok(true)
+            }
+            Self::Http {
+                port,
+                path,
+                host: header,
+            } => {
+                let mut stream = TcpStream::connect(SocketAddr::new(ip, *port)).await?;
+                stream
+                    .write_all(
+                        format!(
+                            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: dcompass\r\nConnection: close\r\n\r\n",
+                            path,
+                            header.as_deref().unwrap_or(host)
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                // Only the status line is needed, e.g. `HTTP/1.1 200 OK`
+                let mut buf = [0; 32];
+                let mut len = 0;
+                while len < 12 {
+                    match stream.read(&mut buf[len..]).await? {
+                        0 => break,
+                        n => len += n,
+                    }
+                }
+                Ok(matches!(http_status(&buf[..len]), Some(200..=399)))
+            }
+        }
+    }
+}
+
+// Parse the status code out of the beginning of an HTTP/1.x response.
+fn http_status(resp: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(resp.get(..12)?).ok()?;
+    if !line.starts_with("HTTP/1.") {
+        return None;
+    }
+    line.get(9..12)?.parse().ok()
+}
+
+// The addresses answered along with their health, shared with the health checker.
+struct Targets {
+    name: Dname<Bytes>,
+    addrs: Vec<(IpAddr, AtomicBool)>,
+    ttl: u32,
+    failover: bool,
+}
+
+impl Targets {
+    // The addresses of the family `rtype` answered with. If none of the addresses is healthy, all of them are answered in the hope that some still work.
+    fn pick(&self, rtype: Rtype) -> Vec<IpAddr> {
+        let family = self.addrs.iter().filter(|(ip, _)| match rtype {
+            Rtype::A => ip.is_ipv4(),
+            Rtype::Aaaa => ip.is_ipv6(),
+            _ => false,
+        });
+        let mut addrs: Vec<IpAddr> = family
+            .clone()
+            .filter(|(_, healthy)| healthy.load(Ordering::Relaxed))
+            .map(|(ip, _)| *ip)
+            .collect();
+        if addrs.is_empty() {
+            addrs = family.map(|(ip, _)| *ip).collect();
+        }
+        if self.failover {
+            addrs.truncate(1);
+        }
+        addrs
+    }
+
+    fn answer(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let builder = MessageBuilder::from_target(pool::buffer())?;
+        let question = match query.first_question() {
+            Some(q) => q,
+            None => return Ok(builder.start_answer(query, Rcode::FormErr)?.into_message()),
+        };
+        if question.qclass() != Class::In || question.qname().to_bytes() != self.name {
+            return Ok(builder.start_answer(query, Rcode::Refused)?.into_message());
+        }
+
+        let mut builder = builder.start_answer(query, Rcode::NoError)?;
+        builder.header_mut().set_aa(true);
+        let qtype = [question.qtype()];
+        let types: &[Rtype] = match question.qtype() {
+            Rtype::Any => &[Rtype::A, Rtype::Aaaa],
+            _ => &qtype,
+        };
+        for &t in types {
+            for ip in self.pick(t) {
+                match ip {
+                    IpAddr::V4(ip) => builder.push((&self.name, self.ttl, A::new(ip)))?,
+                    IpAddr::V6(ip) => builder.push((&self.name, self.ttl, Aaaa::new(ip)))?,
+                }
+            }
+        }
+        Ok(builder.into_message())
+    }
+
+    // Check all the addresses concurrently and record their health.
+    async fn check(&self, check: &HealthCheck, limit: Duration) {
+        let host = self.name.to_string();
+        let results = join_all(
+            self.addrs
+                .iter()
+                .map(|(ip, _)| timeout(limit, check.check(*ip, &host))),
+        )
+        .await;
+        for ((ip, healthy), r) in self.addrs.iter().zip(results) {
+            let now = matches!(r, Ok(Ok(true)));
+            if healthy.swap(now, Ordering::Relaxed) != now {
+                if now {
+                    log::info!("`{}` at {} is back up", self.name, ip);
+                } else {
+                    log::warn!("`{}` at {} failed the health check", self.name, ip);
+                }
+            }
+        }
+    }
+}
+
+/// Answers pinned to a name, served only for the addresses passing the health checks which run in the background.
+pub struct Pinned {
+    targets: Arc<Targets>,
+    task: JoinHandle<()>,
+}
+
+impl Pinned {
+    /// Serve `addrs` for `name` with `ttl`, checking them with `check` every `interval`. Checks taking longer than `limit` fail. If `failover` is set, only the first healthy address of each family is answered.
+    pub fn new(
+        name: Dname<Bytes>,
+        addrs: Vec<IpAddr>,
+        check: HealthCheck,
+        interval: Duration,
+        limit: Duration,
+        ttl: u32,
+        failover: bool,
+    ) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(QHandleError::InvalidPinned(format!(
+                "no address given for `{}`",
+                name
+            )));
+        }
+        let targets = Arc::new(Targets {
+            name,
+            // Taken as healthy until checked
+            addrs: addrs
+                .into_iter()
+                .map(|ip| (ip, AtomicBool::new(true)))
+                .collect(),
+            ttl,
+            failover,
+        });
+        let checked = targets.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                checked.check(&check, limit).await;
+            }
+        });
+        Ok(Self { targets, task })
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl QHandle for Pinned {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.targets.answer(msg)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{http_status, HealthCheck, Targets};
+    use crate::mock::query;
+    use bytes::Bytes;
+    use domain::{
+        base::{iana::Rcode, Dname, Message, ParsedDname, Rtype},
+        rdata::AllRecordData,
+    };
+    use std::{
+        net::IpAddr,
+        str::FromStr,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use tokio::net::TcpListener;
+
+    fn targets(addrs: &[&str], failover: bool) -> Targets {
+        Targets {
+            name: Dname::from_str("nas.home.arpa").unwrap(),
+            addrs: addrs
+                .iter()
+                .map(|ip| (ip.parse().unwrap(), AtomicBool::new(true)))
+                .collect(),
+            ttl: 10,
+            failover,
+        }
+    }
+
+    fn addrs(resp: &Message<Bytes>) -> Vec<IpAddr> {
+        resp.answer()
+            .unwrap()
+            .limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()
+            .filter_map(|r| match r.unwrap().data() {
+                AllRecordData::A(a) => Some(IpAddr::from(a.addr())),
+                AllRecordData::Aaaa(a) => Some(IpAddr::from(a.addr())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn healthy_only() {
+        let t = targets(&["10.0.0.1", "10.0.0.2", "fd00::1"], false);
+        t.addrs[0].1.store(false, Ordering::Relaxed);
+
+        let resp = t.answer(&query("NAS.home.arpa", Rtype::A)).unwrap();
+        assert!(resp.header().aa());
+        assert_eq!(addrs(&resp), vec!["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            addrs(&t.answer(&query("nas.home.arpa", Rtype::Any)).unwrap()).len(),
+            2
+        );
+
+        // All down, answer them all anyway
+        t.addrs[1].1.store(false, Ordering::Relaxed);
+        assert_eq!(
+            addrs(&t.answer(&query("nas.home.arpa", Rtype::A)).unwrap()).len(),
+            2
+        );
+
+        // Other types and names
+        let resp = t.answer(&query("nas.home.arpa", Rtype::Mx)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+        let resp = t.answer(&query("www.home.arpa", Rtype::A)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+    }
+
+    #[test]
+    fn failover() {
+        let t = targets(&["10.0.0.1", "10.0.0.2"], true);
+        assert_eq!(
+            addrs(&t.answer(&query("nas.home.arpa", Rtype::A)).unwrap()),
+            vec!["10.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        t.addrs[0].1.store(false, Ordering::Relaxed);
+        assert_eq!(
+            addrs(&t.answer(&query("nas.home.arpa", Rtype::A)).unwrap()),
+            vec!["10.0.0.2".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Nothing listens on the port of 127.0.0.2
+        let t = targets(&["127.0.0.2", "127.0.0.1"], true);
+        t.check(&HealthCheck::Tcp { port }, Duration::from_secs(1))
+            .await;
+        assert!(!t.addrs[0].1.load(Ordering::Relaxed));
+        assert!(t.addrs[1].1.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(http_status(b"HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(http_status(b"HTTP/1.0 503 "), Some(503));
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH"), None);
+        assert_eq!(http_status(b"HTTP/1.1"), None);
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::net::UdpSocket;
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+use std::time::Duration;
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::sleep,
+};
+
+// Delay before the next connection attempt is started while the previous ones are still pending (RFC 8305 Section 5)
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Order the addresses to connect to per RFC 8305 Section 4: address families interleaved, starting with IPv6. Those not of the family of the source address are left out as they are unreachable.
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+pub(super) fn candidates(
+    bind: Option<IpAddr>,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .filter(|a| bind.map_or(true, |b| b.is_ipv6() == a.is_ipv6()))
+        .partition(|a| a.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while v6.len() + v4.len() > 0 {
+        ordered.extend(v6.next().into_iter().chain(v4.next()));
+    }
+    ordered
+}
+
+/// Options on how the local end of outgoing sockets is bound.
+#[derive(Clone, Default)]
+pub struct BindOpts {
+    /// The source address to bind to
+    pub addr: Option<IpAddr>,
+    /// The network interface to bind to (Linux only)
+    pub interface: Option<String>,
+}
+
+impl BindOpts {
+    // The local address to bind to when connecting to `remote`
+    fn local_addr(&self, remote: &SocketAddr) -> SocketAddr {
+        match (self.addr, remote) {
+            (Some(ip), _) => SocketAddr::new(ip, 0),
+            (None, SocketAddr::V4(_)) => ([0u8; 4], 0).into(),
+            (None, SocketAddr::V6(_)) => ([0u16; 8], 0).into(),
+        }
+    }
+
+    fn socket(&self, remote: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*remote), ty, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        if let Some(interface) = &self.interface {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "binding to interface `{}` is not supported on this platform",
+                    interface
+                ),
+            ));
+        }
+        socket.bind(&self.local_addr(remote).into())?;
+        Ok(socket)
+    }
+
+    // The source address the kernel picks for the connections to `remote`, e.g. the address of the interface bound to. It is for the connectors that take a source address only.
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    pub(super) fn source(&self, remote: SocketAddr) -> io::Result<IpAddr> {
+        let socket = self.socket(&remote, Type::DGRAM, Protocol::UDP)?;
+        // Nothing is sent by connecting an UDP socket
+        socket.connect(&remote.into())?;
+        socket
+            .local_addr()?
+            .as_socket()
+            .map(|a| a.ip())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not an IP socket"))
+    }
+
+    /// Create an UDP socket connected to `remote`.
+    pub async fn udp(&self, remote: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::from_std(self.socket(&remote, Type::DGRAM, Protocol::UDP)?.into())?;
+        socket.connect(remote).await?;
+        Ok(socket)
+    }
+
+    /// Create a TCP stream connected to `remote`.
+    #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+    pub async fn tcp(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        TcpSocket::from_std_stream(self.socket(&remote, Type::STREAM, Protocol::TCP)?.into())
+            .connect(remote)
+            .await
+    }
+
+    /// Create a TCP stream connected to any of `remotes` using Happy Eyeballs (RFC 8305): attempts are staggered, IPv6 first, so that a broken address family only delays the connection slightly instead of failing it.
+    #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+    pub async fn tcp_any(&self, remotes: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut remotes = candidates(self.addr, remotes.iter().copied()).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        attempts.extend(remotes.next().map(|r| self.tcp(r)));
+        let mut last_err = None;
+        while !attempts.is_empty() {
+            tokio::select! {
+                Some(res) = attempts.next() => match res {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        last_err = Some(e);
+                        // Don't wait for the delay if the attempt failed already
+                        attempts.extend(remotes.next().map(|r| self.tcp(r)));
+                    }
+                },
+                _ = sleep(ATTEMPT_DELAY), if remotes.len() > 0 => {
+                    attempts.extend(remotes.next().map(|r| self.tcp(r)));
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address of the family of the source address to connect to",
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BindOpts;
+    use socket2::{Protocol, Type};
+    use std::net::{IpAddr, SocketAddr};
+
+    #[test]
+    fn local_addr() {
+        let v4: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let v6: SocketAddr = "[2606:4700::1111]:53".parse().unwrap();
+        let bind = BindOpts::default();
+        assert_eq!(bind.local_addr(&v4), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(bind.local_addr(&v6), "[::]:0".parse().unwrap());
+
+        let bind = BindOpts {
+            addr: Some("192.0.2.1".parse().unwrap()),
+            interface: None,
+        };
+        // The source address is taken regardless of the remote
+        assert_eq!(bind.local_addr(&v6), "192.0.2.1:0".parse().unwrap());
+    }
+
+    #[test]
+    fn socket() {
+        let remote: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let bind = BindOpts {
+            addr: Some("127.0.0.1".parse().unwrap()),
+            interface: None,
+        };
+        let socket = bind.socket(&remote, Type::DGRAM, Protocol::UDP).unwrap();
+        let local = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(local.port(), 0);
+        #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+        assert_eq!(bind.source(remote).unwrap(), IpAddr::from([127, 0, 0, 1]));
+
+        // Not an address of this host
+        let bind = BindOpts {
+            addr: Some("192.0.2.1".parse().unwrap()),
+            interface: None,
+        };
+        assert!(bind.socket(&remote, Type::DGRAM, Protocol::UDP).is_err());
+
+        // Nonexistent interface
+        let bind = BindOpts {
+            addr: None,
+            interface: Some("nonexistent0".to_string()),
+        };
+        assert!(bind.socket(&remote, Type::STREAM, Protocol::TCP).is_err());
+
+        #[cfg(all(
+            target_os = "linux",
+            any(feature = "doh-rustls", feature = "doh-native-tls")
+        ))]
+        {
+            let bind = BindOpts {
+                addr: None,
+                interface: Some("lo".to_string()),
+            };
+            // Binding to devices requires CAP_NET_RAW on older kernels
+            if let Ok(ip) = bind.source(remote) {
+                assert_eq!(ip, IpAddr::from([127, 0, 0, 1]));
+            }
+        }
+    }
+
+    #[cfg(any(
+        feature = "doh-rustls",
+        feature = "doh-native-tls",
+        feature = "dot-native-tls",
+        feature = "dot-rustls"
+    ))]
+    #[test]
+    fn order() {
+        use super::candidates;
+
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:853", "1.0.0.1:853", "[2606:4700::1111]:853"]
+            .into_iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(
+            candidates(None, addrs.clone()),
+            vec![addrs[2], addrs[0], addrs[1]]
+        );
+        assert_eq!(
+            candidates(Some(IpAddr::from([0u8; 4])), addrs.clone()),
+            vec![addrs[0], addrs[1]]
+        );
+    }
+
+    #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+    #[tokio::test]
+    async fn fallback() {
+        use tokio::net::TcpListener;
+
+        // A port nobody listens on
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = BindOpts::default()
+            .tcp_any(&[closed, listener.local_addr().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert!(BindOpts::default().tcp_any(&[closed]).await.is_err());
+    }
+}
@@ -15,13 +15,21 @@
 
 #[cfg(feature = "doh-rustls")]
 mod rustls_cfgs {
+    use super::{QHandleError, Result};
     use once_cell::sync::Lazy;
-    use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+    use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore};
+    use rustls_pemfile::Item;
 
-    pub static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&false));
-    pub static CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&true));
+    pub static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> =
+        Lazy::new(|| create_client_config(false, None).unwrap());
+    pub static CLIENT_CFG: Lazy<ClientConfig> =
+        Lazy::new(|| create_client_config(true, None).unwrap());
 
-    fn create_client_config(sni: &bool) -> ClientConfig {
+    // `identity` is the PEM encoded client certificate chain and private key
+    pub fn create_client_config(
+        sni: bool,
+        identity: Option<(&[u8], &[u8])>,
+    ) -> Result<ClientConfig> {
         let mut root_store = RootCertStore::empty();
         root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
             OwnedTrustAnchor::from_subject_spki_name_constraints(
@@ -31,77 +39,216 @@ mod rustls_cfgs {
             )
         }));
 
-        let mut client_config = ClientConfig::builder()
+        let builder = ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+            .with_root_certificates(root_store);
 
-        client_config.enable_sni = *sni; // Disable SNI on need.
+        let mut client_config = if let Some((cert, key)) = identity {
+            let certs = rustls_pemfile::certs(&mut &*cert)?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            let key = rustls_pemfile::read_all(&mut &*key)?
+                .into_iter()
+                .find_map(|item| match item {
+                    Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    QHandleError::InvalidClientCert("no private key found".to_string())
+                })?;
+            builder
+                .with_single_cert(certs, key)
+                .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))?
+        } else {
+            builder.with_no_client_auth()
+        };
 
-        client_config
+        client_config.enable_sni = sni; // Disable SNI on need.
+
+        Ok(client_config)
     }
 }
 
 #[cfg(feature = "doh-native-tls")]
 mod native_tls_cfgs {
-    use native_tls::TlsConnector;
+    use super::Result;
+    use native_tls::{Identity, TlsConnector};
     use once_cell::sync::Lazy;
 
     pub static NO_SNI_CLIENT_CFG: Lazy<TlsConnector> =
         Lazy::new(|| TlsConnector::builder().use_sni(false).build().unwrap());
     pub static CLIENT_CFG: Lazy<TlsConnector> = Lazy::new(|| TlsConnector::new().unwrap());
+
+    // `identity` is the PEM encoded client certificate chain and PKCS #8 private key
+    pub fn create_client_config(
+        sni: bool,
+        identity: Option<(&[u8], &[u8])>,
+    ) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder();
+        builder.use_sni(sni);
+        if let Some((cert, key)) = identity {
+            builder.identity(Identity::from_pkcs8(cert, key)?);
+        }
+        Ok(builder.build()?)
+    }
 }
 
 #[cfg(feature = "doh-rustls")]
-use rustls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use rustls_cfgs::{create_client_config, CLIENT_CFG, NO_SNI_CLIENT_CFG};
 
 #[cfg(feature = "doh-native-tls")]
-use native_tls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use native_tls_cfgs::{create_client_config, CLIENT_CFG, NO_SNI_CLIENT_CFG};
 
-use super::{ConnInitiator, QHandle, QHandleError, Result};
+use super::{super::zone::parser, BindOpts, ConnInitiator, QHandle, QHandleError, Result};
+use crate::pool;
 use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bytes::{Bytes, BytesMut};
-use domain::base::Message;
-use reqwest::{Client, Proxy, Url};
+use domain::{
+    base::{iana::Rcode, Dname, Message, MessageBuilder, Record, Rtype},
+    rdata::AllRecordData,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE},
+    Client, Proxy, Url,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
+    collections::HashMap,
+    fmt::Write,
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
     time::Duration,
 };
 
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+
+/// The HTTP method DNS messages are sent with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DohMethod {
+    /// Send the message in the `dns` parameter, which can be cached by HTTP caches.
+    Get,
+    /// Send the message as the body.
+    Post,
+}
+
+impl Default for DohMethod {
+    fn default() -> Self {
+        Self::Post
+    }
+}
+
+/// The format of queries and responses
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DohFormat {
+    /// DNS wire format (`application/dns-message`) as specified by RFC 8484
+    Wire,
+    /// The JSON API (`application/dns-json`) provided by Google, Cloudflare and others. Queries are always sent with `GET`.
+    Json,
+}
+
+impl Default for DohFormat {
+    fn default() -> Self {
+        Self::Wire
+    }
+}
+
 /// Client instance for HTTPS connections
 #[derive(Clone)]
 pub struct Https {
-    client: PostClient,
+    client: HttpsClient,
 }
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
 impl Https {
-    /// Create a new HTTPS client creator instance. with the given remote server address.
+    /// Create a new HTTPS client creator instance with the given remote server addresses, which are raced with Happy Eyeballs.
     // We *CANNOT* reuse the client *WITH* connection pool because if the network changes, *connection* inside client pool of each client remains the same, and cloning them inevitably leads to no reconnection but using stale connections.
     // However, we are able to disable the connection pool and use the client.
     // We cannot store ClientBuilder because it is not Clone.
-    pub async fn new(uri: String, addr: IpAddr, proxy: Option<String>, sni: bool) -> Result<Self> {
+    // `client_auth` is the path to the PEM encoded client certificate chain and private key used for TLS client authentication.
+    // TLS sessions are saved to `session_cache` if given to resume connections after restarts (rustls only).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        uri: String,
+        addrs: Vec<IpAddr>,
+        bind: BindOpts,
+        proxy: Option<String>,
+        sni: bool,
+        headers: HashMap<String, String>,
+        client_auth: Option<(PathBuf, PathBuf)>,
+        method: DohMethod,
+        format: DohFormat,
+        session_cache: Option<PathBuf>,
+    ) -> Result<Self> {
         let uri = Url::from_str(&uri).map_err(|_| QHandleError::InvalidUri(uri))?;
         // Check domain validness
         let _ = uri
             .domain()
             .ok_or_else(|| QHandleError::InvalidDomain(uri.clone()))?;
 
+        let headers = headers
+            .into_iter()
+            .map(|(k, v)| {
+                Ok((
+                    HeaderName::from_str(&k).map_err(|_| QHandleError::InvalidHeader(k.clone()))?,
+                    HeaderValue::from_str(&v).map_err(|_| QHandleError::InvalidHeader(k))?,
+                ))
+            })
+            .collect::<Result<HeaderMap>>()?;
+
+        let tls_cfg = if let Some((cert, key)) = client_auth {
+            let (cert, key) = (tokio::fs::read(cert).await?, tokio::fs::read(key).await?);
+            create_client_config(sni, Some((&cert, &key)))?
+        } else if sni {
+            CLIENT_CFG.clone()
+        } else {
+            NO_SNI_CLIENT_CFG.clone()
+        };
+
+        #[cfg(feature = "doh-rustls")]
+        let tls_cfg = match session_cache {
+            Some(path) => {
+                let mut tls_cfg = tls_cfg;
+                tls_cfg.session_storage =
+                    std::sync::Arc::new(super::session::SessionCache::open(path)?);
+                tls_cfg
+            }
+            None => tls_cfg,
+        };
+        #[cfg(feature = "doh-native-tls")]
+        if session_cache.is_some() {
+            log::warn!("TLS session cache is not supported by native-tls, ignored");
+        }
+
         // This has already been checked and it is safe to unwrap
         let domain = uri.domain().unwrap();
+        let port = uri.port_or_known_default().unwrap_or(443);
+        // The HTTP connector binds to source addresses only, so the interface is bound to through its address.
+        let bind_addr = match (&bind.interface, bind.addr, addrs.first()) {
+            (Some(_), _, Some(&ip)) => Some(bind.source(SocketAddr::new(ip, port))?),
+            (_, addr, _) => addr,
+        };
+        // The HTTP connector races the two address families after trying the first address for a short while, so the order matters.
+        let addrs = super::bind::candidates(
+            bind_addr,
+            addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)),
+        );
         let client = Client::builder()
             // The port in socket addr doesn't take effect here per documentation
-            .resolve(domain, SocketAddr::new(addr, 0))
-            .use_preconfigured_tls(if sni {
-                CLIENT_CFG.clone()
-            } else {
-                NO_SNI_CLIENT_CFG.clone()
-            })
+            .resolve_to_addrs(domain, &addrs)
+            .use_preconfigured_tls(tls_cfg)
+            .default_headers(headers)
             .https_only(true)
             .user_agent(APP_USER_AGENT)
             .connect_timeout(Duration::from_secs(3))
+            .local_address(bind_addr)
             // Disable the inner connection pool
             .pool_max_idle_per_host(0);
 
@@ -113,22 +260,24 @@ impl Https {
         };
 
         Ok(Self {
-            client: PostClient(
-                client.build().map_err(|_| {
+            client: HttpsClient {
+                client: client.build().map_err(|_| {
                     std::io::Error::new(
                         std::io::ErrorKind::Other,
                         "TLS backend failed to initialize",
                     )
                 })?,
-                uri.clone(),
-            ),
+                uri,
+                method,
+                format,
+            },
         })
     }
 }
 
 #[async_trait]
 impl ConnInitiator for Https {
-    type Connection = PostClient;
+    type Connection = HttpsClient;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         Ok(self.client.clone())
@@ -140,23 +289,84 @@ impl ConnInitiator for Https {
 }
 
 #[derive(Clone)]
-pub struct PostClient(Client, Url);
+pub struct HttpsClient {
+    client: Client,
+    uri: Url,
+    method: DohMethod,
+    format: DohFormat,
+}
 
-#[async_trait]
-impl QHandle for PostClient {
-    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+type JsonRecord = Record<Dname<Bytes>, AllRecordData<Bytes, Dname<Bytes>>>;
+
+// Records in a section of the JSON response, which are parsed as if they were in a master file.
+fn json_records(section: &Value) -> Result<Vec<JsonRecord>> {
+    let invalid = || QHandleError::InvalidJson("malformed record".to_string());
+    let mut lines = String::new();
+    for r in section.as_array().into_iter().flatten() {
+        let rtype = Rtype::from_int(r["type"].as_u64().ok_or_else(invalid)? as u16);
+        // Unwrapping is fine as writing to String never fails
+        writeln!(
+            lines,
+            "{} {} IN {} {}",
+            r["name"].as_str().ok_or_else(invalid)?,
+            r["TTL"].as_u64().ok_or_else(invalid)?,
+            rtype,
+            r["data"].as_str().ok_or_else(invalid)?
+        )
+        .unwrap();
+    }
+    // Unsupported types would otherwise be warned about on every query
+    parser::parse_logging(&lines, Dname::root_bytes(), log::Level::Debug)
+        .map_err(QHandleError::InvalidJson)
+}
+
+// Build the response to the query from the JSON answer. Records of types unsupported are skipped.
+fn from_json(query: &Message<Bytes>, v: &Value) -> Result<Message<Bytes>> {
+    let rcode = v["Status"]
+        .as_u64()
+        .ok_or_else(|| QHandleError::InvalidJson("missing status".to_string()))?;
+    let mut builder = MessageBuilder::from_target(pool::buffer())?
+        .start_answer(query, Rcode::from_int(rcode as u8))?;
+    let header = builder.header_mut();
+    header.set_tc(v["TC"].as_bool().unwrap_or_default());
+    header.set_ra(v["RA"].as_bool().unwrap_or_default());
+    header.set_ad(v["AD"].as_bool().unwrap_or_default());
+    header.set_cd(v["CD"].as_bool().unwrap_or_default());
+    for record in json_records(&v["Answer"])? {
+        builder.push(record)?;
+    }
+    let mut builder = builder.authority();
+    for record in json_records(&v["Authority"])? {
+        builder.push(record)?;
+    }
+    let mut builder = builder.additional();
+    for record in json_records(&v["Additional"])? {
+        builder.push(record)?;
+    }
+    Ok(builder.into_message())
+}
+
+impl HttpsClient {
+    async fn query_wire(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         // Per RFC, the message ID should be set to 0 to better facilitate HTTPS caching.
         let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
         msg.header_mut().set_id(0);
+        let msg = msg.into_octets().freeze();
 
-        let body: reqwest::Body = msg.into_octets().freeze().into();
-        let res = self
-            .0
-            .post(self.1.clone())
-            .header("content-type", "application/dns-message")
-            .body(body)
-            .send()
-            .await?;
+        let req = match self.method {
+            DohMethod::Get => {
+                let mut uri = self.uri.clone();
+                uri.query_pairs_mut()
+                    .append_pair("dns", &URL_SAFE_NO_PAD.encode(&msg));
+                self.client.get(uri)
+            }
+            DohMethod::Post => self
+                .client
+                .post(self.uri.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(reqwest::Body::from(msg)),
+        };
+        let res = req.header(ACCEPT, DNS_MESSAGE).send().await?;
 
         if res.status().is_success() {
             let res = res.bytes().await?;
@@ -167,7 +377,77 @@ impl QHandle for PostClient {
         }
     }
 
+    async fn query_json(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let question = msg
+            .first_question()
+            .ok_or_else(|| QHandleError::InvalidJson("no question to send".to_string()))?;
+        let mut uri = self.uri.clone();
+        uri.query_pairs_mut()
+            .append_pair("name", &question.qname().to_string())
+            .append_pair("type", &question.qtype().to_int().to_string());
+        if msg.header().cd() {
+            uri.query_pairs_mut().append_pair("cd", "1");
+        }
+        let res = self.client.get(uri).header(ACCEPT, DNS_JSON).send().await?;
+
+        if res.status().is_success() {
+            let v: Value = serde_json::from_slice(&res.bytes().await?)
+                .map_err(|e| QHandleError::InvalidJson(e.to_string()))?;
+            from_json(msg, &v)
+        } else {
+            Err(QHandleError::FailedHttp(res.status()))
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for HttpsClient {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.format {
+            DohFormat::Wire => self.query_wire(msg).await,
+            DohFormat::Json => self.query_json(msg).await,
+        }
+    }
+
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::from_json;
+    use crate::mock::query;
+    use domain::base::{iana::Rcode, Rtype};
+    use serde_json::json;
+
+    #[test]
+    fn json() {
+        let query = query("example.com", Rtype::A);
+
+        let resp = from_json(
+            &query,
+            &json!({
+                "Status": 0,
+                "TC": false,
+                "RA": true,
+                "Question": [{ "name": "example.com.", "type": 1 }],
+                "Answer": [
+                    { "name": "example.com.", "type": 5, "TTL": 300, "data": "www.example.com." },
+                    { "name": "www.example.com.", "type": 1, "TTL": 300, "data": "93.184.216.34" },
+                    { "name": "www.example.com.", "type": 46, "TTL": 300, "data": "A 8 2 300 ..." }
+                ]
+            }),
+        )
+        .unwrap();
+        assert_eq!(resp.header().id(), 42);
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(resp.header().ra());
+        // RRSIG is skipped
+        assert_eq!(resp.header_counts().ancount(), 2);
+
+        let resp = from_json(&query, &json!({ "Status": 3 })).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert!(from_json(&query, &json!({})).is_err());
+    }
+}
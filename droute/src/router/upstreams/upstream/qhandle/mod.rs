@@ -13,15 +13,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+mod bind;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 pub mod https;
 #[cfg_attr(target_pointer_width = "64", path = "qos_governor.rs")]
 #[cfg_attr(not(target_pointer_width = "64"), path = "qos_none.rs")]
 mod qos;
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+pub mod session;
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 pub mod tls;
 pub mod udp;
 
+pub use bind::BindOpts;
+
+use crate::errors::ErrorKind;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use deadpool::{
@@ -40,10 +46,12 @@ use tokio::time::{error::Elapsed, timeout};
 const MAX_ERROR_TOLERANCE: u8 = 2;
 const WAIT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
 
-static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+// The query used to test connections and to probe the latency of upstreams.
+pub(crate) static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("example.com").unwrap();
     let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
     builder.header_mut().set_id(0);
+    builder.header_mut().set_rd(true);
     let mut builder = builder.question();
     builder.push((&name, Rtype::A)).unwrap();
     builder.into_message()
@@ -101,12 +109,18 @@ pub trait QHandle: Send + Sync {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    // Whether queries are answered locally without contacting any server, e.g. by a zone file.
+    fn is_local(&self) -> bool {
+        false
+    }
 }
 
 pub type Result<T> = std::result::Result<T, QHandleError>;
 
 /// Error related to client pools
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum QHandleError {
     /// Error forwarded from `tokio::time::error`. This indicates a timeout probably.
     #[error(transparent)]
@@ -120,34 +134,125 @@ pub enum QHandleError {
     #[error(transparent)]
     PoolRunError(#[from] managed::PoolError<std::io::Error>),
 
+    /// Failed to build the connection pool
     #[error(transparent)]
     PoolBuildError(#[from] managed::BuildError<std::io::Error>),
 
+    /// Error forwarded from the HTTP client
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
 
+    /// The URL of the DoH server is invalid
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     #[error("the URL '{0}' is invalid")]
     InvalidUri(String),
 
+    /// The URL of the DoH server doesn't contain a domain
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     #[error("the URL '{0}' doesn't contain a valid domain")]
     InvalidDomain(Url),
 
+    /// The DoH server responded with an unsuccessful HTTP status code
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     #[error("unsuccessful HTTP code: {0}")]
     FailedHttp(StatusCode),
 
-    #[cfg(any(feature = "dot-native-tls"))]
+    /// The HTTP header specified is invalid
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("the HTTP header '{0}' is invalid")]
+    InvalidHeader(String),
+
+    /// The JSON response of the DoH server is invalid
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("invalid JSON response: {0}")]
+    InvalidJson(String),
+
+    /// The TLS client certificate or key specified is invalid
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("invalid TLS client certificate: {0}")]
+    InvalidClientCert(String),
+
+    /// Error forwarded from `native-tls`
+    #[cfg(any(feature = "dot-native-tls", feature = "doh-native-tls"))]
     #[error(transparent)]
     NativeTlsError(#[from] native_tls::Error),
 
+    /// The buffer is too short
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
 
+    /// Failed to parse the message
+    #[error(transparent)]
+    ParseError(#[from] domain::base::octets::ParseError),
+
+    /// The zone file is invalid
+    #[error("invalid zone file: {0}")]
+    InvalidZone(String),
+
+    /// The pinned answers are invalid
+    #[error("invalid pinned answers: {0}")]
+    InvalidPinned(String),
+
+    /// The TSIG key is invalid
+    #[cfg(feature = "tsig")]
+    #[error("invalid TSIG key {0}")]
+    InvalidTsigKey(String),
+
+    /// The TSIG signature of the response is missing or invalid
+    #[cfg(feature = "tsig")]
+    #[error("TSIG verification failed: {0}")]
+    TsigError(String),
+
+    /// The query is throttled by the ratelimiter
     #[error("ratelimiter throttled the upstream query")]
     Throttled,
+
+    /// Failed to load the IP CIDRs
+    #[error(transparent)]
+    UtilsError(#[from] crate::router::script::utils::UtilsError),
+
+    /// Upstreams are not contacted in offline mode
+    #[error("the query is not cached and upstreams are not contacted in offline mode")]
+    Offline,
+
+    /// The upstream initialized in the background is not reachable yet
+    #[error("the upstream is not ready yet")]
+    Pending,
+}
+
+impl QHandleError {
+    /// The general category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TimeError(_) | Self::IoError(_) | Self::PoolRunError(_) | Self::Pending => {
+                ErrorKind::Network
+            }
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(e) if e.is_builder() => ErrorKind::Config,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(_) => ErrorKind::Network,
+            #[cfg(any(feature = "dot-native-tls", feature = "doh-native-tls"))]
+            Self::NativeTlsError(_) => ErrorKind::Network,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::FailedHttp(_) | Self::InvalidJson(_) => ErrorKind::Protocol,
+            Self::ShortBuf(_) | Self::ParseError(_) => ErrorKind::Protocol,
+            #[cfg(feature = "tsig")]
+            Self::TsigError(_) => ErrorKind::Protocol,
+            Self::Throttled | Self::Offline => ErrorKind::Policy,
+            Self::UtilsError(e) => e.kind(),
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::InvalidUri(_)
+            | Self::InvalidDomain(_)
+            | Self::InvalidHeader(_)
+            | Self::InvalidClientCert(_) => ErrorKind::Config,
+            Self::PoolBuildError(_) | Self::InvalidZone(_) | Self::InvalidPinned(_) => {
+                ErrorKind::Config
+            }
+            #[cfg(feature = "tsig")]
+            Self::InvalidTsigKey(_) => ErrorKind::Config,
+        }
+    }
 }
 
 // For HTTPS connections, ConnPool enables parallelism
@@ -214,4 +319,9 @@ impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    // Whether queries are answered locally without contacting any server, e.g. by a zone file.
+    fn is_local(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,185 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! TLS sessions persisted to a file, so that connections after a restart can be resumed with abbreviated handshakes.
+
+use rustls::client::StoresClientSessions;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Same as the in-memory cache of rustls
+const MAX_SESSIONS: usize = 256;
+
+// Servers hand out tickets on every handshake, so the file is written at most once in this interval.
+const WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Sessions {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    // Keys in the order they are inserted, the oldest of which is evicted first
+    order: VecDeque<Vec<u8>>,
+    dirty: bool,
+    written: Option<Instant>,
+}
+
+impl Sessions {
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if self.map.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > MAX_SESSIONS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.map.remove(&oldest);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    // Entries are length prefixed keys and values one after another.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for key in &self.order {
+            for field in [key, &self.map[key]] {
+                buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                buf.extend_from_slice(field);
+            }
+        }
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        let mut field = || {
+            let len = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+            let v = buf.get(4..4 + len)?.to_vec();
+            buf = &buf[4 + len..];
+            Some(v)
+        };
+        let mut sessions = Self::default();
+        while let Some(key) = field() {
+            sessions.insert(key, field()?);
+        }
+        sessions.dirty = false;
+        Some(sessions)
+    }
+}
+
+/// A cache of TLS sessions kept in memory and saved to a file.
+pub struct SessionCache {
+    path: PathBuf,
+    sessions: Mutex<Sessions>,
+}
+
+impl SessionCache {
+    /// Load the sessions saved at `path`. The cache starts empty if the file doesn't exist yet or is corrupted.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let sessions = match std::fs::read(&path) {
+            Ok(buf) => Sessions::decode(&buf).unwrap_or_else(|| {
+                log::warn!("TLS session cache {} is corrupted", path.display());
+                Sessions::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Sessions::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            sessions: Mutex::new(sessions),
+        })
+    }
+
+    fn save(&self, sessions: &mut Sessions) {
+        // Replace the file as a whole so that it is never left half written
+        let tmp = self.path.with_extension("tmp");
+        if let Err(e) =
+            std::fs::write(&tmp, sessions.encode()).and_then(|_| std::fs::rename(&tmp, &self.path))
+        {
+            log::warn!(
+                "failed to save TLS session cache {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        sessions.dirty = false;
+        sessions.written = Some(Instant::now());
+    }
+}
+
+impl StoresClientSessions for SessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(key, value);
+        if sessions
+            .written
+            .map_or(true, |t| t.elapsed() >= WRITE_INTERVAL)
+        {
+            self.save(&mut sessions);
+        }
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().map.get(key).cloned()
+    }
+}
+
+impl Drop for SessionCache {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.dirty {
+            self.save(&mut sessions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionCache, MAX_SESSIONS};
+    use rustls::client::StoresClientSessions;
+
+    #[test]
+    fn persist() {
+        let path = std::env::temp_dir().join(format!("dcompass-sessions-{}", std::process::id()));
+        let cache = SessionCache::open(path.clone()).unwrap();
+        for i in 0..MAX_SESSIONS + 1 {
+            assert!(cache.put(i.to_string().into_bytes(), vec![i as u8; 100]));
+        }
+        // The oldest session is evicted
+        assert_eq!(cache.get(b"0"), None);
+        drop(cache);
+
+        let cache = SessionCache::open(path.clone()).unwrap();
+        assert_eq!(cache.get(b"0"), None);
+        assert_eq!(cache.get(b"1"), Some(vec![1; 100]));
+        assert_eq!(
+            cache.get(MAX_SESSIONS.to_string().as_bytes()),
+            Some(vec![MAX_SESSIONS as u8; 100])
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted() {
+        let path = std::env::temp_dir().join(format!("dcompass-corrupted-{}", std::process::id()));
+        std::fs::write(&path, [0, 0, 0, 10, 1]).unwrap();
+        let cache = SessionCache::open(path.clone()).unwrap();
+        assert_eq!(cache.get(&[1]), None);
+        drop(cache);
+        std::fs::remove_file(path).unwrap();
+    }
+}
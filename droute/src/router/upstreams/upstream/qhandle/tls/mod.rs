@@ -17,8 +17,9 @@
 #[cfg_attr(feature = "dot-rustls", path = "rustls.rs")]
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 mod connector;
+mod pipeline;
 
-use super::{ConnInitiator, QHandle, Result};
+use super::{BindOpts, ConnInitiator, QHandle, Result};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 pub use connector::Tls;
@@ -26,6 +27,7 @@ use connector::TlsStream;
 use deadpool::managed::{self, RecycleError};
 use domain::base::Message;
 use log::debug;
+pub use pipeline::Pipeline;
 use std::time::Instant;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
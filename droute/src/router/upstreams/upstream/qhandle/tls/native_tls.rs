@@ -13,11 +13,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{BindOpts, ConnInitiator, Result};
 use async_trait::async_trait;
 use native_tls::{Protocol, TlsConnector as NativeTlsConnector};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, time::Instant};
+use std::{net::SocketAddr, path::PathBuf, time::Instant};
 use tokio::{net::TcpStream, sync::Mutex};
 use tokio_native_tls::TlsConnector;
 pub use tokio_native_tls::TlsStream;
@@ -26,28 +26,36 @@ pub use tokio_native_tls::TlsStream;
 #[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
-    addr: SocketAddr,
+    addrs: Vec<SocketAddr>,
+    bind: BindOpts,
     domain: String,
     tcp_reuse_timeout: u64,
     max_reuse_tcp_queries: usize,
 }
 
 impl Tls {
-    /// Create a new TLS connection creator instance. with the given remote server address.
+    /// Create a new TLS connection creator instance with the given remote server addresses, which are raced with Happy Eyeballs.
+    /// `session_cache` is not supported by native-tls and ignored.
     pub fn new(
         domain: String,
-        addr: SocketAddr,
+        addrs: Vec<SocketAddr>,
+        bind: BindOpts,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        session_cache: Option<PathBuf>,
     ) -> Result<Self> {
+        if session_cache.is_some() {
+            log::warn!("TLS session cache is not supported by native-tls, ignored");
+        }
         Ok(Self {
             client: NativeTlsConnector::builder()
                 .use_sni(sni)
                 .min_protocol_version(Some(Protocol::Tlsv12))
                 .build()?
                 .into(),
-            addr,
+            addrs,
+            bind,
             domain,
             tcp_reuse_timeout,
             max_reuse_tcp_queries,
@@ -55,12 +63,10 @@ impl Tls {
     }
 }
 
-#[async_trait]
-impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
-
-    async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
+impl Tls {
+    /// Establish a TLS stream to the server.
+    pub async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut stream = self.bind.tcp_any(&self.addrs).await?;
 
         // Good default as reqwest also sets this
         let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
@@ -68,15 +74,20 @@ impl ConnInitiator for Tls {
         socket.set_tcp_keepalive(&keepalive)?;
         stream = TcpStream::from_std(socket.into())?;
 
+        self.client
+            .connect(&self.domain, stream)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))
+    }
+}
+
+#[async_trait]
+impl ConnInitiator for Tls {
+    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
         Ok((
-            Mutex::new((
-                self.client
-                    .connect(&self.domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
+            Mutex::new((self.connect().await?, Instant::now(), 0)),
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
         ))
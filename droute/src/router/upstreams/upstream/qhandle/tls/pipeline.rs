@@ -0,0 +1,306 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Queries pipelined over a single TLS stream (RFC 7766 Section 6.2.1): they are sent without waiting for the responses to the previous ones, which may come back in any order and are matched by their IDs.
+
+use super::{
+    super::{qos::QosPolicy, QHandleError},
+    QHandle, Result, Tls,
+};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use domain::base::Message;
+use log::debug;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+    time::timeout,
+};
+
+// Number of frames queued to be written before the queries wait
+const WRITE_QUEUE_LEN: usize = 64;
+
+// Queries awaiting the responses, keyed by their IDs. `None` once the connection is closed.
+type Pending = Arc<StdMutex<Option<HashMap<u16, oneshot::Sender<Message<Bytes>>>>>>;
+
+fn closed() -> QHandleError {
+    io::Error::new(io::ErrorKind::ConnectionReset, "TLS connection closed").into()
+}
+
+// Read the responses and hand them to the queries waiting for them
+async fn read(mut reader: impl AsyncRead + Unpin, pending: Pending) {
+    loop {
+        let mut len = [0; 2];
+        if let Err(e) = reader.read_exact(&mut len).await {
+            debug!("pipelined TLS connection closed: {}", e);
+            break;
+        }
+        let len: usize = u16::from_be_bytes(len).into();
+        let mut buf = BytesMut::with_capacity(len);
+        buf.resize(len, 0);
+        if reader.read_exact(&mut buf).await.is_err() {
+            break;
+        }
+        // Garbage and responses to queries given up are ignored
+        if let Ok(answer) = Message::from_octets(buf.freeze()) {
+            let tx = pending
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|p| p.remove(&answer.header().id()));
+            if let Some(tx) = tx {
+                let _ = tx.send(answer);
+            }
+        }
+    }
+    // Fail the queries still waiting
+    *pending.lock().unwrap() = None;
+}
+
+// Write the queries one after another. Frames are written as a whole here, so that a query cancelled halfway can't corrupt the stream.
+async fn write(mut writer: impl AsyncWrite + Unpin, mut frames: mpsc::Receiver<Bytes>) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = async {
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        }
+        .await
+        {
+            debug!("failed to write to the pipelined TLS connection: {}", e);
+            break;
+        }
+    }
+}
+
+struct Conn {
+    frames: mpsc::Sender<Bytes>,
+    pending: Pending,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    established: Instant,
+    queries: AtomicUsize,
+}
+
+impl Conn {
+    async fn new(tls: &Tls) -> io::Result<Self> {
+        let (reader, writer) = tokio::io::split(tls.connect().await?);
+        let pending: Pending = Arc::new(StdMutex::new(Some(HashMap::new())));
+        let (tx, rx) = mpsc::channel(WRITE_QUEUE_LEN);
+        Ok(Self {
+            frames: tx,
+            reader: tokio::spawn(read(reader, pending.clone())),
+            writer: tokio::spawn(write(writer, rx)),
+            pending,
+            established: Instant::now(),
+            queries: AtomicUsize::new(0),
+        })
+    }
+
+    fn alive(&self) -> bool {
+        !self.reader.is_finished() && !self.writer.is_finished()
+    }
+}
+
+// The connection is dropped once it is replaced and all the queries on it are done.
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+// Remove the query from the pending ones if it is given up (e.g. timed out)
+struct Slot<'a> {
+    pending: &'a Pending,
+    id: u16,
+    rx: Option<oneshot::Receiver<Message<Bytes>>>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        // The ID may have been answered and taken by another query since, whose entry must be kept.
+        drop(self.rx.take());
+        if let Some(p) = self.pending.lock().unwrap().as_mut() {
+            if p.get(&self.id).map_or(false, |tx| tx.is_closed()) {
+                p.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// Client sending all the queries over a single TLS connection at once, replacing it once it is closed or has been used for too long or too many queries.
+pub struct Pipeline {
+    tls: Tls,
+    conn: Mutex<Option<Arc<Conn>>>,
+    timeout: Duration,
+    ratelimiter: QosPolicy,
+    reuse_timeout: Duration,
+    max_reuse: usize,
+}
+
+impl Pipeline {
+    /// Create a pipelined client over the connections made by `tls`.
+    pub fn new(
+        tls: Tls,
+        timeout: Duration,
+        ratelimiter: QosPolicy,
+        reuse_timeout: Duration,
+        max_reuse: usize,
+    ) -> Self {
+        Self {
+            tls,
+            conn: Mutex::new(None),
+            timeout,
+            ratelimiter,
+            reuse_timeout,
+            max_reuse,
+        }
+    }
+
+    // The connection to send the query on. Queries arriving while connecting wait for the same connection rather than making their own.
+    async fn conn(&self) -> io::Result<Arc<Conn>> {
+        let mut guard = self.conn.lock().await;
+        let reusable = matches!(&*guard, Some(c) if c.alive()
+            && c.established.elapsed() < self.reuse_timeout
+            && c.queries.load(Ordering::Relaxed) < self.max_reuse);
+        if !reusable {
+            *guard = Some(Arc::new(Conn::new(&self.tls).await?));
+        }
+        let conn = guard.clone().unwrap();
+        conn.queries.fetch_add(1, Ordering::Relaxed);
+        Ok(conn)
+    }
+
+    async fn send(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let conn = self.conn().await?;
+        let mut query = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+
+        let (tx, rx) = oneshot::channel();
+        let mut slot = {
+            let mut pending = conn.pending.lock().unwrap();
+            let pending = pending.as_mut().ok_or_else(closed)?;
+            // IDs of the queries in flight must be unique on the connection
+            let id = loop {
+                let id: u16 = rand::random();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            query.header_mut().set_id(id);
+            pending.insert(id, tx);
+            Slot {
+                pending: &conn.pending,
+                id,
+                rx: Some(rx),
+            }
+        };
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(query.as_slice().len()).expect("request too long");
+        let mut frame = BytesMut::with_capacity(query.as_slice().len() + 2);
+        frame.put_u16(len);
+        frame.extend_from_slice(query.as_slice());
+        conn.frames
+            .send(frame.freeze())
+            .await
+            .map_err(|_| closed())?;
+
+        let answer = slot.rx.as_mut().unwrap().await.map_err(|_| closed())?;
+        if !answer.is_answer(&query.for_slice()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the response doesn't answer the query",
+            )
+            .into());
+        }
+        Ok(answer)
+    }
+}
+
+#[async_trait]
+impl QHandle for Pipeline {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        if !self.ratelimiter.check() {
+            return Err(QHandleError::Throttled);
+        }
+        timeout(self.timeout, self.send(msg)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, Pending};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::{
+        collections::HashMap,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{io::AsyncWriteExt, sync::oneshot};
+
+    fn frame(id: u16) -> Vec<u8> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        builder.header_mut().set_qr(true);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let msg = builder.finish();
+        let mut frame = (msg.len() as u16).to_be_bytes().to_vec();
+        frame.extend_from_slice(&msg);
+        frame
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (tx1, rx1) = oneshot::channel();
+        let (tx2, rx2) = oneshot::channel();
+        {
+            let mut p = pending.lock().unwrap();
+            let p = p.as_mut().unwrap();
+            p.insert(1, tx1);
+            p.insert(2, tx2);
+        }
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let reader = tokio::spawn(read(client, pending.clone()));
+        // A response nobody waits for, then the responses in reverse order
+        for id in [3, 2, 1] {
+            server.write_all(&frame(id)).await.unwrap();
+        }
+        assert_eq!(rx2.await.unwrap().header().id(), 2);
+        assert_eq!(rx1.await.unwrap().header().id(), 1);
+
+        // Queries still waiting fail once the connection is closed
+        let (tx, rx) = oneshot::channel();
+        pending.lock().unwrap().as_mut().unwrap().insert(4, tx);
+        drop(server);
+        reader.await.unwrap();
+        assert!(rx.await.is_err());
+        assert!(pending.lock().unwrap().is_none());
+    }
+}
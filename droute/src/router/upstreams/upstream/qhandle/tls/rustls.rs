@@ -13,16 +13,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{super::session::SessionCache, BindOpts, ConnInitiator, Result};
 use async_trait::async_trait;
 use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
 use tokio::{net::TcpStream, sync::Mutex};
 pub use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 
-fn create_client_config(sni: &bool) -> ClientConfig {
+fn create_client_config(sni: &bool, session_cache: Option<PathBuf>) -> Result<ClientConfig> {
     let mut root_store = RootCertStore::empty();
     root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
         OwnedTrustAnchor::from_subject_spki_name_constraints(
@@ -38,32 +38,40 @@ fn create_client_config(sni: &bool) -> ClientConfig {
         .with_no_client_auth();
 
     client_config.enable_sni = *sni; // Disable SNI on need.
+    if let Some(path) = session_cache {
+        client_config.session_storage = Arc::new(SessionCache::open(path)?);
+    }
 
-    client_config
+    Ok(client_config)
 }
 
 /// Client instance for TLS connections
 #[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
-    addr: SocketAddr,
+    addrs: Vec<SocketAddr>,
+    bind: BindOpts,
     domain: String,
     tcp_reuse_timeout: u64,
     max_reuse_tcp_queries: usize,
 }
 
 impl Tls {
-    /// Create a new TLS connection creator instance. with the given remote server address.
+    /// Create a new TLS connection creator instance with the given remote server addresses, which are raced with Happy Eyeballs.
+    /// TLS sessions are saved to `session_cache` if given to resume connections after restarts.
     pub fn new(
         domain: String,
-        addr: SocketAddr,
+        addrs: Vec<SocketAddr>,
+        bind: BindOpts,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        session_cache: Option<PathBuf>,
     ) -> Result<Self> {
         Ok(Self {
-            client: TlsConnector::from(Arc::new(create_client_config(&sni))),
-            addr,
+            client: TlsConnector::from(Arc::new(create_client_config(&sni, session_cache)?)),
+            addrs,
+            bind,
             domain,
             tcp_reuse_timeout,
             max_reuse_tcp_queries,
@@ -71,12 +79,10 @@ impl Tls {
     }
 }
 
-#[async_trait]
-impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
-
-    async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
+impl Tls {
+    /// Establish a TLS stream to the server.
+    pub async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut stream = self.bind.tcp_any(&self.addrs).await?;
 
         // Good default as reqwest also sets this.
         let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
@@ -87,15 +93,20 @@ impl ConnInitiator for Tls {
         let domain = rustls::ServerName::try_from(self.domain.as_str()).map_err(|_| {
             std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname")
         })?;
+        self.client
+            .connect(domain, stream)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))
+    }
+}
+
+#[async_trait]
+impl ConnInitiator for Tls {
+    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
         Ok((
-            Mutex::new((
-                self.client
-                    .connect(domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
+            Mutex::new((self.connect().await?, Instant::now(), 0)),
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
         ))
@@ -13,36 +13,52 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::MAX_LEN;
+//! Plain DNS over UDP. Being connectionless, it is the easiest to spoof, so every query goes out with a random ID from a random port, and only responses passing [`verify`] are accepted.
 
-use super::{ConnInitiator, QHandle, Result};
+use crate::{pool, MAX_LEN};
+
+use super::{BindOpts, ConnInitiator, QHandle, Result};
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use domain::base::Message;
-use std::net::SocketAddr;
+use rand::Rng;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use tokio::net::UdpSocket;
 
 /// Client instance for UDP connections
 #[derive(Clone)]
 pub struct Udp {
     addr: SocketAddr,
+    bind: BindOpts,
+    max_reuse: usize,
 }
 
 impl Udp {
-    /// Create a new UDP client creator instance. with the given remote server address.
-    pub async fn new(addr: SocketAddr) -> Result<Self> {
-        Ok(Self { addr })
+    /// Create a new UDP client creator instance with the given remote server address. Every socket is used for at most `max_reuse` queries before it is replaced by one bound to a new port.
+    pub async fn new(addr: SocketAddr, bind: BindOpts, max_reuse: usize) -> Result<Self> {
+        Ok(Self {
+            addr,
+            bind,
+            max_reuse,
+        })
     }
 }
 
 #[async_trait]
 impl ConnInitiator for Udp {
-    type Connection = UdpSocket;
+    type Connection = UdpConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        let socket = UdpSocket::bind(bind_addr(self.addr.is_ipv4())).await?;
-        socket.connect(self.addr).await?;
-        Ok(socket)
+        // The port is picked by the OS, which randomizes ephemeral ports on all major platforms.
+        Ok(UdpConn {
+            socket: self.bind.udp(self.addr).await?,
+            addr: self.addr,
+            queries: AtomicUsize::new(0),
+            max_reuse: self.max_reuse,
+        })
     }
 
     fn conn_type(&self) -> &'static str {
@@ -50,36 +66,60 @@ impl ConnInitiator for Udp {
     }
 }
 
-fn bind_addr(is_ipv4: bool) -> SocketAddr {
-    if is_ipv4 {
-        ([0u8; 4], 0).into()
-    } else {
-        ([0u16; 8], 0).into()
-    }
+/// Check whether the response received from `src` answers the query sent to `addr`: the source address, the ID, the opcode, and the question must all match.
+pub fn verify(
+    query: &Message<&[u8]>,
+    addr: SocketAddr,
+    resp: &Message<Bytes>,
+    src: SocketAddr,
+) -> bool {
+    let (q, r) = (query.header(), resp.header());
+    src == addr
+        && r.qr()
+        && r.id() == q.id()
+        && r.opcode() == q.opcode()
+        && resp.header_counts().qdcount() == query.header_counts().qdcount()
+        && resp
+            .question()
+            .zip(query.question())
+            .all(|(r, q)| matches!((r, q), (Ok(r), Ok(q)) if r == q))
+}
+
+/// A socket connected to the upstream, which is retired once it has sent `max_reuse` queries.
+pub struct UdpConn {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    queries: AtomicUsize,
+    max_reuse: usize,
 }
 
 #[async_trait]
-impl QHandle for UdpSocket {
+impl QHandle for UdpConn {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
-        // Randomnize the message
-        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
-        msg.header_mut().set_random_id();
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
+        // Randomnize the message. `ThreadRng` is a CSPRNG periodically reseeded from the OS, so the ID can't be predicted by off-path attackers.
+        let mut buf = pool::buffer();
+        buf.extend_from_slice(msg.as_slice());
+        let mut msg = Message::from_octets(buf)?;
+        msg.header_mut().set_id(rand::thread_rng().gen());
         let msg = msg.for_slice();
 
-        self.send(msg.as_slice()).await?;
+        self.socket.send(msg.as_slice()).await?;
 
         loop {
-            let mut buf = BytesMut::with_capacity(MAX_LEN);
+            let mut buf = pool::buffer();
             buf.resize(MAX_LEN, 0);
-            let len = self.recv(&mut buf).await?;
+            let (len, src) = self.socket.recv_from(&mut buf).await?;
             buf.resize(len, 0);
 
-            // We ignore garbage since there is a timer on this whole thing.
+            // We ignore garbage and spoofed responses since there is a timer on this whole thing.
             let answer = match Message::from_octets(buf.freeze()) {
                 Ok(answer) => answer,
                 Err(_) => continue,
             };
-            if !answer.is_answer(&msg) {
+            if !verify(&msg, self.addr, &answer, src) {
+                log::debug!("dropped a response from {} not matching the query", src);
                 continue;
             }
             return Ok(answer);
@@ -87,10 +127,91 @@ impl QHandle for UdpSocket {
     }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
+        if self.queries.load(Ordering::Relaxed) >= self.max_reuse {
+            return Err(deadpool::managed::RecycleError::StaticMessage(
+                "max reuse UDP queries reached",
+            ));
+        }
         // We don't care about the response of our test query because we would ignore unrelated response that up in receive loop.
-        self.send(super::DUMMY_QUERY.as_slice())
+        self.socket
+            .send(super::DUMMY_QUERY.as_slice())
             .await
             .map(|_| ())
             .map_err(deadpool::managed::RecycleError::Backend)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Opcode, Dname, Message, MessageBuilder, Rtype};
+    use std::{net::SocketAddr, str::FromStr};
+
+    fn message(name: &str, id: u16, qr: bool) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        builder.header_mut().set_qr(qr);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn anti_spoofing() {
+        let addr: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let query = message("example.com", 1234, false);
+        let query = query.for_slice();
+
+        assert!(verify(
+            &query,
+            addr,
+            &message("example.com", 1234, true),
+            addr
+        ));
+        // Names are compared case-insensitively
+        assert!(verify(
+            &query,
+            addr,
+            &message("EXAMPLE.com", 1234, true),
+            addr
+        ));
+        // From somewhere else
+        assert!(!verify(
+            &query,
+            addr,
+            &message("example.com", 1234, true),
+            "1.1.1.1:5353".parse().unwrap()
+        ));
+        // Wrong ID
+        assert!(!verify(
+            &query,
+            addr,
+            &message("example.com", 4321, true),
+            addr
+        ));
+        // Not a response
+        assert!(!verify(
+            &query,
+            addr,
+            &message("example.com", 1234, false),
+            addr
+        ));
+        // Wrong question
+        assert!(!verify(
+            &query,
+            addr,
+            &message("example.org", 1234, true),
+            addr
+        ));
+
+        let mut resp = Message::from_octets(BytesMut::from(
+            message("example.com", 1234, true).as_slice(),
+        ))
+        .unwrap();
+        resp.header_mut().set_opcode(Opcode::Notify);
+        let resp = Message::from_octets(resp.into_octets().freeze()).unwrap();
+        assert!(!verify(&query, addr, &resp, addr));
+    }
+}
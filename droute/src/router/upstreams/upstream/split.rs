@@ -0,0 +1,102 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    router::script::utils::{addrs, IpCidr},
+    Label,
+};
+use bytes::Bytes;
+use domain::base::Message;
+use std::sync::Arc;
+
+/// An upstream that queries a domestic and a foreign member concurrently, and picks the answer by where the addresses answered are rather than by speed (the ChinaDNS algorithm).
+#[derive(Clone)]
+pub struct Split {
+    // The domestic member followed by the foreign one
+    tags: [Label; 2],
+    cidrs: Arc<IpCidr>,
+}
+
+impl Split {
+    /// Create a new `Split` upstream. The response of `domestic` is taken if all the addresses it answers are within `cidrs`, otherwise the one of `foreign` is.
+    pub fn new(domestic: Label, foreign: Label, cidrs: IpCidr) -> Self {
+        Self {
+            tags: [domestic, foreign],
+            cidrs: Arc::new(cidrs),
+        }
+    }
+
+    /// The tag of the domestic member.
+    pub fn domestic(&self) -> &Label {
+        &self.tags[0]
+    }
+
+    /// The tag of the foreign member.
+    pub fn foreign(&self) -> &Label {
+        &self.tags[1]
+    }
+
+    /// Tags of both members.
+    pub fn tags(&self) -> &[Label] {
+        &self.tags
+    }
+
+    // Whether the response of the domestic member can be trusted. Responses without addresses, e.g. to other query types, are trusted as well.
+    pub(crate) fn accepts(&self, resp: &Message<Bytes>) -> bool {
+        addrs(resp).into_iter().all(|ip| self.cidrs.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Split;
+    use crate::router::script::utils::IpCidr;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::str::FromStr;
+
+    fn resp(ips: &[[u8; 4]]) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let query = builder.into_message();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap();
+        for ip in ips {
+            builder
+                .push((&name, 300, A::from_octets(ip[0], ip[1], ip[2], ip[3])))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    #[test]
+    fn accepts() {
+        let mut cidrs = IpCidr::new();
+        cidrs.add_cidr("114.114.0.0/16").unwrap();
+        let split = Split::new("domestic".into(), "foreign".into(), cidrs);
+
+        assert!(split.accepts(&resp(&[[114, 114, 114, 114]])));
+        assert!(split.accepts(&resp(&[])));
+        assert!(!split.accepts(&resp(&[[114, 114, 114, 114], [8, 8, 8, 8]])));
+    }
+}
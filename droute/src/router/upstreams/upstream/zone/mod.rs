@@ -0,0 +1,309 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Also used to parse records in the JSON responses of DoH servers
+pub(super) mod parser;
+
+use super::{QHandle, QHandleError};
+use crate::pool;
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, Record, Rtype, ToDname,
+    },
+    rdata::AllRecordData,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
+
+type ZoneRecord = Record<Dname<Bytes>, AllRecordData<Bytes, Dname<Bytes>>>;
+
+type Result<T> = std::result::Result<T, QHandleError>;
+
+// Maximum number of CNAME records followed within the zone for a single query
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// A local zone loaded from a master file (RFC 1035), which answers authoritatively.
+pub struct Zone {
+    origin: Dname<Bytes>,
+    soa: ZoneRecord,
+    records: HashMap<Dname<Bytes>, Vec<ZoneRecord>>,
+    // Names owning no records but having descendants which do, e.g. `b.example.com` with only `a.b.example.com` in the zone
+    empty: HashSet<Dname<Bytes>>,
+}
+
+impl Zone {
+    /// Create a zone from the content of a master file. Relative names in the file are relative to `origin`.
+    #[allow(clippy::mutable_key_type)]
+    pub fn parse(origin: Dname<Bytes>, content: &str) -> Result<Self> {
+        let mut records: HashMap<Dname<Bytes>, Vec<ZoneRecord>> = HashMap::new();
+        for record in parser::parse(content, origin.clone()).map_err(QHandleError::InvalidZone)? {
+            if !record.owner().ends_with(&origin) {
+                return Err(QHandleError::InvalidZone(format!(
+                    "`{}` is out of the zone `{}`",
+                    record.owner(),
+                    origin
+                )));
+            }
+            records
+                .entry(record.owner().clone())
+                .or_default()
+                .push(record);
+        }
+
+        let soa = records
+            .get(&origin)
+            .and_then(|v| v.iter().find(|r| r.rtype() == Rtype::Soa))
+            .cloned()
+            .ok_or_else(|| {
+                QHandleError::InvalidZone(format!("no SOA record found at `{}`", origin))
+            })?;
+
+        let mut empty = HashSet::new();
+        for owner in records.keys() {
+            for name in owner.iter_suffixes().skip(1) {
+                // Ancestors further up are covered by the name found
+                if !name.ends_with(&origin) || records.contains_key(&name) || !empty.insert(name) {
+                    break;
+                }
+            }
+        }
+
+        Ok(Self {
+            origin,
+            soa,
+            records,
+            empty,
+        })
+    }
+
+    /// Load the zone from the master file located at `path`.
+    pub async fn from_file(origin: Dname<Bytes>, path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(origin, &tokio::fs::read_to_string(path).await?)
+    }
+
+    // The SOA record attached to negative answers, whose TTL is the lesser of the TTL and the MINIMUM field (RFC 2308).
+    fn negative_soa(&self) -> ZoneRecord {
+        let mut soa = self.soa.clone();
+        if let AllRecordData::Soa(data) = self.soa.data() {
+            soa.set_ttl(soa.ttl().min(data.minimum()));
+        }
+        soa
+    }
+
+    // Whether the name exists in the zone, either owning records or being an empty non-terminal.
+    fn exists(&self, name: &Dname<Bytes>) -> bool {
+        self.records.contains_key(name) || self.empty.contains(name)
+    }
+
+    // Get records owned by the name. `None` if the name doesn't exist. Only the records expanded from wildcards are owned.
+    fn lookup(&self, name: &Dname<Bytes>) -> Option<Cow<'_, [ZoneRecord]>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(Cow::Borrowed(records));
+        }
+        if self.exists(name) {
+            return Some(Cow::Borrowed(&[]));
+        }
+
+        // Find the closest encloser and expand its wildcard if there is one.
+        for encloser in name.iter_suffixes().skip(1) {
+            if !encloser.ends_with(&self.origin) {
+                break;
+            }
+            let wildcard = if encloser.is_root() {
+                Dname::<Bytes>::from_str("*")
+            } else {
+                Dname::<Bytes>::from_str(&format!("*.{}", encloser))
+            };
+            if let Some(records) = wildcard.ok().and_then(|w| self.records.get(&w)) {
+                return Some(Cow::Owned(
+                    records
+                        .iter()
+                        .map(|r| Record::new(name.clone(), r.class(), r.ttl(), r.data().clone()))
+                        .collect(),
+                ));
+            }
+            if self.exists(&encloser) {
+                break;
+            }
+        }
+        None
+    }
+
+    fn reply(
+        &self,
+        query: &Message<Bytes>,
+        rcode: Rcode,
+        answer: Vec<ZoneRecord>,
+        negative: bool,
+    ) -> Result<Message<Bytes>> {
+        let mut builder =
+            MessageBuilder::from_target(pool::buffer())?.start_answer(query, rcode)?;
+        builder.header_mut().set_aa(rcode != Rcode::Refused);
+        for record in answer {
+            builder.push(record)?;
+        }
+        let mut builder = builder.authority();
+        if negative {
+            builder.push(self.negative_soa())?;
+        }
+        Ok(builder.into_message())
+    }
+
+    fn answer(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let question = match query.first_question() {
+            Some(q) => q,
+            None => return self.reply(query, Rcode::FormErr, Vec::new(), false),
+        };
+        let (mut qname, qtype) = (question.qname().to_bytes(), question.qtype());
+
+        if question.qclass() != Class::In || !qname.ends_with(&self.origin) {
+            return self.reply(query, Rcode::Refused, Vec::new(), false);
+        }
+
+        let mut answer = Vec::new();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let records = match self.lookup(&qname) {
+                Some(records) => records,
+                None => return self.reply(query, Rcode::NXDomain, answer, true),
+            };
+
+            match records.iter().find(|r| r.rtype() == Rtype::Cname) {
+                Some(cname) if qtype != Rtype::Cname && qtype != Rtype::Any => {
+                    answer.push(cname.clone());
+                    if let AllRecordData::Cname(data) = cname.data() {
+                        qname = data.cname().clone();
+                    }
+                    // Leave the rest of the chain to the requestor
+                    if !qname.ends_with(&self.origin) {
+                        return self.reply(query, Rcode::NoError, answer, false);
+                    }
+                }
+                _ => {
+                    let len = answer.len();
+                    // Only the records matched are cloned
+                    answer.extend(
+                        records
+                            .iter()
+                            .filter(|r| qtype == Rtype::Any || r.rtype() == qtype)
+                            .cloned(),
+                    );
+                    // NODATA if nothing matches
+                    let negative = answer.len() == len;
+                    return self.reply(query, Rcode::NoError, answer, negative);
+                }
+            }
+        }
+
+        log::warn!("CNAME chain in zone `{}` is too long", self.origin);
+        self.reply(query, Rcode::ServFail, answer, false)
+    }
+}
+
+#[async_trait]
+impl QHandle for Zone {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.answer(msg)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Zone;
+    use crate::mock::query;
+    use bytes::Bytes;
+    use domain::{
+        base::{iana::Rcode, Dname, Message, ParsedDname, Rtype},
+        rdata::AllRecordData,
+    };
+    use std::str::FromStr;
+
+    fn zone() -> Zone {
+        Zone::parse(
+            Dname::from_str("a.cn").unwrap(),
+            include_str!("../../../../../../data/a.cn.zone"),
+        )
+        .unwrap()
+    }
+
+    fn answer_types(msg: &Message<Bytes>) -> Vec<Rtype> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()
+            .map(|r| r.unwrap().rtype())
+            .collect()
+    }
+
+    #[test]
+    fn positive() {
+        let resp = zone().answer(&query("www.a.cn", Rtype::A)).unwrap();
+        assert_eq!(resp.header().id(), 42);
+        assert!(resp.header().aa());
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(answer_types(&resp), vec![Rtype::A]);
+
+        let resp = zone().answer(&query("a.cn", Rtype::Soa)).unwrap();
+        assert_eq!(answer_types(&resp), vec![Rtype::Soa]);
+        let resp = zone().answer(&query("a.cn", Rtype::Ns)).unwrap();
+        assert_eq!(answer_types(&resp), vec![Rtype::Ns]);
+    }
+
+    #[test]
+    fn cname_chain() {
+        let resp = zone()
+            .answer(&query("alias-chain.a.cn", Rtype::Aaaa))
+            .unwrap();
+        assert_eq!(
+            answer_types(&resp),
+            vec![Rtype::Cname, Rtype::Cname, Rtype::Aaaa]
+        );
+
+        // Wildcard gets expanded
+        let resp = zone()
+            .answer(&query("foo.wildcard.a.cn", Rtype::A))
+            .unwrap();
+        assert_eq!(answer_types(&resp), vec![Rtype::Cname, Rtype::A]);
+    }
+
+    #[test]
+    fn negative() {
+        let resp = zone().answer(&query("www.a.cn", Rtype::Mx)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(answer_types(&resp).is_empty());
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        // Empty non-terminal
+        let resp = zone().answer(&query("has.dots.a.cn", Rtype::A)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(answer_types(&resp).is_empty());
+
+        let resp = zone().answer(&query("nothing.a.cn", Rtype::A)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        let resp = zone().answer(&query("www.b.cn", Rtype::A)).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+    }
+}
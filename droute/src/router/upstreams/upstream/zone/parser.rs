@@ -0,0 +1,444 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// A parser for the subset of RFC 1035 master files commonly seen in small zones.
+
+use super::ZoneRecord;
+use bytes::Bytes;
+use domain::{
+    base::{iana::Class, Dname, Record},
+    rdata::{Aaaa, AllRecordData, Cname, Mx, Ns, Ptr, Soa, Srv, Txt, A},
+};
+use std::{net::IpAddr, str::FromStr};
+
+type Result<T> = std::result::Result<T, String>;
+
+struct Token {
+    text: String,
+    // Whether the token is a quoted character string
+    quoted: bool,
+}
+
+struct Entry {
+    // Line number where the entry starts
+    line: usize,
+    // Whether the entry starts with a blank, which means the owner is omitted
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+// Split the content into entries, dealing with comments, quotes and parentheses.
+fn tokenize(content: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = content.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut current: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+                if depth == 0 {
+                    if let Some(entry) = current.take() {
+                        entries.push(entry);
+                    }
+                }
+                continue;
+            }
+            ';' => {
+                while chars.peek().map(|c| *c != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(format!("line {}: unbalanced parentheses", line));
+                }
+                depth -= 1
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let entry = current.get_or_insert_with(|| Entry {
+                    line,
+                    blank_owner: !at_line_start,
+                    tokens: Vec::new(),
+                });
+                let mut text = String::new();
+                let quoted = c == '"';
+                if quoted {
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => text.extend(chars.next()),
+                            Some('\n') | None => {
+                                return Err(format!("line {}: unterminated quoted string", line))
+                            }
+                            Some(c) => text.push(c),
+                        }
+                    }
+                } else {
+                    text.push(c);
+                    while let Some(c) = chars.peek() {
+                        if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        text.push(*c);
+                        chars.next();
+                    }
+                }
+                entry.tokens.push(Token { text, quoted });
+            }
+        }
+        at_line_start = false;
+    }
+
+    if depth != 0 {
+        return Err(format!("line {}: unbalanced parentheses", line));
+    }
+    entries.extend(current);
+    Ok(entries)
+}
+
+// Parse TTL like `3600` or `1h30m`
+fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(v) = s.parse() {
+        return Some(v);
+    }
+    let mut total: u32 = 0;
+    let mut num: Option<u32> = None;
+    for c in s.to_ascii_lowercase().chars() {
+        if let Some(d) = c.to_digit(10) {
+            num = Some(num.unwrap_or(0).checked_mul(10)?.checked_add(d)?);
+        } else {
+            let unit = match c {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return None,
+            };
+            total = total.checked_add(num.take()?.checked_mul(unit)?)?;
+        }
+    }
+    // Trailing number without unit is not allowed
+    match num {
+        Some(_) => None,
+        None => Some(total),
+    }
+}
+
+fn parse_name(s: &str, origin: &Dname<Bytes>) -> Result<Dname<Bytes>> {
+    let name = if s == "@" {
+        return Ok(origin.clone());
+    } else if s == "." {
+        // `FromStr` takes the lone dot for an empty label
+        return Ok(Dname::root_bytes());
+    } else if s.ends_with('.') {
+        s.to_string()
+    } else if origin.is_root() {
+        format!("{}.", s)
+    } else {
+        format!("{}.{}", s, origin)
+    };
+    Dname::from_str(&name).map_err(|e| format!("invalid domain name `{}`: {}", s, e))
+}
+
+fn parse_num<T: FromStr>(s: Option<&Token>) -> Result<T> {
+    let s = s.ok_or_else(|| "missing field".to_string())?;
+    s.text
+        .parse()
+        .map_err(|_| format!("invalid number `{}`", s.text))
+}
+
+fn parse_rdata(
+    rtype: &str,
+    rdata: &[Token],
+    origin: &Dname<Bytes>,
+) -> Result<Option<AllRecordData<Bytes, Dname<Bytes>>>> {
+    Ok(Some(match rtype {
+        "A" | "AAAA" => {
+            let addr = rdata.first().ok_or_else(|| "missing address".to_string())?;
+            match (rtype, IpAddr::from_str(&addr.text)) {
+                ("A", Ok(IpAddr::V4(addr))) => A::new(addr).into(),
+                ("AAAA", Ok(IpAddr::V6(addr))) => Aaaa::new(addr).into(),
+                _ => return Err(format!("invalid {} address `{}`", rtype, addr.text)),
+            }
+        }
+        "CNAME" => Cname::new(parse_name_at(rdata, 0, origin)?).into(),
+        "NS" => Ns::new(parse_name_at(rdata, 0, origin)?).into(),
+        "PTR" => Ptr::new(parse_name_at(rdata, 0, origin)?).into(),
+        "MX" => Mx::new(parse_num(rdata.first())?, parse_name_at(rdata, 1, origin)?).into(),
+        "SRV" => Srv::new(
+            parse_num(rdata.first())?,
+            parse_num(rdata.get(1))?,
+            parse_num(rdata.get(2))?,
+            parse_name_at(rdata, 3, origin)?,
+        )
+        .into(),
+        "TXT" => {
+            // Multiple character strings are joined together
+            let text = rdata.iter().map(|t| t.text.as_str()).collect::<String>();
+            Txt::from_slice(text.as_bytes())
+                .map_err(|_| "TXT data too long".to_string())?
+                .into()
+        }
+        "SOA" => {
+            let (mname, rname) = (
+                parse_name_at(rdata, 0, origin)?,
+                parse_name_at(rdata, 1, origin)?,
+            );
+            let mut times = rdata[2..].iter().map(|t| {
+                parse_ttl(&t.text).ok_or_else(|| format!("invalid SOA field `{}`", t.text))
+            });
+            let mut next = || {
+                times
+                    .next()
+                    .unwrap_or_else(|| Err("missing field".to_string()))
+            };
+            Soa::new(
+                mname,
+                rname,
+                next()?.into(),
+                next()?,
+                next()?,
+                next()?,
+                next()?,
+            )
+            .into()
+        }
+        _ => return Ok(None),
+    }))
+}
+
+fn parse_name_at(rdata: &[Token], index: usize, origin: &Dname<Bytes>) -> Result<Dname<Bytes>> {
+    parse_name(
+        &rdata
+            .get(index)
+            .ok_or_else(|| "missing field".to_string())?
+            .text,
+        origin,
+    )
+}
+
+/// Parse the content of a master file with the given origin. Records of unsupported types are skipped with a warning.
+pub fn parse(content: &str, origin: Dname<Bytes>) -> Result<Vec<ZoneRecord>> {
+    parse_logging(content, origin, log::Level::Warn)
+}
+
+/// Same as `parse`, but the records skipped are logged at `skipped`, e.g. `Debug` for the records parsed on every query.
+pub fn parse_logging(
+    content: &str,
+    origin: Dname<Bytes>,
+    skipped: log::Level,
+) -> Result<Vec<ZoneRecord>> {
+    let mut origin = origin;
+    // TTL set by `$TTL`
+    let mut default_ttl = None;
+    // The last explicitly stated TTL
+    let mut last_ttl = None;
+    let mut last_owner: Option<Dname<Bytes>> = None;
+    // Records with their TTL possibly missing
+    let mut records = Vec::new();
+
+    for entry in tokenize(content)? {
+        let with_line = |e: String| format!("line {}: {}", entry.line, e);
+        let mut tokens = entry.tokens.iter().peekable();
+
+        // Control entries
+        if !entry.blank_owner {
+            match tokens
+                .peek()
+                .map(|t| t.text.to_ascii_uppercase())
+                .as_deref()
+            {
+                Some("$ORIGIN") => {
+                    let name = entry
+                        .tokens
+                        .get(1)
+                        .ok_or_else(|| with_line("missing origin".into()))?;
+                    origin = parse_name(&name.text, &origin).map_err(with_line)?;
+                    continue;
+                }
+                Some("$TTL") => {
+                    let ttl = entry
+                        .tokens
+                        .get(1)
+                        .ok_or_else(|| with_line("missing TTL".into()))?;
+                    default_ttl = Some(
+                        parse_ttl(&ttl.text)
+                            .ok_or_else(|| with_line(format!("invalid TTL `{}`", ttl.text)))?,
+                    );
+                    continue;
+                }
+                Some(s) if s.starts_with('$') => {
+                    return Err(with_line(format!("unsupported control entry `{}`", s)))
+                }
+                _ => {}
+            }
+        }
+
+        let owner = if entry.blank_owner {
+            last_owner
+                .clone()
+                .ok_or_else(|| with_line("missing owner name".into()))?
+        } else {
+            // It's safe to unwrap as entry has at least one token
+            parse_name(&tokens.next().unwrap().text, &origin).map_err(with_line)?
+        };
+        last_owner = Some(owner.clone());
+
+        // TTL and class can appear in either order before the type
+        let mut ttl = None;
+        let rtype = loop {
+            let token = tokens
+                .next()
+                .ok_or_else(|| with_line("missing record type".into()))?;
+            let text = token.text.to_ascii_uppercase();
+            if token.quoted {
+                return Err(with_line(format!("unexpected string `{}`", token.text)));
+            } else if ttl.is_none() && text.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(
+                    parse_ttl(&text).ok_or_else(|| with_line(format!("invalid TTL `{}`", text)))?,
+                );
+            } else if text == "IN" {
+            } else if matches!(text.as_str(), "CH" | "CS" | "HS") {
+                return Err(with_line(format!("unsupported class `{}`", text)));
+            } else {
+                break text;
+            }
+        };
+
+        let rdata = &entry.tokens[entry.tokens.len() - tokens.len()..];
+        match parse_rdata(&rtype, rdata, &origin).map_err(with_line)? {
+            Some(data) => {
+                // Without an explicit TTL, use `$TTL` or the last explicitly stated one.
+                if ttl.is_some() {
+                    last_ttl = ttl;
+                }
+                records.push((owner, ttl.or(default_ttl).or(last_ttl), data))
+            }
+            None => log::log!(
+                skipped,
+                "line {}: record type `{}` is not supported, skipped",
+                entry.line,
+                rtype
+            ),
+        }
+    }
+
+    // Records still without TTL fall back to the minimum TTL in SOA
+    let minimum = records.iter().find_map(|(_, _, data)| match data {
+        AllRecordData::Soa(soa) => Some(soa.minimum()),
+        _ => None,
+    });
+
+    records
+        .into_iter()
+        .map(|(owner, ttl, data)| {
+            Ok(Record::new(
+                owner,
+                Class::In,
+                ttl.or(minimum)
+                    .ok_or_else(|| "no TTL specified for records".to_string())?,
+                data,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_ttl};
+    use bytes::Bytes;
+    use domain::base::{Dname, Rtype};
+    use std::str::FromStr;
+
+    fn name(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn ttl() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("1h30"), None);
+        assert_eq!(parse_ttl("abc"), None);
+    }
+
+    #[test]
+    fn parse_example_zone() {
+        let records = parse(
+            include_str!("../../../../../../data/a.cn.zone"),
+            name("a.cn"),
+        )
+        .unwrap();
+
+        let soa = &records[0];
+        assert_eq!(soa.owner(), &name("a.cn"));
+        assert_eq!(soa.rtype(), Rtype::Soa);
+        assert_eq!(soa.ttl(), 86400);
+
+        // Owner omitted, inherited from the previous entry
+        let has = |owner: &str, rtype: Rtype| {
+            records
+                .iter()
+                .any(|r| r.owner() == &name(owner) && r.rtype() == rtype)
+        };
+        assert!(has("www.a.cn", Rtype::Aaaa));
+        assert!(has("*.wildcard.a.cn", Rtype::Cname));
+        assert!(has("no-service.a.cn", Rtype::Mx));
+        assert!(has("server.a.cn", Rtype::Srv));
+    }
+
+    #[test]
+    fn parse_directives() {
+        let records = parse(
+            r#"
+$TTL 1h
+$ORIGIN example.org.
+@ IN SOA ns hostmaster ( 1 2 3 4 5 )
+txt 60 IN TXT "hello; world" "!"
+$ORIGIN sub.example.org.
+host A 10.0.0.1
+"#,
+            Dname::root_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].ttl(), 3600);
+        assert_eq!(records[1].ttl(), 60);
+        assert_eq!(records[2].owner(), &name("host.sub.example.org"));
+        assert_eq!(records[2].ttl(), 3600);
+    }
+
+    #[test]
+    fn malformed() {
+        let root = Dname::root_bytes;
+        assert!(parse("@ SOA ns host ( 1 2 3 4 5", root()).is_err());
+        assert!(parse("www 60 A 1.1.1", root()).is_err());
+        assert!(parse("www 60 A ::1", root()).is_err());
+        assert!(parse("www 60 CH A 1.1.1.1", root()).is_err());
+        // No TTL can be inferred
+        assert!(parse("www A 1.1.1.1", root()).is_err());
+        assert!(parse("www 60 A 1.1.1.1", root()).is_ok());
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Record of the routing decisions made for a query, e.g. to replay captured traffic against a configuration before deploying it, or to annotate the responses with them for debugging.
+
+use crate::{inbound::skip_name, padding::signed, Label};
+use bytes::Bytes;
+use domain::base::Message;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, fmt, future::Future};
+
+/// The routing decisions made for a query.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    /// Tags of the upstreams the query is sent to in the order they are tried, excluding those only dispatching it to others like `hybrid`. Upstreams answering from the cache are included.
+    pub upstreams: Vec<Label>,
+    /// Names of the rule logs the query matches in the order they are hit, regardless of their levels and sample rates.
+    pub rules: Vec<String>,
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |v: Vec<&str>| match v.is_empty() {
+            true => "none".to_string(),
+            false => v.join(", "),
+        };
+        write!(
+            f,
+            "rules: {}; upstreams: {}",
+            list(self.rules.iter().map(String::as_str).collect()),
+            list(self.upstreams.iter().map(Label::as_str).collect())
+        )
+    }
+}
+
+tokio::task_local! {
+    static TRACE: RefCell<Trace>;
+}
+
+/// Run the future, and return its output along with the decisions made while it runs. Tasks spawned by it are not traced. The decisions are recorded in the enclosing scope as well, if any.
+pub async fn scope<F: Future>(f: F) -> (F::Output, Trace) {
+    let (output, trace) = TRACE
+        .scope(RefCell::new(Trace::default()), async move {
+            let output = f.await;
+            (output, TRACE.with(|t| t.take()))
+        })
+        .await;
+    let _ = TRACE.try_with(|t| {
+        let mut t = t.borrow_mut();
+        t.upstreams.extend(trace.upstreams.iter().cloned());
+        t.rules.extend(trace.rules.iter().cloned());
+    });
+    (output, trace)
+}
+
+// Nothing is recorded outside of `scope`.
+pub(crate) fn upstream(tag: &Label) {
+    let _ = TRACE.try_with(|t| t.borrow_mut().upstreams.push(tag.clone()));
+}
+
+pub(crate) fn rule(name: &str) {
+    let _ = TRACE.try_with(|t| t.borrow_mut().rules.push(name.to_string()));
+}
+
+// Type codes of the records and the option added
+const OPT: u16 = 41;
+const TXT: u16 = 16;
+const EDE: u16 = 15;
+
+// Extra text longer than this is cut off, which keeps the responses annotated well within the limits of the wire format.
+const MAX_NOTE_LEN: usize = 1024;
+
+/// Where the routing decisions are put in the responses, so that they can be told from client machines, e.g. with `dig`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Annotation {
+    /// An Extended DNS Error option (RFC 8914) with the info code 0 ("Other Error") and the decisions as the extra text. Responses to clients not using EDNS are left alone.
+    Ede,
+    /// A `TXT` record of class `CH` and TTL 0, owned by the name queried, in the additional section.
+    Txt,
+}
+
+// The offset of the data length field of the OPT record in the message, if any. `None` if the message is malformed or followed by trailing bytes, to which nothing can be appended.
+fn opt_rdlen(msg: &[u8]) -> Option<Option<usize>> {
+    if msg.len() < 12 {
+        return None;
+    }
+    let count = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]) as usize;
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = skip_name(msg, pos).ok()? + 4;
+    }
+    let (records, additional) = (count(6) + count(8), count(10));
+    let mut opt = None;
+    for i in 0..records + additional {
+        let start = skip_name(msg, pos).ok()?;
+        let field = msg.get(start..start + 10)?;
+        if i >= records && u16::from_be_bytes([field[0], field[1]]) == OPT {
+            opt = Some(start + 8);
+        }
+        pos = start + 10 + u16::from_be_bytes([field[8], field[9]]) as usize;
+    }
+    if pos == msg.len() {
+        Some(opt)
+    } else {
+        None
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: usize) {
+    buf.extend((v as u16).to_be_bytes());
+}
+
+impl Annotation {
+    /// Annotate `resp` to `query` with the decisions in `trace`. `None` if it can't be annotated, e.g. if it is signed with TSIG or the client doesn't use EDNS.
+    pub fn annotate(
+        self,
+        query: &Message<Bytes>,
+        resp: &Message<Bytes>,
+        trace: &Trace,
+    ) -> Option<Message<Bytes>> {
+        // Anything added after the TSIG record would invalidate it
+        if signed(resp) {
+            return None;
+        }
+        let mut note = format!("dcompass {}", trace);
+        if note.len() > MAX_NOTE_LEN {
+            let mut end = MAX_NOTE_LEN;
+            while !note.is_char_boundary(end) {
+                end -= 1;
+            }
+            note.truncate(end);
+        }
+
+        let msg = resp.as_slice();
+        let opt = opt_rdlen(msg)?;
+        let mut buf = msg.to_vec();
+        // The record appended to the additional section
+        let mut record = Vec::new();
+        match self {
+            Self::Ede => {
+                let mut ede = Vec::new();
+                push_u16(&mut ede, EDE as usize);
+                push_u16(&mut ede, note.len() + 2);
+                push_u16(&mut ede, 0);
+                ede.extend(note.as_bytes());
+                match opt {
+                    // Appended to the options of the existing OPT record
+                    Some(rdlen) => {
+                        let len = u16::from_be_bytes([msg[rdlen], msg[rdlen + 1]]) as usize;
+                        buf[rdlen..rdlen + 2]
+                            .copy_from_slice(&u16::try_from(len + ede.len()).ok()?.to_be_bytes());
+                        let tail = buf.split_off(rdlen + 2 + len);
+                        buf.extend(ede);
+                        buf.extend(tail);
+                    }
+                    None => {
+                        // Root name, type, payload size, extended RCODE, version, and flags
+                        record.push(0);
+                        push_u16(&mut record, OPT as usize);
+                        push_u16(&mut record, query.opt()?.udp_payload_size() as usize);
+                        record.extend([0; 4]);
+                        push_u16(&mut record, ede.len());
+                        record.extend(ede);
+                    }
+                }
+            }
+            Self::Txt => {
+                // Compressed to point to the name in the question
+                match resp.header_counts().qdcount() {
+                    0 => record.push(0),
+                    _ => record.extend([0xc0, 12]),
+                }
+                push_u16(&mut record, TXT as usize);
+                // Class CH, TTL 0
+                push_u16(&mut record, 3);
+                record.extend([0; 4]);
+                let chunks = note.as_bytes().chunks(255);
+                push_u16(&mut record, note.len() + chunks.len());
+                for chunk in chunks {
+                    record.push(chunk.len() as u8);
+                    record.extend(chunk);
+                }
+            }
+        }
+        if !record.is_empty() {
+            buf.extend(record);
+            let arcount = resp.header_counts().arcount().checked_add(1)?;
+            buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+        }
+        if buf.len() > u16::MAX as usize {
+            return None;
+        }
+        Message::from_octets(Bytes::from(buf)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rule, scope, upstream, Annotation, Trace};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            opt::{AllOptData, Padding},
+            Dname, Message, MessageBuilder, Rtype, ToDname,
+        },
+        rdata::{Txt, A},
+    };
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn record() {
+        // Outside of any scope
+        upstream(&"ignored".into());
+        let (output, trace) = scope(async {
+            upstream(&"domestic".into());
+            rule("ads");
+            upstream(&"secure".into());
+            42
+        })
+        .await;
+        assert_eq!(output, 42);
+        assert_eq!(
+            trace,
+            Trace {
+                upstreams: vec!["domestic".into(), "secure".into()],
+                rules: vec!["ads".to_string()],
+            }
+        );
+        // Scopes don't leak into each other
+        assert_eq!(scope(async {}).await.1, Trace::default());
+
+        // Nested scopes record into the enclosing ones as well
+        let ((_, inner), outer) = scope(async {
+            rule("ads");
+            scope(async { upstream(&"domestic".into()) }).await
+        })
+        .await;
+        assert_eq!(inner.to_string(), "rules: none; upstreams: domestic");
+        assert_eq!(outer.to_string(), "rules: ads; upstreams: domestic");
+    }
+
+    fn query(edns: bool) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        if edns {
+            builder
+                .opt(|opt| {
+                    opt.set_udp_payload_size(1232);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn response(query: &Message<Bytes>, edns: bool) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(query, Rcode::NoError)
+            .unwrap();
+        let name = query.first_question().unwrap().qname().to_bytes();
+        builder
+            .push((&name, 300, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+        let mut builder = builder.additional();
+        if edns {
+            builder
+                .opt(|opt| opt.push(&Padding::new(8)))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn trace() -> Trace {
+        Trace {
+            upstreams: vec!["domestic".into()],
+            rules: vec![],
+        }
+    }
+
+    const NOTE: &[u8] = b"dcompass rules: none; upstreams: domestic";
+
+    fn contains(msg: &Message<Bytes>, note: &[u8]) -> bool {
+        msg.as_slice().windows(note.len()).any(|w| w == note)
+    }
+
+    fn options(msg: &Message<Bytes>) -> Vec<&'static str> {
+        msg.opt()
+            .unwrap()
+            .iter::<AllOptData<_>>()
+            .map(|o| match o.unwrap() {
+                AllOptData::Padding(_) => "PADDING",
+                AllOptData::ExtendedError(_) => "EXTENDED_ERROR",
+                _ => "OTHER",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ede() {
+        let q = query(true);
+        let resp = Annotation::Ede
+            .annotate(&q, &response(&q, true), &trace())
+            .unwrap();
+        assert_eq!(options(&resp), vec!["PADDING", "EXTENDED_ERROR"]);
+        assert!(contains(&resp, NOTE));
+        assert_eq!(resp.header_counts().ancount(), 1);
+        assert_eq!(resp.header_counts().arcount(), 1);
+
+        // An OPT record is added if the upstream answered without one
+        let resp = Annotation::Ede
+            .annotate(&q, &response(&q, false), &trace())
+            .unwrap();
+        assert_eq!(options(&resp), vec!["EXTENDED_ERROR"]);
+        assert_eq!(resp.opt().unwrap().udp_payload_size(), 1232);
+
+        // But not for clients not using EDNS
+        let q = query(false);
+        assert!(Annotation::Ede
+            .annotate(&q, &response(&q, false), &trace())
+            .is_none());
+    }
+
+    #[test]
+    fn txt() {
+        let q = query(false);
+        let resp = Annotation::Txt
+            .annotate(&q, &response(&q, false), &trace())
+            .unwrap();
+        let record = resp
+            .additional()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .into_record::<Txt<_>>()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.class(), Class::Ch);
+        assert_eq!(record.ttl(), 0);
+        assert_eq!(record.owner().to_string(), "example.com");
+        assert!(contains(&resp, NOTE));
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Transaction signatures (TSIG, RFC 8945) for queries sent to upstreams and queries received from clients.
+
+use crate::{
+    errors::{QHandleError, ScriptError, UpstreamError},
+    router::upstreams::QHandle,
+    utils::{rebuild_with, records},
+    Label, QueryContext, ScriptBackend, ScriptBuilder, Upstreams, Validatable, MAX_LEN,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::Rcode, message_builder::AdditionalBuilder, octets::OctetsVec, Dname, Message,
+        MessageBuilder,
+    },
+    tsig::{Algorithm, ClientTransaction, Key, ServerTransaction},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+type Result<T> = std::result::Result<T, QHandleError>;
+
+type KeyName = Dname<OctetsVec>;
+
+fn default_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+/// A builder for TSIG key
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct KeyBuilder {
+    /// Name of the key, which must be the same as the one configured on the other end. e.g. `dcompass-key`
+    pub name: String,
+    /// The shared secret encoded in base64, as generated by `tsig-keygen`
+    pub secret: String,
+    /// The HMAC algorithm. One of `hmac-sha1`, `hmac-sha256`, `hmac-sha384`, and `hmac-sha512`.
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+}
+
+impl KeyBuilder {
+    /// Create a builder for `hmac-sha256` key
+    pub fn new(name: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            secret: secret.into(),
+            algorithm: default_algorithm(),
+        }
+    }
+
+    /// Build the key
+    pub fn build(self) -> Result<Key> {
+        let invalid = |e: String| QHandleError::InvalidTsigKey(format!("`{}`: {}", self.name, e));
+        let algorithm = Algorithm::from_str(&self.algorithm)
+            .map_err(|_| invalid(format!("unknown algorithm `{}`", self.algorithm)))?;
+        let name = KeyName::from_str(&self.name).map_err(|e| invalid(e.to_string()))?;
+        let secret = STANDARD
+            .decode(&self.secret)
+            .map_err(|e| invalid(e.to_string()))?;
+        Key::new(algorithm, &secret, name, None, None).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+// Copy all the sections of the message into a builder, to which the TSIG record can be appended.
+fn rebuild(msg: &Message<Bytes>) -> Result<AdditionalBuilder<BytesMut>> {
+    rebuild_with(msg, BytesMut::with_capacity(MAX_LEN), |s, section| {
+        records(s, section, &|_, _, _| true)
+    })
+    .map_err(|e| QHandleError::TsigError(format!("malformed message: {}", e)))
+}
+
+fn freeze(builder: AdditionalBuilder<BytesMut>) -> Result<Message<Bytes>> {
+    Ok(Message::from_octets(builder.finish().freeze())?)
+}
+
+/// A query handle which signs every query with the key and verifies the signature of every response.
+pub struct Signed {
+    inner: Arc<dyn QHandle>,
+    key: Arc<Key>,
+}
+
+impl Signed {
+    /// Sign the queries sent through `inner`
+    pub fn new(inner: Arc<dyn QHandle>, key: Key) -> Self {
+        Self {
+            inner,
+            key: Arc::new(key),
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Signed {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut builder = rebuild(msg)?;
+        let tran = ClientTransaction::request(self.key.clone(), &mut builder)?;
+        let resp = self.inner.query(&freeze(builder)?).await?;
+
+        let mut resp = Message::from_octets(resp.as_slice().to_vec())?;
+        tran.answer(&mut resp)
+            .map_err(|e| QHandleError::TsigError(e.to_string()))?;
+        Ok(Message::from_octets(Bytes::from(resp.into_octets()))?)
+    }
+}
+
+/// A script backend which verifies the signatures of the queries received from clients before routing them with the inner script, and signs the responses to them.
+pub struct Guarded<T: ScriptBackend> {
+    inner: T,
+    keys: HashMap<(KeyName, Algorithm), Arc<Key>>,
+    zones: Vec<Dname<Bytes>>,
+}
+
+impl<T: ScriptBackend> Guarded<T> {
+    fn protected(&self, query: &Message<Vec<u8>>) -> bool {
+        query
+            .first_question()
+            .map(|q| self.zones.iter().any(|z| q.qname().ends_with(z)))
+            .unwrap_or(false)
+    }
+
+    fn refuse(query: &Message<Vec<u8>>) -> std::result::Result<Message<Bytes>, ScriptError> {
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(query, Rcode::Refused)?;
+        Ok(Message::from_octets(builder.finish().freeze())?)
+    }
+}
+
+#[async_trait]
+impl<T: ScriptBackend + Send + Sync> ScriptBackend for Guarded<T> {
+    async fn route(
+        &self,
+        query: Message<Bytes>,
+        ctx: Option<QueryContext>,
+    ) -> std::result::Result<Message<Bytes>, ScriptError> {
+        // Nothing to verify
+        if self.keys.is_empty() && self.zones.is_empty() {
+            return self.inner.route(query, ctx).await;
+        }
+
+        let mut query = Message::from_octets(query.as_slice().to_vec())?;
+        let tran = match ServerTransaction::request(&self.keys, &mut query) {
+            Ok(tran) => tran,
+            // Answer with the error (e.g. BADKEY, BADSIG) as defined by RFC 8945
+            Err(e) => {
+                log::warn!("TSIG verification failed for an incoming query");
+                let builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+                return Ok(Message::from_octets(
+                    e.build_message(&query, builder)?.finish().freeze(),
+                )?);
+            }
+        };
+
+        if tran.is_none() && self.protected(&query) {
+            log::warn!("refused an unsigned query for a protected zone");
+            return Self::refuse(&query);
+        }
+
+        // The TSIG record has been removed from the query
+        let resp = self
+            .inner
+            .route(Message::from_octets(Bytes::from(query.into_octets()))?, ctx)
+            .await?;
+        Ok(match tran {
+            Some(tran) => {
+                let mut builder = rebuild(&resp).map_err(UpstreamError::from)?;
+                tran.answer(&mut builder)?;
+                freeze(builder).map_err(UpstreamError::from)?
+            }
+            None => resp,
+        })
+    }
+}
+
+impl<T: ScriptBackend> Validatable for Guarded<T> {
+    type Error = ScriptError;
+
+    fn validate(&self, used: Option<&Vec<Label>>) -> std::result::Result<(), ScriptError> {
+        self.inner.validate(used)
+    }
+}
+
+/// The builder for `Guarded`
+pub struct GuardedBuilder<S> {
+    script: S,
+    keys: Vec<KeyBuilder>,
+    zones: Vec<Dname<Bytes>>,
+}
+
+impl<S> GuardedBuilder<S> {
+    /// Guard the script, which accepts no signed query and protects no zone until keys and zones are added.
+    pub fn new(script: S) -> Self {
+        Self {
+            script,
+            keys: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    /// Accept queries signed with the key
+    pub fn add_key(mut self, key: KeyBuilder) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Refuse unsigned queries for names within the zone
+    pub fn add_zone(mut self, zone: Dname<Bytes>) -> Self {
+        self.zones.push(zone);
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, T> ScriptBuilder<Guarded<T>> for GuardedBuilder<S>
+where
+    S: ScriptBuilder<T>,
+    T: ScriptBackend + Send + Sync,
+{
+    async fn build(self, upstreams: Upstreams) -> std::result::Result<Guarded<T>, ScriptError> {
+        let mut keys = HashMap::new();
+        for key in self.keys {
+            let key = key.build().map_err(UpstreamError::from)?;
+            keys.insert((key.name().clone(), key.algorithm()), Arc::new(key));
+        }
+        Ok(Guarded {
+            inner: self.script.build(upstreams).await?,
+            keys,
+            zones: self.zones,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{freeze, rebuild, GuardedBuilder, KeyBuilder, KeyName, Signed};
+    use crate::{
+        builders::{NativeScriptBuilder, UpstreamBuilder, UpstreamsBuilder},
+        errors::{QHandleError, ScriptError},
+        mock::query,
+        router::upstreams::QHandle,
+        AsyncTryInto, QueryContext, ScriptBackend, ScriptBuilder, Upstreams,
+    };
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        tsig::{Algorithm, ClientTransaction, Key, ServerTransaction},
+    };
+    use futures::future::ready;
+    use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+    fn key(name: &str) -> Key {
+        // base64 of `secret-secret-secret`
+        KeyBuilder::new(name, "c2VjcmV0LXNlY3JldC1zZWNyZXQ=")
+            .build()
+            .unwrap()
+    }
+
+    // An empty answering server which requires every query to be signed
+    struct Server(HashMap<(KeyName, Algorithm), Arc<Key>>);
+
+    #[async_trait]
+    impl QHandle for Server {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+            let mut query = Message::from_octets(msg.as_slice().to_vec())?;
+            let tran = ServerTransaction::request(&self.0, &mut query)
+                .ok()
+                .flatten()
+                .ok_or_else(|| QHandleError::TsigError("unsigned or bad query".to_string()))?;
+            let mut builder = MessageBuilder::from_target(BytesMut::new())?
+                .start_answer(&query, Rcode::NoError)?
+                .additional();
+            tran.answer(&mut builder)?;
+            freeze(builder)
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_outbound() {
+        let k = key("test-key");
+        let server = || {
+            let mut keys = HashMap::new();
+            keys.insert((k.name().clone(), k.algorithm()), Arc::new(key("test-key")));
+            Arc::new(Server(keys))
+        };
+        let msg = query("example.com", Rtype::A);
+
+        let resp = Signed::new(server(), key("test-key"))
+            .query(&msg)
+            .await
+            .unwrap();
+        assert_eq!(resp.header().id(), 42);
+        // Unknown key is rejected by the server
+        assert!(Signed::new(server(), key("other-key"))
+            .query(&msg)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn guard_inbound() {
+        let guarded = GuardedBuilder::new(NativeScriptBuilder::new(
+            // `domain` 0.7 misplaces the TSIG record of the messages with other additional records, so answer without any
+            |_: Upstreams, query: Message<Bytes>, _: Option<QueryContext>| {
+                ready(
+                    MessageBuilder::from_target(BytesMut::new())
+                        .and_then(|builder| builder.start_answer(&query, Rcode::NoError))
+                        .map(|builder| Message::from_octets(builder.finish().freeze()).unwrap())
+                        .map_err(ScriptError::from),
+                )
+            },
+        ))
+        .add_key(KeyBuilder::new("test-key", "c2VjcmV0LXNlY3JldC1zZWNyZXQ="))
+        .add_zone(Dname::from_str("corp.example.com").unwrap())
+        .build(
+            UpstreamsBuilder::<UpstreamBuilder>::new(1)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let unsigned = |name| query(name, Rtype::A);
+        let resp = guarded
+            .route(unsigned("www.corp.example.com"), None)
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+        let resp = guarded.route(unsigned("example.com"), None).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+
+        let mut builder = rebuild(&query("www.corp.example.com", Rtype::A)).unwrap();
+        let tran = ClientTransaction::request(Arc::new(key("test-key")), &mut builder).unwrap();
+        let resp = guarded.route(freeze(builder).unwrap(), None).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        let mut resp = Message::from_octets(resp.as_slice().to_vec()).unwrap();
+        assert!(tran.answer(&mut resp).is_ok());
+    }
+}
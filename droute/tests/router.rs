@@ -13,11 +13,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder, Rtype},
+    base::{
+        iana::{Class, Opcode, Rcode},
+        Dname, Message, MessageBuilder, Rtype,
+    },
     rdata::A,
 };
 use droute::{builders::*, errors::*, mock::Server, AsyncTryInto, QueryContext, Upstreams};
@@ -63,6 +69,11 @@ async fn test_resolve() {
                 max_pool_size: 256,
                 timeout: 10,
                 ratelimit: None,
+                bind_addr: None,
+                bind_interface: None,
+                #[cfg(feature = "tsig")]
+                tsig: None,
+                max_reuse: 1,
             },
         ),
     )
@@ -80,6 +91,154 @@ async fn test_resolve() {
     );
 }
 
+#[tokio::test]
+async fn test_minimal_any() {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232))
+        .unwrap()
+        .question();
+    builder.push((&name, Rtype::Any)).unwrap();
+    let query = builder.into_message();
+
+    // Nothing is routed, so the upstream is never reached.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53534".parse().unwrap(),
+                max_pool_size: 256,
+                timeout: 1,
+                ratelimit: None,
+                bind_addr: None,
+                bind_interface: None,
+                #[cfg(feature = "tsig")]
+                tsig: None,
+                max_reuse: 1,
+            },
+        ),
+    )
+    .with_minimal_any()
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router.resolve(query, None).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    let answer: Vec<_> = resp.answer().unwrap().map(|r| r.unwrap().rtype()).collect();
+    assert_eq!(answer, vec![Rtype::Hinfo]);
+}
+
+#[tokio::test]
+async fn test_unsupported() {
+    let query = |name: &str, class: Class, opcode: Opcode| {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+        builder.header_mut().set_opcode(opcode);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::Txt, class)).unwrap();
+        builder.into_message()
+    };
+
+    // Nothing is routed, so the upstream is never reached.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53535".parse().unwrap(),
+                max_pool_size: 256,
+                timeout: 1,
+                ratelimit: None,
+                bind_addr: None,
+                bind_interface: None,
+                #[cfg(feature = "tsig")]
+                tsig: None,
+                max_reuse: 1,
+            },
+        ),
+    )
+    .with_version("dcompass")
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router
+        .resolve(query("VERSION.bind", Class::Ch, Opcode::Query), None)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    let answer: Vec<_> = resp
+        .answer()
+        .unwrap()
+        .map(|r| r.map(|r| (r.class(), r.rtype())).unwrap())
+        .collect();
+    assert_eq!(answer, vec![(Class::Ch, Rtype::Txt)]);
+
+    let resp = router
+        .resolve(query("hostname.bind", Class::Ch, Opcode::Query), None)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::Refused);
+
+    let resp = router
+        .resolve(query("example.com", Class::In, Opcode::Notify), None)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NotImp);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_timeout() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53536").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+    // Bound but never answering
+    let _silent = UdpSocket::bind(&"127.0.0.1:53537").await.unwrap();
+
+    let router = |port: u16| {
+        RouterBuilder::new(
+            NativeScriptBuilder::new(resolve_script),
+            UpstreamsBuilder::new(1).unwrap().add_upstream(
+                "mock",
+                UdpBuilder {
+                    addr: ([127, 0, 0, 1], port).into(),
+                    max_pool_size: 256,
+                    timeout: 10,
+                    ratelimit: None,
+                    bind_addr: None,
+                    bind_interface: None,
+                    #[cfg(feature = "tsig")]
+                    tsig: None,
+                    max_reuse: 1,
+                },
+            ),
+        )
+        .with_timeout(Duration::from_millis(500))
+        .async_try_into()
+    };
+
+    // The fast upstream is unaffected
+    let resp = router(53536)
+        .await
+        .unwrap()
+        .resolve(QUERY.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(resp.into_octets(), DUMMY_MSG.clone().into_octets());
+
+    // Answered with SERVFAIL once the budget runs out rather than the timeout of the upstream
+    let start = Instant::now();
+    let resp = router(53537)
+        .await
+        .unwrap()
+        .resolve(QUERY.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
 async fn resolve_script(
     upstreams: Upstreams,
     query: Message<Bytes>,